@@ -1,38 +1,174 @@
+use zymogen::diagnostic::{Format, Renderer, Source};
 use zymogen::*;
 
 use std::env;
 use std::fs;
 use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::process;
 
 fn display_usage() {
-    let _usage = "zymogen";
+    println!("usage: zymogen [--error-format=json] [--library-path=DIR]... [FILE]...");
+    println!("       zymogen fmt [--error-format=json] [--check] [PATH]...");
+}
+
+/// Collect the files to format from a command line path: files are taken as
+/// is, directories are searched recursively for `.scm` sources
+fn collect_sources(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if path.is_dir() {
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.extension().map(|ext| ext == "scm").unwrap_or(false) {
+                collect_sources(&entry, files)?;
+            }
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// `zymogen fmt [--check] [PATH]...`
+///
+/// Rewrite files in place in canonical layout. With `--check`, files are
+/// left untouched and the exit status is 1 if any of them would change.
+/// Without paths, standard input is formatted to standard output
+fn format(renderer: Renderer, args: Vec<String>) -> io::Result<()> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let mut files = Vec::new();
+    for arg in args.iter().filter(|arg| *arg != "--check") {
+        collect_sources(Path::new(arg), &mut files)?;
+    }
+
+    if files.is_empty() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        match syntax::format(&input) {
+            Ok(ref output) if check && output != &input => process::exit(1),
+            Ok(output) if !check => print!("{}", output),
+            Ok(_) => {}
+            Err(e) => {
+                eprint!("{}", renderer.render(&e, Some(Source::new("<stdin>", &input))));
+                process::exit(2);
+            }
+        }
+        return Ok(());
+    }
+
+    let mut status = 0;
+    for file in files {
+        let input = fs::read_to_string(&file)?;
+        match syntax::format(&input) {
+            Ok(ref output) if output == &input => {}
+            Ok(_) if check => {
+                println!("would reformat {}", file.display());
+                status = status.max(1);
+            }
+            Ok(output) => fs::write(&file, output)?,
+            Err(e) => {
+                let name = file.display().to_string();
+                eprint!("{}", renderer.render(&e, Some(Source::new(&name, &input))));
+                status = 2;
+            }
+        }
+    }
+    process::exit(status)
+}
+
+/// Render an error in the forms of `source`, located by `spans`, or in the
+/// file it is located in when that is another one read by `include`
+fn render_error(
+    renderer: &Renderer,
+    e: &Error,
+    spans: &syntax::SpanTree,
+    source: Option<Source>,
+) -> String {
+    let diag = e.diagnostic(Some(spans));
+    match e.file() {
+        Some(name) => match fs::read_to_string(name) {
+            Ok(text) => renderer.render(&diag, Some(Source::new(name, &text))),
+            Err(_) => renderer.render(&diag, None),
+        },
+        None => renderer.render(&diag, source),
+    }
+}
+
+/// Render an error of a program run by `load`, in the file it is located in
+/// when that is a file read by `load`
+fn render_load_error(renderer: &Renderer, e: &Error) -> String {
+    let located = match e {
+        Error::File(name, spans, e) => Some((name, spans, (**e).clone())),
+        Error::Backtrace(calls, e) => match &**e {
+            Error::File(name, spans, e) => {
+                Some((name, spans, Error::Backtrace(calls.clone(), e.clone())))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    match located {
+        Some((name, spans, e)) => match fs::read_to_string(name) {
+            Ok(text) => render_error(renderer, &e, spans, Some(Source::new(name, &text))),
+            Err(_) => render_error(renderer, &e, spans, None),
+        },
+        None => renderer.render(&e.diagnostic(None), None),
+    }
 }
 
 fn main() -> io::Result<()> {
+    let mut renderer = Renderer::from_env();
+    let mut library_paths = Vec::new();
+    let mut args = env::args()
+        .skip(1)
+        .filter(|arg| match arg.as_ref() {
+            "--error-format=json" => {
+                renderer.format = Format::Json;
+                false
+            }
+            "--error-format=human" => false,
+            _ if arg.starts_with("--library-path=") => {
+                library_paths.push(PathBuf::from(&arg["--library-path=".len()..]));
+                false
+            }
+            _ => true,
+        })
+        .collect::<Vec<String>>()
+        .into_iter()
+        .peekable();
+    if args.peek().map(|arg| arg == "fmt").unwrap_or(false) {
+        return format(renderer, args.skip(1).collect());
+    }
+
     let mut inputs = Vec::new();
-    for arg in env::args().skip(1) {
+    for arg in args {
         match arg.as_ref() {
             "--help" | "-h" => display_usage(),
-            _ => match fs::read_to_string(&arg) {
-                Ok(data) => inputs.push(data),
-                Err(e) => panic!("Error encountered while trying to read {}: {}", arg, e),
-            },
+            _ => inputs.push(PathBuf::from(arg)),
         }
     }
-    println!("zymogen interpreter");
-    for s in inputs {
-        let sexprs = match syntax::parse(&s.trim()) {
-            Ok(tokens) => tokens,
+    // Only for humans, tools asking for JSON parse what is printed
+    if renderer.format == Format::Human {
+        println!("zymogen interpreter");
+    }
+    let mut machine = compiler::cps::Machine::new();
+    for dir in library_paths {
+        machine.expander().add_library_path(dir);
+    }
+    machine.expander().add_library_path(".");
+    let mut table = compiler::SymbolTable::new();
+    // Programs are run as `load` runs them, so the files they load are
+    // relative to them
+    for path in inputs {
+        match machine.load(&path, &mut table) {
+            Ok(value) => println!("===> {}", value),
             Err(e) => {
-                eprintln!("{}", e);
-                return Ok(());
+                eprint!("{}", render_load_error(&renderer, &e));
+                process::exit(1);
             }
-        };
-        let mut last = None;
-        for exp in sexprs {
-            last = Some(compiler::desugar(compiler::analyze(exp).unwrap()));
         }
-        println!("===> {}", last.unwrap());
     }
 
     println!("REPL mode:");
@@ -40,12 +176,14 @@ fn main() -> io::Result<()> {
     let stdin = io::stdin();
     let mut handle = stdin.lock();
 
-    let mut table = compiler::SymbolTable::new();
     loop {
         let mut indent: u32 = 0;
         let mut depth: i32 = 0;
         loop {
-            handle.read_line(&mut buffer)?;
+            // The end of the input ends the session
+            if handle.read_line(&mut buffer)? == 0 {
+                return Ok(());
+            }
             let left = buffer.matches('(').count() as i32;
             let right = buffer.matches(')').count() as i32;
             if left <= right && !buffer.trim().is_empty() {
@@ -63,32 +201,20 @@ fn main() -> io::Result<()> {
             print!("{}", (0..indent).map(|_| ' ').collect::<String>());
             io::stdout().flush()?;
         }
-        let sexprs = match syntax::parse(&buffer.trim()) {
-            Ok(tokens) => tokens,
+        let source = Some(Source::new("<stdin>", &buffer));
+        let sexprs = match syntax::parse_spanned(&buffer) {
+            Ok(sexprs) => sexprs,
             Err(e) => {
-                eprint!("{}", e);
+                eprint!("{}", renderer.render(&e, source));
                 break;
             }
         };
 
         let mut last = None;
-        for exp in sexprs {
-            let base = format!("{}", exp);
-            match compiler::analyze(exp) {
-                Ok(exp) => {
-                    let e = compiler::normalize_expr(
-                        compiler::desugar(exp),
-                        &mut table,
-                    );
-                    println!("{}", &e);
-                    let mut com = compiler::Context::from(&mut table);
-                    com.compile(e);
-                    last = Some(format!("{:#?}", com));
-                }
-                Err(e) => {
-                    println!("Error {:?} during analysis of expression `{}`", e, base);
-                    return Ok(());
-                }
+        for (exp, spans) in sexprs {
+            match machine.eval(&exp, &mut table) {
+                Ok(value) => last = Some(value),
+                Err(e) => eprint!("{}", render_error(&renderer, &e, &spans, source)),
             }
         }
         if let Some(exp) = last {
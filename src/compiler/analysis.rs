@@ -1,180 +1,651 @@
 //! Parse from raw Sexps to the HIR abstract syntax tree
+//!
+//! Special forms are destructured with [`Pattern`]s, so that a malformed
+//! form is reported as an [`Error::Syntax`] pointing at the offending part
 use super::ir::hir::{Expression::*, *};
-use super::sexp::{List, Sexp, Ty};
+use super::sexp::pattern::{Bindings, Mismatch, Pattern};
+use super::sexp::{Keyword as Kw, List, Sexp};
 use super::*;
+use std::sync::OnceLock;
 
-fn analyze_lambda(exprs: List) -> Result<Expression, Error> {
-    let (args, body) = exprs.unpack()?;
-    let body = analyze_sequence(body)?;
-    let mut params = Vec::new();
-    let mut rest = None;
-
-    match args {
-        Sexp::List(List::Nil) => {}
-        Sexp::List(inner) => {
-            let mut iter = inner.into_iter();
-            loop {
-                let n = iter.next();
-                match n {
-                    Some(Sexp::Keyword(sexp::Keyword::Dot)) => {
-                        rest = Some(iter.next().ok_or(Error::Arity)?.ident()?);
-                    }
-                    Some(Sexp::Identifier(s)) => {
-                        params.push(s);
+/// Patterns for the shapes of a special form, compiled on first use
+macro_rules! patterns {
+    ($($source:expr),+ $(,)?) => {{
+        static PATTERNS: OnceLock<Vec<Pattern>> = OnceLock::new();
+        PATTERNS.get_or_init(|| vec![$(Pattern::new($source)),+]).as_slice()
+    }};
+}
+
+/// Destructure the special form `kw` with the first matching pattern
+fn destructure<'a>(
+    kw: Kw,
+    patterns: &[Pattern],
+    form: &'a Sexp,
+) -> Result<(usize, Bindings<'a>), Error> {
+    Pattern::match_first(patterns, form).map_err(|m| Error::Syntax(kw, m))
+}
+
+/// Path from `form` to `child`, one of its sub-expressions
+fn path_to(form: &Sexp, child: &Sexp) -> Option<Vec<usize>> {
+    if std::ptr::eq(form, child) {
+        return Some(Vec::new());
+    }
+    match form {
+        Sexp::List(list) => list.iter().enumerate().find_map(|(idx, elem)| {
+            let mut path = path_to(elem, child)?;
+            path.insert(0, idx);
+            Some(path)
+        }),
+        _ => None,
+    }
+}
+
+/// Analyze `child`, a sub-expression of `form`, locating its errors within
+/// `form`
+fn analyze_in(form: &Sexp, child: &Sexp) -> Result<Expression, Error> {
+    located(form, child, analyze_expr(child))
+}
+
+/// Locate the error of a `result` computed from `child` within `form`
+fn located<T>(form: &Sexp, child: &Sexp, result: Result<T, Error>) -> Result<T, Error> {
+    result.map_err(|e| match path_to(form, child) {
+        Some(path) if !path.is_empty() => e.within(path),
+        _ => e,
+    })
+}
+
+/// A syntax error in the special form `kw`, at its sub-expression `at`
+fn syntax_error(kw: Kw, form: &Sexp, at: &Sexp, expected: &str) -> Error {
+    Error::Syntax(
+        kw,
+        Mismatch {
+            path: path_to(form, at).unwrap_or_default(),
+            expected: expected.into(),
+            found: Some(at.write().to_string()),
+        },
+    )
+}
+
+/// Reject identifiers bound twice by the same form
+fn distinct(kw: Kw, form: &Sexp, names: Vec<&Sexp>) -> Result<(), Error> {
+    for (idx, name) in names.iter().enumerate() {
+        if names[..idx].contains(name) {
+            return Err(syntax_error(
+                kw,
+                form,
+                name,
+                "an identifier that is not already bound by this form",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn analyze_body(form: &Sexp, exprs: Vec<&Sexp>) -> Result<Sequence, Error> {
+    exprs.into_iter().map(|e| analyze_in(form, e)).collect()
+}
+
+fn is_definition(form: &Sexp) -> bool {
+    matches!(
+        form,
+        Sexp::List(List::Cons(car, _))
+            if matches!(
+                **car,
+                Sexp::Keyword(Kw::Define)
+                    | Sexp::Keyword(Kw::DefineRecordType)
+                    | Sexp::Keyword(Kw::DefineValues)
+            )
+    )
+}
+
+/// Identifiers defined by a well-formed definition
+fn defined_names(form: &Sexp) -> Vec<&Sexp> {
+    let elems = match form.as_list() {
+        Ok(list) => list.iter().collect::<Vec<_>>(),
+        Err(_) => return Vec::new(),
+    };
+    match elems[..] {
+        [Sexp::Keyword(Kw::DefineRecordType), name, ctor, pred, ref fields @ ..] => {
+            let mut names = vec![name];
+            names.extend(ctor.as_list().and_then(List::car).ok());
+            names.push(pred);
+            for field in fields {
+                names.extend(field.as_list().iter().flat_map(|f| f.iter().skip(1)));
+            }
+            names
+        }
+        [Sexp::Keyword(Kw::DefineValues), Sexp::List(formals), ..] => formals
+            .iter()
+            .filter(|f| matches!(f, Sexp::Identifier(_)))
+            .collect(),
+        [_, Sexp::List(List::Cons(name, _)), ..] => vec![name],
+        [_, name, ..] => vec![name],
+        _ => Vec::new(),
+    }
+}
+
+/// Variables defined by a top level form, within its `begin` forms
+pub(super) fn toplevel_names(form: &Sexp) -> Vec<&Sexp> {
+    match form {
+        Sexp::List(List::Cons(car, cdr)) if **car == Sexp::Keyword(Kw::Begin) => {
+            cdr.iter().flat_map(toplevel_names).collect()
+        }
+        _ if is_definition(form) => defined_names(form),
+        _ => Vec::new(),
+    }
+}
+
+/// Analyze the body of a lambda or let, turning its leading internal
+/// definitions into a `letrec*` scope around the expressions that follow
+fn analyze_scope(kw: Kw, form: &Sexp, exprs: Vec<&Sexp>) -> Result<Sequence, Error> {
+    let split = exprs
+        .iter()
+        .position(|e| !is_definition(e))
+        .unwrap_or(exprs.len());
+    let (defs, rest) = exprs.split_at(split);
+    if defs.is_empty() {
+        return analyze_body(form, exprs);
+    }
+    if rest.is_empty() {
+        let mut path = path_to(form, defs[split - 1]).unwrap_or_default();
+        if let Some(last) = path.last_mut() {
+            *last += 1;
+        }
+        let mismatch = Mismatch {
+            path,
+            expected: "an expression after the internal definitions".into(),
+            found: None,
+        };
+        return Err(Error::Syntax(kw, mismatch));
+    }
+
+    let mut bind = Vec::new();
+    for def in defs {
+        match located(form, def, analyze_definition(def))? {
+            Expression::Assignment(var, expr) => {
+                // The procedures of a record type are bound here, and
+                // assigned when its descriptor is evaluated
+                if let Expression::DefineRecordType(rt) = &*expr {
+                    for proc in record_procedures(rt) {
+                        let expr = Expression::Literal(Value::Nil);
+                        bind.push(Definition::Single(LetBindings {
+                            var: proc.to_string(),
+                            expr,
+                        }));
                     }
-                    Some(exp) => return Err(Error::WrongType(Ty::Identifier, exp.ty())),
-                    None => break,
                 }
+                bind.push(Definition::Single(LetBindings { var, expr: *expr }))
             }
+            Expression::DefineValues(def) => bind.push(Definition::Values(*def)),
+            _ => unreachable!("definitions are analyzed into assignments"),
         }
-        Sexp::Identifier(rest_id) => {
-            rest = Some(rest_id);
-        }
-        _ => return Err(Error::WrongType(Ty::Identifier, args.ty())),
     }
-    Ok(Expression::Lambda(LambdaExpr {
-        args: params,
+    distinct(
+        kw,
+        form,
+        defs.iter().flat_map(|d| defined_names(d)).collect(),
+    )?;
+    let body = analyze_body(form, rest.to_vec())?;
+    Ok(vec![Expression::Let(LetExpr::LetRecStar(bind, body))])
+}
+
+/// Build a lambda from the `id`, `rest-id` and `body` pattern variables
+fn lambda(kw: Kw, form: &Sexp, m: &Bindings) -> Result<LambdaExpr, Error> {
+    let (args, rest) = formals(kw, form, m)?;
+    Ok(LambdaExpr {
+        args,
         rest,
-        body,
-    }))
+        body: analyze_scope(kw, form, m.all("body"))?,
+    })
+}
+
+/// Distinct variables of the `id` and `rest-id` pattern variables
+fn formals(kw: Kw, form: &Sexp, m: &Bindings) -> Result<(Vec<String>, Option<String>), Error> {
+    let mut params = match m.binding("id") {
+        Some(_) => m.all("id"),
+        None => Vec::new(),
+    };
+    params.extend(m.opt("rest-id"));
+    distinct(kw, form, params)?;
+    let args = match m.binding("id") {
+        Some(_) => m.idents("id").into_iter().map(String::from).collect(),
+        None => Vec::new(),
+    };
+    Ok((
+        args,
+        m.opt("rest-id").map(|_| m.ident("rest-id").to_string()),
+    ))
 }
 
-fn analyze_let_bindings(exprs: List) -> Vec<LetBindings> {
-    exprs
+fn analyze_lambda(form: &Sexp) -> Result<Expression, Error> {
+    let patterns = patterns![
+        "(lambda (id ...) body ...+)",
+        "(lambda (id ... . rest-id) body ...+)",
+        "(lambda rest-id body ...+)",
+    ];
+    let (_, m) = destructure(Kw::Lambda, patterns, form)?;
+    Ok(Expression::Lambda(lambda(Kw::Lambda, form, &m)?))
+}
+
+fn analyze_case_lambda(form: &Sexp) -> Result<Expression, Error> {
+    let (_, m) = destructure(Kw::CaseLambda, patterns!["(case-lambda clause ...)"], form)?;
+    let clause_patterns = patterns![
+        "((id ...) body ...+)",
+        "((id ... . rest-id) body ...+)",
+        "(rest-id body ...+)",
+    ];
+    let mut clauses = Vec::new();
+    for (idx, clause) in m.all("clause").into_iter().enumerate() {
+        let (_, c) = Pattern::match_first(clause_patterns, clause)
+            .map_err(|e| Error::Syntax(Kw::CaseLambda, e.within(idx + 1)))?;
+        clauses.push(lambda(Kw::CaseLambda, form, &c)?);
+    }
+    Ok(Expression::CaseLambda(clauses))
+}
+
+fn let_bindings(form: &Sexp, m: &Bindings) -> Result<Vec<LetBindings>, Error> {
+    m.idents("id")
         .into_iter()
-        .filter_map(|bind| match bind {
-            Sexp::List(List::Nil) => None,
-            Sexp::List(l) => {
-                let (car, cadr, _) = l.unpack2().ok()?;
-                Some(LetBindings {
-                    var: car.ident().ok()?,
-                    expr: analyze(cadr).ok()?,
-                })
-            }
-            _ => None,
+        .zip(m.all("expr"))
+        .map(|(var, expr)| {
+            Ok(LetBindings {
+                var: var.to_string(),
+                expr: analyze_in(form, expr)?,
+            })
         })
         .collect()
 }
 
-fn analyze_let(exprs: List) -> Result<Expression, Error> {
-    if let Sexp::Identifier(_) = exprs.car()? {
-        return analyze_namedlet(exprs);
+fn analyze_let(kw: Kw, form: &Sexp) -> Result<Expression, Error> {
+    let patterns = match kw {
+        Kw::Let => patterns![
+            "(let ((id expr) ...) body ...+)",
+            "(let name-id ((id expr) ...) body ...+)",
+        ],
+        Kw::Letstar => patterns!["(let* ((id expr) ...) body ...+)"],
+        _ => patterns!["(letrec ((id expr) ...) body ...+)"],
+    };
+    let (_, m) = destructure(kw, patterns, form)?;
+    if kw != Kw::Letstar {
+        distinct(kw, form, m.all("id"))?;
     }
-    let (bindings, body) = exprs.unpack()?;
-    let bind = analyze_let_bindings(bindings.list()?);
-    let body = analyze_sequence(body)?;
-    Ok(Expression::Let(LetExpr::Let(bind, body)))
+    let bind = let_bindings(form, &m)?;
+    let body = analyze_scope(kw, form, m.all("body"))?;
+    Ok(Expression::Let(match m.opt("name-id") {
+        Some(_) => LetExpr::NamedLet(m.ident("name-id").to_string(), bind, body),
+        None if kw == Kw::Letrec => LetExpr::LetRec(bind, body),
+        None if kw == Kw::Letstar => LetExpr::LetStar(bind, body),
+        None => LetExpr::Let(bind, body),
+    }))
+}
+
+/// Build the bindings of the `id` and `rest-id` pattern variables to the
+/// values of `expr`
+fn values_bindings(kw: Kw, form: &Sexp, m: &Bindings) -> Result<ValuesBindings, Error> {
+    let (args, rest) = formals(kw, form, m)?;
+    Ok(ValuesBindings {
+        args,
+        rest,
+        expr: analyze_in(form, m.get("expr"))?,
+    })
 }
 
-fn analyze_letrec(exprs: List) -> Result<Expression, Error> {
-    if let Sexp::Identifier(_) = exprs.car()? {
-        return analyze_namedlet(exprs);
+fn analyze_let_values(kw: Kw, form: &Sexp) -> Result<Expression, Error> {
+    let patterns = match kw {
+        Kw::LetValues => patterns!["(let-values (binding ...) body ...+)"],
+        _ => patterns!["(let*-values (binding ...) body ...+)"],
+    };
+    let (_, m) = destructure(kw, patterns, form)?;
+    let binding_patterns = patterns![
+        "((id ...) expr)",
+        "((id ... . rest-id) expr)",
+        "(rest-id expr)",
+    ];
+    let mut bind = Vec::new();
+    let mut vars = Vec::new();
+    for (idx, binding) in m.all("binding").into_iter().enumerate() {
+        let (_, b) = Pattern::match_first(binding_patterns, binding)
+            .map_err(|e| Error::Syntax(kw, e.within(idx).within(1)))?;
+        if b.binding("id").is_some() {
+            vars.extend(b.all("id"));
+        }
+        vars.extend(b.opt("rest-id"));
+        bind.push(values_bindings(kw, form, &b)?);
     }
-    let (bindings, body) = exprs.unpack()?;
-    let bind = analyze_let_bindings(bindings.list()?);
-    let body = analyze_sequence(body)?;
-    Ok(Expression::Let(LetExpr::LetRec(bind, body)))
+    if kw == Kw::LetValues {
+        distinct(kw, form, vars)?;
+    }
+    let body = analyze_scope(kw, form, m.all("body"))?;
+    Ok(Expression::Let(match kw {
+        Kw::LetValues => LetExpr::LetValues(bind, body),
+        _ => LetExpr::LetStarValues(bind, body),
+    }))
+}
+
+fn analyze_call(form: &Sexp, func: Expression, args: &List) -> Result<Expression, Error> {
+    let rands = analyze_body(form, args.iter().collect())?;
+    Ok(Expression::Call(Box::new(func), rands))
+}
+
+fn analyze_if(form: &Sexp) -> Result<Expression, Error> {
+    let patterns = patterns!["(if test csq)", "(if test csq alt)"];
+    let (_, m) = destructure(Kw::If, patterns, form)?;
+    let alt = match m.opt("alt") {
+        Some(alt) => Some(Box::new(analyze_in(form, alt)?)),
+        None => None,
+    };
+    Ok(Expression::If(
+        Box::new(analyze_in(form, m.get("test"))?),
+        Box::new(analyze_in(form, m.get("csq"))?),
+        alt,
+    ))
 }
 
-fn analyze_namedlet(exprs: List) -> Result<Expression, Error> {
-    let (name, bindings, body) = exprs.unpack2()?;
-    let name = name.ident()?;
-    let bind = analyze_let_bindings(bindings.list()?);
-    let body = analyze_sequence(body)?;
+fn analyze_cond(form: &Sexp) -> Result<Expression, Error> {
+    let (_, m) = destructure(Kw::Cond, patterns!["(cond clause ...+)"], form)?;
+    analyze_cond_clauses(Kw::Cond, form, m.all("clause"))
+}
+
+/// Analyze the `cond` clauses of the `kw` expression `form`, the last of
+/// which may be an `else` clause
+fn analyze_cond_clauses(kw: Kw, form: &Sexp, clauses: Vec<&Sexp>) -> Result<Expression, Error> {
+    let clause_patterns = patterns!["(else body ...+)", "(test '=> expr)", "(test body ...)"];
+    let mut conds = Vec::new();
+    let mut else_clause = None;
+    for (idx, clause) in clauses.iter().enumerate() {
+        let (which, c) = Pattern::match_first(clause_patterns, clause)
+            .and_then(|(which, c)| match which {
+                // `=>` is not a variable to evaluate in a body
+                2 if c.all("body").first() == Some(&&Sexp::Identifier("=>".into())) => {
+                    clause_patterns[1].matches(clause).map(|c| (1, c))
+                }
+                _ => Ok((which, c)),
+            })
+            .map_err(|e| {
+                let path = path_to(form, clause).unwrap_or_default();
+                let e = path.into_iter().rev().fold(e, Mismatch::within);
+                Error::Syntax(kw, e)
+            })?;
+        if which > 0 {
+            let body = match which {
+                1 => CondBody::Arrow(Box::new(analyze_in(form, c.get("expr"))?)),
+                _ => CondBody::Sequence(analyze_body(form, c.all("body"))?),
+            };
+            conds.push(CondClause {
+                test: Box::new(analyze_in(form, c.get("test"))?),
+                body,
+            });
+        } else if idx + 1 == clauses.len() {
+            else_clause = Some(analyze_body(form, c.all("body"))?);
+        } else {
+            let expected = "the end of the list after an `else` clause";
+            return Err(syntax_error(kw, form, clauses[idx + 1], expected));
+        }
+    }
+    Ok(Expression::Cond(conds, else_clause))
+}
 
-    Ok(Expression::Let(LetExpr::NamedLet(name, bind, body)))
+fn analyze_guard(form: &Sexp) -> Result<Expression, Error> {
+    let (_, m) = destructure(
+        Kw::Guard,
+        patterns!["(guard (var-id clause ...+) body ...+)"],
+        form,
+    )?;
+    let handler = analyze_cond_clauses(Kw::Guard, form, m.all("clause"))?;
+    Ok(Expression::Guard(
+        m.ident("var-id").to_string(),
+        Box::new(handler),
+        analyze_scope(Kw::Guard, form, m.all("body"))?,
+    ))
 }
 
-fn analyze_call(func: Expression, exprs: List) -> Result<Expression, Error> {
-    if exprs == List::Nil {
-        Ok(Expression::Call(Box::new(func), vec![]))
-    } else {
-        let rands = analyze_sequence(exprs)?;
-        Ok(Expression::Call(Box::new(func), rands))
+fn analyze_parameterize(form: &Sexp) -> Result<Expression, Error> {
+    let (_, m) = destructure(
+        Kw::Parameterize,
+        patterns!["(parameterize ((param value) ...) body ...+)"],
+        form,
+    )?;
+    let mut bind = Vec::new();
+    for (param, value) in m.all("param").into_iter().zip(m.all("value")) {
+        bind.push((analyze_in(form, param)?, analyze_in(form, value)?));
     }
+    let body = analyze_scope(Kw::Parameterize, form, m.all("body"))?;
+    Ok(Expression::Parameterize(bind, body))
 }
 
-fn analyze_if(exprs: List) -> Result<Expression, Error> {
-    let (test, csq, alt) = exprs.unpack2()?;
-    let test = Box::new(analyze(test)?);
-    let csq = Box::new(analyze(csq)?);
-    let alt = match alt.unpack() {
-        Ok((sexp, _)) => Some(Box::new(analyze(sexp)?)),
-        Err(_) => None,
+fn analyze_when(kw: Kw, form: &Sexp) -> Result<Expression, Error> {
+    let patterns = match kw {
+        Kw::When => patterns!["(when test body ...+)"],
+        _ => patterns!["(unless test body ...+)"],
     };
-    Ok(Expression::If(test, csq, alt))
+    let (_, m) = destructure(kw, patterns, form)?;
+    let test = Box::new(analyze_in(form, m.get("test"))?);
+    let body = analyze_body(form, m.all("body"))?;
+    Ok(match kw {
+        Kw::When => Expression::When(test, body),
+        _ => Expression::Unless(test, body),
+    })
 }
 
-fn analyze_cond(exprs: List) -> Result<Expression, Error> {
-    let mut clauses = Vec::new();
+fn analyze_case(form: &Sexp) -> Result<Expression, Error> {
+    let (_, m) = destructure(Kw::Case, patterns!["(case key clause ...+)"], form)?;
+    let clause_patterns = patterns![
+        "(else '=> expr)",
+        "(else body ...+)",
+        "((datum ...) '=> expr)",
+        "((datum ...) body ...+)",
+    ];
+    let clauses = m.all("clause");
+    let mut cases = Vec::new();
     let mut else_clause = None;
-    let mut next = exprs;
-    while let Ok((car, cdr)) = next.unpack() {
-        if let Ok((test, body)) = car.list()?.unpack() {
-            match test {
-                Sexp::Keyword(sexp::Keyword::Else) => {
-                    else_clause = Some(analyze_sequence(body)?);
-                    break;
+    for (idx, clause) in clauses.iter().enumerate() {
+        let (which, c) = Pattern::match_first(clause_patterns, clause)
+            .and_then(|(which, c)| match which {
+                // `=>` is not a variable to evaluate in a body
+                1 | 3 if c.all("body")[0] == &Sexp::Identifier("=>".into()) => {
+                    let arrow = &clause_patterns[which - 1];
+                    arrow.matches(clause).map(|c| (which - 1, c))
                 }
-                _ => clauses.push(CondClause {
-                    test: Box::new(analyze(test)?),
-                    body: analyze_sequence(body)?,
-                }),
-            }
+                _ => Ok((which, c)),
+            })
+            .map_err(|e| Error::Syntax(Kw::Case, e.within(idx + 2)))?;
+        let body = match which % 2 {
+            0 => CaseBody::Arrow(Box::new(analyze_in(form, c.get("expr"))?)),
+            _ => CaseBody::Sequence(analyze_body(form, c.all("body"))?),
+        };
+        if which >= 2 {
+            cases.push(CaseClause {
+                data: c
+                    .all("datum")
+                    .into_iter()
+                    .map(analyze_quote)
+                    .collect::<Result<_, _>>()?,
+                body,
+            });
+        } else if idx + 1 == clauses.len() {
+            else_clause = Some(body);
         } else {
-            break;
+            let mismatch = Mismatch {
+                path: vec![idx + 3],
+                expected: "the end of the list after an `else` clause".into(),
+                found: Some(clauses[idx + 1].write().to_string()),
+            };
+            return Err(Error::Syntax(Kw::Case, mismatch));
         }
-        next = cdr;
     }
+    Ok(Expression::Case(
+        Box::new(analyze_in(form, m.get("key"))?),
+        cases,
+        else_clause,
+    ))
+}
 
-    Ok(Expression::Cond(clauses, else_clause))
+fn analyze_do(form: &Sexp) -> Result<Expression, Error> {
+    let patterns = patterns!["(do (binding ...) (test result ...) body ...)"];
+    let (_, m) = destructure(Kw::Do, patterns, form)?;
+    let binding_patterns = patterns!["(id init)", "(id init step)"];
+    let mut bind = Vec::new();
+    let mut vars = Vec::new();
+    for (idx, binding) in m.all("binding").into_iter().enumerate() {
+        let (_, b) = Pattern::match_first(binding_patterns, binding)
+            .map_err(|e| Error::Syntax(Kw::Do, e.within(idx).within(1)))?;
+        vars.push(b.get("id"));
+        bind.push(DoBinding {
+            var: b.ident("id").to_string(),
+            init: analyze_in(form, b.get("init"))?,
+            step: match b.opt("step") {
+                Some(step) => Some(analyze_in(form, step)?),
+                None => None,
+            },
+        });
+    }
+    distinct(Kw::Do, form, vars)?;
+    Ok(Expression::Do(DoExpr {
+        bind,
+        test: Box::new(analyze_in(form, m.get("test"))?),
+        result: analyze_body(form, m.all("result"))?,
+        body: analyze_body(form, m.all("body"))?,
+    }))
 }
 
-fn analyze_assignment(exprs: List) -> Result<Expression, Error> {
-    let (var, exp, _) = exprs.unpack2()?;
+fn analyze_assignment(form: &Sexp) -> Result<Expression, Error> {
+    let (_, m) = destructure(Kw::Set, patterns!["(set! id expr)"], form)?;
     Ok(Expression::Assignment(
-        var.ident()?,
-        Box::new(analyze(exp)?),
+        m.ident("id").to_string(),
+        Box::new(analyze_in(form, m.get("expr"))?),
     ))
 }
 
-fn analyze_define(exprs: List) -> Result<Expression, Error> {
-    let (var, rest) = exprs.unpack()?;
+fn analyze_define(form: &Sexp) -> Result<Expression, Error> {
+    let patterns = patterns![
+        "(define name-id expr)",
+        "(define (name-id id ...) body ...+)",
+        "(define (name-id id ... . rest-id) body ...+)",
+    ];
+    let (which, m) = destructure(Kw::Define, patterns, form)?;
+    let value = match which {
+        0 => analyze_in(form, m.get("expr"))?,
+        _ => Expression::Lambda(lambda(Kw::Define, form, &m)?),
+    };
+    Ok(Expression::Assignment(
+        m.ident("name-id").to_string(),
+        Box::new(value),
+    ))
+}
 
-    match var {
-        Sexp::List(List::Cons(f, args)) => {
-            // Easiest way to handle this is to construct a mock lambda body
-            // and then pass to the analyze_lambda function
-            let lambda_body = List::Cons(Box::new(Sexp::List(*args)), Box::new(rest));
+/// Analyze a definition into the assignment of the variable it defines,
+/// or of the values of `define-values`
+fn analyze_definition(form: &Sexp) -> Result<Expression, Error> {
+    match form.as_list().and_then(List::car) {
+        Ok(Sexp::Keyword(Kw::DefineValues)) => {
+            let patterns = patterns![
+                "(define-values (id ...) expr)",
+                "(define-values (id ... . rest-id) expr)",
+                "(define-values rest-id expr)",
+            ];
+            let (_, m) = destructure(Kw::DefineValues, patterns, form)?;
+            Ok(Expression::DefineValues(Box::new(values_bindings(
+                Kw::DefineValues,
+                form,
+                &m,
+            )?)))
+        }
+        Ok(Sexp::Keyword(Kw::DefineRecordType)) => {
+            let rt = analyze_record_type(form)?;
             Ok(Expression::Assignment(
-                f.as_ident()?.clone(),
-                Box::new(analyze_lambda(lambda_body)?),
+                rt.name.clone(),
+                Box::new(Expression::DefineRecordType(rt)),
             ))
         }
+        _ => analyze_define(form),
+    }
+}
+
+fn analyze_record_type(form: &Sexp) -> Result<RecordType, Error> {
+    let kw = Kw::DefineRecordType;
+    let patterns = patterns!["(define-record-type name-id (ctor-id arg-id ...) pred-id field ...)"];
+    let (_, m) = destructure(kw, patterns, form)?;
+    let field_patterns = patterns![
+        "(field-id accessor-id)",
+        "(field-id accessor-id modifier-id)"
+    ];
+    let mut names = Vec::new();
+    let mut fields = Vec::new();
+    for (idx, field) in m.all("field").into_iter().enumerate() {
+        let (_, f) = Pattern::match_first(field_patterns, field)
+            .map_err(|e| Error::Syntax(kw, e.within(idx + 4)))?;
+        if names.contains(&f.get("field-id")) {
+            let expected = "a field that is not already declared";
+            return Err(syntax_error(kw, form, f.get("field-id"), expected));
+        }
+        names.push(f.get("field-id"));
+        fields.push(RecordField {
+            name: f.ident("field-id").to_string(),
+            accessor: f.ident("accessor-id").to_string(),
+            modifier: f
+                .opt("modifier-id")
+                .map(|_| f.ident("modifier-id").to_string()),
+        });
+    }
+    let args = m.all("arg-id");
+    distinct(kw, form, args.clone())?;
+    for arg in args {
+        if !names.contains(&arg) {
+            return Err(syntax_error(kw, form, arg, "a field of the record type"));
+        }
+    }
+    Ok(RecordType {
+        name: m.ident("name-id").to_string(),
+        constructor: (
+            m.ident("ctor-id").to_string(),
+            m.idents("arg-id").into_iter().map(String::from).collect(),
+        ),
+        predicate: m.ident("pred-id").to_string(),
+        fields,
+    })
+}
 
-        Sexp::Identifier(s) => Ok(Expression::Assignment(
-            s,
-            Box::new(analyze(rest.unpack()?.0)?),
-        )),
-        x => Err(Error::WrongType(Ty::Identifier, x.ty())),
+/// Variables of the procedures of a record type
+fn record_procedures(rt: &RecordType) -> Vec<&str> {
+    let mut procs = vec![rt.constructor.0.as_str(), rt.predicate.as_str()];
+    for field in &rt.fields {
+        procs.push(&field.accessor);
+        procs.extend(field.modifier.as_deref());
     }
+    procs
 }
 
-fn analyze_quote(exp: Sexp) -> Result<Expression, Error> {
+fn analyze_quote(exp: &Sexp) -> Result<Expression, Error> {
     Ok(match exp {
-        Sexp::List(List::Cons(car, cdr)) => Expression::Call(
-            Box::new(Expression::Variable("cons".to_string())),
-            vec![analyze_quote(*car)?, analyze_quote(Sexp::List(*cdr))?],
-        ),
-        Sexp::List(List::Nil) => Expression::Quotation(Value::Nil),
-        Sexp::Identifier(s) => Expression::Quotation(Value::Str(s)),
-        Sexp::Literal(s) => Expression::Literal(Value::Str(s)),
-        Sexp::Integer(i) => Expression::Literal(Value::Int(i)),
-        Sexp::Boolean(b) => Expression::Literal(Value::Bool(b)),
-        Sexp::Keyword(kw) => Expression::Quotation(Value::Str(format!("{:?}", kw).to_lowercase())),
+        Sexp::Literal(_) | Sexp::Integer(_) | Sexp::Boolean(_) => Expression::Literal(datum(exp)),
+        _ => Expression::Quotation(datum(exp)),
     })
 }
 
+/// Constant value of a quoted datum. The `.` of a dotted list is the one
+/// before its last element
+fn datum(exp: &Sexp) -> Value {
+    match exp {
+        Sexp::List(list) => {
+            let elems = list.iter().collect::<Vec<_>>();
+            let n = elems.len();
+            let (elems, tail) = match n >= 3 && *elems[n - 2] == Sexp::Keyword(Kw::Dot) {
+                true => (&elems[..n - 2], datum(elems[n - 1])),
+                false => (&elems[..], Value::Nil),
+            };
+            elems
+                .iter()
+                .rev()
+                .fold(tail, |cdr, car| Value::cons(datum(car), cdr))
+        }
+        Sexp::Identifier(s) => Value::Symbol(Symbol::intern(s)),
+        Sexp::Literal(s) => Value::Str(s.clone()),
+        Sexp::Integer(i) => Value::Int(*i),
+        Sexp::Boolean(b) => Value::Bool(*b),
+        Sexp::Keyword(kw) => Value::Symbol(Symbol::intern(kw.name())),
+    }
+}
+
 #[inline]
 /// Helper function to eliminate [`List`] datatype by transforming it into calls
 /// to "cons"
@@ -185,135 +656,717 @@ fn mock_call(func_name: &str, args: Vec<Expression>) -> Result<Expression, Error
     ))
 }
 
-/// Desugar and eliminate quasiquote forms
-fn analyze_quasiquote(depth: u32, qqexp: Sexp) -> Result<Expression, Error> {
-    match &qqexp {
-        Sexp::List(List::Cons(_, _)) => {
-            let (car, cadr) = qqexp.list()?.unpack()?;
-            match &car {
-                Sexp::Keyword(sexp::Keyword::Unquote) => {
-                    // car is Unquote
-                    if depth == 1 {
-                        analyze(cadr.unpack()?.0)
-                    } else {
-                        mock_call(
-                            "cons",
-                            vec![
-                                analyze_quote(car)?,
-                                analyze_quasiquote(depth - 1, Sexp::List(cadr))?,
-                            ],
-                        )
-                    }
-                }
-                Sexp::Keyword(sexp::Keyword::Quasiquote) => mock_call(
-                    "list",
+/// Desugar and eliminate quasiquote forms, within the quasiquote `form`
+fn analyze_quasiquote(form: &Sexp, depth: u32, qqexp: &Sexp) -> Result<Expression, Error> {
+    match qqexp {
+        Sexp::List(list) => quasiquote_list(form, depth, list),
+        _ => analyze_quote(qqexp),
+    }
+}
+
+fn quasiquote_list(form: &Sexp, depth: u32, list: &List) -> Result<Expression, Error> {
+    let (car, cdr) = match list {
+        List::Cons(car, cdr) => (&**car, &**cdr),
+        List::Nil => return Ok(Expression::Quotation(Value::Nil)),
+    };
+    match car {
+        Sexp::Keyword(Kw::Unquote) if depth == 1 => analyze_in(form, unquoted(form, car, cdr)?),
+        Sexp::Keyword(Kw::Unquote) => mock_call(
+            "cons",
+            vec![analyze_quote(car)?, quasiquote_list(form, depth - 1, cdr)?],
+        ),
+        Sexp::Keyword(Kw::Quasiquote) => mock_call(
+            "list",
+            vec![analyze_quote(car)?, quasiquote_list(form, depth + 1, cdr)?],
+        ),
+        Sexp::List(List::Cons(caar, cdar)) if **caar == Sexp::Keyword(Kw::UnquoteAt) => {
+            let spliced = unquoted(form, caar, cdar)?;
+            if depth == 1 {
+                mock_call(
+                    "append",
                     vec![
-                        analyze_quote(car)?,
-                        analyze_quasiquote(depth + 1, Sexp::List(cadr))?,
+                        analyze_in(form, spliced)?,
+                        quasiquote_list(form, depth, cdr)?,
                     ],
-                ),
-                Sexp::List(List::Cons(caar, _)) => {
-                    if **caar == Sexp::Keyword(sexp::Keyword::UnquoteAt) {
-                        let (caar, cdar, _) = car.list()?.unpack2()?;
-                        if depth == 1 {
-                            mock_call(
-                                "append",
-                                vec![analyze(cdar)?, analyze_quasiquote(depth, Sexp::List(cadr))?],
-                            )
-                        } else {
-                            mock_call(
-                                "cons",
-                                vec![
-                                    mock_call(
-                                        "cons",
-                                        vec![
-                                            analyze_quote(caar)?,
-                                            analyze_quasiquote(depth - 1, cdar)?,
-                                        ],
-                                    )?,
-                                    analyze_quasiquote(depth, Sexp::List(cadr))?,
-                                ],
-                            )
-                        }
-                    } else {
+                )
+            } else {
+                mock_call(
+                    "cons",
+                    vec![
                         mock_call(
                             "cons",
                             vec![
-                                analyze_quasiquote(depth, car)?,
-                                analyze_quasiquote(depth, Sexp::List(cadr))?,
+                                analyze_quote(caar)?,
+                                analyze_quasiquote(form, depth - 1, spliced)?,
                             ],
-                        )
-                    }
-                }
-                _ => mock_call(
-                    "cons",
-                    vec![
-                        analyze_quasiquote(depth, car)?,
-                        analyze_quasiquote(depth, Sexp::List(cadr))?,
+                        )?,
+                        quasiquote_list(form, depth, cdr)?,
                     ],
-                ),
+                )
             }
         }
-        _ => analyze_quote(qqexp),
+        _ => mock_call(
+            "cons",
+            vec![
+                analyze_quasiquote(form, depth, car)?,
+                quasiquote_list(form, depth, cdr)?,
+            ],
+        ),
+    }
+}
+
+/// The single expression following the `unquote` or `unquote-splicing`
+/// keyword `kw`, whose tail is `rest`
+fn unquoted<'a>(form: &Sexp, kw: &'a Sexp, rest: &'a List) -> Result<&'a Sexp, Error> {
+    let expected = format!("a single expression after `{}`", kw);
+    match rest {
+        List::Cons(expr, tail) => match &**tail {
+            List::Nil => Ok(expr),
+            List::Cons(extra, _) => Err(syntax_error(Kw::Quasiquote, form, extra, &expected)),
+        },
+        List::Nil => Err(syntax_error(Kw::Quasiquote, form, kw, &expected)),
     }
 }
 
 #[inline]
-/// Generate a thunk to delay computation
-fn analyze_delay(exprs: List) -> Result<Expression, Error> {
-    Ok(Expression::Lambda(LambdaExpr {
-        args: vec![],
-        rest: None,
-        body: analyze_sequence(exprs)?,
-    }))
+fn analyze_delay(form: &Sexp) -> Result<Expression, Error> {
+    let (_, m) = destructure(Kw::Delay, patterns!["(delay body ...+)"], form)?;
+    Ok(Expression::Delay(analyze_body(form, m.all("body"))?))
+}
+
+fn analyze_delay_force(form: &Sexp) -> Result<Expression, Error> {
+    let (_, m) = destructure(Kw::DelayForce, patterns!["(delay-force expr)"], form)?;
+    Ok(Expression::DelayForce(Box::new(analyze_in(
+        form,
+        m.get("expr"),
+    )?)))
 }
 
 #[inline]
-fn analyze_list(exprs: List) -> Result<Expression, Error> {
-    let (car, cdr) = exprs.unpack()?;
-    let f = analyze(car)?;
-    match f {
-        Keyword(sexp::Keyword::Lambda) => analyze_lambda(cdr),
-        Keyword(sexp::Keyword::Let) | Keyword(sexp::Keyword::Letstar) => analyze_let(cdr),
-        Keyword(sexp::Keyword::Letrec) => analyze_letrec(cdr),
-        Keyword(sexp::Keyword::Begin) => Ok(Expression::Begin(analyze_sequence(cdr)?)),
-        Keyword(sexp::Keyword::If) => analyze_if(cdr),
-        Keyword(sexp::Keyword::Cond) => analyze_cond(cdr),
-        Keyword(sexp::Keyword::Define) => analyze_define(cdr),
-        Keyword(sexp::Keyword::Set) => analyze_assignment(cdr),
-        Keyword(sexp::Keyword::And) => Ok(Expression::And(analyze_sequence(cdr)?)),
-        Keyword(sexp::Keyword::Or) => Ok(Expression::Or(analyze_sequence(cdr)?)),
-        Keyword(sexp::Keyword::Quote) => analyze_quote(cdr.unpack()?.0),
-        Keyword(sexp::Keyword::Quasiquote) => analyze_quasiquote(1, cdr.unpack()?.0),
-        Keyword(sexp::Keyword::Delay) => analyze_delay(cdr),
-        Lambda(_) | Call(_, _) => analyze_call(f, cdr),
-        Variable(_) => analyze_call(f, cdr),
-        _ => Err(Error::Message(format!(
-            "`{:?}` cannot be applied to `{}`",
-            f, cdr
+fn analyze_list(form: &Sexp, list: &List) -> Result<Expression, Error> {
+    let (car, cdr) = match list {
+        List::Cons(car, cdr) => (&**car, &**cdr),
+        List::Nil => return Err(Error::EmptyList),
+    };
+    let kw = match car {
+        Sexp::Keyword(kw) => *kw,
+        _ => {
+            return match analyze_in(form, car)? {
+                Literal(_) | Quotation(_) => {
+                    let message = format!("`{}` cannot be applied", car.write());
+                    Err(Error::Message(message).within(vec![0]))
+                }
+                f => analyze_call(form, f, cdr),
+            };
+        }
+    };
+    match kw {
+        Kw::Lambda => analyze_lambda(form),
+        Kw::CaseLambda => analyze_case_lambda(form),
+        Kw::Let | Kw::Letstar | Kw::Letrec => analyze_let(kw, form),
+        Kw::LetValues | Kw::LetstarValues => analyze_let_values(kw, form),
+        Kw::Begin => {
+            let (_, m) = destructure(Kw::Begin, patterns!["(begin body ...+)"], form)?;
+            Ok(Expression::Begin(analyze_body(form, m.all("body"))?))
+        }
+        Kw::If => analyze_if(form),
+        Kw::Cond => analyze_cond(form),
+        Kw::Guard => analyze_guard(form),
+        Kw::Parameterize => analyze_parameterize(form),
+        Kw::When | Kw::Unless => analyze_when(kw, form),
+        Kw::Case => analyze_case(form),
+        Kw::Do => analyze_do(form),
+        Kw::Define | Kw::DefineRecordType | Kw::DefineValues => Err(Error::Message(format!(
+            "`{}` is only allowed at the top level or at the start of a body",
+            kw.name()
         ))),
+        Kw::Set => analyze_assignment(form),
+        Kw::And => {
+            let (_, m) = destructure(Kw::And, patterns!["(and body ...+)"], form)?;
+            Ok(Expression::And(analyze_body(form, m.all("body"))?))
+        }
+        Kw::Or => {
+            let (_, m) = destructure(Kw::Or, patterns!["(or body ...+)"], form)?;
+            Ok(Expression::Or(analyze_body(form, m.all("body"))?))
+        }
+        Kw::Quote => {
+            let (_, m) = destructure(Kw::Quote, patterns!["('quote datum)"], form)?;
+            analyze_quote(m.get("datum"))
+        }
+        Kw::Quasiquote => {
+            let (_, m) = destructure(Kw::Quasiquote, patterns!["(quasiquote datum)"], form)?;
+            analyze_quasiquote(form, 1, m.get("datum"))
+        }
+        Kw::Delay => analyze_delay(form),
+        Kw::DelayForce => analyze_delay_force(form),
+        _ => Err(Error::Message(format!("`{}` is not allowed here", kw.name())).within(vec![0])),
     }
 }
 
-#[inline]
-fn analyze_sequence(exprs: List) -> Result<Sequence, Error> {
-    if exprs == List::Nil {
-        return Err(Error::EmptyList);
+fn analyze_expr(expr: &Sexp) -> Result<Expression, Error> {
+    match expr {
+        Sexp::Literal(s) => Ok(Expression::Literal(Value::Str(s.clone()))),
+        Sexp::Integer(i) => Ok(Expression::Literal(Value::Int(*i))),
+        Sexp::Boolean(b) => Ok(Expression::Literal(Value::Bool(*b))),
+        Sexp::Identifier(s) => Ok(Variable(s.clone())),
+        Sexp::List(list) => analyze_list(expr, list),
+        Sexp::Keyword(kw) => Err(Error::Message(format!(
+            "syntactic keyword `{}` cannot be used as an expression",
+            kw.name()
+        ))),
+    }
+}
+
+/// Analyze a top level form, where definitions are allowed, including
+/// within `begin`
+fn analyze_toplevel(form: &Sexp) -> Result<Expression, Error> {
+    if is_definition(form) {
+        return analyze_definition(form);
+    }
+    match form.as_list().and_then(List::car) {
+        Ok(Sexp::Keyword(Kw::Begin)) => {
+            let (_, m) = destructure(Kw::Begin, patterns!["(begin body ...+)"], form)?;
+            let body = m
+                .all("body")
+                .into_iter()
+                .map(|e| located(form, e, analyze_toplevel(e)))
+                .collect::<Result<_, _>>()?;
+            Ok(Expression::Begin(body))
+        }
+        _ => analyze_expr(form),
     }
-    exprs
-        .into_iter()
-        .map(analyze)
-        .collect::<Result<Vec<Expression>, Error>>()
 }
 
 #[inline]
 pub fn analyze(expr: Sexp) -> Result<Expression, Error> {
-    match expr {
-        Sexp::Literal(s) => Ok(Expression::Literal(Value::Str(s))),
-        Sexp::Integer(i) => Ok(Expression::Literal(Value::Int(i))),
-        Sexp::Boolean(b) => Ok(Expression::Literal(Value::Bool(b))),
-        Sexp::Identifier(s) => Ok(Variable(s)),
-        Sexp::List(list) => analyze_list(list),
-        Sexp::Keyword(kw) => Ok(Expression::Keyword(kw)),
+    analyze_toplevel(&expr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::{parse, Span};
+
+    /// Variables bound by the internal definitions of a body
+    fn defined(bind: &[Definition]) -> Vec<&str> {
+        bind.iter()
+            .flat_map(|def| match def {
+                Definition::Single(bind) => vec![&bind.var],
+                Definition::Values(def) => def.args.iter().chain(&def.rest).collect(),
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn analyze_str(s: &str) -> Result<Expression, Error> {
+        analyze(parse(s).unwrap().remove(0))
+    }
+
+    fn mismatch(s: &str) -> (Kw, Vec<usize>, String) {
+        match analyze_str(s) {
+            Err(Error::Syntax(kw, m)) => (kw, m.path.clone(), m.to_string()),
+            other => panic!("expected a syntax error for `{}`, got {:?}", s, other),
+        }
+    }
+
+    #[test]
+    fn special_forms() {
+        assert_eq!(
+            analyze_str("(lambda (x . y) x)"),
+            Ok(Expression::Lambda(LambdaExpr {
+                args: vec!["x".into()],
+                rest: Some("y".into()),
+                body: vec![Variable("x".into())],
+            }))
+        );
+        assert_eq!(
+            analyze_str("(define (f) 1)"),
+            Ok(Expression::Assignment(
+                "f".into(),
+                Box::new(Expression::Lambda(LambdaExpr {
+                    args: vec![],
+                    rest: None,
+                    body: vec![Expression::Literal(Value::Int(1))],
+                }))
+            ))
+        );
+        assert_eq!(
+            analyze_str("(delay (f) 1)"),
+            Ok(Expression::Delay(vec![
+                Call(Box::new(Variable("f".into())), vec![]),
+                Expression::Literal(Value::Int(1)),
+            ]))
+        );
+        assert_eq!(
+            analyze_str("(delay-force p)"),
+            Ok(Expression::DelayForce(Box::new(Variable("p".into()))))
+        );
+        assert_eq!(
+            analyze_str("(case-lambda ((x) x) (r r))"),
+            Ok(Expression::CaseLambda(vec![
+                LambdaExpr {
+                    args: vec!["x".into()],
+                    rest: None,
+                    body: vec![Variable("x".into())],
+                },
+                LambdaExpr {
+                    args: vec![],
+                    rest: Some("r".into()),
+                    body: vec![Variable("r".into())],
+                },
+            ]))
+        );
+        match analyze_str("(let loop ((i 0) (j 1)) (loop i j))") {
+            Ok(Expression::Let(LetExpr::NamedLet(name, bind, _))) => {
+                assert_eq!(name, "loop");
+                assert_eq!(bind.len(), 2);
+            }
+            other => panic!("{:?}", other),
+        }
+        // Quoted lists are constants rather than calls to `cons`
+        let a = Value::Symbol(Symbol::intern("a"));
+        assert_eq!(
+            analyze_str("'(a 1)"),
+            Ok(Quotation(Value::cons(
+                a.clone(),
+                Value::cons(Value::Int(1), Value::Nil)
+            )))
+        );
+        assert_eq!(
+            analyze_str("'(a . (1 . 2))"),
+            Ok(Quotation(Value::cons(
+                a,
+                Value::cons(Value::Int(1), Value::Int(2))
+            )))
+        );
+        assert!(matches!(
+            analyze_str("(let* ((x 1) (y x)) y)"),
+            Ok(Expression::Let(LetExpr::LetStar(..)))
+        ));
+        assert!(matches!(
+            analyze_str("(let ((x 1) (y x)) y)"),
+            Ok(Expression::Let(LetExpr::Let(..)))
+        ));
+        match analyze_str("(cond (a 1) (else 2))") {
+            Ok(Expression::Cond(clauses, Some(_))) => assert_eq!(clauses.len(), 1),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_forms() {
+        assert_eq!(
+            mismatch("(let ((x 1) (y)) x)"),
+            (
+                Kw::Let,
+                vec![1, 1, 1],
+                "expected an expression, found the end of the list".into()
+            )
+        );
+        assert_eq!(
+            mismatch("(lambda (x 1) x)").1,
+            vec![1, 1],
+            "the deepest mismatch of all lambda shapes is reported"
+        );
+        assert_eq!(mismatch("(set! 1 2)").1, vec![1]);
+        assert_eq!(
+            mismatch("(if)").2,
+            "expected an expression, found the end of the list"
+        );
+        assert_eq!(mismatch("(cond (else 1) (x 2))").1, vec![2]);
+        assert_eq!(mismatch("(cond (x 1) y)").1, vec![2]);
+        assert_eq!(mismatch("(cond)").1, vec![1]);
+        assert_eq!(mismatch("(cond (a => b c))").1, vec![1, 3]);
+        assert_eq!(mismatch("(cond (a =>))").1, vec![1, 2]);
+        assert_eq!(mismatch("(when a)").1, vec![2]);
+        assert_eq!(mismatch("(unless)").1, vec![1]);
+    }
+
+    #[test]
+    fn case_clauses() {
+        match analyze_str("(case x ((1 2) a b) ((c) => f) (else => g))") {
+            Ok(Expression::Case(key, clauses, Some(CaseBody::Arrow(_)))) => {
+                assert_eq!(*key, Variable("x".into()));
+                assert_eq!(clauses.len(), 2);
+                assert_eq!(
+                    clauses[0].data,
+                    vec![
+                        Expression::Literal(Value::Int(1)),
+                        Expression::Literal(Value::Int(2))
+                    ]
+                );
+                assert!(matches!(&clauses[0].body, CaseBody::Sequence(seq) if seq.len() == 2));
+                assert_eq!(
+                    clauses[1].data,
+                    vec![Quotation(Value::Symbol(Symbol::intern("c")))]
+                );
+                assert!(matches!(clauses[1].body, CaseBody::Arrow(_)));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(mismatch("(case x)").1, vec![2]);
+        assert_eq!(mismatch("(case x (1 a))").1, vec![2, 0]);
+        assert_eq!(mismatch("(case x ((1) => f g))").1, vec![2, 3]);
+        assert_eq!(mismatch("(case x (else 1) ((2) 3))").1, vec![3]);
+    }
+
+    #[test]
+    fn do_loops() {
+        match analyze_str("(do ((i 0 (+ i 1)) (j 1)) ((= i 10)) (f i j))") {
+            Ok(Expression::Do(d)) => {
+                assert_eq!(d.bind.len(), 2);
+                assert!(d.bind[0].step.is_some());
+                assert!(d.bind[1].step.is_none());
+                assert!(d.result.is_empty());
+                assert_eq!(d.body.len(), 1);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(mismatch("(do ((i 0 1 2)) (#t))").1, vec![1, 0, 3]);
+        assert_eq!(mismatch("(do ((i 0) (i 1)) (#t))").1, vec![1, 1, 0]);
+        assert_eq!(mismatch("(do ((i 0)) ())").1, vec![2, 0]);
+    }
+
+    #[test]
+    fn internal_definitions() {
+        let src = "(lambda (n)
+            (define (even? n) (if (= n 0) #t (odd? (- n 1))))
+            (define (odd? . args) (not (apply even? args)))
+            (even? n))";
+        match analyze_str(src) {
+            Ok(Expression::Lambda(LambdaExpr { mut body, .. })) => match body.remove(0) {
+                Expression::Let(LetExpr::LetRecStar(bind, body)) => {
+                    let vars = defined(&bind);
+                    assert_eq!(vars, vec!["even?", "odd?"]);
+                    assert!(matches!(
+                        bind[1],
+                        Definition::Single(LetBindings {
+                            expr: Expression::Lambda(LambdaExpr { rest: Some(_), .. }),
+                            ..
+                        })
+                    ));
+                    assert_eq!(body.len(), 1);
+                }
+                other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            analyze_str("(let ((x 1)) (define x 2) x)"),
+            Ok(Expression::Let(LetExpr::Let(_, ref body)))
+                if matches!(body[0], Expression::Let(LetExpr::LetRecStar(..)))
+        ));
+        assert!(matches!(
+            analyze_str("(begin (define x 1) (define y x))"),
+            Ok(Expression::Begin(ref body)) if body.len() == 2
+        ));
+
+        assert_eq!(
+            mismatch("(lambda () (define x 1))"),
+            (
+                Kw::Lambda,
+                vec![3],
+                "expected an expression after the internal definitions, found the end of the list"
+                    .into()
+            )
+        );
+        assert_eq!(
+            mismatch("(let () (define x 1) (define (x) 2) x)").1,
+            vec![3, 1, 0]
+        );
+        match analyze_str("(lambda () (f) (define x 1) x)") {
+            Err(Error::Within(path, _)) => assert_eq!(path, vec![3]),
+            other => panic!("{:?}", other),
+        }
+        match analyze_str("(if a (define x 1))") {
+            Err(Error::Within(path, _)) => assert_eq!(path, vec![2]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn cond_clauses() {
+        assert_eq!(
+            analyze_str("(cond ((f) => g) (x) (else 1))"),
+            Ok(Expression::Cond(
+                vec![
+                    CondClause {
+                        test: Box::new(Call(Box::new(Variable("f".into())), vec![])),
+                        body: CondBody::Arrow(Box::new(Variable("g".into()))),
+                    },
+                    CondClause {
+                        test: Box::new(Variable("x".into())),
+                        body: CondBody::Sequence(vec![]),
+                    },
+                ],
+                Some(vec![Expression::Literal(Value::Int(1))])
+            ))
+        );
+        assert_eq!(
+            analyze_str("(when a b c)"),
+            Ok(Expression::When(
+                Box::new(Variable("a".into())),
+                vec![Variable("b".into()), Variable("c".into())]
+            ))
+        );
+        assert!(matches!(
+            analyze_str("(unless a b)"),
+            Ok(Expression::Unless(..))
+        ));
+    }
+
+    #[test]
+    fn guard_clauses() {
+        match analyze_str("(guard (e ((f e) => g) (else e)) (define x 1) x)") {
+            Ok(Expression::Guard(var, handler, body)) => {
+                assert_eq!(var, "e");
+                assert!(matches!(*handler, Expression::Cond(ref c, Some(_)) if c.len() == 1));
+                assert!(matches!(
+                    body[..],
+                    [Expression::Let(LetExpr::LetRecStar(..))]
+                ));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            analyze_str("(guard (e (#t 1)) (raise 2))"),
+            Ok(Expression::Guard(_, _, _))
+        ));
+
+        assert_eq!(mismatch("(guard (e) x)").1, vec![1, 1]);
+        assert_eq!(mismatch("(guard ((e) (#t 1)) x)").1, vec![1, 0]);
+        assert_eq!(mismatch("(guard (e (#t 1)))").1, vec![2]);
+        assert_eq!(mismatch("(guard (e (else 1) (#t 2)) x)").1, vec![1, 2]);
+        assert_eq!(mismatch("(guard (e (a => b c)) x)").1, vec![1, 1, 3]);
+        assert_eq!(mismatch("(guard (e y) x)").1, vec![1, 1]);
+    }
+
+    #[test]
+    fn parameterize() {
+        match analyze_str("(parameterize ((p 1) ((f) x)) (define y 2) y)") {
+            Ok(Expression::Parameterize(bind, body)) => {
+                assert_eq!(
+                    bind,
+                    vec![
+                        (Variable("p".into()), Literal(Value::Int(1))),
+                        (
+                            Call(Box::new(Variable("f".into())), vec![]),
+                            Variable("x".into())
+                        ),
+                    ]
+                );
+                assert!(matches!(
+                    body[..],
+                    [Expression::Let(LetExpr::LetRecStar(..))]
+                ));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            analyze_str("(parameterize () 1)"),
+            Ok(Expression::Parameterize(ref bind, _)) if bind.is_empty()
+        ));
+
+        assert_eq!(mismatch("(parameterize ((p)) x)").1, vec![1, 0, 1]);
+        assert_eq!(mismatch("(parameterize ((p 1)))").1, vec![2]);
+        assert_eq!(mismatch("(parameterize (p 1) x)").1, vec![1, 0]);
+    }
+
+    #[test]
+    fn multiple_values() {
+        let binding = |args: &[&str], rest: Option<&str>, expr| ValuesBindings {
+            args: args.iter().map(|a| a.to_string()).collect(),
+            rest: rest.map(String::from),
+            expr,
+        };
+        assert_eq!(
+            analyze_str("(let-values (((a b) (f)) ((c . d) g) (e h)) a)"),
+            Ok(Expression::Let(LetExpr::LetValues(
+                vec![
+                    binding(
+                        &["a", "b"],
+                        None,
+                        Call(Box::new(Variable("f".into())), vec![])
+                    ),
+                    binding(&["c"], Some("d"), Variable("g".into())),
+                    binding(&[], Some("e"), Variable("h".into())),
+                ],
+                vec![Variable("a".into())]
+            )))
+        );
+        assert!(matches!(
+            analyze_str("(let*-values (((a) 1) ((a) a)) a)"),
+            Ok(Expression::Let(LetExpr::LetStarValues(..)))
+        ));
+        assert_eq!(
+            analyze_str("(define-values (a . b) (f))"),
+            Ok(Expression::DefineValues(Box::new(binding(
+                &["a"],
+                Some("b"),
+                Call(Box::new(Variable("f".into())), vec![])
+            ))))
+        );
+        // Within a body, the definition binds all of its variables
+        match analyze_str("(lambda () (define-values (a b) (f)) (define c a) c)") {
+            Ok(Expression::Lambda(LambdaExpr { mut body, .. })) => match body.remove(0) {
+                Expression::Let(LetExpr::LetRecStar(bind, _)) => {
+                    let vars = defined(&bind);
+                    assert_eq!(vars, vec!["a", "b", "c"]);
+                    assert!(matches!(bind[0], Definition::Values(_)));
+                }
+                other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        }
+
+        assert_eq!(mismatch("(let-values (((a a) 1)) a)").1, vec![1, 0, 0, 1]);
+        assert_eq!(
+            mismatch("(let-values (((a) 1) ((a) 2)) a)").1,
+            vec![1, 1, 0, 0]
+        );
+        assert_eq!(mismatch("(let-values (((a) 1 2)) a)").1, vec![1, 0, 2]);
+        assert_eq!(mismatch("(define-values (a 1) 2)").1, vec![1, 1]);
+        assert_eq!(
+            mismatch("(let () (define-values (a b) 1) (define a 2) a)").1,
+            vec![3, 1]
+        );
+    }
+
+    #[test]
+    fn record_types() {
+        let src = "(define-record-type point (make-point x y) point? (x point-x) (y point-y set-point-y!))";
+        assert_eq!(
+            analyze_str(src),
+            Ok(Expression::Assignment(
+                "point".into(),
+                Box::new(Expression::DefineRecordType(RecordType {
+                    name: "point".into(),
+                    constructor: ("make-point".into(), vec!["x".into(), "y".into()]),
+                    predicate: "point?".into(),
+                    fields: vec![
+                        RecordField {
+                            name: "x".into(),
+                            accessor: "point-x".into(),
+                            modifier: None,
+                        },
+                        RecordField {
+                            name: "y".into(),
+                            accessor: "point-y".into(),
+                            modifier: Some("set-point-y!".into()),
+                        },
+                    ],
+                }))
+            ))
+        );
+        // Within a body, the procedures are bound before the descriptor
+        match analyze_str(&format!("(lambda () {} (make-point 1 2))", src)) {
+            Ok(Expression::Lambda(LambdaExpr { mut body, .. })) => match body.remove(0) {
+                Expression::Let(LetExpr::LetRecStar(bind, _)) => {
+                    let vars = defined(&bind);
+                    assert_eq!(
+                        vars,
+                        vec![
+                            "make-point",
+                            "point?",
+                            "point-x",
+                            "point-y",
+                            "set-point-y!",
+                            "point"
+                        ]
+                    );
+                    assert!(matches!(
+                        bind[5],
+                        Definition::Single(LetBindings {
+                            expr: Expression::DefineRecordType(_),
+                            ..
+                        })
+                    ));
+                }
+                other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        }
+
+        assert_eq!(
+            mismatch("(define-record-type p (mk z) p? (x px))"),
+            (
+                Kw::DefineRecordType,
+                vec![2, 1],
+                "expected a field of the record type, found `z`".into()
+            )
+        );
+        assert_eq!(
+            mismatch("(define-record-type p (mk) p? (x a) (x b))").1,
+            vec![5, 0]
+        );
+        assert_eq!(
+            mismatch("(define-record-type p (mk x x) p? (x a))").1,
+            vec![2, 2]
+        );
+        assert_eq!(mismatch("(define-record-type p (mk) p? (x))").1, vec![4, 1]);
+        assert_eq!(mismatch("(define-record-type p mk p?)").1, vec![2]);
+        assert_eq!(
+            mismatch("(let () (define-record-type p (mk) p?) (define (mk) 1) 1)").1,
+            vec![3, 1, 0]
+        );
+        assert!(matches!(
+            analyze_str("(f (define-record-type p (mk) p?))"),
+            Err(Error::Within(path, _)) if path == vec![1]
+        ));
+    }
+
+    #[test]
+    fn strict_validation() {
+        assert_eq!(mismatch("(let ((x)) x)").1, vec![1, 0, 1]);
+        assert_eq!(mismatch("(let (5) x)").1, vec![1, 0]);
+        assert_eq!(mismatch("(if a b c d)").1, vec![4]);
+        assert_eq!(
+            mismatch("(set! x 1 2)"),
+            (
+                Kw::Set,
+                vec![3],
+                "expected the end of the list, found `2`".into()
+            )
+        );
+        assert_eq!(mismatch("(lambda (x y x) x)").1, vec![1, 2]);
+        assert_eq!(mismatch("(define (f x . x) x)").1, vec![1, 3]);
+        assert_eq!(mismatch("(letrec ((a 1) (a 2)) a)").1, vec![1, 1, 0]);
+        assert!(analyze_str("(let* ((a 1) (a 2)) a)").is_ok());
+        assert_eq!(mismatch("`(a (unquote))").1, vec![1, 1, 0]);
+        assert_eq!(mismatch("`(a (unquote b c))").1, vec![1, 1, 2]);
+        assert_eq!(mismatch("(delay-force p q)").1, vec![2]);
+        assert_eq!(mismatch("(case-lambda ((x) x) (1 2))").1, vec![2, 0]);
+        assert_eq!(mismatch("(case-lambda ((x) x) ((y y) y))").1, vec![2, 0, 1]);
+
+        match analyze_str("(f if)") {
+            Err(Error::Within(path, e)) => {
+                assert_eq!(path, vec![1]);
+                assert!(e.to_string().contains("`if`"));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            analyze_str("(1 2)"),
+            Err(Error::Within(ref path, _)) if path == &vec![0]
+        ));
+        assert!(analyze_str("((if a f g) 1)").is_ok());
+    }
+
+    #[test]
+    fn nested_errors_are_located() {
+        let src = "(define (f x)\n  (if x\n      (let ((y)) y)))";
+        let (expr, spans) = crate::syntax::parse_spanned(src).unwrap().remove(0);
+        let err = analyze(expr).unwrap_err();
+        match &err {
+            Error::Within(path, e) => {
+                assert_eq!(path, &vec![2, 2]);
+                assert!(matches!(**e, Error::Syntax(Kw::Let, _)));
+            }
+            other => panic!("{:?}", other),
+        }
+        let diag = err.diagnostic(Some(&spans));
+        assert_eq!(diag.message, "malformed `let` expression");
+        assert_eq!(diag.span(), Some(Span { line: 2, pos: 12 }));
+        assert_eq!(diag.labels[1].span, Span { line: 2, pos: 6 });
     }
 }
@@ -1,56 +1,209 @@
 use super::*;
-use super::ir::{mir::Expr, bytecode::Operation};
+use super::ir::{mir::Expr, bytecode::{Operation, Prototype}};
+use crate::diagnostic::Diagnostic;
 
-#[derive(Debug)]
-pub struct Context<'t> {
-    symbols: &'t mut SymbolTable,
+#[derive(Debug, Default)]
+pub struct Context {
     locals: Vec<String>,
     code: Vec<Operation>,
     constants: Vec<Value>,
+    prototypes: Vec<Prototype>,
+    /// Locals of the procedures enclosing the one being compiled
+    enclosing: Vec<String>,
 }
 
-impl<'t> Context<'t> {
-    pub fn from(symbols: &'t mut SymbolTable) -> Context<'t> {
-        Context {
-            symbols,
-            locals: Vec::new(),
-            code: Vec::new(),
-            constants: Vec::new(),
-        }
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
     }
 
-    fn find_var(&self, s: String) -> Operation {
+    fn find_var(&self, s: String) -> Result<Operation, Diagnostic> {
         match self.locals.iter().position(|item| item == &s) {
-            Some(idx) => Operation::Bound(idx),
-            None => Operation::Var(s),
+            Some(idx) => Ok(Operation::Bound(idx)),
+            None if self.enclosing.contains(&s) => Err(Diagnostic::error(
+                "E0201",
+                "closures over local variables are not supported by the bytecode compiler yet",
+            )
+            .with_note(format!("while compiling a reference to `{}`", s))),
+            None => Ok(Operation::Var(s)),
         }
     }
 
-    
-    pub fn compile(&mut self, expr: Expr) {
+    /// Index of `v` in the constant table, so that a quoted datum appearing
+    /// several times is stored once
+    fn constant(&mut self, v: Value) -> usize {
+        match self.constants.iter().position(|c| c == &v) {
+            Some(idx) => idx,
+            None => {
+                self.constants.push(v);
+                self.constants.len() - 1
+            }
+        }
+    }
+
+    /// Compile one clause into a prototype of its own, sharing the constant
+    /// table with the enclosing code
+    fn prototype(&mut self, args: Vec<String>, rest: Option<String>, body: Expr) -> Result<usize, Diagnostic> {
+        let required = args.len();
+        let mut locals = args;
+        locals.extend(rest.iter().cloned());
+        let has_rest = rest.is_some();
+        let outer_locals = std::mem::replace(&mut self.locals, locals);
+        let outer_code = std::mem::take(&mut self.code);
+        let depth = self.enclosing.len();
+        self.enclosing.extend(outer_locals.iter().cloned());
+        let result = self.compile(body);
+        self.enclosing.truncate(depth);
+        let locals = std::mem::replace(&mut self.locals, outer_locals);
+        let code = std::mem::replace(&mut self.code, outer_code);
+        result?;
+        self.prototypes.push(Prototype {
+            required,
+            rest: has_rest,
+            locals: locals.len(),
+            code,
+        });
+        Ok(self.prototypes.len() - 1)
+    }
+
+    /// Compile an expression in administrative normal form, reporting the
+    /// forms that have no bytecode translation yet
+    pub fn compile(&mut self, expr: Expr) -> Result<(), Diagnostic> {
         match expr {
-            Expr::Var(s) => self.code.push(self.find_var(s)),
+            Expr::Var(s) => {
+                let op = self.find_var(s)?;
+                self.code.push(op);
+            },
             Expr::Val(v) | Expr::Quote(v) => {
-                let idx = self.constants.len();
-                self.constants.push(v);
+                let idx = self.constant(v);
                 self.code.push(Operation::Constant(idx));
             },
             Expr::App(rator, rands) => {
                 let arity = rands.len();
-                rands.into_iter().for_each(|r| self.compile(r));
-                self.compile(*rator);
+                for r in rands {
+                    self.compile(r)?;
+                }
+                self.compile(*rator)?;
                 self.code.push(Operation::Call(arity));
             },
             Expr::Let(var, val, body) => {
                 let idx = self.locals.len();
                 self.locals.push(var);                
-                self.compile(*val);
+                self.compile(*val)?;
                 self.code.push(Operation::Bind(idx));
-                self.compile(*body);
+                self.compile(*body)?;
+            },
+            Expr::Lambda(args, rest, body) => {
+                let idx = self.prototype(args, rest, *body)?;
+                self.code.push(Operation::Closure(vec![idx]));
+            },
+            Expr::CallWithValues(producer, consumer) => {
+                self.compile(*producer)?;
+                self.compile(*consumer)?;
+                self.code.push(Operation::CallWithValues);
+            },
+            Expr::RecordType(name, fields) => self.code.push(Operation::RecordType(name, fields)),
+            Expr::Record(op, rands) => {
+                let arity = rands.len();
+                for r in rands {
+                    self.compile(r)?;
+                }
+                self.code.push(Operation::Record(op, arity));
+            },
+            Expr::CaseLambda(clauses) => {
+                let mut closure = Vec::with_capacity(clauses.len());
+                for (args, rest, body) in clauses {
+                    closure.push(self.prototype(args, rest, body)?);
+                }
+                self.code.push(Operation::Closure(closure));
             },
-            _ => unimplemented!()
+            unsupported => {
+                let form = match unsupported {
+                    Expr::If(..) => "if",
+                    Expr::Switch(..) | Expr::Eqv(..) => "case",
+                    Expr::Delay(..) => "delay",
+                    Expr::DelayForce(..) => "delay-force",
+                    Expr::Guard(..) | Expr::Raise(..) => "guard",
+                    Expr::DynamicWind(..) | Expr::Parameter(..) => "parameterize",
+                    _ => "set!",
+                };
+                return Err(Diagnostic::error(
+                    "E0201",
+                    format!("`{}` expressions are not supported by the bytecode compiler yet", form),
+                )
+                .with_note(format!("while compiling `{}`", unsupported)));
+            }
         }
+        Ok(())
         //println!("{:#?} {:?} {:?}", self.code, self.locals, self.constants);
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::ir::mir::RecordOp;
+    use crate::syntax::parse;
+
+    fn compile_str(table: &mut SymbolTable, s: &str) -> Result<(Vec<Operation>, Vec<Prototype>), Diagnostic> {
+        let expr = normalize_expr(desugar(analyze(parse(s).unwrap().remove(0)).unwrap(), table), table);
+        let mut context = Context::new();
+        context.compile(expr)?;
+        Ok((context.code, context.prototypes))
+    }
+
+    #[test]
+    fn case_lambda_prototypes() {
+        let mut table = SymbolTable::new();
+        let (code, prototypes) = compile_str(&mut table, "(case-lambda ((x) x) ((x y) y) ((x . r) r))").unwrap();
+        assert_eq!(code, vec![Operation::Closure(vec![0, 1, 2])]);
+        assert_eq!(
+            prototypes.iter().map(|p| (p.required, p.rest, p.locals)).collect::<Vec<_>>(),
+            vec![(1, false, 1), (2, false, 2), (1, true, 2)]
+        );
+        assert_eq!(prototypes[1].code, vec![Operation::Bound(1)]);
+    }
+
+    #[test]
+    fn record_accessors() {
+        let mut context = Context::new();
+        let accessor = Expr::Record(RecordOp::Ref(1), vec![Expr::Var("point".into()), Expr::Var("p".into())]);
+        context.compile(Expr::Lambda(vec!["p".into()], None, Box::new(accessor))).unwrap();
+        assert_eq!(
+            context.prototypes[0].code,
+            vec![Operation::Var("point".into()), Operation::Bound(0), Operation::Record(RecordOp::Ref(1), 2)]
+        );
+    }
+
+    #[test]
+    fn call_with_values() {
+        let mut context = Context::new();
+        let expr = Expr::CallWithValues(Box::new(Expr::Var("f".into())), Box::new(Expr::Var("g".into())));
+        context.compile(expr).unwrap();
+        assert_eq!(
+            context.code,
+            vec![Operation::Var("f".into()), Operation::Var("g".into()), Operation::CallWithValues]
+        );
+    }
+
+    #[test]
+    fn quoted_constants() {
+        let mut table = SymbolTable::new();
+        let expr = normalize_expr(desugar(analyze(parse("(f '(1 2 3) '(1 2 3) 1)").unwrap().remove(0)).unwrap(), &mut table), &mut table);
+        let mut context = Context::new();
+        context.compile(expr).unwrap();
+        assert_eq!(
+            context.code,
+            vec![Operation::Constant(0), Operation::Constant(0), Operation::Constant(1), Operation::Var("f".into()), Operation::Call(3)]
+        );
+        let list = Value::cons(Value::Int(1), Value::cons(Value::Int(2), Value::cons(Value::Int(3), Value::Nil)));
+        assert_eq!(context.constants, vec![list, Value::Int(1)]);
+    }
+
+    #[test]
+    fn closures_over_locals() {
+        let mut table = SymbolTable::new();
+        assert!(compile_str(&mut table, "(lambda (x) (lambda (y) (f x y)))").is_err());
+        assert!(compile_str(&mut table, "(lambda (x) (lambda (y) (f y)))").is_ok());
+    }
+}
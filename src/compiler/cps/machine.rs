@@ -0,0 +1,1356 @@
+//! Machine running programs in continuation-passing style
+//!
+//! Every step of the machine ends in a tail call, so the machine is a loop
+//! over the next step rather than a recursion, and the rest of the
+//! computation is always a continuation object. Capturing it is free, which
+//! gives `call-with-current-continuation` full, re-entrant continuations.
+//! The objects and the primitives without control effects are those of
+//! [`eval`](super::super::eval); procedures that call back into the program
+//! are implemented by the machine, passing continuations.
+//!
+//! `load` expands, converts and runs the forms of a file in the global scope.
+//! The file is loaded in a dynamic extent of its own, which files it loads
+//! in turn are relative to.
+//!
+//! The expander runs procedural macro transformers on a machine of their
+//! own, with only the primitives in scope and a limit on the steps of each
+//! run, so that a transformer that doesn't return is an error.
+use super::super::eval::{self, Env, Object, Promise};
+use super::{convert, Atom, Case, Cont, Lambda, Primop, Term};
+use crate::compiler::analysis::toplevel_names;
+use crate::compiler::hir::Expression;
+use crate::compiler::ir::Value;
+use crate::compiler::mir::Expr;
+use crate::compiler::{analyze, desugar, Expander, Symbol, SymbolTable};
+use crate::syntax::SpanTree;
+use crate::{Error, Sexp};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Procedure or continuation of a program, closed over its scope
+pub enum Procedure {
+    /// Clauses of a `lambda` or `case-lambda`
+    Closure(Rc<[Lambda]>, Env),
+    Continuation(Rc<Cont>, Env),
+    /// Continuation captured by `call-with-current-continuation`, which
+    /// reinstalls the handlers and calls of where it was captured and winds
+    /// back into its `dynamic-wind` calls
+    Escape(Object, Vec<Handler>, Winders, Calls),
+    /// Continuation of a procedure implemented by the machine
+    Frame(Frame),
+    /// Procedure implemented by the machine, called with the continuation
+    Control(&'static str, Control),
+}
+
+type Control = fn(&mut Run, Vec<Object>, Object) -> Result<Step, Error>;
+
+/// Names of the procedures being called, outermost first, with the
+/// continuations they return to
+type Calls = Vec<(String, Object)>;
+
+/// Handler of the exceptions raised by a program
+#[derive(Clone)]
+pub enum Handler {
+    /// Procedure installed by `with-exception-handler`
+    Procedure(Object),
+    /// Handler of a `guard`, called with the continuation of the `guard`
+    /// once unwound to its `dynamic-wind` calls and the number of calls it
+    /// is within
+    Guard(Object, Object, Winders, usize),
+}
+
+/// Call of `dynamic-wind` whose thunk is running, within the calls of its
+/// parent
+pub struct Winder {
+    before: Object,
+    after: Object,
+    depth: usize,
+    parent: Winders,
+    /// Canonical path of the file of a `load`, whose extent is a call
+    /// without thunks
+    file: Option<PathBuf>,
+}
+
+/// Innermost `dynamic-wind` call a continuation is within
+type Winders = Option<Rc<Winder>>;
+
+fn depth(winders: &Winders) -> usize {
+    winders.as_ref().map_or(0, |w| w.depth)
+}
+
+/// Files being loaded within `winders`, innermost first
+fn loading(winders: &Winders) -> impl Iterator<Item = &PathBuf> {
+    std::iter::successors(winders.as_ref(), |w| w.parent.as_ref()).filter_map(|w| w.file.as_ref())
+}
+
+pub enum Frame {
+    /// End of the program
+    Halt,
+    /// Return from the extent of a handler, reinstalling the handlers
+    /// outside of it
+    Restore(Vec<Handler>, Object),
+    /// Return of a producer, whose values are passed to a consumer
+    Values(Object, Object),
+    /// Return of the call of a procedure on the elements of lists, with the
+    /// rest of the lists and, for `map`, the results so far in reverse
+    Map {
+        f: Object,
+        lists: Vec<Object>,
+        acc: Option<Object>,
+        k: Object,
+    },
+    /// Return of the thunk of a promise being forced
+    Force(Rc<Promise>, bool, Object),
+    /// Return of a handler from an exception that is not continuable
+    Raise(Object),
+    /// Return of the before thunk of a `dynamic-wind`, which calls its thunk
+    Wind(Rc<Winder>, Object, Object),
+    /// Return of the thunk of a `dynamic-wind`, which calls its after thunk
+    Unwind(Rc<Winder>, Object),
+    /// Return of a thunk, whose values are passed on instead
+    Return(Object, Object),
+    /// Return of a before or after thunk run by a continuation of other
+    /// `dynamic-wind` calls, with those left to run in reverse order
+    Reroot {
+        thunks: Vec<(Object, Winders)>,
+        winders: Winders,
+        k: Object,
+        val: Object,
+    },
+    /// Return of the converter of a new parameter object
+    Parameter(Object, Object),
+}
+
+/// Next step of the machine
+pub enum Step {
+    Eval(Rc<Term>, Env),
+    Apply(Object, Vec<Object>, Object),
+    /// Pass a value to a continuation
+    Resume(Object, Object),
+    Done(Object),
+}
+
+/// Objects of the quoted lists of a program, by the address of their first
+/// pair. Each list is converted once, so that every evaluation of its
+/// quotation is the same object, and its pair is kept so that the address is
+/// not reused. Vectors have no syntax yet, so lists are the only constants
+/// with an identity
+type Constants = HashMap<*const (Value, Value), (Rc<(Value, Value)>, Object)>;
+
+/// Maximum number of steps of a run of a transformer
+const TRANSFORMER_STEPS: usize = 1_000_000;
+
+/// Machine holding the global scope of the primitives
+pub struct Machine {
+    global: Env,
+    expander: Expander,
+    constants: Constants,
+}
+
+/// Machine running the procedural macro transformers of an expander.
+/// Transformers only see the primitives and each other's expansions, never
+/// the definitions of the program being expanded, and can't `load` files
+pub(crate) struct Transformers {
+    global: Env,
+    constants: Constants,
+}
+
+/// State of one run of the machine
+pub struct Run<'a> {
+    symbols: &'a mut SymbolTable,
+    /// Expander of the forms of `load`, which transformers don't have
+    expander: Option<&'a mut Expander>,
+    constants: &'a mut Constants,
+    global: Env,
+    /// Handlers installed, innermost last
+    handlers: Vec<Handler>,
+    winders: Winders,
+    /// Calls that have not returned. An error leaves them in place for its
+    /// backtrace
+    calls: Calls,
+    /// Steps left before the run is stopped, if it is limited
+    steps: Option<usize>,
+}
+
+impl fmt::Display for Procedure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Procedure::Closure(..) => f.write_str("#<procedure>"),
+            Procedure::Control(name, _) => write!(f, "#<procedure {}>", name),
+            _ => f.write_str("#<continuation>"),
+        }
+    }
+}
+
+fn procedure(p: Procedure) -> Object {
+    Object::Procedure(Rc::new(p))
+}
+
+fn frame(f: Frame) -> Object {
+    procedure(Procedure::Frame(f))
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Machine {
+        Machine {
+            global: globals(),
+            expander: Expander::new(),
+            constants: Constants::new(),
+        }
+    }
+
+    /// Expander of the files read by `load`
+    pub fn expander(&mut self) -> &mut Expander {
+        &mut self.expander
+    }
+
+    /// Expand, convert and run a top level form in the global scope, where
+    /// the variables it defines are bound first. Variables of the
+    /// conversion and `gensym` symbols are named apart from `symbols`
+    pub fn eval(&mut self, form: &Sexp, symbols: &mut SymbolTable) -> Result<Object, Error> {
+        let mut run = self.start(symbols);
+        match run.compile(form)? {
+            Some(expr) => {
+                let expr = desugar(expr, run.symbols);
+                run.run(expr)
+            }
+            None => Ok(Object::Nil),
+        }
+    }
+
+    /// Run the forms of the file at `path` in the global scope as `load`
+    /// does, so that the files it loads are relative to it. Returns the
+    /// value of the last form
+    pub fn load(&mut self, path: &Path, symbols: &mut SymbolTable) -> Result<Object, Error> {
+        let mut run = self.start(symbols);
+        match run.load(path, frame(Frame::Halt)) {
+            Ok(step) => run.complete(step),
+            Err(e) => Err(run.uncaught(e)),
+        }
+    }
+
+    fn start<'a>(&'a mut self, symbols: &'a mut SymbolTable) -> Run<'a> {
+        Run {
+            symbols,
+            expander: Some(&mut self.expander),
+            constants: &mut self.constants,
+            global: self.global.clone(),
+            handlers: Vec::new(),
+            winders: None,
+            calls: Vec::new(),
+            steps: None,
+        }
+    }
+}
+
+impl Transformers {
+    pub(crate) fn new() -> Transformers {
+        Transformers {
+            global: globals(),
+            constants: Constants::new(),
+        }
+    }
+
+    /// Convert and run `expr` in the global scope. `gensym` symbols are
+    /// named apart from `symbols`
+    pub(crate) fn eval(&mut self, expr: Expr, symbols: &mut SymbolTable) -> Result<Object, Error> {
+        self.start(symbols).run(expr)
+    }
+
+    pub(crate) fn apply(
+        &mut self,
+        f: &Object,
+        args: Vec<Object>,
+        symbols: &mut SymbolTable,
+    ) -> Result<Object, Error> {
+        let step = Step::Apply(f.clone(), args, frame(Frame::Halt));
+        self.start(symbols).complete(step)
+    }
+
+    fn start<'a>(&'a mut self, symbols: &'a mut SymbolTable) -> Run<'a> {
+        Run {
+            symbols,
+            expander: None,
+            constants: &mut self.constants,
+            global: self.global.clone(),
+            handlers: Vec::new(),
+            winders: None,
+            calls: Vec::new(),
+            steps: Some(TRANSFORMER_STEPS),
+        }
+    }
+}
+
+/// Global scope of the primitives and the procedures implemented by the
+/// machine
+fn globals() -> Env {
+    let global = eval::globals();
+    for (name, f) in CONTROLS.iter() {
+        eval::define(&global, name, procedure(Procedure::Control(name, *f)));
+    }
+    global
+}
+
+impl Run<'_> {
+    fn atom(&mut self, atom: &Atom, env: &Env) -> Result<Object, Error> {
+        Ok(match atom {
+            Atom::Var(var) => eval::lookup(env, var)?,
+            Atom::Val(Value::Pair(pair)) | Atom::Quote(Value::Pair(pair)) => self
+                .constants
+                .entry(Rc::as_ptr(pair))
+                .or_insert_with(|| (pair.clone(), Object::from(Value::Pair(pair.clone()))))
+                .1
+                .clone(),
+            Atom::Val(val) | Atom::Quote(val) => Object::from(val.clone()),
+            Atom::Lambda(clauses) => procedure(Procedure::Closure(clauses.clone(), env.clone())),
+            Atom::Cont(k) => procedure(Procedure::Continuation(k.clone(), env.clone())),
+        })
+    }
+
+    fn atoms(&mut self, atoms: &[Atom], env: &Env) -> Result<Vec<Object>, Error> {
+        atoms.iter().map(|a| self.atom(a, env)).collect()
+    }
+
+    fn step(&mut self, step: Step) -> Result<Step, Error> {
+        match step {
+            Step::Eval(term, env) => self.eval(&term, env),
+            Step::Apply(f, args, k) => self.apply(f, args, k),
+            Step::Resume(k, val) => self.resume(k, val),
+            Step::Done(val) => Ok(Step::Done(val)),
+        }
+    }
+
+    fn eval(&mut self, term: &Term, env: Env) -> Result<Step, Error> {
+        Ok(match term {
+            Term::App(rator, rands, k) => {
+                let f = self.atom(rator, &env)?;
+                let args = self.atoms(rands, &env)?;
+                let k = self.atom(k, &env)?;
+                // A call of a closure in tail position replaces the
+                // procedure being called
+                if let Object::Procedure(p) = &f {
+                    if let Procedure::Closure(..) = **p {
+                        self.returned(&k);
+                    }
+                }
+                self.calls.push((name(rator, &f), k.clone()));
+                Step::Apply(f, args, k)
+            }
+            Term::Continue(k, val) => Step::Resume(self.atom(k, &env)?, self.atom(val, &env)?),
+            Term::Let(var, val, body) => {
+                let val = self.atom(val, &env)?;
+                Step::Eval(body.clone(), eval::bind(&env, var, val))
+            }
+            Term::Prim(var, op, args, body) => {
+                let mut args = self.atoms(args, &env)?;
+                let val = match op {
+                    Primop::Delay(chained) => Object::delayed(args.remove(0), *chained),
+                    Primop::RecordType(name, fields) => Object::record_type(name, fields),
+                    Primop::Record(op) => eval::record(*op, args)?,
+                    Primop::Eqv => Object::Bool(args[0].eqv(&args[1])),
+                    Primop::Parameter(op) => eval::parameter_op(*op, args)?,
+                };
+                Step::Eval(body.clone(), eval::bind(&env, var, val))
+            }
+            Term::If(test, csq, alt) => match self.atom(test, &env)?.is_true() {
+                true => Step::Eval(csq.clone(), env),
+                false => Step::Eval(alt.clone(), env),
+            },
+            Term::Switch(key, arms, default) => {
+                let case = match self.atom(key, &env)? {
+                    Object::Int(i) => Some(Case::Int(i)),
+                    Object::Symbol(s) => Some(Case::Symbol(s)),
+                    _ => None,
+                };
+                let body = case.and_then(|case| arms.get(case)).unwrap_or(default);
+                Step::Eval(body.clone(), env)
+            }
+            Term::Set(var, val, body) => {
+                let val = self.atom(val, &env)?;
+                eval::assign(&env, var, val)?;
+                Step::Eval(body.clone(), env)
+            }
+            Term::CallWithValues(producer, consumer, k) => {
+                let k = frame(Frame::Values(
+                    self.atom(consumer, &env)?,
+                    self.atom(k, &env)?,
+                ));
+                Step::Apply(self.atom(producer, &env)?, Vec::new(), k)
+            }
+            Term::Raise(obj, k) => {
+                let obj = self.atom(obj, &env)?;
+                let k = self.atom(k, &env)?;
+                self.raise(obj, Some(k))?
+            }
+            Term::DynamicWind(before, thunk, after, k) => {
+                let k = self.atom(k, &env)?;
+                let thunks = self.atoms(&[before.clone(), thunk.clone(), after.clone()], &env)?;
+                self.dynamic_wind(thunks, k)
+            }
+            Term::Guard(thunk, handler, k) => {
+                let k = self.atom(k, &env)?;
+                let outer = self.handlers.clone();
+                let handler = self.atom(handler, &env)?;
+                let winders = self.winders.clone();
+                let calls = self.calls.len();
+                self.handlers
+                    .push(Handler::Guard(handler, k.clone(), winders, calls));
+                let k = frame(Frame::Restore(outer, k));
+                Step::Apply(self.atom(thunk, &env)?, Vec::new(), k)
+            }
+        })
+    }
+
+    fn apply(&mut self, f: Object, mut args: Vec<Object>, k: Object) -> Result<Step, Error> {
+        let p = match &f {
+            Object::Procedure(p) => p,
+            Object::Primitive(_, prim) => return Ok(Step::Resume(k, prim(args)?)),
+            Object::Parameter(p) => return Ok(Step::Resume(k, eval::parameter(p, &args)?)),
+            _ => return Err(Error::Message(format!("{} cannot be applied", f))),
+        };
+        match &**p {
+            Procedure::Closure(clauses, env) => {
+                // A call enters the first clause whose arity accepts the
+                // arguments, and is an error when none does
+                let lambda = match clauses.iter().find(|l| l.accepts(args.len())) {
+                    Some(lambda) => lambda,
+                    None => return Err(arity(clauses, args.len())),
+                };
+                let rest = Object::list(args.split_off(lambda.args.len()));
+                let mut env = eval::bind(env, &lambda.cont, k);
+                for (var, val) in lambda.args.iter().zip(args) {
+                    env = eval::bind(&env, var, val);
+                }
+                if let Some(var) = &lambda.rest {
+                    env = eval::bind(&env, var, rest);
+                }
+                Ok(Step::Eval(lambda.body.clone(), env))
+            }
+            Procedure::Control(_, control) => control(self, args, k),
+            // A continuation called as a procedure returns its arguments
+            _ => {
+                let val = match args.len() {
+                    1 => args.remove(0),
+                    _ => Object::Values(args.into()),
+                };
+                Ok(Step::Resume(f, val))
+            }
+        }
+    }
+
+    fn resume(&mut self, k: Object, val: Object) -> Result<Step, Error> {
+        self.returned(&k);
+        let p = match &k {
+            Object::Procedure(p) => p,
+            _ => return Err(Error::Message(format!("{} is not a continuation", k))),
+        };
+        Ok(match &**p {
+            Procedure::Continuation(cont, env) => {
+                Step::Eval(cont.body.clone(), eval::bind(env, &cont.var, val))
+            }
+            Procedure::Escape(k, handlers, winders, calls) => {
+                self.handlers = handlers.clone();
+                self.calls = calls.clone();
+                self.reroot(winders, k.clone(), val)
+            }
+            Procedure::Frame(Frame::Halt) => Step::Done(val),
+            Procedure::Frame(Frame::Restore(handlers, k)) => {
+                self.handlers = handlers.clone();
+                Step::Resume(k.clone(), val)
+            }
+            Procedure::Frame(Frame::Values(consumer, k)) => {
+                Step::Apply(consumer.clone(), val.into_values(), k.clone())
+            }
+            Procedure::Frame(Frame::Map { f, lists, acc, k }) => {
+                let acc = acc.clone().map(|acc| Object::cons(val, acc));
+                map(f.clone(), lists.clone(), acc, k.clone())?
+            }
+            Procedure::Frame(Frame::Force(promise, chained, k)) => {
+                promise.settle(val, *chained);
+                force(promise.clone(), k.clone())?
+            }
+            Procedure::Frame(Frame::Raise(obj)) => {
+                let message = "exception handler returned".to_string();
+                self.raise(Object::error(message, vec![obj.clone()]), None)?
+            }
+            Procedure::Frame(Frame::Wind(winder, thunk, k)) => {
+                self.winders = Some(winder.clone());
+                let k = frame(Frame::Unwind(winder.clone(), k.clone()));
+                Step::Apply(thunk.clone(), Vec::new(), k)
+            }
+            Procedure::Frame(Frame::Unwind(winder, k)) => {
+                self.winders = winder.parent.clone();
+                let k = frame(Frame::Return(val, k.clone()));
+                Step::Apply(winder.after.clone(), Vec::new(), k)
+            }
+            Procedure::Frame(Frame::Return(val, k)) => Step::Resume(k.clone(), val.clone()),
+            Procedure::Frame(Frame::Reroot {
+                thunks,
+                winders,
+                k,
+                val,
+            }) => self.wind(thunks.clone(), winders.clone(), k.clone(), val.clone()),
+            Procedure::Frame(Frame::Parameter(converter, k)) => {
+                Step::Resume(k.clone(), Object::parameter(val, Some(converter.clone())))
+            }
+            Procedure::Closure(..) | Procedure::Control(..) => {
+                return Err(Error::Message(format!("{} is not a continuation", k)))
+            }
+        })
+    }
+
+    /// Call the thunk of `thunks` after the before thunk, and the after thunk
+    /// when it returns or is escaped from
+    fn dynamic_wind(&mut self, mut thunks: Vec<Object>, k: Object) -> Step {
+        let after = thunks.remove(2);
+        let thunk = thunks.remove(1);
+        let before = thunks.remove(0);
+        let winder = Rc::new(Winder {
+            before: before.clone(),
+            after,
+            depth: depth(&self.winders) + 1,
+            parent: self.winders.clone(),
+            file: None,
+        });
+        let k = frame(Frame::Wind(winder, thunk, k));
+        Step::Apply(before, Vec::new(), k)
+    }
+
+    /// Drop the calls returning to `k`, which are the innermost ones
+    fn returned(&mut self, k: &Object) {
+        while matches!(self.calls.last(), Some((_, c)) if c.eqv(k)) {
+            self.calls.pop();
+        }
+    }
+
+    /// Pass `val` to `k` within the `dynamic-wind` calls of `winders`,
+    /// first running the after thunks of the calls being left, innermost
+    /// first, then the before thunks of those being entered, outermost first
+    fn reroot(&mut self, winders: &Winders, k: Object, val: Object) -> Step {
+        let mut afters = Vec::new();
+        let mut befores = Vec::new();
+        let (mut from, mut to) = (self.winders.clone(), winders.clone());
+        loop {
+            match (&from, &to) {
+                (Some(a), Some(b)) if Rc::ptr_eq(a, b) => break,
+                (None, None) => break,
+                _ if depth(&from) >= depth(&to) => {
+                    let w = from.unwrap();
+                    afters.push((w.after.clone(), w.parent.clone()));
+                    from = w.parent.clone();
+                }
+                _ => {
+                    let w = to.unwrap();
+                    befores.push((w.before.clone(), w.parent.clone()));
+                    to = w.parent.clone();
+                }
+            }
+        }
+        befores.extend(afters.into_iter().rev());
+        self.wind(befores, winders.clone(), k, val)
+    }
+
+    /// Run the last of `thunks` within its `dynamic-wind` calls, then the
+    /// others, before passing `val` to `k` within `winders`
+    fn wind(
+        &mut self,
+        mut thunks: Vec<(Object, Winders)>,
+        winders: Winders,
+        k: Object,
+        val: Object,
+    ) -> Step {
+        match thunks.pop() {
+            None => {
+                self.winders = winders;
+                Step::Resume(k, val)
+            }
+            Some((thunk, within)) => {
+                self.winders = within;
+                let k = frame(Frame::Reroot {
+                    thunks,
+                    winders,
+                    k,
+                    val,
+                });
+                Step::Apply(thunk, Vec::new(), k)
+            }
+        }
+    }
+
+    /// Raise `obj` to the current handler, which is called with the handlers
+    /// outside of it installed. A handler of a `continuable` exception
+    /// returns to its continuation
+    fn raise(&mut self, obj: Object, continuable: Option<Object>) -> Result<Step, Error> {
+        let handlers = self.handlers.clone();
+        Ok(match self.handlers.pop() {
+            None => return Err(eval::uncaught(&obj)),
+            Some(Handler::Guard(handler, k, winders, calls)) => {
+                self.calls.truncate(calls);
+                let k = frame(Frame::Values(handler, k));
+                self.reroot(&winders, k, obj)
+            }
+            Some(Handler::Procedure(handler)) => {
+                let k = match continuable {
+                    Some(k) => Frame::Restore(handlers, k),
+                    None => Frame::Raise(obj.clone()),
+                };
+                Step::Apply(handler, vec![obj], frame(k))
+            }
+        })
+    }
+
+    /// Convert `expr` and run it until it returns
+    fn run(&mut self, expr: Expr) -> Result<Object, Error> {
+        let halt = self.symbols.fresh("halt");
+        let term = convert(expr, Atom::Var(halt.clone()), self.symbols);
+        let env = eval::bind(&self.global, &halt, frame(Frame::Halt));
+        self.complete(Step::Eval(Rc::new(term), env))
+    }
+
+    /// Take steps from `step` until the program halts
+    fn complete(&mut self, mut step: Step) -> Result<Object, Error> {
+        loop {
+            match &mut self.steps {
+                Some(0) => {
+                    let message = format!("evaluation took more than {} steps", TRANSFORMER_STEPS);
+                    return Err(self.uncaught(Error::Message(message)));
+                }
+                Some(steps) => *steps -= 1,
+                None => {}
+            }
+            step = match self.step(step) {
+                Ok(Step::Done(value)) => return Ok(value),
+                Ok(step) => step,
+                Err(e) if self.handlers.is_empty() => return Err(self.uncaught(e)),
+                // An error of the machine is raised as an error object
+                Err(e) => self.raise(Object::error(e.to_string(), vec![]), None)?,
+            };
+        }
+    }
+
+    /// Error returned from the run for an error not handled, with a
+    /// backtrace of the procedures that were called
+    fn uncaught(&mut self, e: Error) -> Error {
+        let calls = std::mem::take(&mut self.calls);
+        match calls.is_empty() {
+            true => e,
+            false => Error::Backtrace(
+                calls.into_iter().rev().map(|(name, _)| name).collect(),
+                Box::new(e),
+            ),
+        }
+    }
+
+    /// Expand and analyze a top level form, binding the variables it
+    /// defines in the global scope. Forms that only define syntax have no
+    /// expression
+    fn compile(&mut self, form: &Sexp) -> Result<Option<Expression>, Error> {
+        let form = match expander(&mut self.expander)?.expand(form, self.symbols)? {
+            Some(form) => form,
+            None => return Ok(None),
+        };
+        for name in toplevel_names(&form) {
+            if let Sexp::Identifier(name) = name {
+                if eval::lookup(&self.global, name).is_err() {
+                    eval::define(&self.global, name, Object::Nil);
+                }
+            }
+        }
+        analyze(form).map(Some)
+    }
+
+    /// Run the forms of the file at `path`, relative to the file being
+    /// loaded if any, in a dynamic extent of its own, then pass the value of
+    /// the last one to `k`
+    fn load(&mut self, path: &Path, k: Object) -> Result<Step, Error> {
+        expander(&mut self.expander)?;
+        let path = match loading(&self.winders).next() {
+            Some(file) => file.parent().unwrap_or_else(|| Path::new("")).join(path),
+            None => path.to_path_buf(),
+        };
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if loading(&self.winders).any(|file| *file == canonical) {
+            return Err(Error::Message(format!(
+                "`{}` is loaded within itself",
+                path.display()
+            )));
+        }
+        let expr = self.read(&path)?;
+        let noop = procedure(Procedure::Control("load", |_, _, k| {
+            Ok(Step::Resume(k, Object::Nil))
+        }));
+        let winder = Rc::new(Winder {
+            before: noop.clone(),
+            after: noop,
+            depth: depth(&self.winders) + 1,
+            parent: self.winders.clone(),
+            file: Some(canonical),
+        });
+        let halt = self.symbols.fresh("k");
+        let term = convert(expr, Atom::Var(halt.clone()), self.symbols);
+        let k = frame(Frame::Unwind(winder.clone(), k));
+        self.winders = Some(winder);
+        Ok(Step::Eval(
+            Rc::new(term),
+            eval::bind(&self.global, &halt, k),
+        ))
+    }
+
+    /// Expand and analyze the forms of the file at `path`, as a sequence,
+    /// binding the variables they define in the global scope. Errors are
+    /// located in the file
+    fn read(&mut self, path: &Path) -> Result<Expr, Error> {
+        let name = path.display().to_string();
+        let source = fs::read_to_string(path)
+            .map_err(|e| Error::Message(format!("cannot read `{}`: {}", name, e)))?;
+        let forms = crate::syntax::parse_spanned(&source).map_err(|d| {
+            let spans = SpanTree::leaf(d.span().unwrap_or_default());
+            Error::File(name.clone(), spans, Box::new(Error::Message(d.message)))
+        })?;
+        let outer = expander(&mut self.expander)?.set_file(Some(path.to_path_buf()));
+        let mut exprs = Vec::new();
+        let mut result = Ok(());
+        for (form, spans) in forms {
+            match self.compile(&form) {
+                Ok(expr) => exprs.extend(expr),
+                Err(e) => {
+                    result = Err(Error::File(name, spans, Box::new(e)));
+                    break;
+                }
+            }
+        }
+        expander(&mut self.expander)?.set_file(outer);
+        result.map(|_| desugar(Expression::Begin(exprs), self.symbols))
+    }
+}
+
+/// Name of the procedure `f` in a backtrace, after the variable `rator` it
+/// is called through
+fn name(rator: &Atom, f: &Object) -> String {
+    match (rator, f) {
+        // Variables renamed by the expander have a `~N` suffix, and
+        // temporaries have no other name
+        (Atom::Var(var), _) if !var.starts_with('~') => {
+            var.split('~').next().unwrap_or_default().to_string()
+        }
+        (_, Object::Primitive(name, _)) => name.to_string(),
+        (_, Object::Procedure(p)) => match &**p {
+            Procedure::Control(name, _) => name.to_string(),
+            _ => f.to_string(),
+        },
+        _ => f.to_string(),
+    }
+}
+
+/// Expander of a run, which only runs of transformers don't have
+fn expander<'a>(expander: &'a mut Option<&mut Expander>) -> Result<&'a mut Expander, Error> {
+    expander
+        .as_deref_mut()
+        .ok_or_else(|| Error::Message("files cannot be loaded at expansion time".into()))
+}
+
+fn arity(clauses: &[Lambda], argc: usize) -> Error {
+    match clauses {
+        [lambda] => Error::Message(format!(
+            "procedure expects {}{} arguments, found {}",
+            lambda.args.len(),
+            if lambda.rest.is_some() {
+                " or more"
+            } else {
+                ""
+            },
+            argc
+        )),
+        _ => Error::Message(format!(
+            "no clause of the procedure accepts {} arguments",
+            argc
+        )),
+    }
+}
+
+/// Call `f` on the next elements of `lists`, or pass the results in `acc`
+/// to `k` when the shortest list is exhausted
+fn map(f: Object, lists: Vec<Object>, acc: Option<Object>, k: Object) -> Result<Step, Error> {
+    let mut cars = Vec::with_capacity(lists.len());
+    let mut cdrs = Vec::with_capacity(lists.len());
+    for list in &lists {
+        match list {
+            Object::Pair(pair) => {
+                cars.push(pair.0.clone());
+                cdrs.push(pair.1.clone());
+            }
+            Object::Nil => {
+                let results = match acc {
+                    Some(acc) => {
+                        let mut elems = acc.elements()?;
+                        elems.reverse();
+                        Object::list(elems)
+                    }
+                    None => Object::Nil,
+                };
+                return Ok(Step::Resume(k, results));
+            }
+            _ => return Err(Error::Message(format!("expected a list, found {}", list))),
+        }
+    }
+    let k = frame(Frame::Map {
+        f: f.clone(),
+        lists: cdrs,
+        acc,
+        k,
+    });
+    Ok(Step::Apply(f, cars, k))
+}
+
+/// Force `promise`, calling its thunk unless it was forced already
+fn force(promise: Rc<Promise>, k: Object) -> Result<Step, Error> {
+    if let Some(value) = promise.value() {
+        return Ok(Step::Resume(k, value));
+    }
+    match promise.thunk() {
+        Some((thunk, chained)) => {
+            let k = frame(Frame::Force(promise, chained, k));
+            Ok(Step::Apply(thunk, Vec::new(), k))
+        }
+        None => Err(Error::Message(
+            "the promise was not made by the program".into(),
+        )),
+    }
+}
+
+fn map_control(
+    name: &str,
+    args: Vec<Object>,
+    acc: Option<Object>,
+    k: Object,
+) -> Result<Step, Error> {
+    let mut args = args.into_iter();
+    match args.next() {
+        Some(f) if args.len() > 0 => map(f, args.collect(), acc, k),
+        _ => Err(Error::Message(format!(
+            "`{}` expects a procedure and lists",
+            name
+        ))),
+    }
+}
+
+/// Call a procedure with the continuation `k`, captured with the handlers,
+/// `dynamic-wind` calls and calls it is within
+fn call_cc(run: &mut Run, args: Vec<Object>, k: Object) -> Result<Step, Error> {
+    eval::arity("call-with-current-continuation", &args, 1)?;
+    let escape = procedure(Procedure::Escape(
+        k.clone(),
+        run.handlers.clone(),
+        run.winders.clone(),
+        run.calls.clone(),
+    ));
+    Ok(Step::Apply(args[0].clone(), vec![escape], k))
+}
+
+const CONTROLS: &[(&str, Control)] = &[
+    ("call-with-current-continuation", call_cc),
+    ("call/cc", call_cc),
+    ("apply", |_, mut args, k| {
+        let list = match args.pop() {
+            Some(list) if !args.is_empty() => list,
+            _ => {
+                return Err(Error::Message(
+                    "`apply` expects a procedure and a list".into(),
+                ))
+            }
+        };
+        let f = args.remove(0);
+        args.extend(list.elements()?);
+        Ok(Step::Apply(f, args, k))
+    }),
+    ("call-with-values", |_, args, k| {
+        eval::arity("call-with-values", &args, 2)?;
+        let k = frame(Frame::Values(args[1].clone(), k));
+        Ok(Step::Apply(args[0].clone(), Vec::new(), k))
+    }),
+    ("map", |_, args, k| {
+        map_control("map", args, Some(Object::Nil), k)
+    }),
+    ("for-each", |_, args, k| {
+        map_control("for-each", args, None, k)
+    }),
+    ("force", |_, args, k| {
+        eval::arity("force", &args, 1)?;
+        match &args[0] {
+            Object::Promise(promise) => force(promise.clone(), k),
+            value => Ok(Step::Resume(k, value.clone())),
+        }
+    }),
+    ("dynamic-wind", |run, args, k| {
+        eval::arity("dynamic-wind", &args, 3)?;
+        Ok(run.dynamic_wind(args, k))
+    }),
+    ("make-parameter", |_, mut args, k| match args.len() {
+        1 => Ok(Step::Resume(k, Object::parameter(args.remove(0), None))),
+        2 => {
+            let converter = args.pop().unwrap_or(Object::Nil);
+            let k = frame(Frame::Parameter(converter.clone(), k));
+            Ok(Step::Apply(converter, args, k))
+        }
+        _ => Err(Error::Message(
+            "`make-parameter` expects a value and an optional converter".into(),
+        )),
+    }),
+    ("load", |run, args, k| {
+        eval::arity("load", &args, 1)?;
+        let name = match &args[0] {
+            Object::Str(name) => name,
+            obj => {
+                return Err(Error::Message(format!(
+                    "expected a file name, found {}",
+                    obj
+                )))
+            }
+        };
+        run.load(Path::new(name), k)
+    }),
+    ("error", |run, mut args, _| {
+        let message = match args.first() {
+            Some(Object::Str(s)) => s.clone(),
+            Some(obj) => obj.to_string(),
+            None => return Err(Error::Message("`error` expects a message".into())),
+        };
+        let irritants = args.split_off(1);
+        run.raise(Object::error(message, irritants), None)
+    }),
+    ("raise", |run, mut args, _| {
+        eval::arity("raise", &args, 1)?;
+        run.raise(args.remove(0), None)
+    }),
+    ("raise-continuable", |run, mut args, k| {
+        eval::arity("raise-continuable", &args, 1)?;
+        run.raise(args.remove(0), Some(k))
+    }),
+    ("gensym", |run, args, k| {
+        eval::arity("gensym", &args, 0)?;
+        let name = run.symbols.gensym();
+        Ok(Step::Resume(k, Object::Symbol(Symbol::intern(&name))))
+    }),
+    ("with-exception-handler", |run, args, k| {
+        eval::arity("with-exception-handler", &args, 2)?;
+        let k = frame(Frame::Restore(run.handlers.clone(), k));
+        run.handlers.push(Handler::Procedure(args[0].clone()));
+        Ok(Step::Apply(args[1].clone(), Vec::new(), k))
+    }),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval_str(input: &str) -> Result<String, Error> {
+        let mut symbols = SymbolTable::new();
+        let mut machine = Machine::new();
+        let mut result = Object::Nil;
+        for form in crate::syntax::parse(input).unwrap() {
+            result = machine.eval(&form, &mut symbols)?;
+        }
+        Ok(result.to_string())
+    }
+
+    fn run(input: &str) -> Result<String, Error> {
+        eval_str(input).map_err(|e| match e {
+            Error::Backtrace(_, e) => *e,
+            e => e,
+        })
+    }
+
+    #[test]
+    fn evaluation() {
+        assert_eq!(run("((lambda (x y) (+ x y)) 1 2)"), Ok("3".into()));
+        assert_eq!(
+            run("(let loop ((i 0) (acc '())) (if (= i 3) acc (loop (+ i 1) (cons i acc))))"),
+            Ok("(2 1 0)".into())
+        );
+        assert_eq!(
+            run("(map (lambda (x y) (* x y)) '(1 2 3) '(4 5 6))"),
+            Ok("(4 10 18)".into())
+        );
+        assert_eq!(run("(force (delay (+ 1 2)))"), Ok("3".into()));
+        assert_eq!(
+            run("(call-with-values (lambda () (values 1 2)) list)"),
+            Ok("(1 2)".into())
+        );
+        // Recursion is not limited by the stack of the machine
+        assert_eq!(
+            run("(let f ((n 10000)) (if (= n 0) 0 (+ 1 (f (- n 1)))))"),
+            Ok("10000".into())
+        );
+        // Top level definitions are global
+        assert_eq!(run("(define x 1) (define (f) x) (f)"), Ok("1".into()));
+        assert_eq!(
+            run("(define-values (a . b) (values 1 2)) (begin (define c 3)) (list a b c)"),
+            Ok("(1 (2) 3)".into())
+        );
+        // The rest of an expression is not in the scope of a nested let
+        assert_eq!(
+            run("(let ((y 5)) (list (let ((y 1)) y) y (let ((car cdr)) 2) (car '(3))))"),
+            Ok("(1 5 2 3)".into())
+        );
+        // The temporaries of nested parallel lets are distinct
+        assert_eq!(
+            run("(let ((x 1) (y 2))
+                   (let ((x y) (y (let ((a 10) (b 20)) (list a b x))))
+                     (list x y)))"),
+            Ok("(2 (10 20 1))".into())
+        );
+        // The temporary of a `=>` clause doesn't capture variables of the
+        // receiver
+        assert_eq!(
+            run("(let ((~test 5)) (cond (1 => (lambda (v) (list v ~test)))))"),
+            Ok("(1 5)".into())
+        );
+        // Renamed variables don't capture identifiers that look renamed
+        assert_eq!(
+            run("(define x~0 'user) (let ((x 1) (y 2)) x~0)"),
+            Ok("user".into())
+        );
+        assert_eq!(
+            run("(define key~0 'user) (case 1 ((1) key~0))"),
+            Ok("user".into())
+        );
+        // `case` doesn't call the `eqv?` of the program
+        assert_eq!(
+            run("(define (eqv? a b) #t) (case 1 ((\"x\") 'two) (else 'other))"),
+            Ok("other".into())
+        );
+        // The loop of a `do` doesn't capture variables of its body
+        assert_eq!(
+            run("(define ~do 5) (define do~0 6) (do ((i 0 (+ i 1))) ((= i 2) (list ~do do~0)))"),
+            Ok("(5 6)".into())
+        );
+        // A `case` of integers and symbols takes the first clause of the key
+        assert_eq!(
+            run(
+                "(map (lambda (x) (case x ((1 a) 'one) ((1 b 4294967296) 'two) (else 'other)))
+                      '(1 a b 4294967296 \"a\"))"
+            ),
+            Ok("(one one two two other)".into())
+        );
+        // Every evaluation of a quotation is the same object
+        assert_eq!(
+            run("(let ((f (lambda (x) '(1 2)))) (eq? (f 1) (f 2)))"),
+            Ok("#t".into())
+        );
+        // A call enters the first clause accepting its arguments
+        let f = "(define f (case-lambda ((x) 'one) ((x y) 'two) ((x . r) r)))";
+        assert_eq!(
+            run(&format!("{} (list (f 1) (f 1 2) (f 1 2 3))", f)),
+            Ok("(one two (2 3))".into())
+        );
+        assert_eq!(
+            run(&format!("{} (f)", f)),
+            Err(Error::Message(
+                "no clause of the procedure accepts 0 arguments".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn continuations() {
+        assert_eq!(
+            run("(+ 1 (call/cc (lambda (k) (+ 10 (k 1)))))"),
+            Ok("2".into())
+        );
+        assert_eq!(
+            run("(call-with-current-continuation (lambda (k) (map (lambda (x) (if (< x 0) (k x) x)) '(1 -2 3))))"),
+            Ok("-2".into())
+        );
+        // Re-entering a continuation returns from `call/cc` again
+        assert_eq!(
+            run("(let ((n 0) (k #f))
+                   (let ((v (+ 1 (call/cc (lambda (c) (set! k c) 1)))))
+                     (set! n (+ n 1))
+                     (if (< n 3) (k v) (list n v))))"),
+            Ok("(3 4)".into())
+        );
+        // Generator walking a list, suspended between elements
+        assert_eq!(
+            run("(let ((return #f) (resume #f))
+                   (define (next)
+                     (call/cc (lambda (r)
+                       (set! return r)
+                       (if resume
+                           (resume #f)
+                           (begin
+                             (for-each (lambda (x) (call/cc (lambda (c) (set! resume c) (return x))))
+                                       '(1 2 3))
+                             (return 'done))))))
+                   (let loop ((acc '()))
+                     (let ((x (next)))
+                       (if (eq? x 'done) (reverse (cons x acc)) (loop (cons x acc))))))"),
+            Ok("(1 2 3 done)".into())
+        );
+    }
+
+    #[test]
+    fn exceptions() {
+        assert_eq!(
+            run("(guard (e (#t (list 'caught e))) (+ 1 (raise 'oops)))"),
+            Ok("(caught oops)".into())
+        );
+        assert_eq!(
+            run(
+                "(with-exception-handler (lambda (e) 10) (lambda () (+ 1 (raise-continuable 'c))))"
+            ),
+            Ok("11".into())
+        );
+        assert_eq!(
+            run("(guard (e ((error-object? e) (error-object-message e))) (car 1))"),
+            Ok("\"expected a pair, found 1\"".into())
+        );
+        // Escaping from a handler reinstalls the handlers of the escape
+        assert_eq!(
+            run("(guard (e (#t (list 'outer e)))
+                   (list (call/cc (lambda (k)
+                           (with-exception-handler (lambda (e) (k 'escaped)) (lambda () (raise 'inner)))))
+                         (guard (e (#t 'inner)) (raise 'again))))"),
+            Ok("(escaped inner)".into())
+        );
+        assert_eq!(
+            run("(call/cc (lambda (k) (with-exception-handler (lambda (e) (k 1)) (lambda () 2))))"),
+            Ok("2".into())
+        );
+        assert_eq!(
+            run("(car 1)"),
+            Err(Error::Message("expected a pair, found 1".into()))
+        );
+        // A `guard` raises again without calling the program's procedures
+        assert_eq!(
+            run("(define (raise-continuable x) 'swallowed) (guard (e (#f 1)) (raise 'boom))"),
+            Err(Error::Message("uncaught exception: boom".into()))
+        );
+        assert_eq!(
+            run("(with-exception-handler (lambda (e) 10)
+                   (lambda () (+ 1 (guard (e ((string? e) e)) (raise-continuable 'c)))))"),
+            Ok("11".into())
+        );
+        assert_eq!(
+            run("(raise 'oops)"),
+            Err(Error::Message("uncaught exception: oops".into()))
+        );
+        assert_eq!(
+            run("(with-exception-handler (lambda (e) 0) (lambda () (raise 'oops)))"),
+            Err(Error::Message("exception handler returned oops".into()))
+        );
+    }
+
+    #[test]
+    fn dynamic_extent() {
+        let log = |body: &str| {
+            run(&format!(
+                "(let ((log '()))
+                   (define (note x) (set! log (cons x log)))
+                   (define (wind name thunk)
+                     (dynamic-wind (lambda () (note (list 'in name)))
+                                   thunk
+                                   (lambda () (note (list 'out name)))))
+                   {}
+                   (reverse log))",
+                body
+            ))
+        };
+        assert_eq!(
+            log("(wind 'a (lambda () (note 'body)))"),
+            Ok("((in a) body (out a))".into())
+        );
+        // Escaping runs the after thunks, innermost first
+        assert_eq!(
+            log("(call/cc (lambda (k) (wind 'a (lambda () (wind 'b (lambda () (k 0)))))))"),
+            Ok("((in a) (in b) (out b) (out a))".into())
+        );
+        // Re-entering runs the before thunks, outermost first
+        assert_eq!(
+            log("(let ((k #f) (n 0))
+                   (wind 'a (lambda () (wind 'b (lambda () (call/cc (lambda (c) (set! k c)))))))
+                   (set! n (+ n 1))
+                   (if (< n 2) (k 0)))"),
+            Ok("((in a) (in b) (out b) (out a) (in a) (in b) (out b) (out a))".into())
+        );
+        // Jumping between extents leaves and enters up to the shared one
+        assert_eq!(
+            log("(let ((k #f) (n 0))
+                   (wind 'a (lambda ()
+                     (wind 'b (lambda () (call/cc (lambda (c) (set! k c)))))
+                     (set! n (+ n 1))
+                     (if (< n 2) (wind 'c (lambda () (k 0)))))))"),
+            Ok("((in a) (in b) (out b) (in c) (out c) (in b) (out b) (out a))".into())
+        );
+        assert_eq!(
+            log("(guard (e (#t (note e))) (wind 'a (lambda () (raise 'oops))))"),
+            Ok("((in a) (out a) oops)".into())
+        );
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(
+            run("(let ((p (make-parameter 10 (lambda (x) (* x 2)))))
+                   (list (p) (parameterize ((p 3)) (p)) (p)))"),
+            Ok("(20 6 20)".into())
+        );
+        // The values of parameters are swapped back in on re-entry
+        assert_eq!(
+            run("(let ((p (make-parameter 1)) (k #f) (seen '()))
+                   (parameterize ((p 2))
+                     (call/cc (lambda (c) (set! k c)))
+                     (set! seen (cons (p) seen)))
+                   (set! seen (cons (p) seen))
+                   (if (< (length seen) 4) (k 0) (reverse seen)))"),
+            Ok("(2 1 2 1)".into())
+        );
+        assert_eq!(
+            run("(let ((p (make-parameter 1)))
+                   (guard (e (#t (p))) (parameterize ((p 2)) (raise 'oops))))"),
+            Ok("1".into())
+        );
+        // The operations aren't variables of the program
+        assert_eq!(
+            run("(define (dynamic-wind a b c) 'oops)
+                 (define p (make-parameter 1))
+                 (parameterize ((p 2)) (p))"),
+            Ok("2".into())
+        );
+        // The temporaries don't capture variables of the body
+        assert_eq!(
+            run("(let ((p (make-parameter 1)) (~p0 'a) (~swap 'b))
+                   (parameterize ((p 2)) (list (p) ~p0 ~swap)))"),
+            Ok("(2 a b)".into())
+        );
+    }
+
+    #[test]
+    fn backtraces() {
+        let backtrace = |calls: &[&str], message: &str| {
+            Err(Error::Backtrace(
+                calls.iter().map(|call| call.to_string()).collect(),
+                Box::new(Error::Message(message.into())),
+            ))
+        };
+        assert_eq!(
+            eval_str(
+                "(define (f x) (car x))
+                 (define (g x) (+ 1 (f x)))
+                 (g 1)"
+            ),
+            backtrace(&["car", "f", "g"], "expected a pair, found 1")
+        );
+        // Handled errors and calls that returned leave no calls behind
+        assert_eq!(
+            eval_str(
+                "(define (f x) (guard (e (#t (car x))) (list (car 1))))
+                 (f 2)"
+            ),
+            backtrace(&["car", "f"], "expected a pair, found 2")
+        );
+        assert_eq!(
+            eval_str("(define (f x) (car x)) (f (length '(1)))"),
+            backtrace(&["car", "f"], "expected a pair, found 1")
+        );
+        // An escape reinstalls the calls of where it was captured
+        assert_eq!(
+            eval_str(
+                "(define (f k) (list (k 1)))
+                 (define (h x) (car x))
+                 (h (call/cc f))"
+            ),
+            backtrace(&["car", "h"], "expected a pair, found 1")
+        );
+        assert_eq!(
+            eval_str("(raise 'oops)"),
+            backtrace(&["raise"], "uncaught exception: oops")
+        );
+    }
+
+    #[test]
+    fn load() {
+        let dir = std::env::temp_dir().join(format!("zymogen-load-{}", std::process::id()));
+        let write = |name: &str, source: &str| {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        };
+        write("a.scm", "(define x 1)\n(load \"sub/b.scm\")");
+        write("sub/b.scm", "(define y (+ x 1))\n(load \"c.scm\")");
+        write(
+            "sub/c.scm",
+            "(define-syntax three (syntax-rules () ((_) 3)))\n(define z (three))",
+        );
+        write("loop.scm", "(load \"loop.scm\")");
+        write("bad.scm", "(define w 1)\n(f (import (scheme base)))");
+        let file = |name: &str| dir.join(name).display().to_string();
+
+        let mut symbols = SymbolTable::new();
+        let mut machine = Machine::new();
+        let mut run = |input: &str| {
+            let form = crate::syntax::parse(input).unwrap().remove(0);
+            machine.eval(&form, &mut symbols).map(|val| val.to_string())
+        };
+        let load = |name: &str| format!("(load {:?})", file(name));
+        // Files are relative to the file loading them
+        assert_eq!(run(&load("a.scm")), Ok("()".into()));
+        assert_eq!(run("(list x y z)"), Ok("(1 2 3)".into()));
+
+        let message = format!("`{}` is loaded within itself", file("loop.scm"));
+        assert_eq!(
+            run(&load("loop.scm")),
+            Err(Error::Backtrace(
+                vec!["load".into(), "load".into()],
+                Box::new(Error::Message(message.clone()))
+            ))
+        );
+        // Escaping from a load leaves its extent
+        let caught = format!(
+            "(guard (e (#t (error-object-message e))) {})",
+            load("loop.scm")
+        );
+        assert_eq!(run(&caught), Ok(format!("{:?}", message)));
+        assert!(run(&load("sub/c.scm")).is_ok());
+
+        let e = run(&load("bad.scm")).unwrap_err();
+        assert_eq!(e.file(), Some(file("bad.scm").as_str()));
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "in `{}`: `import` is only allowed at the top level",
+                file("bad.scm")
+            )
+        );
+        assert_eq!(
+            run("(load 1)"),
+            Err(Error::Backtrace(
+                vec!["load".into()],
+                Box::new(Error::Message("expected a file name, found 1".into()))
+            ))
+        );
+        // A program run as a file loads files relative to it
+        let mut machine = Machine::new();
+        let value = machine.load(&dir.join("a.scm"), &mut symbols);
+        assert_eq!(value.map(|val| val.to_string()), Ok("()".into()));
+        let form = crate::syntax::parse("(list x y z)").unwrap().remove(0);
+        let value = machine.eval(&form, &mut symbols);
+        assert_eq!(value.map(|val| val.to_string()), Ok("(1 2 3)".into()));
+        assert_eq!(
+            machine.load(&dir.join("loop.scm"), &mut symbols).err(),
+            Some(Error::Backtrace(
+                vec!["load".into()],
+                Box::new(Error::Message(message))
+            ))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
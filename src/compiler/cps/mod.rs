@@ -0,0 +1,413 @@
+//! Conversion of MIR into continuation-passing style
+//!
+//! Each expression is converted with its continuation, which is either an
+//! atom of the output or a function of the compiler building the term that
+//! uses the value of the expression. The latter are applied during the
+//! conversion, so that values are bound where they are used rather than by
+//! administrative redexes, e.g.
+//! ```skip
+//! (f (g x))
+//! ===>
+//! (g x (κ (v) (f v k)))
+//! ```
+//! instead of
+//! ```skip
+//! ((λ (a k1) (a x (κ (b) ((λ (c k2) (f c k2)) b k1)))) g k)
+//! ```
+use super::mir::Expr;
+use super::*;
+use std::rc::Rc;
+use std::vec;
+
+mod machine;
+
+pub use super::ir::cps::{Arms, Atom, Case, Cont, Lambda, Primop, Term};
+pub(crate) use machine::Transformers;
+pub use machine::{Machine, Procedure};
+
+type Meta = Box<dyn FnOnce(&mut Converter, Atom) -> Term>;
+type MetaAll = Box<dyn FnOnce(&mut Converter, Vec<Atom>) -> Term>;
+
+/// Continuation of an expression being converted
+enum K {
+    /// Continuation of the output
+    Atom(Atom),
+    /// Function building the rest of the output from the value
+    Meta(Meta),
+    /// Term of the output with the value bound to a variable
+    Bind(String, Term),
+}
+
+struct Converter<'a> {
+    table: &'a mut SymbolTable,
+}
+
+/// Convert `expr` into CPS, passing its value to the continuation `k`
+pub fn convert(expr: Expr, k: Atom, table: &mut SymbolTable) -> Term {
+    Converter { table }.convert(expr, K::Atom(k))
+}
+
+fn is_atomic(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Var(_) | Expr::Val(_) | Expr::Quote(_) | Expr::Lambda(..) | Expr::CaseLambda(_)
+    )
+}
+
+/// Constant of a `Switch`, which `desugar` only makes of integers and
+/// symbols
+fn case(datum: Expr) -> Case {
+    match datum {
+        Expr::Val(Value::Int(i)) | Expr::Quote(Value::Int(i)) => Case::Int(i),
+        Expr::Val(Value::Symbol(s)) | Expr::Quote(Value::Symbol(s)) => Case::Symbol(s),
+        datum => unreachable!("`{}` is not a constant of a switch", datum),
+    }
+}
+
+/// Replace the free occurrences of `var` in `expr` with `to`
+fn rename(expr: Expr, var: &str, to: &str) -> Expr {
+    let re = |e: Expr| rename(e, var, to);
+    let binds =
+        |args: &[String], rest: &Option<String>| args.iter().chain(rest).any(|arg| arg == var);
+    match expr {
+        Expr::Var(v) if v == var => Expr::Var(to.into()),
+        Expr::Let(v, val, body) => {
+            let body = if v == var { *body } else { re(*body) };
+            Expr::Let(v, Box::new(re(*val)), Box::new(body))
+        }
+        Expr::Lambda(args, rest, body) if !binds(&args, &rest) => {
+            Expr::Lambda(args, rest, Box::new(re(*body)))
+        }
+        Expr::CaseLambda(clauses) => Expr::CaseLambda(
+            clauses
+                .into_iter()
+                .map(|(args, rest, body)| match binds(&args, &rest) {
+                    true => (args, rest, body),
+                    false => (args, rest, re(body)),
+                })
+                .collect(),
+        ),
+        Expr::App(rator, rands) => {
+            Expr::App(Box::new(re(*rator)), rands.into_iter().map(re).collect())
+        }
+        Expr::CallWithValues(producer, consumer) => {
+            Expr::CallWithValues(Box::new(re(*producer)), Box::new(re(*consumer)))
+        }
+        Expr::If(test, csq, alt) => Expr::If(
+            Box::new(re(*test)),
+            Box::new(re(*csq)),
+            alt.map(|alt| Box::new(re(*alt))),
+        ),
+        Expr::Set(v, val) => {
+            let v = if v == var { to.into() } else { v };
+            Expr::Set(v, Box::new(re(*val)))
+        }
+        Expr::Switch(key, cases, default) => Expr::Switch(
+            Box::new(re(*key)),
+            cases
+                .into_iter()
+                .map(|(data, body)| (data, re(body)))
+                .collect(),
+            Box::new(re(*default)),
+        ),
+        Expr::Delay(expr) => Expr::Delay(Box::new(re(*expr))),
+        Expr::DelayForce(expr) => Expr::DelayForce(Box::new(re(*expr))),
+        Expr::Guard(v, body, handler) => {
+            let handler = if v == var { *handler } else { re(*handler) };
+            Expr::Guard(v, Box::new(re(*body)), Box::new(handler))
+        }
+        Expr::Record(op, rands) => Expr::Record(op, rands.into_iter().map(re).collect()),
+        Expr::Eqv(a, b) => Expr::Eqv(Box::new(re(*a)), Box::new(re(*b))),
+        Expr::Raise(obj) => Expr::Raise(Box::new(re(*obj))),
+        Expr::DynamicWind(before, thunk, after) => Expr::DynamicWind(
+            Box::new(re(*before)),
+            Box::new(re(*thunk)),
+            Box::new(re(*after)),
+        ),
+        Expr::Parameter(op, rands) => Expr::Parameter(op, rands.into_iter().map(re).collect()),
+        expr => expr,
+    }
+}
+
+impl Converter<'_> {
+    fn fresh(&mut self, base: &str) -> String {
+        self.table.fresh(base)
+    }
+
+    fn lambda(&mut self, args: Vec<String>, rest: Option<String>, body: Expr) -> Lambda {
+        let cont = self.fresh("k");
+        let body = self.convert(body, K::Atom(Atom::Var(cont.clone())));
+        Lambda {
+            args,
+            rest,
+            cont,
+            body: Rc::new(body),
+        }
+    }
+
+    /// Procedure of a single clause without arguments
+    fn thunk(&mut self, body: Expr) -> Atom {
+        Atom::Lambda(Rc::new([self.lambda(Vec::new(), None, body)]))
+    }
+
+    fn atom(&mut self, expr: Expr) -> Atom {
+        match expr {
+            Expr::Var(var) => Atom::Var(var),
+            Expr::Val(val) => Atom::Val(val),
+            Expr::Quote(val) => Atom::Quote(val),
+            Expr::Lambda(args, rest, body) => {
+                Atom::Lambda(Rc::new([self.lambda(args, rest, *body)]))
+            }
+            Expr::CaseLambda(clauses) => Atom::Lambda(
+                clauses
+                    .into_iter()
+                    .map(|(args, rest, body)| self.lambda(args, rest, body))
+                    .collect(),
+            ),
+            expr => unreachable!("`{}` is not atomic", expr),
+        }
+    }
+
+    /// Pass `val` to the continuation `k`
+    fn ret(&mut self, k: K, val: Atom) -> Term {
+        match k {
+            K::Atom(k) => Term::Continue(k, val),
+            K::Meta(f) => f(self, val),
+            K::Bind(var, body) => Term::Let(var, val, Rc::new(body)),
+        }
+    }
+
+    /// Continuation atom of `k`
+    fn reify(&mut self, k: K) -> Atom {
+        match k {
+            K::Atom(k) => k,
+            K::Meta(f) => {
+                let var = self.fresh("v");
+                let body = f(self, Atom::Var(var.clone()));
+                Atom::Cont(Rc::new(Cont {
+                    var,
+                    body: Rc::new(body),
+                }))
+            }
+            K::Bind(var, body) => Atom::Cont(Rc::new(Cont {
+                var,
+                body: Rc::new(body),
+            })),
+        }
+    }
+
+    /// Build a term that passes values to `k` from more than one place,
+    /// binding `k` to a variable unless it is one, so that the
+    /// continuation is not duplicated
+    fn share(&mut self, k: K, f: impl FnOnce(&mut Self, Atom) -> Term) -> Term {
+        match self.reify(k) {
+            Atom::Var(var) => f(self, Atom::Var(var)),
+            k => {
+                let var = self.fresh("j");
+                let body = f(self, Atom::Var(var.clone()));
+                Term::Let(var, k, Rc::new(body))
+            }
+        }
+    }
+
+    /// Convert `exprs` in order, then build the term using their values
+    fn convert_all(
+        &mut self,
+        mut exprs: vec::IntoIter<Expr>,
+        mut vals: Vec<Atom>,
+        f: MetaAll,
+    ) -> Term {
+        match exprs.next() {
+            None => f(self, vals),
+            Some(expr) => {
+                let k = move |c: &mut Converter, val| {
+                    vals.push(val);
+                    c.convert_all(exprs, vals, f)
+                };
+                self.convert(expr, K::Meta(Box::new(k)))
+            }
+        }
+    }
+
+    /// Bind the result of a primitive operation on `args` for `k`
+    fn prim(&mut self, op: Primop, args: Vec<Atom>, k: K) -> Term {
+        let var = self.fresh("v");
+        let body = self.ret(k, Atom::Var(var.clone()));
+        Term::Prim(var, op, args, Rc::new(body))
+    }
+
+    fn convert(&mut self, expr: Expr, k: K) -> Term {
+        match expr {
+            expr if is_atomic(&expr) => {
+                let val = self.atom(expr);
+                self.ret(k, val)
+            }
+            Expr::App(rator, rands) => {
+                let mut exprs = vec![*rator];
+                exprs.extend(rands);
+                let f = move |c: &mut Converter, mut vals: Vec<Atom>| {
+                    let rator = vals.remove(0);
+                    let k = c.reify(k);
+                    Term::App(rator, vals, k)
+                };
+                self.convert_all(exprs.into_iter(), Vec::new(), Box::new(f))
+            }
+            Expr::CallWithValues(producer, consumer) => {
+                let f = move |c: &mut Converter, mut vals: Vec<Atom>| {
+                    let consumer = vals.remove(1);
+                    let producer = vals.remove(0);
+                    let k = c.reify(k);
+                    Term::CallWithValues(producer, consumer, k)
+                };
+                let exprs = vec![*producer, *consumer];
+                self.convert_all(exprs.into_iter(), Vec::new(), Box::new(f))
+            }
+            Expr::Let(var, val, body) => {
+                // The rest of the output is built in the scope of the
+                // variable unless the let is in tail position, so the
+                // variable is renamed apart from the names it might use
+                let (var, body) = match k {
+                    K::Atom(_) => (var, *body),
+                    _ => {
+                        let fresh = self.fresh(&var);
+                        let body = rename(*body, &var, &fresh);
+                        (fresh, body)
+                    }
+                };
+                let body = self.convert(body, k);
+                self.convert(*val, K::Bind(var, body))
+            }
+            Expr::If(test, csq, alt) => {
+                let f = move |c: &mut Converter, test| {
+                    c.share(k, move |c, k| {
+                        let csq = c.convert(*csq, K::Atom(k.clone()));
+                        let alt = match alt {
+                            Some(alt) => c.convert(*alt, K::Atom(k)),
+                            None => Term::Continue(k, Atom::Val(Value::Nil)),
+                        };
+                        Term::If(test, Rc::new(csq), Rc::new(alt))
+                    })
+                };
+                self.convert(*test, K::Meta(Box::new(f)))
+            }
+            Expr::Switch(key, cases, default) => {
+                let f = move |c: &mut Converter, key| {
+                    c.share(k, move |c, k| {
+                        let mut arms = Vec::with_capacity(cases.len());
+                        for (data, body) in cases {
+                            let data = data.into_iter().map(case).collect();
+                            let body = c.convert(body, K::Atom(k.clone()));
+                            arms.push((data, Rc::new(body)));
+                        }
+                        let default = c.convert(*default, K::Atom(k));
+                        Term::Switch(key, Arms::new(arms), Rc::new(default))
+                    })
+                };
+                self.convert(*key, K::Meta(Box::new(f)))
+            }
+            Expr::Set(var, val) => {
+                let f = move |c: &mut Converter, val| {
+                    let body = c.ret(k, Atom::Val(Value::Nil));
+                    Term::Set(var, val, Rc::new(body))
+                };
+                self.convert(*val, K::Meta(Box::new(f)))
+            }
+            Expr::Delay(expr) => {
+                let thunk = self.thunk(*expr);
+                self.prim(Primop::Delay(false), vec![thunk], k)
+            }
+            Expr::DelayForce(expr) => {
+                let thunk = self.thunk(*expr);
+                self.prim(Primop::Delay(true), vec![thunk], k)
+            }
+            Expr::RecordType(name, fields) => {
+                self.prim(Primop::RecordType(name, fields), Vec::new(), k)
+            }
+            Expr::Record(op, rands) => {
+                let f = move |c: &mut Converter, vals| c.prim(Primop::Record(op), vals, k);
+                self.convert_all(rands.into_iter(), Vec::new(), Box::new(f))
+            }
+            Expr::Eqv(a, b) => {
+                let f = move |c: &mut Converter, vals| c.prim(Primop::Eqv, vals, k);
+                self.convert_all(vec![*a, *b].into_iter(), Vec::new(), Box::new(f))
+            }
+            Expr::Raise(obj) => {
+                let f = move |c: &mut Converter, obj| {
+                    let k = c.reify(k);
+                    Term::Raise(obj, k)
+                };
+                self.convert(*obj, K::Meta(Box::new(f)))
+            }
+            Expr::Parameter(op, rands) => {
+                let f = move |c: &mut Converter, vals| c.prim(Primop::Parameter(op), vals, k);
+                self.convert_all(rands.into_iter(), Vec::new(), Box::new(f))
+            }
+            Expr::DynamicWind(before, thunk, after) => {
+                let f = move |c: &mut Converter, mut vals: Vec<Atom>| {
+                    let after = vals.remove(2);
+                    let thunk = vals.remove(1);
+                    let before = vals.remove(0);
+                    let k = c.reify(k);
+                    Term::DynamicWind(before, thunk, after, k)
+                };
+                let exprs = vec![*before, *thunk, *after];
+                self.convert_all(exprs.into_iter(), Vec::new(), Box::new(f))
+            }
+            Expr::Guard(var, body, handler) => {
+                let thunk = self.thunk(*body);
+                let handler = Atom::Lambda(Rc::new([self.lambda(vec![var], None, *handler)]));
+                let k = self.reify(k);
+                Term::Guard(thunk, handler, k)
+            }
+            // Matched by `is_atomic`
+            Expr::Var(_)
+            | Expr::Val(_)
+            | Expr::Quote(_)
+            | Expr::Lambda(..)
+            | Expr::CaseLambda(_) => {
+                unreachable!()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cps(input: &str) -> String {
+        let mut table = SymbolTable::new();
+        let form = crate::syntax::parse(input).unwrap().remove(0);
+        let expr = desugar(analyze(form).unwrap(), &mut table);
+        convert(expr, Atom::Var("k".into()), &mut table).to_string()
+    }
+
+    #[test]
+    fn conversion() {
+        assert_eq!(cps("(lambda (x) (f x))"), "(k (λ (x k~0) (f x k~0)))");
+        assert_eq!(cps("(f (g x))"), "(g x (κ (v~0) (f v~0 k)))");
+        assert_eq!(cps("(let ((x (f 1))) (g x))"), "(f 1 (κ (x) (g x k)))");
+        assert_eq!(
+            cps("(if (f x) 1 2)"),
+            "(f x (κ (v~0) (if v~0 (k 1) (k 2))))"
+        );
+        // A continuation passed values from both branches is bound once
+        assert_eq!(
+            cps("(+ 1 (if (f x) 1 2))"),
+            "(f x (κ (v~0) (let ((j~2 (κ (v~1) (+ 1 v~1 k)))) (if v~0 (j~2 1) (j~2 2)))))"
+        );
+        assert_eq!(
+            cps("(begin (set! x (f 1)) x)"),
+            "(f 1 (κ (v~0) (begin (set! x v~0) (let ((~s0 ())) (k x)))))"
+        );
+        assert_eq!(
+            cps("(case x ((1 a) 'one) ((1) 'two))"),
+            "(let ((key~0 x)) (switch key~0 ((1 'a) (k 'one)) ((1) (k 'two)) (else (k ()))))"
+        );
+        // A let in the operands doesn't scope over the operands after it
+        assert_eq!(
+            cps("(f (let ((x (g 1))) x) x)"),
+            "(g 1 (κ (x~0) (f x~0 x k)))"
+        );
+    }
+}
@@ -3,7 +3,7 @@
 //! AST transformations in this phase should NOT produce errors or panics
 
 use super::hir::*;
-use super::mir::Expr;
+use super::mir::{Expr, ParameterOp, RecordOp};
 use super::*;
 
 /// Helper function to recursively generate nested let expressions
@@ -19,17 +19,38 @@ fn desugar_bindings(mut args: Vec<String>, mut vals: Vec<Expr>, body: Expr) -> E
     }
 }
 
-/// Desugar a let expression with multiple bindings into
-/// nested let expressions
+/// Desugar a let expression with multiple bindings into nested let
+/// expressions. The initializers of a parallel `let` are bound to
+/// fresh temporaries first, so that none of them sees the new variables
 /// ```skip
 /// (let ((x 0)
-///       (y 1))
+///       (y x))
 ///     (cons x y))
 /// ===>
-/// (let ((x 0)) (let ((y 1)) (cons x y)))
-fn desugar_let(letexpr: LetExpr) -> Expr {
+/// (let ((x~0 0)) (let ((y~1 x)) (let ((x x~0)) (let ((y y~1)) (cons x y)))))
+///
+/// (let* ((x 0)
+///        (y x))
+///     (cons x y))
+/// ===>
+/// (let ((x 0)) (let ((y x)) (cons x y)))
+fn desugar_let(letexpr: LetExpr, table: &mut SymbolTable) -> Expr {
     match letexpr {
-        LetExpr::Let(bind, body) => {
+        LetExpr::Let(bind, body) if bind.len() > 1 => {
+            let mut temps = Vec::new();
+            let mut rands = Vec::new();
+            let mut args = Vec::new();
+            for bind in bind {
+                let temp = table.fresh(&bind.var);
+                temps.push(temp.clone());
+                rands.push(desugar(bind.expr, table));
+                args.push(bind.var);
+            }
+            let vars = temps.iter().cloned().map(Expr::Var).collect();
+            let body = desugar_bindings(args, vars, desugar_begin(body, table));
+            desugar_bindings(temps, rands, body)
+        }
+        LetExpr::Let(bind, body) | LetExpr::LetStar(bind, body) => {
             // Destructure bindings into lists of arguments and values
             let mut args = Vec::new();
             let mut rands = Vec::new();
@@ -40,8 +61,8 @@ fn desugar_let(letexpr: LetExpr) -> Expr {
 
             desugar_bindings(
                 args,
-                rands.into_iter().map(desugar).collect(),
-                desugar_begin(body),
+                rands.into_iter().map(|e| desugar(e, table)).collect(),
+                desugar_begin(body, table),
             )
         }
         LetExpr::NamedLet(name, bind, body) => {
@@ -63,23 +84,123 @@ fn desugar_let(letexpr: LetExpr) -> Expr {
             };
             let body = Expression::Call(Box::new(Expression::Variable(name)), rands);
 
-            desugar_let(LetExpr::LetRec(vec![nbinds], vec![body]))
+            desugar_let(LetExpr::LetRec(vec![nbinds], vec![body]), table)
         }
+        // Initializers are assigned in order, so `letrec` is desugared as
+        // `letrec*`
         LetExpr::LetRec(bind, body) => {
+            let bind = bind.into_iter().map(Definition::Single).collect();
+            desugar_let(LetExpr::LetRecStar(bind, body), table)
+        }
+        LetExpr::LetRecStar(bind, body) => {
             let mut args = Vec::new();
-            let rands = (0..bind.len()).map(|_| Expr::Val(Value::Nil)).collect();
-            let mut expanded = bind
-                .into_iter()
-                .map(|bind| {
-                    args.push(bind.var.clone());
-                    Expression::Assignment(bind.var, Box::new(bind.expr))
-                })
-                .collect::<Vec<Expression>>();
+            let mut expanded = Vec::new();
+            for def in bind {
+                match def {
+                    Definition::Single(bind) => {
+                        args.push(bind.var.clone());
+                        expanded.push(Expression::Assignment(bind.var, Box::new(bind.expr)));
+                    }
+                    Definition::Values(def) => {
+                        args.extend(def.args.iter().chain(&def.rest).cloned());
+                        expanded.push(Expression::DefineValues(Box::new(def)));
+                    }
+                }
+            }
+            let rands = args.iter().map(|_| Expr::Val(Value::Nil)).collect();
 
             expanded.extend(body);
-            desugar_bindings(args, rands, desugar_begin(expanded))
+            desugar_bindings(args, rands, desugar_begin(expanded, table))
+        }
+        LetExpr::LetValues(bind, body) => desugar_let_values(bind, body, true, table),
+        LetExpr::LetStarValues(bind, body) => desugar_let_values(bind, body, false, table),
+    }
+}
+
+/// Call a producer evaluating `expr`, with a consumer binding the formals
+/// to its values around `body`
+fn call_with_values(
+    expr: Expression,
+    args: Vec<String>,
+    rest: Option<String>,
+    body: Expr,
+    table: &mut SymbolTable,
+) -> Expr {
+    Expr::CallWithValues(
+        Box::new(Expr::Lambda(vec![], None, Box::new(desugar(expr, table)))),
+        Box::new(Expr::Lambda(args, rest, Box::new(body))),
+    )
+}
+
+/// Desugar `let-values` and `let*-values` into nested `call-with-values`.
+/// The variables of a parallel `let-values` are bound to fresh temporaries
+/// first, so that none of the initializers sees them
+/// ```skip
+/// (let-values (((a b) (f))
+///              (c (g)))
+///     (list a b c))
+/// ===>
+/// (call-with-values (λ () (f))
+///     (λ (a~0 b~1)
+///         (call-with-values (λ () (g))
+///             (λ c~2
+///                 (let* ((a a~0) (b b~1) (c c~2)) (list a b c))))))
+fn desugar_let_values(
+    mut bind: Vec<ValuesBindings>,
+    body: Sequence,
+    parallel: bool,
+    table: &mut SymbolTable,
+) -> Expr {
+    let mut body = desugar_begin(body, table);
+    if parallel && bind.len() > 1 {
+        let mut vars = Vec::new();
+        let mut temps = Vec::new();
+        for b in &mut bind {
+            for var in b.args.iter_mut().chain(b.rest.iter_mut()) {
+                let temp = table.fresh(var);
+                vars.push(std::mem::replace(var, temp.clone()));
+                temps.push(Expr::Var(temp));
+            }
         }
+        body = desugar_bindings(vars, temps, body);
     }
+    bind.into_iter().rev().fold(body, |body, b| {
+        call_with_values(b.expr, b.args, b.rest, body, table)
+    })
+}
+
+/// Desugar `define-values` into a consumer assigning the variables from
+/// fresh parameters
+/// ```skip
+/// (define-values (a . b) (f))
+/// ===>
+/// (call-with-values (λ () (f))
+///     (λ (a~0 . b~1)
+///         (set! a a~0)
+///         (set! b b~1)))
+fn desugar_define_values(def: ValuesBindings, table: &mut SymbolTable) -> Expr {
+    let args = def
+        .args
+        .iter()
+        .map(|var| table.fresh(var))
+        .collect::<Vec<String>>();
+    let rest = def.rest.as_ref().map(|var| table.fresh(var));
+    let mut sets = def
+        .args
+        .into_iter()
+        .chain(def.rest)
+        .zip(args.iter().chain(&rest))
+        .map(|(var, temp)| Expr::Set(var, Box::new(Expr::Var(temp.clone()))))
+        .collect::<Vec<Expr>>();
+    let body = sets.pop().unwrap_or(Expr::Val(Value::Nil));
+    let vars = (0..sets.len()).map(|i| format!("~s{}", i)).collect();
+    call_with_values(
+        def.expr,
+        args,
+        rest,
+        desugar_bindings(vars, sets, body),
+        table,
+    )
 }
 
 /// desugar a begin statement into let bindings
@@ -91,43 +212,299 @@ fn desugar_let(letexpr: LetExpr) -> Expr {
 /// )
 /// ===>
 /// (let (($t0 exp1) ... ($tN-1 expN-1)) expN)
-fn desugar_begin(mut exprs: Sequence) -> Expr {
+fn desugar_begin(mut exprs: Sequence, table: &mut SymbolTable) -> Expr {
     if exprs.len() == 1 {
-        desugar(exprs.remove(0))
+        desugar(exprs.remove(0), table)
     } else {
-        let mut exprs = exprs.into_iter().map(desugar).collect::<Vec<Expr>>();
+        let mut exprs = exprs
+            .into_iter()
+            .map(|e| desugar(e, table))
+            .collect::<Vec<Expr>>();
         let body = exprs.pop().unwrap_or(Expr::Val(Value::Nil));
         let vars = (0..exprs.len()).map(|i| format!("~s{}", i)).collect();
         desugar_bindings(vars, exprs, body)
     }
 }
 
-/// Desugar a `cond` expression into nested `if` statements
-fn desugar_cond(mut clauses: Vec<CondClause>, else_clause: Option<Sequence>) -> Expr {
+/// Desugar a `cond` expression into nested `if` statements. The value of a
+/// test that is also the value of its clause, or passed to the receiver of
+/// a `=>` clause, is bound to a fresh temporary so that it is evaluated once
+/// ```skip
+/// (cond ((assv x alist) => cdr)
+///       ((f x))
+///       (else y))
+/// ===>
+/// (let ((test~0 (assv x alist)))
+///     (if test~0
+///         (cdr test~0)
+///         (let ((test~1 (f x)))
+///             (if test~1 test~1 y))))
+fn desugar_cond(
+    clauses: Vec<CondClause>,
+    else_clause: Option<Sequence>,
+    table: &mut SymbolTable,
+) -> Expr {
+    desugar_clauses(clauses, table, |table| match else_clause {
+        Some(mut seq) => match seq.len() {
+            0 => Expr::Val(Value::Nil),
+            1 => desugar(seq.remove(0), table),
+            _ => desugar(Expression::Begin(seq), table),
+        },
+        None => Expr::Val(Value::Nil),
+    })
+}
+
+/// Desugar the clauses of a `cond` expression, whose alternative when no
+/// clause applies is built by `alt`
+fn desugar_clauses(
+    mut clauses: Vec<CondClause>,
+    table: &mut SymbolTable,
+    alt: impl FnOnce(&mut SymbolTable) -> Expr,
+) -> Expr {
     if !clauses.is_empty() {
         let fst = clauses.remove(0);
-        Expr::If(
-            Box::new(desugar(*fst.test)),
-            Box::new(desugar(Expression::Begin(fst.body))),
-            Some(Box::new(desugar_cond(clauses, else_clause))),
+        let var = table.fresh("test");
+        let rest = Some(Box::new(desugar_clauses(clauses, table, alt)));
+        let body = match fst.body {
+            CondBody::Sequence(body) if !body.is_empty() => {
+                return Expr::If(
+                    Box::new(desugar(*fst.test, table)),
+                    Box::new(desugar_begin(body, table)),
+                    rest,
+                )
+            }
+            CondBody::Sequence(_) => Expr::Var(var.clone()),
+            CondBody::Arrow(f) => {
+                Expr::App(Box::new(desugar(*f, table)), vec![Expr::Var(var.clone())])
+            }
+        };
+        Expr::Let(
+            var.clone(),
+            Box::new(desugar(*fst.test, table)),
+            Box::new(Expr::If(Box::new(Expr::Var(var)), Box::new(body), rest)),
         )
-    } else if let Some(mut seq) = else_clause {
-        match seq.len() {
-            0 => Expr::Val(Value::Nil),
-            1 => desugar(seq.remove(0)),
-            _ => desugar(Expression::Begin(seq)),
+    } else {
+        alt(table)
+    }
+}
+
+/// Desugar a `guard` expression, whose handler raises the condition again
+/// when no clause applies to it, as by a `raise-continuable` the program
+/// can't rebind
+/// ```skip
+/// (guard (e ((string? e) e)) body)
+/// ===>
+/// (guard e
+///     body
+///     (if (string? e) e (raise-continuable e)))
+/// ```
+fn desugar_guard(
+    var: String,
+    handler: Expression,
+    body: Sequence,
+    table: &mut SymbolTable,
+) -> Expr {
+    let handler = match handler {
+        Expression::Cond(clauses, None) => {
+            let reraise = Expr::Raise(Box::new(Expr::Var(var.clone())));
+            desugar_clauses(clauses, table, |_| reraise)
+        }
+        handler => desugar(handler, table),
+    };
+    Expr::Guard(var, Box::new(desugar_begin(body, table)), Box::new(handler))
+}
+
+/// Desugar `parameterize` into a `dynamic-wind` whose before and after
+/// thunks swap the converted values with those of the parameters, so that
+/// the values are swapped back in when a continuation re-enters the body.
+/// The parameters and values are bound to fresh temporaries, and the
+/// operations are primitives the program can't rebind
+/// ```skip
+/// (parameterize ((p x)) body)
+/// ===>
+/// (let ((p~0 p))
+///     (let ((v~1 x))
+///         (let ((c~2 ((parameter-converter p~0) v~1)))
+///             (let ((swap~3 (λ () (set! c~2 (parameter-swap! p~0 c~2)))))
+///                 (dynamic-wind swap~3 (λ () body) swap~3)))))
+fn desugar_parameterize(
+    bind: Vec<(Expression, Expression)>,
+    body: Sequence,
+    table: &mut SymbolTable,
+) -> Expr {
+    let var = |name: &String| Expr::Var(name.clone());
+    let mut vars = Vec::new();
+    let mut vals = Vec::new();
+    let mut params = Vec::new();
+    for (param, value) in bind {
+        let (p, v) = (table.fresh("p"), table.fresh("v"));
+        vars.push(p.clone());
+        vals.push(desugar(param, table));
+        vars.push(v.clone());
+        vals.push(desugar(value, table));
+        params.push((p, v));
+    }
+    let mut swaps = Vec::new();
+    for (p, v) in &params {
+        let c = table.fresh("c");
+        let converter = Expr::Parameter(ParameterOp::Converter, vec![var(p)]);
+        vars.push(c.clone());
+        vals.push(Expr::App(Box::new(converter), vec![var(v)]));
+        let swap = Expr::Parameter(ParameterOp::Swap, vec![var(p), var(&c)]);
+        swaps.push(Expr::Set(c, Box::new(swap)));
+    }
+    let last = swaps.pop().unwrap_or(Expr::Val(Value::Nil));
+    let temps = (0..swaps.len()).map(|i| format!("~s{}", i)).collect();
+    let swap = table.fresh("swap");
+    vars.push(swap.clone());
+    vals.push(Expr::Lambda(
+        vec![],
+        None,
+        Box::new(desugar_bindings(temps, swaps, last)),
+    ));
+    let wind = Expr::DynamicWind(
+        Box::new(var(&swap)),
+        Box::new(Expr::Lambda(
+            vec![],
+            None,
+            Box::new(desugar_begin(body, table)),
+        )),
+        Box::new(var(&swap)),
+    );
+    desugar_bindings(vars, vals, wind)
+}
+
+/// Whether a `case` datum is a constant `Switch` can look the key up by
+fn is_switch_datum(datum: &Expression) -> bool {
+    matches!(
+        datum,
+        Expression::Literal(Value::Int(_)) | Expression::Quotation(Value::Symbol(_))
+    )
+}
+
+/// Body of a `case` clause, which passes the key to the receiver of a `=>`
+fn desugar_case_body(body: CaseBody, var: &str, table: &mut SymbolTable) -> Expr {
+    match body {
+        CaseBody::Sequence(seq) => desugar_begin(seq, table),
+        CaseBody::Arrow(f) => Expr::App(Box::new(desugar(*f, table)), vec![Expr::Var(var.into())]),
+    }
+}
+
+/// Desugar a `case` expression. The key is bound to a temporary, then
+/// looked up among the data by a `Switch` when every datum is an integer or
+/// a symbol, and compared with each of them by `eqv` otherwise, which the
+/// program can't rebind
+/// ```skip
+/// (case (f x)
+///     ((1 "a") one)
+///     (else => g))
+/// ===>
+/// (let ((key~0 (f x)))
+///     (if (or (eqv key~0 1) (eqv key~0 "a"))
+///         one
+///         (g key~0)))
+/// ```
+fn desugar_case(
+    key: Expression,
+    clauses: Vec<CaseClause>,
+    else_clause: Option<CaseBody>,
+    table: &mut SymbolTable,
+) -> Expr {
+    let var = table.fresh("key");
+    let default = match else_clause {
+        Some(body) => desugar_case_body(body, &var, table),
+        None => Expr::Val(Value::Nil),
+    };
+
+    let dispatch = if clauses.iter().all(|c| c.data.iter().all(is_switch_datum)) {
+        let mut cases = Vec::new();
+        for c in clauses {
+            let data = c.data.into_iter().map(|e| desugar(e, table)).collect();
+            cases.push((data, desugar_case_body(c.body, &var, table)));
         }
+        Expr::Switch(Box::new(Expr::Var(var.clone())), cases, Box::new(default))
     } else {
-        Expr::Val(Value::Nil)
+        clauses.into_iter().rev().fold(default, |alt, c| {
+            let data = c
+                .data
+                .into_iter()
+                .map(|datum| desugar(datum, table))
+                .collect::<Vec<Expr>>();
+            let test = data
+                .into_iter()
+                .rev()
+                .fold(Expr::Val(Value::Bool(false)), |alt, datum| {
+                    let key = Box::new(Expr::Var(var.clone()));
+                    Expr::If(
+                        Box::new(Expr::Eqv(key, Box::new(datum))),
+                        Box::new(Expr::Val(Value::Bool(true))),
+                        Some(Box::new(alt)),
+                    )
+                });
+            Expr::If(
+                Box::new(test),
+                Box::new(desugar_case_body(c.body, &var, table)),
+                Some(Box::new(alt)),
+            )
+        })
+    };
+    Expr::Let(
+        var.clone(),
+        Box::new(desugar(key, table)),
+        Box::new(dispatch),
+    )
+}
+
+/// Desugar a `do` loop into a named let with a fresh name, whose variables
+/// without a step are passed along unchanged
+/// ```skip
+/// (do ((i 0 (+ i 1))
+///      (acc '()))
+///     ((= i n) acc)
+///     (set! acc (cons i acc)))
+/// ===>
+/// (let do~0 ((i 0) (acc '()))
+///     (if (= i n)
+///         acc
+///         (begin
+///             (set! acc (cons i acc))
+///             (do~0 (+ i 1) acc))))
+fn desugar_do(expr: DoExpr, table: &mut SymbolTable) -> Expr {
+    let name = table.fresh("do");
+    let mut bind = Vec::new();
+    let mut steps = Vec::new();
+    for b in expr.bind {
+        let var = Expression::Variable(b.var.clone());
+        steps.push(b.step.unwrap_or(var));
+        bind.push(LetBindings {
+            var: b.var,
+            expr: b.init,
+        });
     }
+
+    let mut body = expr.body;
+    body.push(Expression::Call(
+        Box::new(Expression::Variable(name.clone())),
+        steps,
+    ));
+    let result = match expr.result.is_empty() {
+        true => Expression::Literal(Value::Nil),
+        false => Expression::Begin(expr.result),
+    };
+    let test = Expression::If(
+        expr.test,
+        Box::new(result),
+        Some(Box::new(Expression::Begin(body))),
+    );
+    desugar_let(LetExpr::NamedLet(name, bind, vec![test]), table)
 }
 
 /// Desugar an `and` expression into nested `if` statements
-fn desugar_and(mut body: Sequence) -> Expr {
+fn desugar_and(mut body: Sequence, table: &mut SymbolTable) -> Expr {
     if !body.is_empty() {
         Expr::If(
-            Box::new(desugar(body.remove(0))),
-            Box::new(desugar_and(body)),
+            Box::new(desugar(body.remove(0), table)),
+            Box::new(desugar_and(body, table)),
             Some(Box::new(Expr::Val(Value::Bool(false)))),
         )
     } else {
@@ -136,12 +513,12 @@ fn desugar_and(mut body: Sequence) -> Expr {
 }
 
 /// Desugar an `or` expression into nested `if` statements
-fn desugar_or(mut body: Sequence) -> Expr {
+fn desugar_or(mut body: Sequence, table: &mut SymbolTable) -> Expr {
     if !body.is_empty() {
         Expr::If(
-            Box::new(desugar(body.remove(0))),
+            Box::new(desugar(body.remove(0), table)),
             Box::new(Expr::Val(Value::Bool(true))),
-            Some(Box::new(desugar_or(body))),
+            Some(Box::new(desugar_or(body, table))),
         )
     } else {
         Expr::Val(Value::Bool(false))
@@ -151,54 +528,411 @@ fn desugar_or(mut body: Sequence) -> Expr {
 /// Desugar lambda body. If the body is a sequence of expressions > 1,
 /// then the expressions in the body will be desugared into nested let
 /// statements
-fn desugar_lambda(lambda: LambdaExpr) -> Expr {
+fn desugar_lambda(lambda: LambdaExpr, table: &mut SymbolTable) -> Expr {
     Expr::Lambda(
         lambda.args,
         lambda.rest,
-        Box::new(desugar_begin(lambda.body)),
+        Box::new(desugar_begin(lambda.body, table)),
     )
 }
 
+/// Desugar the clauses of a `case-lambda`. A single clause is just a lambda
+fn desugar_case_lambda(mut clauses: Vec<LambdaExpr>, table: &mut SymbolTable) -> Expr {
+    if clauses.len() == 1 {
+        return desugar_lambda(clauses.remove(0), table);
+    }
+    Expr::CaseLambda(
+        clauses
+            .into_iter()
+            .map(|c| (c.args, c.rest, desugar_begin(c.body, table)))
+            .collect(),
+    )
+}
+
+/// Desugar a record type into the assignments of its procedures, followed
+/// by its descriptor. The procedures refer to the descriptor through the
+/// variable of the type, and address fields by their offset
+/// ```skip
+/// (define-record-type point (make-point x) point? (x point-x set-point-x!))
+/// ===>
+/// (begin
+///     (set! make-point (λ (~f0) (make-record point ~f0)))
+///     (set! point? (λ (~obj) (record? point ~obj)))
+///     (set! point-x (λ (~obj) (record-ref 0 point ~obj)))
+///     (set! set-point-x! (λ (~obj ~val) (record-set! 0 point ~obj ~val)))
+///     (record-type point (x)))
+fn desugar_record_type(rt: RecordType) -> Expr {
+    let name = rt.name.clone();
+    let rtd = || Expr::Var(name.clone());
+    let (ctor, args) = rt.constructor;
+    let params = (0..args.len())
+        .map(|i| format!("~f{}", i))
+        .collect::<Vec<String>>();
+    let obj = "~obj".to_string();
+    let val = "~val".to_string();
+    let procedure = |var: String, params: Vec<String>, op, mut rands: Vec<Expr>| {
+        rands.insert(0, rtd());
+        let body = Expr::Record(op, rands);
+        Expr::Set(var, Box::new(Expr::Lambda(params, None, Box::new(body))))
+    };
+
+    let mut inits = Vec::new();
+    for field in &rt.fields {
+        inits.push(match args.iter().position(|arg| arg == &field.name) {
+            Some(idx) => Expr::Var(params[idx].clone()),
+            None => Expr::Val(Value::Nil),
+        });
+    }
+    let mut exprs = vec![
+        procedure(ctor, params.clone(), RecordOp::Make, inits),
+        procedure(
+            rt.predicate,
+            vec![obj.clone()],
+            RecordOp::Is,
+            vec![Expr::Var(obj.clone())],
+        ),
+    ];
+    let mut fields = Vec::new();
+    for (idx, field) in rt.fields.into_iter().enumerate() {
+        fields.push(field.name);
+        let target = Expr::Var(obj.clone());
+        exprs.push(procedure(
+            field.accessor,
+            vec![obj.clone()],
+            RecordOp::Ref(idx),
+            vec![target],
+        ));
+        if let Some(modifier) = field.modifier {
+            let operands = vec![Expr::Var(obj.clone()), Expr::Var(val.clone())];
+            let params = vec![obj.clone(), val.clone()];
+            exprs.push(procedure(modifier, params, RecordOp::Set(idx), operands));
+        }
+    }
+    let vars = (0..exprs.len()).map(|i| format!("~s{}", i)).collect();
+    desugar_bindings(vars, exprs, Expr::RecordType(rt.name, fields))
+}
+
 /// Desugar an if expression
-fn desugar_if(test: Expression, csq: Expression, alt: Option<Box<Expression>>) -> Expr {
+fn desugar_if(
+    test: Expression,
+    csq: Expression,
+    alt: Option<Box<Expression>>,
+    table: &mut SymbolTable,
+) -> Expr {
     Expr::If(
-        Box::new(desugar(test)),
-        Box::new(desugar(csq)),
-        alt.map(|exp| Box::new(desugar(*exp))),
+        Box::new(desugar(test, table)),
+        Box::new(desugar(csq, table)),
+        alt.map(|exp| Box::new(desugar(*exp, table))),
     )
 }
 
 /// Desugar rator and rand of an application
-fn desugar_app(rator: Expression, rands: Sequence) -> Expr {
+fn desugar_app(rator: Expression, rands: Sequence, table: &mut SymbolTable) -> Expr {
     Expr::App(
-        Box::new(desugar(rator)),
-        rands.into_iter().map(desugar).collect(),
+        Box::new(desugar(rator, table)),
+        rands.into_iter().map(|e| desugar(e, table)).collect(),
     )
 }
 
 /// Desugar binding of an assignment
-fn desugar_assignment(var: String, val: Expression) -> Expr {
-    Expr::Set(var, Box::new(desugar(val)))
+fn desugar_assignment(var: String, val: Expression, table: &mut SymbolTable) -> Expr {
+    Expr::Set(var, Box::new(desugar(val, table)))
 }
 
-pub fn desugar(expr: Expression) -> Expr {
+pub fn desugar(expr: Expression, table: &mut SymbolTable) -> Expr {
     match expr {
-        Expression::If(test, csq, alt) => desugar_if(*test, *csq, alt),
-        Expression::Lambda(expr) => desugar_lambda(expr),
-        Expression::Call(rator, rands) => desugar_app(*rator, rands),
-        Expression::Assignment(var, val) => desugar_assignment(var, *val),
-        Expression::Let(expr) => desugar_let(expr),
-        Expression::Begin(expr) => desugar_begin(expr),
-        Expression::Cond(clauses, else_clause) => desugar_cond(clauses, else_clause),
-        Expression::And(body) => desugar_and(body),
-        Expression::Or(body) => desugar_or(body),
+        Expression::If(test, csq, alt) => desugar_if(*test, *csq, alt, table),
+        Expression::Lambda(expr) => desugar_lambda(expr, table),
+        Expression::CaseLambda(clauses) => desugar_case_lambda(clauses, table),
+        Expression::Call(rator, rands) => desugar_app(*rator, rands, table),
+        Expression::Assignment(var, val) => desugar_assignment(var, *val, table),
+        Expression::Let(expr) => desugar_let(expr, table),
+        Expression::Begin(expr) => desugar_begin(expr, table),
+        Expression::Cond(clauses, else_clause) => desugar_cond(clauses, else_clause, table),
+        Expression::When(test, body) => Expr::If(
+            Box::new(desugar(*test, table)),
+            Box::new(desugar_begin(body, table)),
+            None,
+        ),
+        Expression::Unless(test, body) => Expr::If(
+            Box::new(desugar(*test, table)),
+            Box::new(Expr::Val(Value::Nil)),
+            Some(Box::new(desugar_begin(body, table))),
+        ),
+        Expression::Case(key, clauses, else_clause) => {
+            desugar_case(*key, clauses, else_clause, table)
+        }
+        Expression::Do(expr) => desugar_do(expr, table),
+        Expression::And(body) => desugar_and(body, table),
+        Expression::Or(body) => desugar_or(body, table),
+        Expression::Delay(body) => Expr::Delay(Box::new(desugar_begin(body, table))),
+        Expression::DelayForce(expr) => Expr::DelayForce(Box::new(desugar(*expr, table))),
+        Expression::DefineRecordType(rt) => desugar_record_type(rt),
+        Expression::DefineValues(def) => desugar_define_values(*def, table),
+        Expression::Guard(var, handler, body) => desugar_guard(var, *handler, body, table),
+        Expression::Parameterize(bind, body) => desugar_parameterize(bind, body, table),
 
         // Self-evalulating expressions
         Expression::Literal(val) => Expr::Val(val),
         Expression::Variable(s) => Expr::Var(s),
         // Desugaring for quote is done in the analysis phase
         Expression::Quotation(inner) => Expr::Quote(inner),
-        // This really shouldn't happen, so we WILL panic here, because it's a bug
-        Expression::Keyword(kw) => panic!("unrecognized HIR::Keyword in desugar: {:?}", kw),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::parse;
+
+    fn desugar_str(s: &str) -> Expr {
+        desugar(
+            analyze(parse(s).unwrap().remove(0)).unwrap(),
+            &mut SymbolTable::new(),
+        )
+    }
+
+    #[test]
+    fn parallel_let() {
+        assert_eq!(
+            desugar_str("(let ((x 1) (y x)) y)").to_string(),
+            desugar_str("(let* ((x~0 1) (y~1 x) (x x~0) (y y~1)) y)").to_string()
+        );
+        assert_eq!(
+            desugar_str("(let* ((x 1) (y x)) y)"),
+            Expr::Let(
+                "x".into(),
+                Box::new(Expr::Val(Value::Int(1))),
+                Box::new(Expr::Let(
+                    "y".into(),
+                    Box::new(Expr::Var("x".into())),
+                    Box::new(Expr::Var("y".into()))
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn do_loop() {
+        let expected = desugar_str(
+            "(let do~0 ((i 0) (acc '()))
+                (if (= i 3)
+                    (begin acc)
+                    (begin (set! acc (cons i acc)) (do~0 (+ i 1) acc))))",
+        );
+        assert_eq!(
+            desugar_str("(do ((i 0 (+ i 1)) (acc '())) ((= i 3) acc) (set! acc (cons i acc)))"),
+            expected
+        );
+        let out = desugar_str("(do ((i 0)) ((f i)))").to_string();
+        assert!(out.contains("(do~0 i)"), "{}", out);
+    }
+
+    #[test]
+    fn case_switch() {
+        match desugar_str("(case (f) ((1 2) 'small) ((a b) 'sym) (else => g))") {
+            Expr::Let(var, _, dispatch) => match *dispatch {
+                Expr::Switch(key, cases, default) => {
+                    assert_eq!(*key, Expr::Var(var.clone()));
+                    assert_eq!(cases.len(), 2);
+                    assert_eq!(
+                        cases[0].0,
+                        vec![Expr::Val(Value::Int(1)), Expr::Val(Value::Int(2))]
+                    );
+                    assert_eq!(
+                        *default,
+                        Expr::App(Box::new(Expr::Var("g".into())), vec![Expr::Var(var)])
+                    );
+                }
+                other => panic!("{}", other),
+            },
+            other => panic!("{}", other),
+        }
+    }
+
+    #[test]
+    fn cond_test_values() {
+        let test = || Box::new(Expr::App(Box::new(Expr::Var("f".into())), vec![]));
+        let var = |name: &str| Box::new(Expr::Var(name.into()));
+        assert_eq!(
+            desugar_str("(cond ((f) => g) ((f)))"),
+            Expr::Let(
+                "test~0".into(),
+                test(),
+                Box::new(Expr::If(
+                    var("test~0"),
+                    Box::new(Expr::App(var("g"), vec![*var("test~0")])),
+                    Some(Box::new(Expr::Let(
+                        "test~1".into(),
+                        test(),
+                        Box::new(Expr::If(
+                            var("test~1"),
+                            var("test~1"),
+                            Some(Box::new(Expr::Val(Value::Nil)))
+                        ))
+                    )))
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn guard_reraise() {
+        let reraise = || Expr::Raise(Box::new(Expr::Var("e".into())));
+        assert_eq!(
+            desugar_str("(guard (e (x 1)) y)"),
+            Expr::Guard(
+                "e".into(),
+                Box::new(Expr::Var("y".into())),
+                Box::new(Expr::If(
+                    Box::new(Expr::Var("x".into())),
+                    Box::new(Expr::Val(Value::Int(1))),
+                    Some(Box::new(reraise()))
+                ))
+            )
+        );
+        assert_eq!(
+            desugar_str("(guard (e (else 1)) y)"),
+            Expr::Guard(
+                "e".into(),
+                Box::new(Expr::Var("y".into())),
+                Box::new(Expr::Val(Value::Int(1)))
+            )
+        );
+    }
+
+    #[test]
+    fn parameterize_swaps() {
+        assert_eq!(
+            desugar_str("(parameterize ((p x) (q y)) z)").to_string(),
+            desugar_str(
+                "(let* ((p~0 p) (v~1 x) (p~2 q) (v~3 y)
+                        (c~4 ((parameter-converter p~0) v~1))
+                        (c~5 ((parameter-converter p~2) v~3))
+                        (swap~6 (lambda ()
+                                 (set! c~4 (parameter-swap! p~0 c~4))
+                                 (set! c~5 (parameter-swap! p~2 c~5)))))
+                   (dynamic-wind swap~6 (lambda () z) swap~6))"
+            )
+            .to_string()
+        );
+        // The operations are primitives rather than calls of variables
+        let mut expr = desugar_str("(parameterize ((p x)) z)");
+        let mut vals = Vec::new();
+        while let Expr::Let(_, val, body) = expr {
+            vals.push(*val);
+            expr = *body;
+        }
+        assert!(vals.iter().any(|val| matches!(val, Expr::App(f, _)
+            if matches!(**f, Expr::Parameter(ParameterOp::Converter, _)))));
+        assert!(matches!(expr, Expr::DynamicWind(..)), "{}", expr);
+    }
+
+    #[test]
+    fn values_bindings() {
+        let thunk = |e| Box::new(Expr::Lambda(vec![], None, Box::new(e)));
+        let var = |v: &str| Expr::Var(v.into());
+        assert_eq!(
+            desugar_str("(let-values (((a b) x) (c y)) c)"),
+            Expr::CallWithValues(
+                thunk(var("x")),
+                Box::new(Expr::Lambda(
+                    vec!["a~0".into(), "b~1".into()],
+                    None,
+                    Box::new(Expr::CallWithValues(
+                        thunk(var("y")),
+                        Box::new(Expr::Lambda(
+                            vec![],
+                            Some("c~2".into()),
+                            Box::new(desugar_str("(let* ((a a~0) (b b~1) (c c~2)) c)"))
+                        ))
+                    ))
+                ))
+            )
+        );
+        assert_eq!(
+            desugar_str("(let*-values (((a) x)) a)"),
+            Expr::CallWithValues(
+                thunk(var("x")),
+                Box::new(Expr::Lambda(vec!["a".into()], None, Box::new(var("a"))))
+            )
+        );
+        assert_eq!(
+            desugar_str("(define-values (a . b) x)"),
+            Expr::CallWithValues(
+                thunk(var("x")),
+                Box::new(Expr::Lambda(
+                    vec!["a~0".into()],
+                    Some("b~1".into()),
+                    Box::new(Expr::Let(
+                        "~s0".into(),
+                        Box::new(Expr::Set("a".into(), Box::new(var("a~0")))),
+                        Box::new(Expr::Set("b".into(), Box::new(var("b~1"))))
+                    ))
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn record_type() {
+        let expr = desugar_str("(define-record-type p (mk b) p? (a p-a) (b p-b set-p-b!))");
+        let printed = expr.to_string();
+        assert!(printed.contains("(record-ref 1 p ~obj)"));
+        assert!(printed.contains("(record-set! 1 p ~obj ~val)"));
+        assert!(printed
+            .trim_end_matches(')')
+            .ends_with("(record-type p (a b"));
+        match expr {
+            Expr::Set(_, init) => match *init {
+                Expr::Let(_, set, _) => match *set {
+                    Expr::Set(var, ctor) => {
+                        assert_eq!(var, "mk");
+                        assert_eq!(
+                            *ctor,
+                            Expr::Lambda(
+                                vec!["~f0".into()],
+                                None,
+                                Box::new(Expr::Record(
+                                    RecordOp::Make,
+                                    vec![
+                                        Expr::Var("p".into()),
+                                        Expr::Val(Value::Nil),
+                                        Expr::Var("~f0".into())
+                                    ]
+                                ))
+                            )
+                        );
+                    }
+                    other => panic!("{}", other),
+                },
+                other => panic!("{}", other),
+            },
+            other => panic!("{}", other),
+        }
+    }
+
+    #[test]
+    fn case_eqv_chain() {
+        match desugar_str("(case x ((1000 \"a\") 1) ((2) 2))") {
+            Expr::Let(var, _, dispatch) => match *dispatch {
+                Expr::If(test, csq, Some(alt)) => {
+                    assert_eq!(*csq, Expr::Val(Value::Int(1)));
+                    match *test {
+                        Expr::If(first, _, _) => assert_eq!(
+                            *first,
+                            Expr::Eqv(
+                                Box::new(Expr::Var(var)),
+                                Box::new(Expr::Val(Value::Int(1000)))
+                            )
+                        ),
+                        other => panic!("{}", other),
+                    }
+                    assert!(matches!(*alt, Expr::If(..)));
+                }
+                other => panic!("{}", other),
+            },
+            other => panic!("{}", other),
+        }
     }
 }
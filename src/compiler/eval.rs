@@ -0,0 +1,1108 @@
+//! Objects and primitives of evaluation
+//!
+//! Programs, and the procedural macro transformers run by the expander on
+//! the forms of their uses, are evaluated by the [`cps`](super::cps)
+//! machine. These are the objects they compute with and the primitives that
+//! return a value without calling back into the program; the machine
+//! implements the others.
+use super::cps::Procedure;
+use super::mir::{ParameterOp, RecordOp};
+use super::*;
+use crate::sexp::{Keyword as Kw, List};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+/// Run-time value of an expression
+#[derive(Clone)]
+pub enum Object {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+    Symbol(Symbol),
+    Nil,
+    Pair(Rc<(Object, Object)>),
+    Primitive(&'static str, Primitive),
+    Promise(Rc<Promise>),
+    RecordType(Rc<RecordType>),
+    Record(Rc<Record>),
+    /// Values returned at once other than a single one, which is returned
+    /// as itself
+    Values(Rc<[Object]>),
+    Error(Rc<ErrorObject>),
+    Parameter(Rc<Parameter>),
+    /// Procedure or continuation of the CPS machine
+    Procedure(Rc<Procedure>),
+}
+
+type Primitive = fn(Vec<Object>) -> Result<Object, Error>;
+
+/// Promise, which holds its state in a box that forcing a chain of
+/// `delay-force` promises shares between them, so that they are all forced
+/// at once
+pub struct Promise(RefCell<Rc<RefCell<Lazy>>>);
+
+/// Record-type descriptor, distinct from those of every other evaluation of
+/// a `define-record-type`
+pub struct RecordType {
+    name: String,
+    fields: Vec<String>,
+}
+
+pub struct Record {
+    rtd: Rc<RecordType>,
+    fields: RefCell<Vec<Object>>,
+}
+
+/// Object raised by `error`, or in place of an error of the machine
+pub struct ErrorObject {
+    message: String,
+    irritants: Vec<Object>,
+}
+
+/// Parameter object, which returns its value when called without
+/// arguments. `parameterize` swaps the value for the extent of its body
+pub struct Parameter {
+    value: RefCell<Object>,
+    converter: Option<Object>,
+}
+
+#[derive(Clone)]
+enum Lazy {
+    Value(Object),
+    /// Promise of calling a thunk
+    Thunk {
+        thunk: Object,
+        chained: bool,
+    },
+}
+
+impl Promise {
+    fn state(&self) -> Rc<RefCell<Lazy>> {
+        self.0.borrow().clone()
+    }
+
+    /// Value of a promise that was forced
+    pub(super) fn value(&self) -> Option<Object> {
+        match &*self.state().borrow() {
+            Lazy::Value(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Thunk to call to force a promise of calling one, and whether the
+    /// promise is chained by `delay-force`
+    pub(super) fn thunk(&self) -> Option<(Object, bool)> {
+        match &*self.state().borrow() {
+            Lazy::Thunk { thunk, chained } => Some((thunk.clone(), *chained)),
+            _ => None,
+        }
+    }
+
+    /// Store the result of forcing the promise, unless the promise was
+    /// forced by computing it already. The promise of a `delay-force` takes
+    /// the place of a promise it returns, by sharing its state
+    pub(super) fn settle(&self, result: Object, chained: bool) {
+        let state = self.state();
+        if let Lazy::Value(_) = *state.borrow() {
+            return;
+        }
+        match result {
+            Object::Promise(next) if chained => {
+                let lazy = next.state().borrow().clone();
+                *state.borrow_mut() = lazy;
+                *next.0.borrow_mut() = state;
+            }
+            value => *state.borrow_mut() = Lazy::Value(value),
+        }
+    }
+}
+
+/// Scope of a single binding, or the global scope
+#[derive(Default)]
+pub struct Scope {
+    vars: RefCell<HashMap<String, Object>>,
+    parent: Option<Env>,
+}
+
+pub(super) type Env = Rc<Scope>;
+
+impl Object {
+    /// Convert a datum. Keywords become symbols and dotted tails become
+    /// improper lists
+    pub fn from_sexp(sexp: &Sexp) -> Object {
+        match sexp {
+            Sexp::Identifier(s) => Object::Symbol(Symbol::intern(s)),
+            Sexp::Keyword(kw) => Object::Symbol(Symbol::intern(kw.name())),
+            Sexp::Literal(s) => Object::Str(s.clone()),
+            Sexp::Integer(i) => Object::Int(*i),
+            Sexp::Boolean(b) => Object::Bool(*b),
+            Sexp::List(list) => {
+                let elems = list.iter().collect::<Vec<&Sexp>>();
+                let n = elems.len();
+                let (elems, mut tail) = match n {
+                    n if n >= 2 && *elems[n - 2] == Sexp::Keyword(Kw::Dot) => {
+                        (&elems[..n - 2], Object::from_sexp(elems[n - 1]))
+                    }
+                    _ => (&elems[..], Object::Nil),
+                };
+                for elem in elems.iter().rev() {
+                    tail = Object::cons(Object::from_sexp(elem), tail);
+                }
+                tail
+            }
+        }
+    }
+
+    /// Convert back into a datum, where symbols spelled like keywords are
+    /// keywords again
+    pub fn to_sexp(&self) -> Result<Sexp, Error> {
+        Ok(match self {
+            Object::Int(i) => Sexp::Integer(*i),
+            Object::Bool(b) => Sexp::Boolean(*b),
+            Object::Str(s) => Sexp::Literal(s.clone()),
+            Object::Symbol(s) => match Kw::from_name(s.name()) {
+                Some(kw) => Sexp::Keyword(kw),
+                None if s.name() == "." => Sexp::Keyword(Kw::Dot),
+                None => Sexp::Identifier(s.name().to_string()),
+            },
+            Object::Nil => Sexp::List(List::Nil),
+            Object::Pair(_) => {
+                let mut elems = Vec::new();
+                let mut obj = self;
+                while let Object::Pair(pair) = obj {
+                    elems.push(pair.0.to_sexp()?);
+                    obj = &pair.1;
+                }
+                if !matches!(obj, Object::Nil) {
+                    elems.push(Sexp::Keyword(Kw::Dot));
+                    elems.push(obj.to_sexp()?);
+                }
+                Sexp::List(elems.into_iter().collect())
+            }
+            Object::Primitive(..)
+            | Object::Promise(_)
+            | Object::RecordType(_)
+            | Object::Record(_)
+            | Object::Values(_)
+            | Object::Error(_)
+            | Object::Parameter(_)
+            | Object::Procedure(_) => {
+                return Err(Error::Message(format!("{} is not a datum", self)))
+            }
+        })
+    }
+
+    /// Values returned as `self`, as arguments to pass on
+    pub(super) fn into_values(self) -> Vec<Object> {
+        match self {
+            Object::Values(values) => values.to_vec(),
+            obj => vec![obj],
+        }
+    }
+
+    pub(super) fn error(message: String, irritants: Vec<Object>) -> Object {
+        Object::Error(Rc::new(ErrorObject { message, irritants }))
+    }
+
+    fn promise(lazy: Lazy) -> Object {
+        Object::Promise(Rc::new(Promise(RefCell::new(Rc::new(RefCell::new(lazy))))))
+    }
+
+    /// Promise of calling `thunk`, chained when made by `delay-force`
+    pub(super) fn delayed(thunk: Object, chained: bool) -> Object {
+        Object::promise(Lazy::Thunk { thunk, chained })
+    }
+
+    /// Record-type descriptor named after the variable `name`, without the
+    /// `~N` suffix it has when renamed by the expander
+    pub(super) fn record_type(name: &str, fields: &[String]) -> Object {
+        Object::RecordType(Rc::new(RecordType {
+            name: name.split('~').next().unwrap_or_default().to_string(),
+            fields: fields.to_vec(),
+        }))
+    }
+
+    /// Parameter object of `value`, converted already by `converter`
+    pub(super) fn parameter(value: Object, converter: Option<Object>) -> Object {
+        Object::Parameter(Rc::new(Parameter {
+            value: RefCell::new(value),
+            converter,
+        }))
+    }
+
+    pub(super) fn cons(car: Object, cdr: Object) -> Object {
+        Object::Pair(Rc::new((car, cdr)))
+    }
+
+    pub(super) fn list(elems: Vec<Object>) -> Object {
+        elems
+            .into_iter()
+            .rev()
+            .fold(Object::Nil, |tail, elem| Object::cons(elem, tail))
+    }
+
+    /// Elements of a proper list
+    pub(super) fn elements(&self) -> Result<Vec<Object>, Error> {
+        let mut elems = Vec::new();
+        let mut obj = self;
+        while let Object::Pair(pair) = obj {
+            elems.push(pair.0.clone());
+            obj = &pair.1;
+        }
+        match obj {
+            Object::Nil => Ok(elems),
+            _ => Err(Error::Message(format!("expected a list, found {}", self))),
+        }
+    }
+
+    pub(super) fn is_procedure(&self) -> bool {
+        matches!(
+            self,
+            Object::Primitive(..) | Object::Parameter(_) | Object::Procedure(_)
+        )
+    }
+
+    pub(super) fn is_true(&self) -> bool {
+        !matches!(self, Object::Bool(false))
+    }
+
+    pub(super) fn eqv(&self, other: &Object) -> bool {
+        match (self, other) {
+            (Object::Int(a), Object::Int(b)) => a == b,
+            (Object::Bool(a), Object::Bool(b)) => a == b,
+            (Object::Symbol(a), Object::Symbol(b)) => a == b,
+            (Object::Str(a), Object::Str(b)) => a == b,
+            (Object::Nil, Object::Nil) => true,
+            (Object::Pair(a), Object::Pair(b)) => Rc::ptr_eq(a, b),
+            (Object::Primitive(a, _), Object::Primitive(b, _)) => a == b,
+            (Object::Promise(a), Object::Promise(b)) => Rc::ptr_eq(a, b),
+            (Object::RecordType(a), Object::RecordType(b)) => Rc::ptr_eq(a, b),
+            (Object::Record(a), Object::Record(b)) => Rc::ptr_eq(a, b),
+            (Object::Error(a), Object::Error(b)) => Rc::ptr_eq(a, b),
+            (Object::Parameter(a), Object::Parameter(b)) => Rc::ptr_eq(a, b),
+            (Object::Procedure(a), Object::Procedure(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn equal(&self, other: &Object) -> bool {
+        match (self, other) {
+            (Object::Pair(a), Object::Pair(b)) => a.0.equal(&b.0) && a.1.equal(&b.1),
+            _ => self.eqv(other),
+        }
+    }
+}
+
+impl From<Value> for Object {
+    fn from(value: Value) -> Object {
+        match value {
+            Value::Str(s) => Object::Str(s),
+            Value::Symbol(s) => Object::Symbol(s),
+            Value::Bool(b) => Object::Bool(b),
+            Value::Int(i) => Object::Int(i),
+            Value::Nil => Object::Nil,
+            Value::Pair(pair) => {
+                let (car, cdr) = (*pair).clone();
+                Object::cons(Object::from(car), Object::from(cdr))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Object::Promise(_) => f.write_str("#<promise>"),
+            Object::Parameter(_) => f.write_str("#<parameter>"),
+            Object::RecordType(rtd) => write!(f, "#<record-type {}>", rtd.name),
+            Object::Values(values) => {
+                let values = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                f.write_str(&values.join(" "))
+            }
+            Object::Record(record) => {
+                write!(f, "#<record {}", record.rtd.name)?;
+                let values = record.fields.borrow();
+                for (name, value) in record.rtd.fields.iter().zip(values.iter()) {
+                    write!(f, " ({} {})", name, value)?;
+                }
+                f.write_str(">")
+            }
+            Object::Primitive(name, _) => write!(f, "#<procedure {}>", name),
+            Object::Procedure(p) => write!(f, "{}", p),
+            Object::Error(error) => {
+                write!(f, "#<error {}", Object::Str(error.message.clone()))?;
+                for irritant in &error.irritants {
+                    write!(f, " {}", irritant)?;
+                }
+                f.write_str(">")
+            }
+            _ => match self.to_sexp() {
+                Ok(sexp) => write!(f, "{}", sexp.write()),
+                Err(_) => f.write_str("#<list>"),
+            },
+        }
+    }
+}
+
+/// Global scope of the primitives
+pub(super) fn globals() -> Env {
+    let global = Env::default();
+    for (name, f) in PRIMITIVES.iter() {
+        define(&global, name, Object::Primitive(name, *f));
+    }
+    global
+}
+
+/// Bind `var` in the innermost scope of `env`, replacing its binding there
+pub(super) fn define(env: &Env, var: &str, val: Object) {
+    env.vars.borrow_mut().insert(var.to_string(), val);
+}
+
+pub(super) fn bind(env: &Env, var: &str, val: Object) -> Env {
+    let mut vars = HashMap::new();
+    vars.insert(var.to_string(), val);
+    Rc::new(Scope {
+        vars: RefCell::new(vars),
+        parent: Some(env.clone()),
+    })
+}
+
+pub(super) fn lookup(env: &Env, var: &str) -> Result<Object, Error> {
+    let mut scope = Some(env);
+    while let Some(s) = scope {
+        if let Some(val) = s.vars.borrow().get(var) {
+            return Ok(val.clone());
+        }
+        scope = s.parent.as_ref();
+    }
+    Err(Error::Message(format!("unbound variable `{}`", var)))
+}
+
+/// Replace the value of the innermost binding of `var` in `env`
+pub(super) fn assign(env: &Env, var: &str, val: Object) -> Result<(), Error> {
+    let mut scope = Some(env);
+    while let Some(s) = scope {
+        if let Some(slot) = s.vars.borrow_mut().get_mut(var) {
+            *slot = val;
+            return Ok(());
+        }
+        scope = s.parent.as_ref();
+    }
+    Err(Error::Message(format!("unbound variable `{}`", var)))
+}
+
+/// Error for an object raised and not handled
+pub(super) fn uncaught(obj: &Object) -> Error {
+    match obj {
+        Object::Error(error) => {
+            let mut message = error.message.clone();
+            for irritant in &error.irritants {
+                message.push(' ');
+                message.push_str(&irritant.to_string());
+            }
+            Error::Message(message)
+        }
+        obj => Error::Message(format!("uncaught exception: {}", obj)),
+    }
+}
+
+/// Perform `op` on the operands `args`, the first of which is the parameter
+pub(super) fn parameter_op(op: ParameterOp, args: Vec<Object>) -> Result<Object, Error> {
+    let p = parameter_object(&args[0])?;
+    Ok(match op {
+        // `values` of one value is the identity
+        ParameterOp::Converter => p
+            .converter
+            .clone()
+            .unwrap_or(Object::Primitive("values", values)),
+        ParameterOp::Swap => p.value.replace(args[1].clone()),
+    })
+}
+
+/// Perform `op` on the operands `args`, the first of which is the
+/// descriptor of the record type
+pub(super) fn record(op: RecordOp, mut args: Vec<Object>) -> Result<Object, Error> {
+    let rtd = match args.remove(0) {
+        Object::RecordType(rtd) => rtd,
+        other => {
+            return Err(Error::Message(format!(
+                "expected a record-type descriptor, found {}",
+                other
+            )))
+        }
+    };
+    if op == RecordOp::Make {
+        return Ok(Object::Record(Rc::new(Record {
+            rtd,
+            fields: RefCell::new(args),
+        })));
+    }
+    let record = match &args[0] {
+        Object::Record(record) if Rc::ptr_eq(&record.rtd, &rtd) => record.clone(),
+        _ if op == RecordOp::Is => return Ok(Object::Bool(false)),
+        other => {
+            return Err(Error::Message(format!(
+                "expected a record of type `{}`, found {}",
+                rtd.name, other
+            )))
+        }
+    };
+    Ok(match op {
+        RecordOp::Ref(idx) => record.fields.borrow()[idx].clone(),
+        RecordOp::Set(idx) => {
+            record.fields.borrow_mut()[idx] = args[1].clone();
+            Object::Nil
+        }
+        _ => Object::Bool(true),
+    })
+}
+
+pub(super) fn arity(name: &str, args: &[Object], n: usize) -> Result<(), Error> {
+    match args.len() == n {
+        true => Ok(()),
+        false => Err(Error::Message(format!(
+            "`{}` expects {} arguments, found {}",
+            name,
+            n,
+            args.len()
+        ))),
+    }
+}
+
+fn int(obj: &Object) -> Result<i64, Error> {
+    match obj {
+        Object::Int(i) => Ok(*i),
+        _ => Err(Error::Message(format!(
+            "expected an integer, found {}",
+            obj
+        ))),
+    }
+}
+
+fn pair(obj: &Object) -> Result<&(Object, Object), Error> {
+    match obj {
+        Object::Pair(pair) => Ok(pair),
+        _ => Err(Error::Message(format!("expected a pair, found {}", obj))),
+    }
+}
+
+fn string(obj: &Object) -> Result<&str, Error> {
+    match obj {
+        Object::Str(s) => Ok(s),
+        _ => Err(Error::Message(format!("expected a string, found {}", obj))),
+    }
+}
+
+fn arithmetic(
+    args: Vec<Object>,
+    unit: i64,
+    op: fn(i64, i64) -> Option<i64>,
+) -> Result<Object, Error> {
+    let mut acc = unit;
+    for arg in &args {
+        acc = op(acc, int(arg)?).ok_or_else(|| Error::Message("integer overflow".into()))?;
+    }
+    Ok(Object::Int(acc))
+}
+
+fn compare(args: Vec<Object>, op: fn(&i64, &i64) -> bool) -> Result<Object, Error> {
+    let ints = args.iter().map(int).collect::<Result<Vec<i64>, Error>>()?;
+    Ok(Object::Bool(ints.windows(2).all(|w| op(&w[0], &w[1]))))
+}
+
+fn error_object(obj: &Object) -> Result<&ErrorObject, Error> {
+    match obj {
+        Object::Error(error) => Ok(error),
+        _ => Err(Error::Message(format!(
+            "expected an error object, found {}",
+            obj
+        ))),
+    }
+}
+
+/// Value of the parameter object `p` called with `args`
+pub(super) fn parameter(p: &Parameter, args: &[Object]) -> Result<Object, Error> {
+    match args.is_empty() {
+        true => Ok(p.value.borrow().clone()),
+        false => Err(Error::Message(format!(
+            "a parameter expects 0 arguments, found {}",
+            args.len()
+        ))),
+    }
+}
+
+fn values(mut args: Vec<Object>) -> Result<Object, Error> {
+    match args.len() {
+        1 => Ok(args.remove(0)),
+        _ => Ok(Object::Values(args.into())),
+    }
+}
+
+fn parameter_object(obj: &Object) -> Result<&Parameter, Error> {
+    match obj {
+        Object::Parameter(p) => Ok(p),
+        _ => Err(Error::Message(format!(
+            "expected a parameter, found {}",
+            obj
+        ))),
+    }
+}
+
+const PRIMITIVES: &[(&str, Primitive)] = &[
+    ("cons", |args| {
+        arity("cons", &args, 2)?;
+        let mut args = args.into_iter();
+        Ok(Object::cons(
+            args.next().unwrap_or(Object::Nil),
+            args.next().unwrap_or(Object::Nil),
+        ))
+    }),
+    ("car", |args| {
+        arity("car", &args, 1)?;
+        Ok(pair(&args[0])?.0.clone())
+    }),
+    ("cdr", |args| {
+        arity("cdr", &args, 1)?;
+        Ok(pair(&args[0])?.1.clone())
+    }),
+    ("cadr", |args| {
+        arity("cadr", &args, 1)?;
+        Ok(pair(&pair(&args[0])?.1)?.0.clone())
+    }),
+    ("cddr", |args| {
+        arity("cddr", &args, 1)?;
+        Ok(pair(&pair(&args[0])?.1)?.1.clone())
+    }),
+    ("caddr", |args| {
+        arity("caddr", &args, 1)?;
+        Ok(pair(&pair(&pair(&args[0])?.1)?.1)?.0.clone())
+    }),
+    ("list", |args| Ok(Object::list(args))),
+    ("length", |args| {
+        arity("length", &args, 1)?;
+        Ok(Object::Int(args[0].elements()?.len() as i64))
+    }),
+    ("reverse", |args| {
+        arity("reverse", &args, 1)?;
+        let mut elems = args[0].elements()?;
+        elems.reverse();
+        Ok(Object::list(elems))
+    }),
+    ("append", |mut args| {
+        let mut tail = args.pop().unwrap_or(Object::Nil);
+        for list in args.iter().rev() {
+            for elem in list.elements()?.into_iter().rev() {
+                tail = Object::cons(elem, tail);
+            }
+        }
+        Ok(tail)
+    }),
+    ("list-ref", |args| {
+        arity("list-ref", &args, 2)?;
+        let elems = args[0].elements()?;
+        let idx = int(&args[1])?;
+        usize::try_from(idx)
+            .ok()
+            .and_then(|idx| elems.get(idx).cloned())
+            .ok_or_else(|| Error::Message(format!("index {} is out of range", idx)))
+    }),
+    ("values", values),
+    ("null?", |args| {
+        arity("null?", &args, 1)?;
+        Ok(Object::Bool(matches!(args[0], Object::Nil)))
+    }),
+    ("pair?", |args| {
+        arity("pair?", &args, 1)?;
+        Ok(Object::Bool(matches!(args[0], Object::Pair(_))))
+    }),
+    ("list?", |args| {
+        arity("list?", &args, 1)?;
+        Ok(Object::Bool(args[0].elements().is_ok()))
+    }),
+    ("symbol?", |args| {
+        arity("symbol?", &args, 1)?;
+        Ok(Object::Bool(matches!(args[0], Object::Symbol(_))))
+    }),
+    ("string?", |args| {
+        arity("string?", &args, 1)?;
+        Ok(Object::Bool(matches!(args[0], Object::Str(_))))
+    }),
+    ("number?", |args| {
+        arity("number?", &args, 1)?;
+        Ok(Object::Bool(matches!(args[0], Object::Int(_))))
+    }),
+    ("boolean?", |args| {
+        arity("boolean?", &args, 1)?;
+        Ok(Object::Bool(matches!(args[0], Object::Bool(_))))
+    }),
+    ("procedure?", |args| {
+        arity("procedure?", &args, 1)?;
+        Ok(Object::Bool(args[0].is_procedure()))
+    }),
+    ("promise?", |args| {
+        arity("promise?", &args, 1)?;
+        Ok(Object::Bool(matches!(args[0], Object::Promise(_))))
+    }),
+    ("make-promise", |args| {
+        arity("make-promise", &args, 1)?;
+        match &args[0] {
+            Object::Promise(_) => Ok(args[0].clone()),
+            value => Ok(Object::promise(Lazy::Value(value.clone()))),
+        }
+    }),
+    ("not", |args| {
+        arity("not", &args, 1)?;
+        Ok(Object::Bool(!args[0].is_true()))
+    }),
+    ("eq?", |args| {
+        arity("eq?", &args, 2)?;
+        Ok(Object::Bool(args[0].eqv(&args[1])))
+    }),
+    ("eqv?", |args| {
+        arity("eqv?", &args, 2)?;
+        Ok(Object::Bool(args[0].eqv(&args[1])))
+    }),
+    ("equal?", |args| {
+        arity("equal?", &args, 2)?;
+        Ok(Object::Bool(args[0].equal(&args[1])))
+    }),
+    ("+", |args| arithmetic(args, 0, i64::checked_add)),
+    ("*", |args| arithmetic(args, 1, i64::checked_mul)),
+    ("-", |args| match args.len() {
+        0 => Err(Error::Message("`-` expects at least 1 argument".into())),
+        1 => arithmetic(args, 0, i64::checked_sub),
+        _ => {
+            let first = int(&args[0])?;
+            arithmetic(args[1..].to_vec(), first, i64::checked_sub)
+        }
+    }),
+    ("quotient", |args| {
+        arity("quotient", &args, 2)?;
+        int(&args[0])?
+            .checked_div(int(&args[1])?)
+            .map(Object::Int)
+            .ok_or_else(|| Error::Message("division by zero".into()))
+    }),
+    ("remainder", |args| {
+        arity("remainder", &args, 2)?;
+        int(&args[0])?
+            .checked_rem(int(&args[1])?)
+            .map(Object::Int)
+            .ok_or_else(|| Error::Message("division by zero".into()))
+    }),
+    ("=", |args| compare(args, i64::eq)),
+    ("<", |args| compare(args, i64::lt)),
+    (">", |args| compare(args, i64::gt)),
+    ("<=", |args| compare(args, i64::le)),
+    (">=", |args| compare(args, i64::ge)),
+    ("symbol->string", |args| {
+        arity("symbol->string", &args, 1)?;
+        match &args[0] {
+            Object::Symbol(s) => Ok(Object::Str(s.name().to_string())),
+            obj => Err(Error::Message(format!("expected a symbol, found {}", obj))),
+        }
+    }),
+    ("string->symbol", |args| {
+        arity("string->symbol", &args, 1)?;
+        Ok(Object::Symbol(Symbol::intern(string(&args[0])?)))
+    }),
+    ("number->string", |args| {
+        arity("number->string", &args, 1)?;
+        Ok(Object::Str(int(&args[0])?.to_string()))
+    }),
+    ("string-append", |args| {
+        let strs = args
+            .iter()
+            .map(string)
+            .collect::<Result<Vec<&str>, Error>>()?;
+        Ok(Object::Str(strs.concat()))
+    }),
+    ("error-object?", |args| {
+        arity("error-object?", &args, 1)?;
+        Ok(Object::Bool(matches!(args[0], Object::Error(_))))
+    }),
+    ("error-object-message", |args| {
+        arity("error-object-message", &args, 1)?;
+        Ok(Object::Str(error_object(&args[0])?.message.clone()))
+    }),
+    ("error-object-irritants", |args| {
+        arity("error-object-irritants", &args, 1)?;
+        Ok(Object::list(error_object(&args[0])?.irritants.clone()))
+    }),
+];
+
+#[cfg(test)]
+mod test {
+    use super::super::cps::Machine;
+    use super::*;
+
+    fn eval_str(input: &str) -> Result<String, Error> {
+        let mut symbols = SymbolTable::new();
+        let mut machine = Machine::new();
+        let mut result = Object::Nil;
+        for form in crate::syntax::parse(input).unwrap() {
+            result = machine.eval(&form, &mut symbols).map_err(|e| match e {
+                Error::Backtrace(_, e) => *e,
+                e => e,
+            })?;
+        }
+        Ok(result.to_string())
+    }
+
+    #[test]
+    fn evaluation() {
+        assert_eq!(eval_str("(+ 1 2 3)"), Ok("6".to_string()));
+        assert_eq!(
+            eval_str("((lambda (x . r) (cons r x)) 1 2 3)"),
+            Ok("((2 3) . 1)".to_string())
+        );
+        assert_eq!(
+            eval_str("(let loop ((i 0) (acc '())) (if (= i 3) (reverse acc) (loop (+ i 1) (cons i acc))))"),
+            Ok("(0 1 2)".to_string())
+        );
+        assert_eq!(
+            eval_str("(let ((x 'a)) `(,x ,@(map (lambda (y) (* y y)) '(1 2)) end))"),
+            Ok("(a 1 4 end)".to_string())
+        );
+        assert_eq!(
+            eval_str("(case (car '(b)) ((a) 1) ((b c) 2) (else 3))"),
+            Ok("2".to_string())
+        );
+        assert_eq!(eval_str("(apply + 1 '(2 3))"), Ok("6".to_string()));
+        // Calls in tail position don't nest
+        assert_eq!(
+            eval_str("(let loop ((i 0)) (if (= i 10000) i (loop (+ i 1))))"),
+            Ok("10000".to_string())
+        );
+    }
+
+    #[test]
+    fn symbols() {
+        assert_eq!(
+            eval_str("(list (symbol? 'abc) (symbol? \"abc\") (string? 'abc))"),
+            Ok("(#t #f #f)".to_string())
+        );
+        assert_eq!(
+            eval_str("(list (eq? 'abc 'abc) (eq? 'abc (string->symbol \"abc\")) (eq? 'abc 'abd))"),
+            Ok("(#t #t #f)".to_string())
+        );
+        assert_eq!(
+            eval_str("(list 'abc \"abc\" (symbol->string 'abc))"),
+            Ok("(abc \"abc\" \"abc\")".to_string())
+        );
+        assert_eq!(eval_str("(eq? (gensym) (gensym))"), Ok("#f".to_string()));
+    }
+
+    #[test]
+    fn quoted_lists() {
+        assert_eq!(
+            eval_str("(let ((cons list)) '(1 (a \"b\") ()))"),
+            Ok("(1 (a \"b\") ())".to_string())
+        );
+        assert_eq!(eval_str("'(1 2 . 3)"), Ok("(1 2 . 3)".to_string()));
+        assert_eq!(
+            eval_str("(equal? '(a b) (list 'a 'b))"),
+            Ok("#t".to_string())
+        );
+        assert_eq!(eval_str("`(1 ,(+ 1 1) ())"), Ok("(1 2 ())".to_string()));
+    }
+
+    #[test]
+    fn promises() {
+        assert_eq!(
+            eval_str("(let* ((n 0) (p (delay (set! n (+ n 1)) n))) (list (force p) (force p) n))"),
+            Ok("(1 1 1)".to_string())
+        );
+        // A promise forced again while it is being forced keeps the first value
+        assert_eq!(
+            eval_str(
+                "(letrec ((x 5)
+                          (p (delay (set! x (+ x 1)) (if (> x 5) x (force p)))))
+                   (list (force p) (begin (set! x 10) (force p))))"
+            ),
+            Ok("(6 6)".to_string())
+        );
+        // Chains of `delay-force` are forced without nesting
+        assert_eq!(
+            eval_str(
+                "(letrec ((loop (lambda (n) (delay-force (if (= n 0) (delay 'done) (loop (- n 1)))))))
+                   (force (loop 100000)))"
+            ),
+            Ok("done".to_string())
+        );
+        assert_eq!(
+            eval_str("(list (force 1) (promise? (make-promise 2)) (force (make-promise 2)))"),
+            Ok("(1 #t 2)".to_string())
+        );
+        assert_eq!(eval_str("(promise? (delay 1))"), Ok("#t".to_string()));
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(
+            eval_str(
+                "(let ((n 0))
+                   (cond ((begin (set! n (+ n 1)) n) => (lambda (x) (list x n)))
+                         (else 'no)))"
+            ),
+            Ok("(1 1)".to_string())
+        );
+        assert_eq!(eval_str("(cond (#f) (2) (else 3))"), Ok("2".to_string()));
+        assert_eq!(
+            eval_str("(list (when (= 1 1) 'a 'b) (unless (= 1 1) 'c) (unless #f 'd))"),
+            Ok("(b () d)".to_string())
+        );
+    }
+
+    #[test]
+    fn multiple_values() {
+        assert_eq!(
+            eval_str("(call-with-values (lambda () (values 1 2)) cons)"),
+            Ok("(1 . 2)".to_string())
+        );
+        assert_eq!(
+            eval_str("(call-with-values (lambda () 1) list)"),
+            Ok("(1)".to_string())
+        );
+        assert_eq!(eval_str("(values 1 2)"), Ok("1 2".to_string()));
+        assert_eq!(
+            eval_str(
+                "(let ((x 1))
+                   (let-values (((a b) (values x 2)) ((x . r) (values 3 4 5)))
+                     (list a b x r)))"
+            ),
+            Ok("(1 2 3 (4 5))".to_string())
+        );
+        assert_eq!(
+            eval_str("(let*-values (((a) (values 1)) (r (values a a))) r)"),
+            Ok("(1 1)".to_string())
+        );
+        assert_eq!(
+            eval_str("(let () (define-values (a . b) (values 1 2 3)) (define c a) (list c b))"),
+            Ok("(1 (2 3))".to_string())
+        );
+        // The parameters of the consumer don't capture the variables
+        assert_eq!(
+            eval_str("(let () (define ~values 3) (define-values (~v1 ~v0) (values 1 2)) (list ~values ~v1 ~v0))"),
+            Ok("(3 1 2)".to_string())
+        );
+        assert_eq!(
+            eval_str("(let-values (((a b) (values 1))) a)"),
+            Err(Error::Message(
+                "procedure expects 2 arguments, found 1".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn records() {
+        let point = "(define-record-type point (make-point x y) point? (x point-x) (y point-y set-point-y!))";
+        assert_eq!(
+            eval_str(&format!(
+                "(let () {} (let ((p (make-point 1 2)))
+                   (set-point-y! p 3)
+                   (list (point? p) (point? 1) (point-x p) (point-y p))))",
+                point
+            )),
+            Ok("(#t #f 1 3)".to_string())
+        );
+        assert_eq!(
+            eval_str(&format!("(let () {} (make-point 1 2))", point)),
+            Ok("#<record point (x 1) (y 2)>".to_string())
+        );
+        // Each evaluation of a definition makes a distinct type
+        assert_eq!(
+            eval_str(
+                "(let ((make (lambda () (define-record-type t (mk) t?) (list mk t?))))
+                   (let ((a (make)) (b (make)))
+                     (list ((cadr a) ((car a))) ((cadr a) ((car b))))))"
+            ),
+            Ok("(#t #f)".to_string())
+        );
+        assert_eq!(
+            eval_str(&format!("(let () {} (point-x 1))", point)),
+            Err(Error::Message(
+                "expected a record of type `point`, found 1".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn case_lambda() {
+        let f = "(let ((f (case-lambda ((x) (list 'one x)) ((x y) (list 'two x y)) ((x . r) (list 'many r)))))";
+        assert_eq!(
+            eval_str(&format!("{} (list (f 1) (f 1 2) (f 1 2 3)))", f)),
+            Ok("((one 1) (two 1 2) (many (2 3)))".to_string())
+        );
+        assert_eq!(
+            eval_str(&format!("{} (f))", f)),
+            Err(Error::Message(
+                "no clause of the procedure accepts 0 arguments".into()
+            ))
+        );
+        assert_eq!(
+            eval_str("((case-lambda ((x) x)) 1 2)"),
+            Err(Error::Message(
+                "procedure expects 1 arguments, found 2".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn exceptions() {
+        assert_eq!(
+            eval_str("(guard (e (#t (list 'caught e))) (+ 1 (raise 'oops)))"),
+            Ok("(caught oops)".to_string())
+        );
+        assert_eq!(
+            eval_str(
+                "(guard (e ((error-object? e)
+                            (cons (error-object-message e) (error-object-irritants e))))
+                   (error \"bad thing:\" 1 2))"
+            ),
+            Ok("(\"bad thing:\" 1 2)".to_string())
+        );
+        assert_eq!(
+            eval_str("(guard (e ((error-object? e) (error-object-message e))) (car 1))"),
+            Ok("\"expected a pair, found 1\"".to_string())
+        );
+        assert_eq!(
+            eval_str("(guard (e ((null? e) 'empty) ((cdr e) => car)) (raise (list 1 2)))"),
+            Ok("2".to_string())
+        );
+        assert_eq!(
+            eval_str("(guard (e ((null? e) 'empty) ((car e))) (raise (list 5)))"),
+            Ok("5".to_string())
+        );
+        assert_eq!(
+            eval_str("(guard (e (#t e)) (define x 1) (raise x))"),
+            Ok("1".to_string())
+        );
+        // Conditions no clause applies to are raised again
+        assert_eq!(
+            eval_str(
+                "(guard (e ((symbol? e) 'outer)) (guard (e ((string? e) 'inner)) (raise 'x)))"
+            ),
+            Ok("outer".to_string())
+        );
+        assert_eq!(
+            eval_str("(guard (e ((string? e) e)) (raise 'x))"),
+            Err(Error::Message("uncaught exception: x".into()))
+        );
+        assert_eq!(
+            eval_str("(raise (list 1 2))"),
+            Err(Error::Message("uncaught exception: (1 2)".into()))
+        );
+    }
+
+    #[test]
+    fn exception_handlers() {
+        assert_eq!(
+            eval_str(
+                "(with-exception-handler
+                   (lambda (c) 42)
+                   (lambda () (+ (raise-continuable 'oops) 1)))"
+            ),
+            Ok("43".to_string())
+        );
+        assert_eq!(
+            eval_str("(with-exception-handler (lambda (c) 0) (lambda () (raise 'oops)))"),
+            Err(Error::Message("exception handler returned oops".into()))
+        );
+        // A handler runs with the handlers outside of it installed
+        assert_eq!(
+            eval_str(
+                "(with-exception-handler
+                   (lambda (c) (list 'outer c))
+                   (lambda ()
+                     (with-exception-handler
+                       (lambda (c) (raise-continuable (list 'inner c)))
+                       (lambda () (raise-continuable 'oops)))))"
+            ),
+            Ok("(outer (inner oops))".to_string())
+        );
+        // Errors of the machine are raised to handlers too
+        assert_eq!(
+            eval_str(
+                "(guard (e (#t (list 'guard e)))
+                   (with-exception-handler
+                     (lambda (c) (raise (error-object-message c)))
+                     (lambda () (car 1))))"
+            ),
+            Ok("(guard \"expected a pair, found 1\")".to_string())
+        );
+        // The innermost `guard` handles exceptions before outer handlers
+        assert_eq!(
+            eval_str(
+                "(with-exception-handler
+                   (lambda (c) 'handler)
+                   (lambda () (guard (e (#t 'guard)) (raise 'oops))))"
+            ),
+            Ok("guard".to_string())
+        );
+    }
+
+    #[test]
+    fn dynamic_extent() {
+        assert_eq!(
+            eval_str(
+                "(let ((log '()))
+                   (define (note x) (set! log (cons x log)))
+                   (dynamic-wind (lambda () (note 'before))
+                                 (lambda () (note 'during))
+                                 (lambda () (note 'after)))
+                   (guard (e (#t (note e)))
+                     (dynamic-wind (lambda () (note 'in))
+                                   (lambda () (raise 'oops))
+                                   (lambda () (note 'out))))
+                   (reverse log))"
+            ),
+            Ok("(before during after in out oops)".to_string())
+        );
+        assert_eq!(
+            eval_str(
+                "(let ((p (make-parameter 10 (lambda (x) (* x 2)))))
+                   (list (p) (parameterize ((p 3)) (p)) (p)))"
+            ),
+            Ok("(20 6 20)".to_string())
+        );
+        assert_eq!(
+            eval_str(
+                "(let ((p (make-parameter 1)))
+                   (guard (e (#t (p))) (parameterize ((p 2)) (raise 'oops))))"
+            ),
+            Ok("1".to_string())
+        );
+        assert_eq!(
+            eval_str("((make-parameter 1) 2)"),
+            Err(Error::Message(
+                "a parameter expects 0 arguments, found 1".into()
+            ))
+        );
+        assert_eq!(
+            eval_str("(parameterize ((car 1)) 2)"),
+            Err(Error::Message(
+                "expected a parameter, found #<procedure car>".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        assert_eq!(
+            eval_str("(car '())"),
+            Err(Error::Message("expected a pair, found ()".into()))
+        );
+        assert_eq!(
+            eval_str("(error \"bad form:\" '(f 1))"),
+            Err(Error::Message("bad form: (f 1)".into()))
+        );
+        assert_eq!(
+            eval_str("(undefined 1)"),
+            Err(Error::Message("unbound variable `undefined`".into()))
+        );
+    }
+}
@@ -0,0 +1,2604 @@
+//! Expansion of hygienic macros, between parsing and analysis
+//!
+//! Macros are defined by `syntax-rules` transformers bound with
+//! `define-syntax`, `let-syntax` and `letrec-syntax`. Expansion is hygienic
+//! by renaming: each identifier that a transcription introduces becomes a
+//! fresh alias, resolved in the environment where the macro was defined,
+//! and each local variable is renamed apart through the [`SymbolTable`].
+//! So neither a macro nor its user can capture the other's bindings.
+//!
+//! Procedural macros defined with `define-macro` are not hygienic: their
+//! transformer is a Scheme procedure, evaluated when the macro is defined,
+//! which is applied to the operands of each use and returns the form to
+//! expand in its place. `gensym` gives them fresh identifiers.
+//!
+//! Libraries defined with `define-library` are expanded once, in their own
+//! scope, and their definitions are renamed apart from those of the program
+//! and of other libraries. `import` binds the exported identifiers, and the
+//! first import of a library expands to its body, after those of the
+//! libraries it imports.
+//!
+//! `include` and `include-ci` splice in the forms of files, read relative
+//! to the file of the including form. Errors in those forms are located in
+//! their own file.
+//!
+//! The expanded program only contains core forms, in which every variable
+//! has its resolved name and quoted data have their original names back.
+use super::cps::Transformers;
+use super::eval::Object;
+use super::library::{LibraryName, SearchPath};
+use super::*;
+use crate::sexp::{Keyword as Kw, List};
+use crate::syntax::SpanTree;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Maximum nesting of macro uses that expand into macro uses
+const MAX_DEPTH: usize = 512;
+
+/// Syntax built into the expander, bound in the global environment
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Core {
+    DefineSyntax,
+    DefineMacro,
+    LetSyntax,
+    LetrecSyntax,
+    SyntaxRules,
+    DefineLibrary,
+    Import,
+    Include,
+    IncludeCi,
+}
+
+impl Core {
+    fn name(self) -> &'static str {
+        Core::ALL
+            .iter()
+            .find(|(_, core)| *core == self)
+            .map_or("", |(name, _)| name)
+    }
+
+    const ALL: [(&'static str, Core); 9] = [
+        ("define-syntax", Core::DefineSyntax),
+        ("define-macro", Core::DefineMacro),
+        ("let-syntax", Core::LetSyntax),
+        ("letrec-syntax", Core::LetrecSyntax),
+        ("syntax-rules", Core::SyntaxRules),
+        ("define-library", Core::DefineLibrary),
+        ("import", Core::Import),
+        ("include", Core::Include),
+        ("include-ci", Core::IncludeCi),
+    ];
+}
+
+/// What an identifier means in some environment
+#[derive(Clone)]
+enum Denotation {
+    /// Variable, with its name in the expanded program
+    Variable(String),
+    Macro(Rc<Macro>),
+    Procedure(Rc<Procedure>),
+    Core(Core),
+}
+
+impl PartialEq for Denotation {
+    fn eq(&self, other: &Denotation) -> bool {
+        match (self, other) {
+            (Denotation::Variable(a), Denotation::Variable(b)) => a == b,
+            (Denotation::Macro(a), Denotation::Macro(b)) => Rc::ptr_eq(a, b),
+            (Denotation::Procedure(a), Denotation::Procedure(b)) => Rc::ptr_eq(a, b),
+            (Denotation::Core(a), Denotation::Core(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Lexical scope. Frames are mutable so that the definitions of a body,
+/// and recursive macros, can be added to the scope they appear in
+#[derive(Default)]
+struct Frame {
+    bindings: RefCell<HashMap<String, Denotation>>,
+    parent: Option<Env>,
+}
+
+type Env = Rc<Frame>;
+
+/// Global environment of the core syntax, for the program or a library
+fn core_env() -> Env {
+    let env = Env::default();
+    for (name, core) in Core::ALL.iter() {
+        env.bindings
+            .borrow_mut()
+            .insert(name.to_string(), Denotation::Core(*core));
+    }
+    env
+}
+
+fn extend(env: &Env) -> Env {
+    Rc::new(Frame {
+        bindings: RefCell::default(),
+        parent: Some(env.clone()),
+    })
+}
+
+/// Identifier introduced by a transcription, standing for `name` in the
+/// environment of the macro definition
+struct Alias {
+    name: String,
+    env: Env,
+}
+
+/// `syntax-rules` transformer
+struct Macro {
+    name: String,
+    ellipsis: String,
+    literals: Vec<Sexp>,
+    rules: Vec<(Sexp, Sexp)>,
+    /// Environment of the definition
+    env: Env,
+}
+
+/// Library expanded by `define-library`
+struct Library {
+    /// Bindings of the exported identifiers, by their external names
+    exports: HashMap<String, Denotation>,
+    /// Libraries imported by the library, instantiated before it
+    imports: Vec<Rc<Library>>,
+    /// Expanded body, taken by the first import that instantiates the
+    /// library
+    body: RefCell<Option<Vec<Sexp>>>,
+}
+
+impl Library {
+    /// Push the bodies of `self` and the libraries it imports onto `out`,
+    /// unless they were instantiated already
+    fn instantiate(&self, out: &mut Vec<Sexp>) {
+        let body = self.body.borrow_mut().take();
+        if let Some(body) = body {
+            for import in &self.imports {
+                import.instantiate(out);
+            }
+            out.extend(body);
+        }
+    }
+}
+
+/// File whose forms are being expanded, within the file including it
+struct Included {
+    path: PathBuf,
+    /// Canonical path, identifying the file in include cycles
+    canonical: PathBuf,
+    parent: Option<Rc<Included>>,
+}
+
+impl Included {
+    fn new(path: PathBuf, parent: Option<Rc<Included>>) -> Rc<Included> {
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        Rc::new(Included {
+            path,
+            canonical,
+            parent,
+        })
+    }
+
+    /// Resolve the path of a file included by this one
+    fn resolve(&self, name: &str) -> PathBuf {
+        self.path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(name)
+    }
+
+    /// Whether `canonical` is this file or one including it
+    fn includes(&self, canonical: &Path) -> bool {
+        self.canonical == canonical || self.parent.as_ref().is_some_and(|p| p.includes(canonical))
+    }
+}
+
+/// Forms read from a file, with their locations
+type Forms = Vec<(Sexp, SpanTree)>;
+
+/// Form of a body spliced in by `include`, with the form of the body it was
+/// included from when that was included too
+struct Origin {
+    file: Rc<Included>,
+    spans: SpanTree,
+    parent: Option<Rc<Origin>>,
+}
+
+impl Origin {
+    /// Locate an error in the file the form was read from, and in those
+    /// of the forms including it
+    fn locate(&self, e: Error) -> Error {
+        let e = Error::File(
+            self.file.path.display().to_string(),
+            self.spans.clone(),
+            Box::new(e),
+        );
+        match &self.parent {
+            Some(parent) => parent.locate(e),
+            None => e,
+        }
+    }
+}
+
+/// The expansion of a form of an included file, once it is checked to
+/// analyze. Analysis only sees the form including the file, so errors in the
+/// syntax of the included forms are found here to be located in their file
+fn analyzed(form: Sexp) -> Result<Sexp, Error> {
+    analyze(form.clone()).map(|_| form)
+}
+
+/// The expansion of a form of a body, checked by [`analyzed`] when the form
+/// was spliced in by `include`
+fn included(form: Result<Sexp, Error>, origin: &Option<Rc<Origin>>) -> Result<Sexp, Error> {
+    match origin {
+        Some(_) => form.and_then(analyzed),
+        None => form,
+    }
+}
+
+/// `define-macro` transformer
+struct Procedure {
+    name: String,
+    transformer: Object,
+}
+
+/// Bindings of the pattern variables of a matched rule
+#[derive(Clone, Debug)]
+enum Matched {
+    One(Sexp),
+    /// Variable under an ellipsis
+    Many(Vec<Matched>),
+}
+
+/// Classification of a form by its head
+enum Head {
+    Keyword(Kw),
+    Macro(Rc<Macro>),
+    Procedure(Rc<Procedure>),
+    Core(Core),
+    Other,
+}
+
+/// Elements of a list form, or `None` for other data
+fn elements(form: &Sexp) -> Option<Vec<&Sexp>> {
+    match form {
+        Sexp::List(list) => Some(list.iter().collect()),
+        _ => None,
+    }
+}
+
+/// Build a list from `elems`, where a `.` followed by a list is spliced so
+/// that substituted tails stay in the flat form produced by the parser
+fn dotted(mut elems: Vec<Sexp>) -> Sexp {
+    let n = elems.len();
+    if n >= 2 && elems[n - 2] == Sexp::Keyword(Kw::Dot) {
+        if let Sexp::List(tail) = elems.pop().unwrap_or(Sexp::List(List::Nil)) {
+            elems.pop();
+            elems.extend(tail);
+        } else {
+            unreachable!()
+        }
+    }
+    Sexp::List(elems.into_iter().collect())
+}
+
+/// Split the elements of a list at its `.`, into the proper part and the
+/// tail
+fn split_tail<'a, 'b>(elems: &'b [&'a Sexp]) -> (&'b [&'a Sexp], Option<&'a Sexp>) {
+    match elems.len() {
+        n if n >= 2 && *elems[n - 2] == Sexp::Keyword(Kw::Dot) => {
+            (&elems[..n - 2], Some(elems[n - 1]))
+        }
+        _ => (elems, None),
+    }
+}
+
+fn at(idx: usize) -> impl FnOnce(Error) -> Error {
+    move |e| e.within(vec![idx])
+}
+
+fn opaque(e: Error) -> Error {
+    match e {
+        Error::Within(_, e) => *e,
+        Error::Backtrace(calls, e) => Error::Backtrace(calls, Box::new(opaque(*e))),
+        e => e,
+    }
+}
+
+/// Internal and external names of an export spec
+fn export_spec(spec: &Sexp) -> Option<(&str, &str)> {
+    match spec {
+        Sexp::Identifier(id) => Some((id, id)),
+        _ => match elements(spec).unwrap_or_default()[..] {
+            [Sexp::Identifier(head), Sexp::Identifier(internal), Sexp::Identifier(external)]
+                if head == "rename" =>
+            {
+                Some((internal, external))
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Add the identifiers of a form the expander reads to `symbols`, so that
+/// the variables it renames are named apart from every identifier of the
+/// program, including those that look renamed
+fn reserve(form: &Sexp, symbols: &mut SymbolTable) {
+    match form {
+        Sexp::Identifier(id) => {
+            symbols.insert_borrowed(id);
+        }
+        Sexp::List(list) => list.iter().for_each(|form| reserve(form, symbols)),
+        _ => {}
+    }
+}
+
+/// Fold the identifiers of a form read by `include-ci` to lower case, as
+/// the parser would have read them
+fn fold_case(form: Sexp) -> Sexp {
+    match form {
+        Sexp::Identifier(id) => {
+            let id = id.to_lowercase();
+            match Kw::from_name(&id) {
+                Some(kw) => Sexp::Keyword(kw),
+                None => Sexp::Identifier(id),
+            }
+        }
+        Sexp::List(list) => Sexp::List(list.into_iter().map(fold_case).collect()),
+        form => form,
+    }
+}
+
+/// Whether `form` has the shape `(id clause ...+)` of the variable and
+/// `cond` clauses of a `guard`
+fn is_guard_clauses(form: &Sexp) -> bool {
+    match elements(form) {
+        Some(elems) if elems.len() >= 2 && ident(elems[0]).is_some() => {
+            elems[1..].iter().all(|c| elements(c).is_some())
+        }
+        _ => false,
+    }
+}
+
+fn ident(sexp: &Sexp) -> Option<&str> {
+    match sexp {
+        Sexp::Identifier(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// Whether `form` has the shape `((id expr) ...)`, or `((id expr step) ...)`
+/// when `step` holds
+fn is_bindings(form: &Sexp, step: bool) -> bool {
+    match elements(form) {
+        Some(bind) => bind.iter().all(|b| match elements(b) {
+            Some(b) => ident(b[0]).is_some() && (b.len() == 2 || (step && b.len() == 3)),
+            None => false,
+        }),
+        None => false,
+    }
+}
+
+/// Whether `form` has the shape `((formals expr) ...)`
+fn is_values_bindings(form: &Sexp) -> bool {
+    elements(form).is_some_and(|bind| {
+        bind.iter()
+            .all(|b| elements(b).is_some_and(|b| b.len() == 2 && is_formals(b[0])))
+    })
+}
+
+/// Whether `formals` is a lambda parameter list
+fn is_formals(formals: &Sexp) -> bool {
+    match elements(formals) {
+        Some(elems) => {
+            let (params, rest) = split_tail(&elems);
+            params.iter().all(|p| ident(p).is_some()) && rest.map_or(true, |r| ident(r).is_some())
+        }
+        None => ident(formals).is_some(),
+    }
+}
+
+/// Macro expander, which keeps the macros and global definitions seen so
+/// far between top level forms
+pub struct Expander {
+    global: Env,
+    aliases: HashMap<String, Alias>,
+    transformers: Transformers,
+    /// Libraries by name, or `None` for those being expanded
+    libraries: HashMap<LibraryName, Option<Rc<Library>>>,
+    search_path: SearchPath,
+    /// File of the top level forms, which included files are relative to
+    file: Option<PathBuf>,
+}
+
+impl Default for Expander {
+    fn default() -> Self {
+        Expander::new()
+    }
+}
+
+impl Expander {
+    pub fn new() -> Expander {
+        Expander {
+            global: core_env(),
+            aliases: HashMap::new(),
+            transformers: Transformers::new(),
+            libraries: HashMap::new(),
+            search_path: SearchPath::default(),
+            file: None,
+        }
+    }
+
+    /// Expand the next top level forms as those of `file`, or of no file,
+    /// whose included files are relative to the current directory. Returns
+    /// the file of the forms before
+    pub fn set_file(&mut self, file: Option<PathBuf>) -> Option<PathBuf> {
+        std::mem::replace(&mut self.file, file)
+    }
+
+    /// Search `dir` for the files of libraries that are imported without
+    /// being defined first, after the directories added before it
+    pub fn add_library_path<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.search_path.push(dir);
+    }
+
+    /// Expand a top level form, renaming through `symbols`. Macro
+    /// definitions expand to nothing
+    pub fn expand(
+        &mut self,
+        form: &Sexp,
+        symbols: &mut SymbolTable,
+    ) -> Result<Option<Sexp>, Error> {
+        reserve(form, symbols);
+        let env = self.global.clone();
+        let included = self.file.clone().map(|file| Included::new(file, None));
+        Expansion {
+            expander: self,
+            symbols,
+            depth: 0,
+            included,
+        }
+        .toplevel(form, &env)
+    }
+}
+
+/// State of the expansion of one top level form
+struct Expansion<'a> {
+    expander: &'a mut Expander,
+    symbols: &'a mut SymbolTable,
+    depth: usize,
+    /// File of the forms being expanded
+    included: Option<Rc<Included>>,
+}
+
+impl Expansion<'_> {
+    fn resolve(&self, env: &Env, name: &str) -> Denotation {
+        let mut frame = Some(env);
+        while let Some(f) = frame {
+            if let Some(d) = f.bindings.borrow().get(name) {
+                return d.clone();
+            }
+            frame = f.parent.as_ref();
+        }
+        match self.expander.aliases.get(name) {
+            Some(alias) => self.resolve(&alias.env, &alias.name),
+            None => Denotation::Variable(name.to_string()),
+        }
+    }
+
+    /// The name an identifier was written with, before any renaming
+    fn strip<'s>(&'s self, mut name: &'s str) -> &'s str {
+        while let Some(alias) = self.expander.aliases.get(name) {
+            name = &alias.name;
+        }
+        name
+    }
+
+    fn strip_datum(&self, datum: &Sexp) -> Sexp {
+        match datum {
+            Sexp::Identifier(s) => Sexp::Identifier(self.strip(s).to_string()),
+            Sexp::List(list) => Sexp::List(list.iter().map(|d| self.strip_datum(d)).collect()),
+            _ => datum.clone(),
+        }
+    }
+
+    /// Bind the variable `name` in `env` to a fresh name
+    fn bind(&mut self, env: &Env, name: &str) -> String {
+        let base = self.strip(name).to_string();
+        let renamed = self.symbols.fresh(&base);
+        env.bindings
+            .borrow_mut()
+            .insert(name.to_string(), Denotation::Variable(renamed.clone()));
+        renamed
+    }
+
+    fn variable(&self, env: &Env, name: &str) -> Result<String, Error> {
+        match self.resolve(env, name) {
+            Denotation::Variable(name) => Ok(name),
+            _ => Err(Error::Message(format!(
+                "syntactic keyword `{}` cannot be used as an expression",
+                self.strip(name)
+            ))),
+        }
+    }
+
+    fn head(&self, form: &Sexp, env: &Env) -> Head {
+        match form {
+            Sexp::List(List::Cons(car, _)) => match &**car {
+                Sexp::Keyword(kw) => Head::Keyword(*kw),
+                Sexp::Identifier(id) => match self.resolve(env, id) {
+                    Denotation::Macro(m) => Head::Macro(m),
+                    Denotation::Procedure(p) => Head::Procedure(p),
+                    Denotation::Core(core) => Head::Core(core),
+                    Denotation::Variable(_) => Head::Other,
+                },
+                _ => Head::Other,
+            },
+            _ => Head::Other,
+        }
+    }
+
+    /// Transcribe macro uses at the head of `form` until it is a core form,
+    /// counting them in the nesting depth. The caller releases them with
+    /// [`Expansion::release`]
+    fn head_expand(&mut self, mut form: Sexp, env: &Env) -> Result<(Sexp, usize), Error> {
+        let mut count = 0;
+        loop {
+            let head = self.head(&form, env);
+            let name = match &head {
+                Head::Macro(m) => &m.name,
+                Head::Procedure(p) => &p.name,
+                _ => break,
+            };
+            if self.depth + count >= MAX_DEPTH {
+                return Err(Error::Message(format!(
+                    "expansion of `{}` is nested more than {} levels deep",
+                    name, MAX_DEPTH
+                )));
+            }
+            form = match head {
+                Head::Macro(m) => self.transcribe(&m, &form, env)?,
+                Head::Procedure(p) => self.call(&p, &form)?,
+                _ => unreachable!(),
+            };
+            count += 1;
+        }
+        self.depth += count;
+        Ok((form, count))
+    }
+
+    /// Release the macro uses counted by [`Expansion::head_expand`]. Errors
+    /// within their transcription are reported at the use, since their paths
+    /// lead into the transcription rather than the source
+    fn release<T>(&mut self, count: usize, result: Result<T, Error>) -> Result<T, Error> {
+        self.depth -= count;
+        match count {
+            0 => result,
+            _ => result.map_err(opaque),
+        }
+    }
+
+    fn toplevel(&mut self, form: &Sexp, env: &Env) -> Result<Option<Sexp>, Error> {
+        let (form, count) = self.head_expand(form.clone(), env)?;
+        let result = match self.head(&form, env) {
+            Head::Core(Core::DefineSyntax) => self.define_syntax(&form, env).map(|_| None),
+            Head::Core(Core::DefineMacro) => self.define_macro(&form, env).map(|_| None),
+            Head::Core(Core::DefineLibrary) => self.define_library(&form).map(|_| None),
+            Head::Core(Core::Import) => self.import(&form, env),
+            Head::Core(core @ Core::Include) | Head::Core(core @ Core::IncludeCi) => self
+                .include(&form, core, |this, form| this.toplevel(form, env))
+                .map(|body| match body.len() {
+                    0 => None,
+                    _ => Some(Sexp::List(
+                        std::iter::once(Sexp::Keyword(Kw::Begin))
+                            .chain(body)
+                            .collect(),
+                    )),
+                }),
+            Head::Keyword(Kw::Define)
+            | Head::Keyword(Kw::DefineRecordType)
+            | Head::Keyword(Kw::DefineValues) => {
+                for name in defined_names(&form) {
+                    // Definitions of a library are renamed apart
+                    if !Rc::ptr_eq(env, &self.expander.global) {
+                        self.bind(env, name);
+                        continue;
+                    }
+                    let global = Denotation::Variable(self.strip(name).to_string());
+                    env.bindings.borrow_mut().insert(name.to_string(), global);
+                }
+                self.definition(&form, env).map(Some)
+            }
+            Head::Keyword(Kw::Begin) => {
+                let mut body = vec![Sexp::Keyword(Kw::Begin)];
+                let elems = elements(&form).unwrap_or_default();
+                for (idx, elem) in elems.into_iter().enumerate().skip(1) {
+                    body.extend(self.toplevel(elem, env).map_err(at(idx))?);
+                }
+                Ok(match body.len() {
+                    1 => None,
+                    _ => Some(Sexp::List(body.into_iter().collect())),
+                })
+            }
+            _ => self.expand(&form, env).map(Some),
+        };
+        self.release(count, result)
+    }
+
+    fn expand(&mut self, form: &Sexp, env: &Env) -> Result<Sexp, Error> {
+        match form {
+            Sexp::Identifier(id) => self.variable(env, id).map(Sexp::Identifier),
+            Sexp::List(List::Cons(..)) => {
+                let (form, count) = self.head_expand(form.clone(), env)?;
+                let result = match form {
+                    Sexp::List(List::Cons(..)) => self.core(&form, env),
+                    _ => self.expand(&form, env),
+                };
+                self.release(count, result)
+            }
+            _ => Ok(form.clone()),
+        }
+    }
+
+    /// Expand the elements of `elems` from `start` on as expressions
+    fn expand_from(
+        &mut self,
+        elems: &[&Sexp],
+        start: usize,
+        env: &Env,
+    ) -> Result<Vec<Sexp>, Error> {
+        (start..elems.len())
+            .map(|idx| self.expand(elems[idx], env).map_err(at(idx)))
+            .collect()
+    }
+
+    /// Expand a form whose head is not a macro use
+    fn core(&mut self, form: &Sexp, env: &Env) -> Result<Sexp, Error> {
+        let elems = elements(form).unwrap_or_default();
+        let expanded = match self.head(form, env) {
+            Head::Keyword(kw) => return self.special(kw, form, env),
+            Head::Core(Core::LetSyntax) => return self.let_syntax(form, env, false),
+            Head::Core(Core::LetrecSyntax) => return self.let_syntax(form, env, true),
+            Head::Core(core @ Core::Include) | Head::Core(core @ Core::IncludeCi) => {
+                let mut body = vec![Sexp::Keyword(Kw::Begin)];
+                body.extend(
+                    self.include(form, core, |this, form| this.expand(form, env).map(Some))?,
+                );
+                body
+            }
+            Head::Core(core @ Core::DefineSyntax) | Head::Core(core @ Core::DefineMacro) => {
+                return Err(Error::Message(format!(
+                    "`{}` is only allowed at the top level or at the start of a body",
+                    core.name()
+                )))
+            }
+            Head::Core(core @ Core::DefineLibrary) | Head::Core(core @ Core::Import) => {
+                return Err(Error::Message(format!(
+                    "`{}` is only allowed at the top level",
+                    core.name()
+                )))
+            }
+            Head::Core(Core::SyntaxRules) => {
+                return Err(Error::Message(
+                    "`syntax-rules` is only allowed as the transformer of a macro".into(),
+                ))
+            }
+            Head::Macro(_) | Head::Procedure(_) | Head::Other => {
+                self.expand_from(&elems, 0, env)?
+            }
+        };
+        Ok(Sexp::List(expanded.into_iter().collect()))
+    }
+
+    /// Expand a special form. Malformed forms are left for analysis to
+    /// report, with their original names
+    fn special(&mut self, kw: Kw, form: &Sexp, env: &Env) -> Result<Sexp, Error> {
+        let elems = elements(form).unwrap_or_default();
+        let lenient = || Ok(self.strip_datum(form));
+        let mut out = vec![Sexp::Keyword(kw)];
+        match kw {
+            Kw::Quote => return lenient(),
+            Kw::Quasiquote if elems.len() == 2 => {
+                out.push(self.quasiquote(elems[1], 1, env).map_err(at(1))?)
+            }
+            Kw::Lambda if elems.len() >= 3 && is_formals(elems[1]) => {
+                let scope = extend(env);
+                out.push(self.formals(elems[1], &scope));
+                out.extend(self.body(&elems, 2, &scope)?);
+            }
+            Kw::CaseLambda
+                if elems[1..]
+                    .iter()
+                    .all(|c| elements(c).is_some_and(|c| c.len() >= 2 && is_formals(c[0]))) =>
+            {
+                for (idx, clause) in elems.iter().enumerate().skip(1) {
+                    let clause = elements(clause).unwrap_or_default();
+                    let scope = extend(env);
+                    let mut expanded = vec![self.formals(clause[0], &scope)];
+                    expanded.extend(self.body(&clause, 1, &scope).map_err(at(idx))?);
+                    out.push(Sexp::List(expanded.into_iter().collect()));
+                }
+            }
+            Kw::Set if elems.len() == 3 && ident(elems[1]).is_some() => {
+                let name = self.variable(env, ident(elems[1]).unwrap_or_default());
+                out.push(Sexp::Identifier(name.map_err(at(1))?));
+                out.push(self.expand(elems[2], env).map_err(at(2))?);
+            }
+            Kw::Let
+                if elems.len() >= 4
+                    && ident(elems[1]).is_some()
+                    && is_bindings(elems[2], false) =>
+            {
+                let scope = extend(env);
+                let inits = self.inits(elems[2], env).map_err(at(2))?;
+                out.push(Sexp::Identifier(
+                    self.bind(&scope, ident(elems[1]).unwrap_or_default()),
+                ));
+                out.push(self.bindings(elems[2], inits, &scope));
+                out.extend(self.body(&elems, 3, &scope)?);
+            }
+            Kw::Let if elems.len() >= 3 && is_bindings(elems[1], false) => {
+                let scope = extend(env);
+                let inits = self.inits(elems[1], env).map_err(at(1))?;
+                out.push(self.bindings(elems[1], inits, &scope));
+                out.extend(self.body(&elems, 2, &scope)?);
+            }
+            Kw::Letstar if elems.len() >= 3 && is_bindings(elems[1], false) => {
+                let mut scope = env.clone();
+                let mut bind = Vec::new();
+                for (idx, b) in elems[1].as_list()?.iter().enumerate() {
+                    let b = elements(b).unwrap_or_default();
+                    let init = self
+                        .expand(b[1], &scope)
+                        .map_err(|e| e.within(vec![1, idx, 1]))?;
+                    scope = extend(&scope);
+                    let var = Sexp::Identifier(self.bind(&scope, ident(b[0]).unwrap_or_default()));
+                    bind.push(Sexp::List(vec![var, init].into_iter().collect()));
+                }
+                out.push(Sexp::List(bind.into_iter().collect()));
+                out.extend(self.body(&elems, 2, &scope)?);
+            }
+            Kw::LetValues | Kw::LetstarValues
+                if elems.len() >= 3 && is_values_bindings(elems[1]) =>
+            {
+                let parallel = extend(env);
+                let mut scope = env.clone();
+                let mut bind = Vec::new();
+                for (idx, b) in elems[1].as_list()?.iter().enumerate() {
+                    let b = elements(b).unwrap_or_default();
+                    let init_env = match kw {
+                        Kw::LetValues => env,
+                        _ => &scope,
+                    };
+                    let init = self
+                        .expand(b[1], init_env)
+                        .map_err(|e| e.within(vec![1, idx, 1]))?;
+                    let formals = match kw {
+                        Kw::LetValues => self.formals(b[0], &parallel),
+                        _ => {
+                            scope = extend(&scope);
+                            self.formals(b[0], &scope)
+                        }
+                    };
+                    bind.push(Sexp::List(vec![formals, init].into_iter().collect()));
+                }
+                out.push(Sexp::List(bind.into_iter().collect()));
+                let scope = match kw {
+                    Kw::LetValues => parallel,
+                    _ => scope,
+                };
+                out.extend(self.body(&elems, 2, &scope)?);
+            }
+            Kw::Letrec if elems.len() >= 3 && is_bindings(elems[1], false) => {
+                let scope = extend(env);
+                for b in elems[1].as_list()?.iter() {
+                    self.bind(
+                        &scope,
+                        ident(elements(b).unwrap_or_default()[0]).unwrap_or_default(),
+                    );
+                }
+                let inits = self.inits(elems[1], &scope).map_err(at(1))?;
+                out.push(self.bindings(elems[1], inits, &scope));
+                out.extend(self.body(&elems, 2, &scope)?);
+            }
+            Kw::Do
+                if elems.len() >= 3
+                    && is_bindings(elems[1], true)
+                    && elements(elems[2]).is_some_and(|t| !t.is_empty()) =>
+            {
+                let scope = extend(env);
+                let mut bind = Vec::new();
+                for (idx, b) in elems[1].as_list()?.iter().enumerate() {
+                    let b = elements(b).unwrap_or_default();
+                    let init = self
+                        .expand(b[1], env)
+                        .map_err(|e| e.within(vec![1, idx, 1]))?;
+                    bind.push((b, init));
+                }
+                let mut vars = Vec::new();
+                for (b, _) in &bind {
+                    vars.push(self.bind(&scope, ident(b[0]).unwrap_or_default()));
+                }
+                let mut out_bind = Vec::new();
+                for (idx, ((b, init), var)) in bind.into_iter().zip(vars).enumerate() {
+                    let mut binding = vec![Sexp::Identifier(var), init];
+                    if b.len() == 3 {
+                        binding.push(
+                            self.expand(b[2], &scope)
+                                .map_err(|e| e.within(vec![1, idx, 2]))?,
+                        );
+                    }
+                    out_bind.push(Sexp::List(binding.into_iter().collect()));
+                }
+                out.push(Sexp::List(out_bind.into_iter().collect()));
+                let test = elements(elems[2]).unwrap_or_default();
+                let test = self.expand_from(&test, 0, &scope).map_err(at(2))?;
+                out.push(Sexp::List(test.into_iter().collect()));
+                out.extend(self.expand_from(&elems, 3, &scope)?);
+            }
+            Kw::Cond if elems[1..].iter().all(|c| elements(c).is_some()) => {
+                for (idx, clause) in elems.iter().enumerate().skip(1) {
+                    let clause = elements(clause).unwrap_or_default();
+                    let clause = self.expand_from(&clause, 0, env).map_err(at(idx))?;
+                    out.push(Sexp::List(clause.into_iter().collect()));
+                }
+            }
+            Kw::Guard if elems.len() >= 3 && is_guard_clauses(elems[1]) => {
+                let spec = elements(elems[1]).unwrap_or_default();
+                let scope = extend(env);
+                let var = self.bind(&scope, ident(spec[0]).unwrap_or_default());
+                let mut handler = vec![Sexp::Identifier(var)];
+                for (idx, clause) in spec.iter().enumerate().skip(1) {
+                    let clause = elements(clause).unwrap_or_default();
+                    let clause = self
+                        .expand_from(&clause, 0, &scope)
+                        .map_err(|e| e.within(vec![1, idx]))?;
+                    handler.push(Sexp::List(clause.into_iter().collect()));
+                }
+                out.push(Sexp::List(handler.into_iter().collect()));
+                out.extend(self.body(&elems, 2, env)?);
+            }
+            Kw::Parameterize
+                if elems.len() >= 3
+                    && elements(elems[1]).is_some_and(|bind| {
+                        bind.iter()
+                            .all(|b| elements(b).is_some_and(|b| b.len() == 2))
+                    }) =>
+            {
+                let mut bind = Vec::new();
+                for (idx, b) in elements(elems[1]).unwrap_or_default().iter().enumerate() {
+                    let b = elements(b).unwrap_or_default();
+                    let b = self
+                        .expand_from(&b, 0, env)
+                        .map_err(|e| e.within(vec![1, idx]))?;
+                    bind.push(Sexp::List(b.into_iter().collect()));
+                }
+                out.push(Sexp::List(bind.into_iter().collect()));
+                out.extend(self.body(&elems, 2, env)?);
+            }
+            Kw::Case
+                if elems.len() >= 2
+                    && elems[2..]
+                        .iter()
+                        .all(|c| elements(c).is_some_and(|c| !c.is_empty())) =>
+            {
+                out.push(self.expand(elems[1], env).map_err(at(1))?);
+                for (idx, clause) in elems.iter().enumerate().skip(2) {
+                    let clause = elements(clause).unwrap_or_default();
+                    let mut expanded = vec![self.strip_datum(clause[0])];
+                    expanded.extend(self.expand_from(&clause, 1, env).map_err(at(idx))?);
+                    out.push(Sexp::List(expanded.into_iter().collect()));
+                }
+            }
+            Kw::If
+            | Kw::Begin
+            | Kw::And
+            | Kw::Or
+            | Kw::When
+            | Kw::Unless
+            | Kw::Delay
+            | Kw::DelayForce => out.extend(self.expand_from(&elems, 1, env)?),
+            _ => return lenient(),
+        }
+        Ok(Sexp::List(out.into_iter().collect()))
+    }
+
+    /// Expand the initializers of a list of bindings
+    fn inits(&mut self, bindings: &Sexp, env: &Env) -> Result<Vec<Sexp>, Error> {
+        let bindings = elements(bindings).unwrap_or_default();
+        (0..bindings.len())
+            .map(|idx| {
+                let b = elements(bindings[idx]).unwrap_or_default();
+                self.expand(b[1], env).map_err(|e| e.within(vec![idx, 1]))
+            })
+            .collect()
+    }
+
+    /// Rebuild a list of bindings with their expanded initializers, binding
+    /// the variables in `scope`
+    fn bindings(&mut self, bindings: &Sexp, inits: Vec<Sexp>, scope: &Env) -> Sexp {
+        let bindings = elements(bindings).unwrap_or_default();
+        let mut out = Vec::new();
+        for (b, init) in bindings.into_iter().zip(inits) {
+            let name = ident(elements(b).unwrap_or_default()[0]).unwrap_or_default();
+            let var = match scope.bindings.borrow().get(name) {
+                Some(Denotation::Variable(var)) => Some(var.clone()),
+                _ => None,
+            };
+            let var = var.unwrap_or_else(|| self.bind(scope, name));
+            out.push(Sexp::List(
+                vec![Sexp::Identifier(var), init].into_iter().collect(),
+            ));
+        }
+        Sexp::List(out.into_iter().collect())
+    }
+
+    /// Bind lambda parameters in `scope`
+    fn formals(&mut self, formals: &Sexp, scope: &Env) -> Sexp {
+        match formals {
+            Sexp::Identifier(name) => Sexp::Identifier(self.bind(scope, name)),
+            _ => {
+                let elems = elements(formals).unwrap_or_default();
+                let out = elems
+                    .into_iter()
+                    .map(|p| match p {
+                        Sexp::Identifier(name) => Sexp::Identifier(self.bind(scope, name)),
+                        other => other.clone(),
+                    })
+                    .collect::<Vec<Sexp>>();
+                Sexp::List(out.into_iter().collect())
+            }
+        }
+    }
+
+    /// Expand the body made of the elements of a form from `start` on, in a
+    /// new scope within `env`. Leading definitions, including those produced
+    /// by macros and spliced from `begin`, are bound before any of them is
+    /// expanded, so that they can refer to each other
+    fn body(&mut self, elems: &[&Sexp], start: usize, env: &Env) -> Result<Vec<Sexp>, Error> {
+        let located = |idx, transcribed, origin: &Option<Rc<Origin>>| {
+            let origin = origin.clone();
+            move |e| {
+                let e = match transcribed {
+                    true => opaque(e),
+                    false => e,
+                };
+                match origin {
+                    Some(origin) => origin.locate(e).within(vec![idx]),
+                    None => e.within(vec![idx]),
+                }
+            }
+        };
+        let scope = extend(env);
+        let mut queue = (start..elems.len())
+            .map(|idx| (idx, elems[idx].clone(), false, None))
+            .collect::<VecDeque<(usize, Sexp, bool, Option<Rc<Origin>>)>>();
+        let mut defs = Vec::new();
+        let mut exprs = Vec::new();
+        let mut count = 0;
+        let mut result = Ok(());
+        let outer = self.included.clone();
+        while let Some((idx, form, transcribed, origin)) = queue.pop_front() {
+            // Forms spliced in by `include` are expanded as those of their file
+            self.included = origin
+                .as_ref()
+                .map_or(outer.clone(), |o| Some(o.file.clone()));
+            let (form, transcribed) = match self.head_expand(form, &scope) {
+                Ok((form, n)) => {
+                    count += n;
+                    (form, transcribed || n > 0)
+                }
+                Err(e) => {
+                    result = Err(located(idx, false, &origin)(e));
+                    break;
+                }
+            };
+            match self.head(&form, &scope) {
+                Head::Keyword(Kw::Define)
+                | Head::Keyword(Kw::DefineRecordType)
+                | Head::Keyword(Kw::DefineValues) => {
+                    for name in defined_names(&form) {
+                        self.bind(&scope, name);
+                    }
+                    defs.push((idx, form, transcribed, origin));
+                }
+                Head::Keyword(Kw::Begin) => {
+                    let spliced = elements(&form).unwrap_or_default();
+                    for elem in spliced.into_iter().skip(1).rev() {
+                        queue.push_front((idx, elem.clone(), transcribed, origin.clone()));
+                    }
+                }
+                Head::Core(core @ Core::Include) | Head::Core(core @ Core::IncludeCi) => {
+                    let files = match self.read_included(&form, core) {
+                        Ok(files) => files,
+                        Err(e) => {
+                            result = Err(located(idx, transcribed, &origin)(e));
+                            break;
+                        }
+                    };
+                    for (file, forms) in files.into_iter().rev() {
+                        for (form, spans) in forms.into_iter().rev() {
+                            let included = Origin {
+                                file: file.clone(),
+                                spans,
+                                parent: origin.clone(),
+                            };
+                            queue.push_front((idx, form, false, Some(Rc::new(included))));
+                        }
+                    }
+                }
+                Head::Core(Core::DefineSyntax) => {
+                    if let Err(e) = self.define_syntax(&form, &scope) {
+                        result = Err(located(idx, transcribed, &origin)(e));
+                        break;
+                    }
+                }
+                Head::Core(Core::DefineMacro) => {
+                    if let Err(e) = self.define_macro(&form, &scope) {
+                        result = Err(located(idx, transcribed, &origin)(e));
+                        break;
+                    }
+                }
+                _ => {
+                    exprs.push((idx, form, transcribed, origin));
+                    exprs.extend(queue.drain(..));
+                }
+            }
+        }
+        let result = result.and_then(|_| {
+            let mut out = Vec::new();
+            for (idx, def, transcribed, origin) in defs {
+                self.included = origin
+                    .as_ref()
+                    .map_or(outer.clone(), |o| Some(o.file.clone()));
+                let def = self.definition(&def, &scope);
+                out.push(included(def, &origin).map_err(located(idx, transcribed, &origin))?);
+            }
+            for (idx, expr, transcribed, origin) in exprs {
+                self.included = origin
+                    .as_ref()
+                    .map_or(outer.clone(), |o| Some(o.file.clone()));
+                let expr = self.expand(&expr, &scope);
+                out.push(included(expr, &origin).map_err(located(idx, transcribed, &origin))?);
+            }
+            Ok(out)
+        });
+        self.included = outer;
+        self.depth -= count;
+        result
+    }
+
+    /// Expand a definition whose names are already bound in `env`
+    fn definition(&mut self, form: &Sexp, env: &Env) -> Result<Sexp, Error> {
+        match form {
+            Sexp::List(List::Cons(car, _)) if **car == Sexp::Keyword(Kw::DefineRecordType) => {
+                self.define_record_type(form, env)
+            }
+            Sexp::List(List::Cons(car, _)) if **car == Sexp::Keyword(Kw::DefineValues) => {
+                let elems = elements(form).unwrap_or_default();
+                if elems.len() != 3 || !is_formals(elems[1]) {
+                    return Ok(self.strip_datum(form));
+                }
+                let formals = match elements(elems[1]) {
+                    Some(formals) => {
+                        let mut out = Vec::new();
+                        for (idx, f) in formals.into_iter().enumerate() {
+                            let var = self.definition_variable(f, env);
+                            out.push(var.map_err(|e| e.within(vec![1, idx]))?);
+                        }
+                        Sexp::List(out.into_iter().collect())
+                    }
+                    None => self.definition_variable(elems[1], env).map_err(at(1))?,
+                };
+                let expr = self.expand(elems[2], env).map_err(at(2))?;
+                Ok(Sexp::List(
+                    vec![Sexp::Keyword(Kw::DefineValues), formals, expr]
+                        .into_iter()
+                        .collect(),
+                ))
+            }
+            _ => self.define(form, env),
+        }
+    }
+
+    /// Expand a `define-record-type` form. Field names are not variables,
+    /// so they keep their original names
+    fn define_record_type(&mut self, form: &Sexp, env: &Env) -> Result<Sexp, Error> {
+        let elems = elements(form).unwrap_or_default();
+        if elems.len() < 4 {
+            return Ok(self.strip_datum(form));
+        }
+        let mut out = vec![Sexp::Keyword(Kw::DefineRecordType)];
+        for (idx, elem) in elems.iter().enumerate().skip(1) {
+            // Range of the elements of a spec that are variables
+            let vars = match idx {
+                1 | 3 => {
+                    out.push(self.definition_variable(elem, env).map_err(at(idx))?);
+                    continue;
+                }
+                2 => 0..1,
+                _ => 1..usize::MAX,
+            };
+            let spec = match elements(elem) {
+                Some(spec) => spec,
+                None => {
+                    out.push(self.strip_datum(elem));
+                    continue;
+                }
+            };
+            let mut expanded = Vec::new();
+            for (i, e) in spec.into_iter().enumerate() {
+                expanded.push(match vars.contains(&i) {
+                    true => self
+                        .definition_variable(e, env)
+                        .map_err(|e| e.within(vec![idx, i]))?,
+                    false => self.strip_datum(e),
+                });
+            }
+            out.push(Sexp::List(expanded.into_iter().collect()));
+        }
+        Ok(Sexp::List(out.into_iter().collect()))
+    }
+
+    /// Variable an identifier of a definition refers to, with anything else
+    /// kept as a datum
+    fn definition_variable(&mut self, sexp: &Sexp, env: &Env) -> Result<Sexp, Error> {
+        match sexp {
+            Sexp::Identifier(name) => Ok(Sexp::Identifier(self.variable(env, name)?)),
+            _ => Ok(self.strip_datum(sexp)),
+        }
+    }
+
+    /// Expand a `define` form whose name is already bound in `env`
+    fn define(&mut self, form: &Sexp, env: &Env) -> Result<Sexp, Error> {
+        let elems = elements(form).unwrap_or_default();
+        let mut out = vec![Sexp::Keyword(Kw::Define)];
+        match elems.get(1) {
+            Some(Sexp::Identifier(name)) if elems.len() == 3 => {
+                out.push(Sexp::Identifier(self.variable(env, name).map_err(at(1))?));
+                out.push(self.expand(elems[2], env).map_err(at(2))?);
+            }
+            Some(Sexp::List(List::Cons(name, formals))) if elems.len() >= 3 => {
+                let formals = Sexp::List((**formals).clone());
+                let name = match ident(name) {
+                    Some(name) if is_formals(&formals) => name,
+                    _ => return Ok(self.strip_datum(form)),
+                };
+                let name = self.variable(env, name).map_err(at(1))?;
+                let scope = extend(env);
+                let mut signature = vec![Sexp::Identifier(name)];
+                match self.formals(&formals, &scope) {
+                    Sexp::List(params) => signature.extend(params),
+                    rest => signature.extend(vec![Sexp::Keyword(Kw::Dot), rest]),
+                }
+                out.push(Sexp::List(signature.into_iter().collect()));
+                out.extend(self.body(&elems, 2, &scope)?);
+            }
+            _ => return Ok(self.strip_datum(form)),
+        }
+        Ok(Sexp::List(out.into_iter().collect()))
+    }
+
+    fn quasiquote(&mut self, datum: &Sexp, depth: usize, env: &Env) -> Result<Sexp, Error> {
+        let elems = match elements(datum) {
+            Some(elems) => elems,
+            None => return Ok(self.strip_datum(datum)),
+        };
+        let mut out = Vec::new();
+        let mut idx = 0;
+        while idx < elems.len() {
+            let elem = elems[idx];
+            match elem {
+                // Unquoted expression, either as the whole list or as its
+                // tail in `(a unquote b)`
+                Sexp::Keyword(Kw::Unquote) | Sexp::Keyword(Kw::UnquoteAt)
+                    if idx + 2 == elems.len() =>
+                {
+                    out.push(elem.clone());
+                    let inner = elems[idx + 1];
+                    out.push(
+                        match depth {
+                            1 => self.expand(inner, env),
+                            _ => self.quasiquote(inner, depth - 1, env),
+                        }
+                        .map_err(at(idx + 1))?,
+                    );
+                    break;
+                }
+                Sexp::Keyword(Kw::Quasiquote) if idx == 0 && elems.len() == 2 => {
+                    out.push(elem.clone());
+                    out.push(self.quasiquote(elems[1], depth + 1, env).map_err(at(1))?);
+                    break;
+                }
+                _ => out.push(self.quasiquote(elem, depth, env).map_err(at(idx))?),
+            }
+            idx += 1;
+        }
+        Ok(Sexp::List(out.into_iter().collect()))
+    }
+
+    /// `(let-syntax ((name transformer) ...) body ...)`, expanded into a
+    /// `let` without bindings
+    fn let_syntax(&mut self, form: &Sexp, env: &Env, recursive: bool) -> Result<Sexp, Error> {
+        let elems = elements(form).unwrap_or_default();
+        let keyword = if recursive {
+            "letrec-syntax"
+        } else {
+            "let-syntax"
+        };
+        let bindings = match elems.get(1).and_then(|b| elements(b)) {
+            Some(bindings) if elems.len() >= 3 => bindings,
+            _ => {
+                return Err(Error::Message(format!(
+                    "`{}` has the form `({} ((keyword transformer) ...) body ...+)`",
+                    keyword, keyword
+                )))
+            }
+        };
+        let scope = extend(env);
+        for (idx, binding) in bindings.into_iter().enumerate() {
+            let binding = match elements(binding) {
+                Some(b) if b.len() == 2 && ident(b[0]).is_some() => b,
+                _ => {
+                    return Err(
+                        Error::Message("expected a `(keyword transformer)` binding".into())
+                            .within(vec![1, idx]),
+                    )
+                }
+            };
+            let name = ident(binding[0]).unwrap_or_default();
+            let macro_env = if recursive { &scope } else { env };
+            let m = self
+                .syntax_rules(name, binding[1], macro_env)
+                .map_err(|e| e.within(vec![1, idx, 1]))?;
+            scope
+                .bindings
+                .borrow_mut()
+                .insert(name.to_string(), Denotation::Macro(Rc::new(m)));
+        }
+        let mut out = vec![Sexp::Keyword(Kw::Let), Sexp::List(List::Nil)];
+        out.extend(self.body(&elems, 2, &scope)?);
+        Ok(Sexp::List(out.into_iter().collect()))
+    }
+
+    /// `(define-syntax name transformer)`, binding the macro in `env`
+    fn define_syntax(&mut self, form: &Sexp, env: &Env) -> Result<(), Error> {
+        let elems = elements(form).unwrap_or_default();
+        let name = match elems.get(1).and_then(|n| ident(n)) {
+            Some(name) if elems.len() == 3 => name,
+            _ => {
+                return Err(Error::Message(
+                    "`define-syntax` has the form `(define-syntax keyword transformer)`".into(),
+                ))
+            }
+        };
+        let m = self.syntax_rules(name, elems[2], env).map_err(at(2))?;
+        env.bindings
+            .borrow_mut()
+            .insert(name.to_string(), Denotation::Macro(Rc::new(m)));
+        Ok(())
+    }
+
+    /// `(define-macro (name . formals) body ...)` or
+    /// `(define-macro name transformer)`, evaluating the transformer and
+    /// binding the macro in `env`. The transformer is expanded in `env`, but
+    /// evaluated with only the primitives of the [`Transformers`] in scope
+    fn define_macro(&mut self, form: &Sexp, env: &Env) -> Result<(), Error> {
+        let elems = elements(form).unwrap_or_default();
+        let signature = elems.get(1).and_then(|s| elements(s)).unwrap_or_default();
+        let (name, transformer) =
+            match (elems.get(1), signature.split_first()) {
+                (Some(Sexp::Identifier(name)), _) if elems.len() == 3 => {
+                    (name.as_str(), elems[2].clone())
+                }
+                (_, Some((Sexp::Identifier(name), formals))) if elems.len() >= 3 => {
+                    let formals = match formals {
+                        [Sexp::Keyword(Kw::Dot), rest] => (*rest).clone(),
+                        _ => Sexp::List(formals.iter().map(|f| (*f).clone()).collect()),
+                    };
+                    let mut lambda = vec![Sexp::Keyword(Kw::Lambda), formals];
+                    lambda.extend(elems[2..].iter().map(|e| (*e).clone()));
+                    (name.as_str(), Sexp::List(lambda.into_iter().collect()))
+                }
+                _ => return Err(Error::Message(
+                    "`define-macro` has the form `(define-macro (keyword formals ...) body ...+)`"
+                        .into(),
+                )),
+            };
+        // A lambda from the signature is located at the whole form
+        let within = |e: Error| match elems.len() {
+            3 if ident(elems[1]).is_some() => e.within(vec![2]),
+            _ => opaque(e),
+        };
+        let transformer = self.expand(&transformer, env).map_err(within)?;
+        let transformer = desugar(analyze(transformer).map_err(within)?, self.symbols);
+        let transformer = self
+            .expander
+            .transformers
+            .eval(transformer, self.symbols)
+            .map_err(within)?;
+        if !transformer.is_procedure() {
+            return Err(Error::Message(format!(
+                "the transformer of `{}` is not a procedure",
+                self.strip(name)
+            ))
+            .within(vec![2]));
+        }
+        let p = Procedure {
+            name: self.strip(name).to_string(),
+            transformer,
+        };
+        env.bindings
+            .borrow_mut()
+            .insert(name.to_string(), Denotation::Procedure(Rc::new(p)));
+        Ok(())
+    }
+
+    /// Expand the library of a `define-library` form in a scope of its own
+    fn define_library(&mut self, form: &Sexp) -> Result<(), Error> {
+        let elems = elements(form).unwrap_or_default();
+        let name =
+            match elems.get(1).and_then(|name| LibraryName::from_sexp(name)) {
+                Some(name) => name,
+                None => return Err(Error::Message(
+                    "`define-library` has the form `(define-library (name ...+) declaration ...)`"
+                        .into(),
+                )),
+            };
+        if self.expander.libraries.contains_key(&name) {
+            return Err(
+                Error::Message(format!("library `{}` is already defined", name)).within(vec![1]),
+            );
+        }
+        self.expander.libraries.insert(name.clone(), None);
+        match self.library(&elems) {
+            Ok(library) => {
+                self.expander.libraries.insert(name, Some(Rc::new(library)));
+                Ok(())
+            }
+            Err(e) => {
+                self.expander.libraries.remove(&name);
+                Err(e)
+            }
+        }
+    }
+
+    /// Expand the declarations of a `define-library` form
+    fn library(&mut self, elems: &[&Sexp]) -> Result<Library, Error> {
+        let env = core_env();
+        let mut exports = Vec::new();
+        let mut imports = Vec::new();
+        let mut body = Vec::new();
+        for (idx, decl) in elems.iter().enumerate().skip(2) {
+            let decl = elements(decl).unwrap_or_default();
+            match decl.first() {
+                Some(Sexp::Identifier(head)) if head == "export" => {
+                    for (i, spec) in decl.iter().enumerate().skip(1) {
+                        let spec = export_spec(spec).ok_or_else(|| {
+                            Error::Message(
+                                "expected an export spec, `id` or `(rename id id)`".into(),
+                            )
+                            .within(vec![idx, i])
+                        })?;
+                        exports.push(spec);
+                    }
+                }
+                Some(Sexp::Identifier(head)) if head == "import" => {
+                    imports.extend(self.import_sets(&decl, &env).map_err(at(idx))?);
+                }
+                Some(Sexp::Keyword(Kw::Begin)) => {
+                    for (i, form) in decl.iter().enumerate().skip(1) {
+                        let form = self
+                            .toplevel(form, &env)
+                            .map_err(|e| e.within(vec![idx, i]))?;
+                        body.extend(form);
+                    }
+                }
+                Some(Sexp::Identifier(head)) if head == "include" || head == "include-ci" => {
+                    let core = match head.as_str() {
+                        "include" => Core::Include,
+                        _ => Core::IncludeCi,
+                    };
+                    let forms = self
+                        .include(elems[idx], core, |this, form| this.toplevel(form, &env))
+                        .map_err(at(idx))?;
+                    body.extend(forms);
+                }
+                _ => {
+                    return Err(Error::Message(
+                        "expected a library declaration, `export`, `import`, `include` or `begin`"
+                            .into(),
+                    )
+                    .within(vec![idx]))
+                }
+            }
+        }
+        let exports = exports
+            .into_iter()
+            .map(|(internal, external)| (external.to_string(), self.resolve(&env, internal)))
+            .collect();
+        Ok(Library {
+            exports,
+            imports,
+            body: RefCell::new(Some(body)),
+        })
+    }
+
+    /// Bind the identifiers of an `import` form in `env`. At the top level
+    /// of the program, the form expands to the bodies of the libraries that
+    /// were not instantiated yet
+    fn import(&mut self, form: &Sexp, env: &Env) -> Result<Option<Sexp>, Error> {
+        let elems = elements(form).unwrap_or_default();
+        let libraries = self.import_sets(&elems, env)?;
+        if !Rc::ptr_eq(env, &self.expander.global) {
+            return Ok(None);
+        }
+        let mut body = vec![Sexp::Keyword(Kw::Begin)];
+        for library in libraries {
+            library.instantiate(&mut body);
+        }
+        Ok(match body.len() {
+            1 => None,
+            _ => Some(Sexp::List(body.into_iter().collect())),
+        })
+    }
+
+    /// Bind the identifiers of the import sets of an `import` form or
+    /// declaration in `env`, returning the libraries they import from
+    fn import_sets(&mut self, elems: &[&Sexp], env: &Env) -> Result<Vec<Rc<Library>>, Error> {
+        if elems.len() < 2 {
+            return Err(Error::Message(
+                "`import` has the form `(import import-set ...+)`".into(),
+            ));
+        }
+        let mut libraries = Vec::new();
+        for (idx, set) in elems.iter().enumerate().skip(1) {
+            let bindings = self.import_set(set, &mut libraries).map_err(at(idx))?;
+            let mut frame = env.bindings.borrow_mut();
+            for (name, denotation) in bindings {
+                frame.insert(name, denotation);
+            }
+        }
+        Ok(libraries)
+    }
+
+    /// Bindings of the identifiers of an import set, by their names in the
+    /// importing scope
+    fn import_set(
+        &mut self,
+        set: &Sexp,
+        libraries: &mut Vec<Rc<Library>>,
+    ) -> Result<Vec<(String, Denotation)>, Error> {
+        let elems = elements(set).unwrap_or_default();
+        let modifier = match elems.first() {
+            Some(Sexp::Identifier(head)) if elems.len() >= 2 => head.as_str(),
+            _ => "",
+        };
+        let ids = |from: usize| {
+            (from..elems.len())
+                .map(|idx| {
+                    ident(elems[idx]).ok_or_else(|| {
+                        Error::Message("expected an identifier".into()).within(vec![idx])
+                    })
+                })
+                .collect::<Result<Vec<&str>, Error>>()
+        };
+        let imported = |bindings: &[(String, Denotation)], id: &str, idx: usize| match bindings
+            .iter()
+            .any(|(name, _)| name == id)
+        {
+            true => Ok(()),
+            false => {
+                Err(Error::Message(format!("`{}` is not in the import set", id)).within(vec![idx]))
+            }
+        };
+        let mut bindings = match modifier {
+            "only" | "except" | "prefix" | "rename" => {
+                self.import_set(elems[1], libraries).map_err(at(1))?
+            }
+            _ => {
+                let name = LibraryName::from_sexp(set).ok_or_else(|| {
+                    Error::Message("expected a library name or an import set".into())
+                })?;
+                let library = self.find_library(&name)?;
+                libraries.push(library.clone());
+                let mut bindings = library
+                    .exports
+                    .iter()
+                    .map(|(name, denotation)| (name.clone(), denotation.clone()))
+                    .collect::<Vec<_>>();
+                bindings.sort_by(|a, b| a.0.cmp(&b.0));
+                return Ok(bindings);
+            }
+        };
+        match modifier {
+            "only" => {
+                let ids = ids(2)?;
+                for (idx, id) in ids.iter().enumerate() {
+                    imported(&bindings, id, idx + 2)?;
+                }
+                bindings.retain(|(name, _)| ids.contains(&name.as_str()));
+            }
+            "except" => {
+                let ids = ids(2)?;
+                for (idx, id) in ids.iter().enumerate() {
+                    imported(&bindings, id, idx + 2)?;
+                }
+                bindings.retain(|(name, _)| !ids.contains(&name.as_str()));
+            }
+            "prefix" => match ids(2)?[..] {
+                [prefix] => {
+                    for (name, _) in bindings.iter_mut() {
+                        name.insert_str(0, prefix);
+                    }
+                }
+                _ => {
+                    return Err(Error::Message(
+                        "`prefix` has the form `(prefix import-set id)`".into(),
+                    ))
+                }
+            },
+            _ => {
+                for (idx, pair) in elems.iter().enumerate().skip(2) {
+                    let (from, to) = match elements(pair).unwrap_or_default()[..] {
+                        [Sexp::Identifier(from), Sexp::Identifier(to)] => (from, to),
+                        _ => {
+                            return Err(Error::Message("expected a renaming, `(id id)`".into())
+                                .within(vec![idx]))
+                        }
+                    };
+                    imported(&bindings, from, idx)?;
+                    for (name, _) in bindings.iter_mut().filter(|(name, _)| name == from) {
+                        *name = to.clone();
+                    }
+                }
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// The library `name`, expanded from the file found on the search path
+    /// the first time it is imported without being defined
+    fn find_library(&mut self, name: &LibraryName) -> Result<Rc<Library>, Error> {
+        match self.expander.libraries.get(name) {
+            Some(Some(library)) => return Ok(library.clone()),
+            Some(None) => return Err(Error::Message(format!("library `{}` imports itself", name))),
+            None if name.is_builtin() => {
+                let library = Rc::new(Library {
+                    exports: HashMap::new(),
+                    imports: Vec::new(),
+                    body: RefCell::new(None),
+                });
+                self.expander
+                    .libraries
+                    .insert(name.clone(), Some(library.clone()));
+                return Ok(library);
+            }
+            None => {}
+        }
+        let path = self.expander.search_path.find(name).ok_or_else(|| {
+            Error::Message(format!(
+                "library `{}` is not found on the search path",
+                name
+            ))
+        })?;
+        // Errors are located in the file rather than at the import
+        let shown = path.display().to_string();
+        let source = fs::read_to_string(&path)
+            .map_err(|e| Error::Message(format!("cannot read `{}`: {}", shown, e)))?;
+        let forms = crate::syntax::parse_spanned(&source).map_err(|d| {
+            let spans = SpanTree::leaf(d.span().unwrap_or_default());
+            Error::File(shown.clone(), spans, Box::new(Error::Message(d.message)))
+        })?;
+        for (form, _) in &forms {
+            reserve(form, self.symbols);
+        }
+        // Files included by the libraries are relative to the library file
+        let outer = self.included.replace(Included::new(path.clone(), None));
+        let defined = forms.into_iter().try_for_each(|(form, spans)| {
+            match elements(&form).unwrap_or_default().first() {
+                Some(Sexp::Identifier(head)) if head == "define-library" => {
+                    self.define_library(&form)
+                }
+                _ => Err(Error::Message(
+                    "a library file only contains `define-library` forms".into(),
+                )),
+            }
+            .map_err(|e| Error::File(shown.clone(), spans, Box::new(e)))
+        });
+        self.included = outer;
+        defined?;
+        match self.expander.libraries.get(name) {
+            Some(Some(library)) => Ok(library.clone()),
+            _ => Err(Error::Message(format!(
+                "library `{}` is not defined in `{}`",
+                name, shown
+            ))),
+        }
+    }
+
+    /// Read the files of an `include` or `include-ci` form, relative to the
+    /// file of the form, with the forms of each and their locations
+    fn read_included(
+        &mut self,
+        form: &Sexp,
+        core: Core,
+    ) -> Result<Vec<(Rc<Included>, Forms)>, Error> {
+        let elems = elements(form).unwrap_or_default();
+        let shape = || {
+            Error::Message(format!(
+                "`{0}` has the form `({0} string ...+)`",
+                core.name()
+            ))
+        };
+        if elems.len() < 2 {
+            return Err(shape());
+        }
+        let mut files = Vec::new();
+        for (idx, elem) in elems.iter().enumerate().skip(1) {
+            let name = match elem {
+                Sexp::Literal(name) => name,
+                _ => return Err(shape().within(vec![idx])),
+            };
+            let path = match &self.included {
+                Some(file) => file.resolve(name),
+                None => PathBuf::from(name),
+            };
+            let file = Included::new(path, self.included.clone());
+            let shown = file.path.display().to_string();
+            if file
+                .parent
+                .as_ref()
+                .is_some_and(|p| p.includes(&file.canonical))
+            {
+                return Err(
+                    Error::Message(format!("`{}` is included within itself", shown))
+                        .within(vec![idx]),
+                );
+            }
+            let source = fs::read_to_string(&file.path).map_err(|e| {
+                Error::Message(format!("cannot read `{}`: {}", shown, e)).within(vec![idx])
+            })?;
+            let forms = crate::syntax::parse_spanned(&source).map_err(|d| {
+                let spans = SpanTree::leaf(d.span().unwrap_or_default());
+                Error::File(shown, spans, Box::new(Error::Message(d.message))).within(vec![idx])
+            })?;
+            let forms = match core {
+                Core::IncludeCi => forms
+                    .into_iter()
+                    .map(|(form, spans)| (fold_case(form), spans))
+                    .collect(),
+                _ => forms,
+            };
+            for (form, _) in &forms {
+                reserve(form, self.symbols);
+            }
+            files.push((file, forms));
+        }
+        Ok(files)
+    }
+
+    /// Expand each form of the files of an `include` or `include-ci` form
+    /// with `f`, as a form of its file
+    fn include(
+        &mut self,
+        form: &Sexp,
+        core: Core,
+        mut f: impl FnMut(&mut Self, &Sexp) -> Result<Option<Sexp>, Error>,
+    ) -> Result<Vec<Sexp>, Error> {
+        let mut out = Vec::new();
+        let outer = self.included.clone();
+        for (file, forms) in self.read_included(form, core)? {
+            self.included = Some(file.clone());
+            for (form, spans) in forms {
+                match f(self, &form).and_then(|form| form.map(analyzed).transpose()) {
+                    Ok(form) => out.extend(form),
+                    Err(e) => {
+                        self.included = outer;
+                        let name = file.path.display().to_string();
+                        return Err(Error::File(name, spans, Box::new(e)));
+                    }
+                }
+            }
+        }
+        self.included = outer;
+        Ok(out)
+    }
+
+    /// Apply the transformer of `p` to the operands of a use
+    fn call(&mut self, p: &Procedure, form: &Sexp) -> Result<Sexp, Error> {
+        let elems = elements(form).unwrap_or_default();
+        if split_tail(&elems).1.is_some() {
+            return Err(Error::Message(format!(
+                "a use of the macro `{}` must be a proper list",
+                p.name
+            )));
+        }
+        let args = elems[1..].iter().map(|e| Object::from_sexp(e)).collect();
+        let error = |e: Error| {
+            let message = |e| Error::Message(format!("in the expansion of `{}`: {}", p.name, e));
+            match opaque(e) {
+                Error::Backtrace(calls, e) => Error::Backtrace(calls, Box::new(message(*e))),
+                e => message(e),
+            }
+        };
+        let result = self
+            .expander
+            .transformers
+            .apply(&p.transformer, args, self.symbols)
+            .map_err(error)?;
+        let form = result.to_sexp().map_err(error)?;
+        reserve(&form, self.symbols);
+        Ok(form)
+    }
+
+    /// Build the macro `name` from a `syntax-rules` transformer, closed
+    /// over `env`
+    fn syntax_rules(&mut self, name: &str, spec: &Sexp, env: &Env) -> Result<Macro, Error> {
+        let elems = match elements(spec) {
+            Some(elems) if self.head(spec, env).is_core(Core::SyntaxRules) => elems,
+            _ => {
+                return Err(Error::Message(format!(
+                    "expected a `syntax-rules` transformer for `{}`",
+                    self.strip(name)
+                )))
+            }
+        };
+        let (ellipsis, start) = match elems.get(1) {
+            Some(Sexp::Identifier(e)) => (e.clone(), 2),
+            _ => ("...".to_string(), 1),
+        };
+        let literals = match elems.get(start).and_then(|l| elements(l)) {
+            Some(literals) => literals.into_iter().cloned().collect(),
+            None => {
+                return Err(Error::Message("expected a list of literals".into()).within(vec![start]))
+            }
+        };
+        let mut rules = Vec::new();
+        for (idx, rule) in elems.iter().enumerate().skip(start + 1) {
+            match elements(rule) {
+                Some(rule)
+                    if rule.len() == 2 && elements(rule[0]).is_some_and(|p| !p.is_empty()) =>
+                {
+                    rules.push((rule[0].clone(), rule[1].clone()))
+                }
+                _ => {
+                    return Err(Error::Message(
+                        "expected a `((keyword pattern ...) template)` rule".into(),
+                    )
+                    .within(vec![idx]))
+                }
+            }
+        }
+        Ok(Macro {
+            name: self.strip(name).to_string(),
+            ellipsis,
+            literals,
+            rules,
+            env: env.clone(),
+        })
+    }
+
+    /// Rewrite a use of the macro `m` with its first matching rule
+    fn transcribe(&mut self, m: &Macro, form: &Sexp, env: &Env) -> Result<Sexp, Error> {
+        let input = elements(form).unwrap_or_default();
+        for (pattern, template) in &m.rules {
+            let pattern = elements(pattern).unwrap_or_default();
+            let mut b = HashMap::new();
+            if self.match_list(m, &pattern[1..], &input[1..], env, &mut b) {
+                let mut renames = HashMap::new();
+                return self.template(m, template, Some(&m.ellipsis), &b, &mut renames);
+            }
+        }
+        Err(Error::Message(format!(
+            "no rule of the macro `{}` matches this use",
+            m.name
+        )))
+    }
+
+    fn is_ellipsis(sexp: &Sexp, ellipsis: Option<&str>) -> bool {
+        matches!((sexp, ellipsis), (Sexp::Identifier(s), Some(e)) if s == e)
+    }
+
+    fn match_pattern(
+        &self,
+        m: &Macro,
+        pattern: &Sexp,
+        input: &Sexp,
+        env: &Env,
+        b: &mut HashMap<String, Matched>,
+    ) -> bool {
+        match pattern {
+            Sexp::Identifier(id) if id == "_" => true,
+            Sexp::Identifier(id) if m.literals.contains(pattern) => match input {
+                Sexp::Identifier(i) => self.resolve(env, i) == self.resolve(&m.env, id),
+                _ => false,
+            },
+            Sexp::Identifier(id) => {
+                b.insert(id.clone(), Matched::One(input.clone()));
+                true
+            }
+            Sexp::List(pattern) => match input {
+                Sexp::List(input) => {
+                    let pattern = pattern.iter().collect::<Vec<&Sexp>>();
+                    let input = input.iter().collect::<Vec<&Sexp>>();
+                    self.match_list(m, &pattern, &input, env, b)
+                }
+                _ => false,
+            },
+            _ => pattern == input,
+        }
+    }
+
+    fn match_list(
+        &self,
+        m: &Macro,
+        pattern: &[&Sexp],
+        input: &[&Sexp],
+        env: &Env,
+        b: &mut HashMap<String, Matched>,
+    ) -> bool {
+        let (pattern, pattern_tail) = split_tail(pattern);
+        let (input, input_tail) = split_tail(input);
+        let ellipsis = Some(m.ellipsis.as_str());
+        let (before, repeat, after) =
+            match pattern.iter().position(|p| Self::is_ellipsis(p, ellipsis)) {
+                Some(idx) if idx > 0 => (
+                    &pattern[..idx - 1],
+                    Some(pattern[idx - 1]),
+                    &pattern[idx + 1..],
+                ),
+                _ => (pattern, None, &pattern[..0]),
+            };
+        let fixed = before.len() + after.len();
+        let proper = pattern_tail.is_none();
+        if input.len() < fixed
+            || (proper && input_tail.is_some())
+            || (proper && repeat.is_none() && input.len() != fixed)
+        {
+            return false;
+        }
+        let end = match repeat {
+            Some(_) => input.len() - after.len(),
+            None => before.len(),
+        };
+        if !before
+            .iter()
+            .zip(input)
+            .all(|(p, i)| self.match_pattern(m, p, i, env, b))
+        {
+            return false;
+        }
+        if let Some(repeat) = repeat {
+            let mut seqs = HashMap::new();
+            for var in pattern_vars(repeat, ellipsis) {
+                seqs.insert(var, Vec::new());
+            }
+            for item in &input[before.len()..end] {
+                let mut inner = HashMap::new();
+                if !self.match_pattern(m, repeat, item, env, &mut inner) {
+                    return false;
+                }
+                for (var, matched) in inner {
+                    seqs.entry(var).or_insert_with(Vec::new).push(matched);
+                }
+            }
+            for (var, seq) in seqs {
+                b.insert(var, Matched::Many(seq));
+            }
+            if !after
+                .iter()
+                .zip(&input[end..])
+                .all(|(p, i)| self.match_pattern(m, p, i, env, b))
+            {
+                return false;
+            }
+        }
+        match pattern_tail {
+            Some(tail) => {
+                let mut rest = input[end..]
+                    .iter()
+                    .map(|s| (*s).clone())
+                    .collect::<Vec<Sexp>>();
+                let rest = match input_tail {
+                    Some(tail) if rest.is_empty() => tail.clone(),
+                    Some(tail) => {
+                        rest.push(Sexp::Keyword(Kw::Dot));
+                        rest.push(tail.clone());
+                        Sexp::List(rest.into_iter().collect())
+                    }
+                    None => Sexp::List(rest.into_iter().collect()),
+                };
+                self.match_pattern(m, tail, &rest, env, b)
+            }
+            None => true,
+        }
+    }
+
+    /// Instantiate `template`, renaming the identifiers it introduces
+    /// consistently within one transcription
+    fn template(
+        &mut self,
+        m: &Macro,
+        template: &Sexp,
+        ellipsis: Option<&str>,
+        b: &HashMap<String, Matched>,
+        renames: &mut HashMap<String, String>,
+    ) -> Result<Sexp, Error> {
+        match template {
+            Sexp::Identifier(id) => match b.get(id) {
+                Some(Matched::One(sexp)) => Ok(sexp.clone()),
+                Some(Matched::Many(_)) => Err(Error::Message(format!(
+                    "pattern variable `{}` is used without an ellipsis in `{}`",
+                    id, m.name
+                ))),
+                None => Ok(Sexp::Identifier(self.alias(m, id, renames))),
+            },
+            Sexp::List(list) => {
+                let elems = list.iter().collect::<Vec<&Sexp>>();
+                if elems.len() == 2 && Self::is_ellipsis(elems[0], ellipsis) {
+                    // `(... template)` escapes the ellipsis
+                    return self.template(m, elems[1], None, b, renames);
+                }
+                let mut out = Vec::new();
+                let mut idx = 0;
+                while idx < elems.len() {
+                    let mut depth = 0;
+                    while idx + depth + 1 < elems.len()
+                        && Self::is_ellipsis(elems[idx + depth + 1], ellipsis)
+                    {
+                        depth += 1;
+                    }
+                    match depth {
+                        0 => out.push(self.template(m, elems[idx], ellipsis, b, renames)?),
+                        _ => self.repeat(m, elems[idx], depth, ellipsis, b, renames, &mut out)?,
+                    }
+                    idx += depth + 1;
+                }
+                Ok(dotted(out))
+            }
+            _ => Ok(template.clone()),
+        }
+    }
+
+    /// Instantiate `template` followed by `depth` ellipses into `out`
+    #[allow(clippy::too_many_arguments)]
+    fn repeat(
+        &mut self,
+        m: &Macro,
+        template: &Sexp,
+        depth: usize,
+        ellipsis: Option<&str>,
+        b: &HashMap<String, Matched>,
+        renames: &mut HashMap<String, String>,
+        out: &mut Vec<Sexp>,
+    ) -> Result<(), Error> {
+        let vars = pattern_vars(template, ellipsis)
+            .into_iter()
+            .filter_map(|var| match b.get(&var) {
+                Some(Matched::Many(seq)) => Some((var, seq.clone())),
+                _ => None,
+            })
+            .collect::<Vec<(String, Vec<Matched>)>>();
+        let len = match vars.first() {
+            Some((_, seq)) => seq.len(),
+            None => {
+                return Err(Error::Message(format!(
+                    "no pattern variable to repeat in `{}` before an ellipsis in `{}`",
+                    template.write(),
+                    m.name
+                )))
+            }
+        };
+        if vars.iter().any(|(_, seq)| seq.len() != len) {
+            return Err(Error::Message(format!(
+                "pattern variables repeated together in `{}` matched different numbers of forms",
+                template.write()
+            )));
+        }
+        for idx in 0..len {
+            let mut b = b.clone();
+            for (var, seq) in &vars {
+                b.insert(var.clone(), seq[idx].clone());
+            }
+            match depth {
+                1 => out.push(self.template(m, template, ellipsis, &b, renames)?),
+                _ => self.repeat(m, template, depth - 1, ellipsis, &b, renames, out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Fresh alias for the identifier `name` introduced by `m`
+    fn alias(&mut self, m: &Macro, name: &str, renames: &mut HashMap<String, String>) -> String {
+        if let Some(alias) = renames.get(name) {
+            return alias.clone();
+        }
+        let base = self.strip(name).to_string();
+        let alias = self.symbols.fresh(&base);
+        self.expander.aliases.insert(
+            alias.clone(),
+            Alias {
+                name: name.to_string(),
+                env: m.env.clone(),
+            },
+        );
+        renames.insert(name.to_string(), alias.clone());
+        alias
+    }
+}
+
+impl Head {
+    fn is_core(&self, core: Core) -> bool {
+        matches!(self, Head::Core(c) if *c == core)
+    }
+}
+
+/// Identifiers of a pattern or template, other than ellipses and `_`
+fn pattern_vars(pattern: &Sexp, ellipsis: Option<&str>) -> Vec<String> {
+    match pattern {
+        Sexp::Identifier(id) if id == "_" || Some(id.as_str()) == ellipsis => Vec::new(),
+        Sexp::Identifier(id) => vec![id.clone()],
+        Sexp::List(list) => list
+            .iter()
+            .flat_map(|p| pattern_vars(p, ellipsis))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Identifiers defined by a `define`, `define-record-type` or
+/// `define-values` form
+fn defined_names(form: &Sexp) -> Vec<&str> {
+    let elems = elements(form).unwrap_or_default();
+    match elems[..] {
+        [Sexp::Keyword(Kw::DefineRecordType), name, ctor, pred, ref fields @ ..] => {
+            let mut names = vec![ident(name)];
+            names.extend(elements(ctor).and_then(|c| c.first().map(|&c| ident(c))));
+            names.push(ident(pred));
+            for field in fields {
+                let field = elements(field).unwrap_or_default();
+                names.extend(field.iter().skip(1).map(|&f| ident(f)));
+            }
+            names.into_iter().flatten().collect()
+        }
+        [Sexp::Keyword(Kw::DefineValues), formals, ..] => match elements(formals) {
+            Some(formals) => formals.into_iter().filter_map(ident).collect(),
+            None => ident(formals).into_iter().collect(),
+        },
+        [_, Sexp::List(List::Cons(name, _)), ..] => ident(name).into_iter().collect(),
+        [_, name, ..] => ident(name).into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::Span;
+
+    fn expand_str(input: &str) -> Result<Vec<String>, Error> {
+        let mut expander = Expander::new();
+        let mut symbols = SymbolTable::new();
+        let mut out = Vec::new();
+        for form in crate::syntax::parse(input).unwrap() {
+            if let Some(form) = expander.expand(&form, &mut symbols)? {
+                out.push(form.to_string());
+            }
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn hygiene() {
+        let swap = "(define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))";
+        assert_eq!(
+            expand_str(&format!("{} (let ((tmp 1) (other 2)) (swap! tmp other))", swap)),
+            Ok(vec![
+                "(let ((tmp~0 1) (other~1 2)) (let ((tmp~3 tmp~0)) (set! tmp~0 other~1) (set! other~1 tmp~3)))".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str(
+                "(define-syntax first (syntax-rules () ((_ x) (car x)))) (let ((car 1)) (first p))"
+            ),
+            Ok(vec!["(let ((car~0 1)) (car p))".to_string()])
+        );
+        assert_eq!(
+            expand_str(
+                "(let ((x 1)) (let-syntax ((m (syntax-rules () ((_) x)))) (let ((x 2)) (m))))"
+            ),
+            Ok(vec![
+                "(let ((x~0 1)) (let () (let ((x~1 2)) x~0)))".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str("(let ((x 1)) (when x (unless x x)) (cond (x => f)))"),
+            Ok(vec![
+                "(let ((x~0 1)) (when x~0 (unless x~0 x~0)) (cond (x~0 => f)))".to_string()
+            ])
+        );
+        // The variable of a `guard` is only bound in its clauses
+        assert_eq!(
+            expand_str("(let ((e 1)) (guard (e ((f e) => g) (else e)) (h e)))"),
+            Ok(vec![
+                "(let ((e~0 1)) (guard (e~1 ((f e~1) => g) (else e~1)) (h e~0)))".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str("(let ((p 1)) (parameterize ((p (lambda (p) p))) (define x p) x))"),
+            Ok(vec![
+                "(let ((p~0 1)) (parameterize ((p~0 (lambda (p~1) p~1))) (define x~2 p~0) x~2))"
+                    .to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str("(define-syntax q (syntax-rules () ((_ x) (quote (tmp x))))) (q y)"),
+            Ok(vec!["'(tmp y)".to_string()])
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(
+            expand_str(
+                "(define-syntax my-or (syntax-rules () ((_) #f) ((_ e) e) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))
+                 (let ((t 5)) (my-or #f t))"
+            ),
+            Ok(vec!["(let ((t~0 5)) (let ((t~3 #f)) (if t~3 t~3 t~0)))".to_string()])
+        );
+        assert_eq!(
+            expand_str("(define-syntax m (syntax-rules () ((_ (a b ...) ...) (quote (a ... b ... ...))))) (m (1 2 3) (4 5))"),
+            Ok(vec!["'(1 4 2 3 5)".to_string()])
+        );
+        assert_eq!(
+            expand_str("(define-syntax m (syntax-rules dots () ((_ x dots) (quote (x dots (dots dots)))))) (m 1 2)"),
+            Ok(vec!["'(1 2 dots)".to_string()])
+        );
+        assert_eq!(
+            expand_str(
+                "(define-syntax arrow (syntax-rules (=>) ((_ a => b) (b a)) ((_ a b c) (list a b c))))
+                 (arrow 1 => f) (arrow 1 2 3) (let ((=> 0)) (arrow 1 => f))"
+            ),
+            Ok(vec![
+                "(f 1)".to_string(),
+                "(list 1 2 3)".to_string(),
+                "(let ((=>~1 0)) (list 1 =>~1 f))".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn syntax_scopes() {
+        assert_eq!(
+            expand_str("(let-syntax ((m (syntax-rules () ((_) 1)))) (m)) (m)"),
+            Ok(vec!["(let () 1)".to_string(), "(m)".to_string()])
+        );
+        assert_eq!(
+            expand_str(
+                "(letrec-syntax ((ev? (syntax-rules () ((_) #t) ((_ x . r) (od? . r))))
+                                 (od? (syntax-rules () ((_) #f) ((_ x . r) (ev? . r)))))
+                   (ev? 1 2 3))"
+            ),
+            Ok(vec!["(let () #f)".to_string()])
+        );
+        assert_eq!(
+            expand_str(
+                "(lambda () (define-syntax two (syntax-rules () ((_) 2))) (define (f) (g)) (define g (lambda () (two))) (f))"
+            ),
+            Ok(vec!["(lambda () (define (f~0) (g~1)) (define g~1 (lambda () 2)) (f~0))".to_string()])
+        );
+        assert_eq!(
+            expand_str(
+                "(define-syntax def (syntax-rules () ((_ n v) (begin (define n v) (define tmp n)))))
+                 (lambda () (def x 1) (def y 2) (list x y))"
+            ),
+            Ok(vec![
+                "(lambda () (define x~1 1) (define tmp~2 x~1) (define y~4 2) (define tmp~5 y~4) (list x~1 y~4))".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn expansion_errors() {
+        assert_eq!(
+            expand_str(
+                "(define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+                 (list 1 (swap! 1))"
+            ),
+            Err(Error::Message("no rule of the macro `swap!` matches this use".into()).within(vec![2]))
+        );
+        assert_eq!(
+            expand_str("(define-syntax loop (syntax-rules () ((_) (loop)))) (loop)"),
+            Err(Error::Message(format!(
+                "expansion of `loop` is nested more than {} levels deep",
+                MAX_DEPTH
+            )))
+        );
+        assert!(expand_str("(define-syntax m (syntax-rules () ((_ x ...) x))) (m 1)").is_err());
+        assert!(expand_str("(define-syntax m 1)").is_err());
+        assert!(expand_str("(if define-syntax 1 2)").is_err());
+    }
+
+    #[test]
+    fn procedural_macros() {
+        assert_eq!(
+            expand_str(
+                "(define-macro (my-unless c . body) `(if ,c #f (begin ,@body)))
+                 (define-macro m (lambda args (cons 'list args)))
+                 (my-unless x 1 2) (m 1 2)"
+            ),
+            Ok(vec![
+                "(if x #f (begin 1 2))".to_string(),
+                "(list 1 2)".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str(
+                "(define-macro (with-temp e) (let ((g (gensym))) `(let ((,g ,e)) (list ,g ,g))))
+                 (with-temp 5)"
+            ),
+            Ok(vec![
+                "(let (($g12~13 5)) (list $g12~13 $g12~13))".to_string()
+            ])
+        );
+        // Identifiers from the transformer are captured by the use
+        assert_eq!(
+            expand_str("(define-macro (aif c t) `(let ((it ,c)) (if it ,t #f))) (aif 1 it)"),
+            Ok(vec!["(let ((it~13 1)) (if it~13 it~13 #f))".to_string()])
+        );
+        assert_eq!(
+            expand_str("(lambda () (define-macro (two) 2) (+ (two) 1))"),
+            Ok(vec!["(lambda () (+ 2 1))".to_string()])
+        );
+    }
+
+    #[test]
+    fn procedural_macro_errors() {
+        assert_eq!(
+            expand_str("(define-macro (bad x) (car x)) (list (bad 1))"),
+            Err(Error::Backtrace(
+                vec!["car".into()],
+                Box::new(Error::Message(
+                    "in the expansion of `bad`: expected a pair, found 1".into()
+                ))
+            )
+            .within(vec![1]))
+        );
+        assert_eq!(
+            expand_str(
+                "(define-macro (m x) (if (symbol? x) (error \"expected a number, found\" x) x))
+                 (m 1) (m a)"
+            ),
+            Err(Error::Backtrace(
+                vec!["error".into()],
+                Box::new(Error::Message(
+                    "in the expansion of `m`: expected a number, found a".into()
+                ))
+            ))
+        );
+        // Transformers that don't return are stopped
+        assert_eq!(
+            expand_str("(define-macro (m) (let loop () (loop))) (m)"),
+            Err(Error::Backtrace(
+                vec!["loop".into()],
+                Box::new(Error::Message(
+                    "in the expansion of `m`: evaluation took more than 1000000 steps".into()
+                ))
+            ))
+        );
+        assert_eq!(
+            expand_str("(define-macro (m) (load \"m.scm\")) (m)"),
+            Err(Error::Backtrace(
+                vec!["load".into()],
+                Box::new(Error::Message(
+                    "in the expansion of `m`: files cannot be loaded at expansion time".into()
+                ))
+            ))
+        );
+        assert_eq!(
+            expand_str("(define-macro m 1)"),
+            Err(Error::Message("the transformer of `m` is not a procedure".into()).within(vec![2]))
+        );
+        assert!(expand_str("(list (define-macro (m) 1))").is_err());
+    }
+
+    #[test]
+    fn values_definitions() {
+        assert_eq!(
+            expand_str("(let ((x 1)) (let-values (((x y) (f x)) (z (g x))) (list x y z)))"),
+            Ok(vec![
+                "(let ((x~0 1)) (let-values (((x~1 y~2) (f x~0)) (z~3 (g x~0))) (list x~1 y~2 z~3)))".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str("(let*-values (((x) 1) ((y . z) x)) y)"),
+            Ok(vec![
+                "(let*-values (((x~0) 1) ((y~1 . z~2) x~0)) y~1)".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str("(define-values (a b) (f)) (let () (define-values (a . b) (g)) a)"),
+            Ok(vec![
+                "(define-values (a b) (f))".to_string(),
+                "(let () (define-values (a~0 . b~1) (g)) a~0)".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn record_definitions() {
+        let point = "(define-record-type point (make-point x) point? (x point-x set-point-x!))";
+        assert_eq!(expand_str(point), Ok(vec![point.to_string()]));
+        assert_eq!(
+            expand_str(&format!("(let ((x 1)) {} (point-x (make-point x)))", point)),
+            Ok(vec![
+                "(let ((x~0 1)) (define-record-type point~1 (make-point~2 x) point?~3 (x point-x~4 set-point-x!~5)) (point-x~4 (make-point~2 x~0)))".to_string()
+            ])
+        );
+        // Procedures named by a macro rather than its use stay hidden
+        assert_eq!(
+            expand_str(
+                "(define-syntax defbox (syntax-rules () ((_ make) (define-record-type box (make v) box? (v unbox)))))
+                 (let () (defbox mk) (unbox (mk 1)))"
+            ),
+            Ok(vec![
+                "(let () (define-record-type box~4 (mk~5 v) box?~6 (v unbox~7)) (unbox (mk~5 1)))".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn libraries() {
+        let counter = "(define-library (counter)
+                         (export next (rename count current) bump!)
+                         (import (scheme base))
+                         (begin
+                           (define count 0)
+                           (define (next) (set! count (+ count 1)) count)
+                           (define-syntax bump! (syntax-rules () ((_) (next))))))";
+        assert_eq!(
+            expand_str(&format!(
+                "{} (import (counter)) (list (next) (current) (bump!))",
+                counter
+            )),
+            Ok(vec![
+                "(begin (define count~0 0) (define (next~1) (set! count~0 (+ count~0 1)) count~0))"
+                    .to_string(),
+                "(list (next~1) (count~0) (next~1))".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str(&format!("{} (import (counter)) current", counter)),
+            Ok(vec![
+                "(begin (define count~0 0) (define (next~1) (set! count~0 (+ count~0 1)) count~0))"
+                    .to_string(),
+                "count~0".to_string()
+            ])
+        );
+        // A library is instantiated by its first import only
+        assert_eq!(
+            expand_str(&format!(
+                "{} (import (only (counter) next)) (import (prefix (except (counter) next) c-)) (c-current) (current)",
+                counter
+            )),
+            Ok(vec![
+                "(begin (define count~0 0) (define (next~1) (set! count~0 (+ count~0 1)) count~0))"
+                    .to_string(),
+                "(count~0)".to_string(),
+                "(current)".to_string()
+            ])
+        );
+        assert_eq!(
+            expand_str(&format!(
+                "{} (define-library (user) (export twice) (import (rename (counter) (next n)))
+                      (begin (define (twice) (n) (n))))
+                    (import (user)) (twice)",
+                counter
+            )),
+            Ok(vec![
+                "(begin (define count~0 0) (define (next~1) (set! count~0 (+ count~0 1)) count~0) (define (twice~2) (next~1) (next~1)))"
+                    .to_string(),
+                "(twice~2)".to_string()
+            ])
+        );
+
+        assert_eq!(
+            expand_str(&format!("{} (import (only (counter) nope))", counter)),
+            Err(Error::Message("`nope` is not in the import set".into()).within(vec![1, 2]))
+        );
+        assert_eq!(
+            expand_str("(import (missing))"),
+            Err(
+                Error::Message("library `(missing)` is not found on the search path".into())
+                    .within(vec![1])
+            )
+        );
+        assert_eq!(
+            expand_str("(define-library (a) (export x) (begin (define x 1))) (define-library (a))"),
+            Err(Error::Message("library `(a)` is already defined".into()).within(vec![1]))
+        );
+        assert_eq!(
+            expand_str("(define-library (a) (provide x))"),
+            Err(Error::Message(
+                "expected a library declaration, `export`, `import`, `include` or `begin`".into()
+            )
+            .within(vec![2]))
+        );
+        assert_eq!(
+            expand_str("(lambda () (import (a)))"),
+            Err(Error::Message("`import` is only allowed at the top level".into()).within(vec![2]))
+        );
+    }
+
+    #[test]
+    fn library_files() {
+        let dir = std::env::temp_dir().join(format!("zymogen-libraries-{}", std::process::id()));
+        let write = |name: &str, source: &str| {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        };
+        write(
+            "util/math.sld",
+            "(define-library (util math) (export square) (begin (define (square x) (* x x))))",
+        );
+        write(
+            "util/cycle.sld",
+            "(define-library (util cycle) (import (util cycle)))",
+        );
+        write("util/wrong.sld", "(define-library (util other))");
+        let mut expander = Expander::new();
+        expander.add_library_path(dir.join("missing"));
+        expander.add_library_path(&dir);
+        let mut symbols = SymbolTable::new();
+        let mut expand = |input: &str| {
+            let form = crate::syntax::parse(input).unwrap().remove(0);
+            expander
+                .expand(&form, &mut symbols)
+                .map(|form| form.map(|form| form.to_string()))
+        };
+        assert_eq!(
+            expand("(import (util math))"),
+            Ok(Some("(begin (define (square~0 x~1) (* x~1 x~1)))".into()))
+        );
+        // The library is read and expanded once
+        fs::remove_file(dir.join("util/math.sld")).unwrap();
+        assert_eq!(expand("(import (util math))"), Ok(None));
+        assert_eq!(expand("(square 2)"), Ok(Some("(square~0 2)".into())));
+
+        let file = |name: &str| dir.join("util").join(name).display().to_string();
+        // Errors in the file are located in it
+        let e = expand("(import (util cycle))").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "in `{}`: library `(util cycle)` imports itself",
+                file("cycle.sld")
+            )
+        );
+        assert_eq!(e.file(), Some(file("cycle.sld").as_str()));
+        let spans = crate::syntax::parse_spanned("(import (util cycle))")
+            .unwrap()
+            .remove(0)
+            .1;
+        let diag = e.diagnostic(Some(&spans));
+        assert_eq!(diag.span(), Some(Span { line: 0, pos: 37 }));
+        assert_eq!(
+            diag.notes,
+            vec![format!("in `{}`, included at 1:9", file("cycle.sld"))]
+        );
+        assert_eq!(
+            expand("(import (util wrong))"),
+            Err(Error::Message(format!(
+                "library `(util wrong)` is not defined in `{}`",
+                file("wrong.sld")
+            ))
+            .within(vec![1]))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("zymogen-includes-{}", std::process::id()));
+        let write = |name: &str, source: &str| {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        };
+        write(
+            "defs.scm",
+            "(define x 1)\n(define (f) (include \"sub/body.scm\"))",
+        );
+        write("sub/body.scm", "(define y 2)\n(+ x y)");
+        write("upper.scm", "(DEFINE (G) X)");
+        write("cycle.scm", "(include \"sub/cycle.scm\")");
+        write("sub/cycle.scm", "(include \"../cycle.scm\")");
+        write("bad.scm", "(define z 1)\n(f (import (scheme base)))");
+        write(
+            "lib/thing.sld",
+            "(define-library (lib thing) (export t) (include \"thing.scm\"))",
+        );
+        write("lib/thing.scm", "(define t 1)");
+        let mut expander = Expander::new();
+        expander.add_library_path(&dir);
+        expander.set_file(Some(dir.join("main.scm")));
+        let mut symbols = SymbolTable::new();
+        let mut expand = |input: &str| {
+            let form = crate::syntax::parse(input).unwrap().remove(0);
+            expander
+                .expand(&form, &mut symbols)
+                .map(|form| form.map(|form| form.to_string()))
+        };
+        // Files are relative to the file including them
+        assert_eq!(
+            expand("(include \"defs.scm\")"),
+            Ok(Some(
+                "(begin (define x 1) (define (f) (define y~0 2) (+ x y~0)))".into()
+            ))
+        );
+        assert_eq!(
+            expand("(include-ci \"upper.scm\")"),
+            Ok(Some("(begin (define (g) x))".into()))
+        );
+        assert_eq!(
+            expand("(import (lib thing))"),
+            Ok(Some("(begin (define t~1 1))".into()))
+        );
+
+        let file = |name: &str| dir.join(name).display().to_string();
+        let e = expand("(include \"cycle.scm\")").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "in `{}`: in `{}`: `{}` is included within itself",
+                file("cycle.scm"),
+                file("sub/cycle.scm"),
+                dir.join("sub").join("../cycle.scm").display()
+            )
+        );
+        // Errors in included forms are located in their file
+        let e = expand("(begin (include \"bad.scm\"))").unwrap_err();
+        assert_eq!(e.file(), Some(file("bad.scm").as_str()));
+        let spans = crate::syntax::parse_spanned("(begin (include \"bad.scm\"))")
+            .unwrap()
+            .remove(0)
+            .1;
+        let diag = e.diagnostic(Some(&spans));
+        assert_eq!(diag.span(), Some(Span { line: 1, pos: 3 }));
+        assert_eq!(
+            diag.notes,
+            vec![format!("in `{}`, included at 1:8", file("bad.scm"))]
+        );
+        // So are errors in their syntax, found when they are analyzed
+        write("let.scm", "(define a 1)\n(let ((b)) b)");
+        for input in [
+            "(include \"let.scm\")",
+            "(define (h) (include \"let.scm\"))",
+        ] {
+            let e = expand(input).unwrap_err();
+            assert_eq!(e.file(), Some(file("let.scm").as_str()));
+            let spans = crate::syntax::parse_spanned(input).unwrap().remove(0).1;
+            let diag = e.diagnostic(Some(&spans));
+            assert_eq!(diag.span(), Some(Span { line: 1, pos: 6 }));
+        }
+        assert_eq!(
+            expand("(include x)"),
+            Err(
+                Error::Message("`include` has the form `(include string ...+)`".into())
+                    .within(vec![1])
+            )
+        );
+        assert!(expand("(include \"missing.scm\")")
+            .unwrap_err()
+            .to_string()
+            .starts_with(&format!("cannot read `{}`", file("missing.scm"))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use super::mir::RecordOp;
 
 #[derive(Debug, PartialEq, PartialOrd)]
 pub enum Operation {
@@ -8,7 +9,29 @@ pub enum Operation {
     Bound(usize),
     /// Reference to constant table
     Constant(usize),
-    JumpNotEqual(usize),
-    /// Call procedure with N-args
+    /// Make a procedure of the prototypes of its clauses
+    Closure(Vec<usize>),
+    /// Make a new record-type descriptor with a name and fields
+    RecordType(String, Vec<String>),
+    /// Operate on a record with N-operands, the first being the descriptor
+    /// of its type
+    Record(RecordOp, usize),
+    /// Call procedure with N-args. Nothing runs bytecode yet, so no clause
+    /// is chosen for the arguments: the prototypes of a procedure only
+    /// record the arity of each of its clauses
     Call(usize),
+    /// Call the procedure below the top of the stack without arguments,
+    /// then the one on top with the values it returns, which are passed on
+    /// the stack rather than collected into a list
+    CallWithValues,
+}
+
+/// Compiled clause of a `lambda` or `case-lambda`, whose parameters are
+/// its first locals
+#[derive(Debug, PartialEq)]
+pub struct Prototype {
+    pub required: usize,
+    pub rest: bool,
+    pub locals: usize,
+    pub code: Vec<Operation>,
 }
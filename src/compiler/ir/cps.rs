@@ -0,0 +1,200 @@
+//! Continuation-passing style intermediate representation
+//!
+//! Every procedure takes the continuation of its call as an extra argument,
+//! and no term returns: a term ends by calling a procedure or by passing a
+//! value to a continuation. Subterms are reference counted, so that the
+//! closures of a running program can share their bodies
+use super::mir::{ParameterOp, RecordOp};
+use super::{Symbol, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Atom {
+    Var(String),
+    Val(Value),
+    Quote(Value),
+    /// Procedure of the clauses of a `lambda` or `case-lambda`
+    Lambda(Rc<[Lambda]>),
+    /// Continuation binding the value passed to it
+    Cont(Rc<Cont>),
+}
+
+/// Clause of a procedure, whose continuation is bound to `cont`
+#[derive(Debug, PartialEq)]
+pub struct Lambda {
+    pub args: Vec<String>,
+    pub rest: Option<String>,
+    pub cont: String,
+    pub body: Rc<Term>,
+}
+
+impl Lambda {
+    pub fn accepts(&self, argc: usize) -> bool {
+        argc == self.args.len() || (self.rest.is_some() && argc > self.args.len())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cont {
+    pub var: String,
+    pub body: Rc<Term>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Term {
+    /// Call of a procedure with arguments and a continuation
+    App(Atom, Vec<Atom>, Atom),
+    /// Value passed to a continuation
+    Continue(Atom, Atom),
+    Let(String, Atom, Rc<Term>),
+    /// Binding of the result of a primitive operation, which returns
+    /// directly
+    Prim(String, Primop, Vec<Atom>, Rc<Term>),
+    If(Atom, Rc<Term>, Rc<Term>),
+    /// Multi-way branch on a key, which takes the arm it is a constant of,
+    /// otherwise the default
+    Switch(Atom, Arms, Rc<Term>),
+    Set(String, Atom, Rc<Term>),
+    /// Call of a producer without arguments, whose values are passed to a
+    /// consumer called with the continuation
+    CallWithValues(Atom, Atom, Atom),
+    /// Call of a thunk with a handler for the exceptions it raises, which
+    /// is called with the raised object and the continuation instead
+    Guard(Atom, Atom, Atom),
+    /// Call of a thunk within a before and an after thunk, with the
+    /// continuation
+    DynamicWind(Atom, Atom, Atom, Atom),
+    /// Object raised continuably, whose handler returns to the continuation
+    Raise(Atom, Atom),
+}
+
+/// Constant of a `Switch`, which is `eqv?` to a key when they are equal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Case {
+    Int(i64),
+    Symbol(Symbol),
+}
+
+/// Arms of a `Switch` with their constants, and a table of the arm of each
+/// constant, so that the key is looked up once rather than compared with
+/// every constant
+#[derive(Debug, PartialEq)]
+pub struct Arms {
+    arms: Vec<(Vec<Case>, Rc<Term>)>,
+    table: HashMap<Case, usize>,
+}
+
+impl Arms {
+    /// Arms tried in order: a constant of several arms takes the first
+    pub fn new(arms: Vec<(Vec<Case>, Rc<Term>)>) -> Arms {
+        let mut table = HashMap::new();
+        for (idx, (cases, _)) in arms.iter().enumerate() {
+            for case in cases {
+                table.entry(*case).or_insert(idx);
+            }
+        }
+        Arms { arms, table }
+    }
+
+    /// Arm of the constant `case`, if any
+    pub fn get(&self, case: Case) -> Option<&Rc<Term>> {
+        self.table.get(&case).map(|&idx| &self.arms[idx].1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Primop {
+    /// Promise of calling a thunk, replaced by the promise the thunk
+    /// returns when chained by `delay-force`
+    Delay(bool),
+    RecordType(String, Vec<String>),
+    Record(RecordOp),
+    Eqv,
+    Parameter(ParameterOp),
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Atom::Var(var) => f.write_str(var),
+            Atom::Val(val) => write!(f, "{}", val.write()),
+            Atom::Quote(Value::Nil) => f.write_str("'()"),
+            Atom::Quote(val) => write!(f, "'{}", val.write()),
+            Atom::Lambda(clauses) => {
+                let clauses = clauses
+                    .iter()
+                    .map(|l| {
+                        let mut args = l.args.clone();
+                        if let Some(rest) = &l.rest {
+                            args.push(".".into());
+                            args.push(rest.clone());
+                        }
+                        args.push(l.cont.clone());
+                        format!("({}) {}", args.join(" "), l.body)
+                    })
+                    .collect::<Vec<String>>();
+                match clauses.len() {
+                    1 => write!(f, "(λ {})", clauses[0]),
+                    _ => write!(f, "(case-λ ({}))", clauses.join(") (")),
+                }
+            }
+            Atom::Cont(k) => write!(f, "(κ ({}) {})", k.var, k.body),
+        }
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Case::Int(i) => write!(f, "{}", i),
+            Case::Symbol(s) => write!(f, "'{}", s),
+        }
+    }
+}
+
+fn join(atoms: &[Atom]) -> String {
+    atoms.iter().map(|a| format!(" {}", a)).collect()
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Term::App(rator, rands, k) => write!(f, "({}{} {})", rator, join(rands), k),
+            Term::Continue(k, val) => write!(f, "({} {})", k, val),
+            Term::Let(var, val, body) => write!(f, "(let (({} {})) {})", var, val, body),
+            Term::Prim(var, op, args, body) => {
+                let op = match op {
+                    Primop::Delay(false) => "delay".to_string(),
+                    Primop::Delay(true) => "delay-force".to_string(),
+                    Primop::RecordType(name, fields) => {
+                        format!("record-type {} ({})", name, fields.join(" "))
+                    }
+                    Primop::Record(op) => op.to_string(),
+                    Primop::Eqv => "eqv".to_string(),
+                    Primop::Parameter(op) => op.to_string(),
+                };
+                write!(f, "(let (({} ({}{}))) {})", var, op, join(args), body)
+            }
+            Term::If(test, csq, alt) => write!(f, "(if {} {} {})", test, csq, alt),
+            Term::Switch(key, arms, default) => {
+                write!(f, "(switch {}", key)?;
+                for (cases, body) in &arms.arms {
+                    let cases = cases.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+                    write!(f, " (({}) {})", cases.join(" "), body)?;
+                }
+                write!(f, " (else {}))", default)
+            }
+            Term::Set(var, val, body) => write!(f, "(begin (set! {} {}) {})", var, val, body),
+            Term::CallWithValues(producer, consumer, k) => {
+                write!(f, "(call-with-values {} {} {})", producer, consumer, k)
+            }
+            Term::Guard(thunk, handler, k) => write!(f, "(guard {} {} {})", thunk, handler, k),
+            Term::Raise(obj, k) => write!(f, "(raise-continuable {} {})", obj, k),
+            Term::DynamicWind(before, thunk, after, k) => {
+                write!(f, "(dynamic-wind {} {} {} {})", before, thunk, after, k)
+            }
+        }
+    }
+}
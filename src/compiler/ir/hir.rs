@@ -3,17 +3,22 @@
 //! These are directly parsed from the input, and in the transformation down to
 //! MIR, all derived expressions will be converted into primitive expressions
 //! and the AST will be simplified
-use super::super::Keyword;
 use super::Value;
 
 pub type Sequence = Vec<Expression>;
 
 #[derive(PartialEq, PartialOrd, Debug)]
 pub enum Expression {
-    Keyword(Keyword),
     Let(LetExpr),
     Begin(Sequence),
     Cond(Vec<CondClause>, Option<Sequence>),
+    /// Body evaluated when the test holds
+    When(Box<Expression>, Sequence),
+    /// Body evaluated when the test does not hold
+    Unless(Box<Expression>, Sequence),
+    /// `case` expression, with its key, clauses and `else` clause
+    Case(Box<Expression>, Vec<CaseClause>, Option<CaseBody>),
+    Do(DoExpr),
     And(Sequence),
     Or(Sequence),
     Literal(Value),
@@ -21,8 +26,28 @@ pub enum Expression {
     Quotation(Value),
     Call(Box<Expression>, Sequence),
     Lambda(LambdaExpr),
+    /// Procedure that runs the first clause accepting its number of
+    /// arguments
+    CaseLambda(Vec<LambdaExpr>),
     If(Box<Expression>, Box<Expression>, Option<Box<Expression>>),
     Assignment(String, Box<Expression>),
+    /// Promise of the value of the body, evaluated once when first forced
+    Delay(Sequence),
+    /// Promise that is replaced, when forced, by the promise its expression
+    /// evaluates to
+    DelayForce(Box<Expression>),
+    /// Descriptor of a new record type, evaluated after the procedures of
+    /// the type are assigned to their variables
+    DefineRecordType(RecordType),
+    /// Assignment of the values of an expression to variables
+    DefineValues(Box<ValuesBindings>),
+    /// Body evaluated with a handler for the exceptions it raises, which
+    /// binds the variable to the raised object for a `cond` expression of
+    /// the clauses
+    Guard(String, Box<Expression>, Sequence),
+    /// Body evaluated with the parameter objects of the bindings set to
+    /// their values, converted by the converters of the parameters
+    Parameterize(Vec<(Expression, Expression)>, Sequence),
 }
 
 #[derive(PartialEq, PartialOrd, Debug)]
@@ -33,9 +58,39 @@ pub struct LetBindings {
 
 #[derive(PartialEq, PartialOrd, Debug)]
 pub enum LetExpr {
+    /// Bindings whose initializers are all evaluated in the outer scope
     Let(Vec<LetBindings>, Sequence),
+    /// Bindings whose initializers see the bindings before them
+    LetStar(Vec<LetBindings>, Sequence),
     LetRec(Vec<LetBindings>, Sequence),
+    /// Scope of the internal definitions of a body, initialized in order
+    LetRecStar(Vec<Definition>, Sequence),
     NamedLet(String, Vec<LetBindings>, Sequence),
+    /// Bindings to the values of initializers all evaluated in the outer
+    /// scope
+    LetValues(Vec<ValuesBindings>, Sequence),
+    /// Bindings to the values of initializers that see the bindings before
+    /// them
+    LetStarValues(Vec<ValuesBindings>, Sequence),
+}
+
+/// Internal definition of a body, whose variables are in scope of all the
+/// definitions of the body
+#[derive(PartialEq, PartialOrd, Debug)]
+pub enum Definition {
+    /// Variable bound to the value of an expression
+    Single(LetBindings),
+    /// Variables bound to the values of an expression, by `define-values`
+    Values(ValuesBindings),
+}
+
+/// Variables bound to the values of an expression, the rest variable to a
+/// list of those left over
+#[derive(PartialEq, PartialOrd, Debug)]
+pub struct ValuesBindings {
+    pub args: Vec<String>,
+    pub rest: Option<String>,
+    pub expr: Expression,
 }
 
 #[derive(PartialEq, PartialOrd, Debug)]
@@ -45,8 +100,70 @@ pub struct LambdaExpr {
     pub body: Sequence,
 }
 
+/// Clause of a `cond` expression, taken when its test holds. A clause
+/// without a body evaluates to the value of the test
 #[derive(PartialEq, PartialOrd, Debug)]
 pub struct CondClause {
     pub test: Box<Expression>,
+    pub body: CondBody,
+}
+
+#[derive(PartialEq, PartialOrd, Debug)]
+pub enum CondBody {
+    Sequence(Sequence),
+    /// `=> expr`, where `expr` evaluates to a procedure applied to the value
+    /// of the test
+    Arrow(Box<Expression>),
+}
+
+/// Clause of a `case` expression, selected when the key is `eqv?` to one of
+/// its data
+#[derive(PartialEq, PartialOrd, Debug)]
+pub struct CaseClause {
+    pub data: Vec<Expression>,
+    pub body: CaseBody,
+}
+
+#[derive(PartialEq, PartialOrd, Debug)]
+pub enum CaseBody {
+    Sequence(Sequence),
+    /// `=> expr`, where `expr` evaluates to a procedure applied to the key
+    Arrow(Box<Expression>),
+}
+
+/// Variable of a `do` loop, updated to `step` on each iteration when it has
+/// one
+#[derive(PartialEq, PartialOrd, Debug)]
+pub struct DoBinding {
+    pub var: String,
+    pub init: Expression,
+    pub step: Option<Expression>,
+}
+
+/// `do` loop, which evaluates `body` until `test` holds, then `result`
+#[derive(PartialEq, PartialOrd, Debug)]
+pub struct DoExpr {
+    pub bind: Vec<DoBinding>,
+    pub test: Box<Expression>,
+    pub result: Sequence,
     pub body: Sequence,
 }
+
+/// Record type of a `define-record-type`, with the variables of its
+/// procedures
+#[derive(PartialEq, PartialOrd, Debug)]
+pub struct RecordType {
+    pub name: String,
+    /// Constructor, and the fields it initializes from its arguments
+    pub constructor: (String, Vec<String>),
+    pub predicate: String,
+    pub fields: Vec<RecordField>,
+}
+
+/// Field of a record type, at the offset of its position in the definition
+#[derive(PartialEq, PartialOrd, Debug)]
+pub struct RecordField {
+    pub name: String,
+    pub accessor: String,
+    pub modifier: Option<String>,
+}
@@ -16,22 +16,105 @@ pub enum Expr {
     Let(String, Box<Expr>, Box<Expr>),
     /// Lambda form required variables, optional rest arg and body
     Lambda(Vec<String>, Option<String>, Box<Expr>),
+    /// Lambda forms for each clause of a `case-lambda`, tried in order on
+    /// the number of arguments
+    CaseLambda(Vec<(Vec<String>, Option<String>, Expr)>),
     /// Function application with arguments
     App(Box<Expr>, Vec<Expr>),
+    /// Application of a consumer to the values returned by calling a
+    /// producer without arguments
+    CallWithValues(Box<Expr>, Box<Expr>),
     /// If expression
     If(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
 
     Set(String, Box<Expr>),
+    /// Multi-way branch on an atomic key: the first case with a constant
+    /// equal to the key is taken, otherwise the default. Constants are
+    /// integers and symbols, so equality is `eqv?` and the case of a key
+    /// can be looked up in a table
+    Switch(Box<Expr>, Vec<(Vec<Expr>, Expr)>, Box<Expr>),
 
     Quote(Value),
+
+    /// Promise of the value of an expression, evaluated once when first
+    /// forced
+    Delay(Box<Expr>),
+    /// Promise forced by forcing the promise its expression evaluates to,
+    /// iteratively rather than by nesting
+    DelayForce(Box<Expr>),
+
+    /// Body evaluated with a handler for the exceptions it raises, which is
+    /// evaluated in place of the body with the variable bound to the raised
+    /// object
+    Guard(String, Box<Expr>, Box<Expr>),
+
+    /// New record-type descriptor with a name and fields
+    RecordType(String, Vec<String>),
+    /// Operation on a record, whose first operand is the descriptor of its
+    /// type
+    Record(RecordOp, Vec<Expr>),
+    /// Whether two values are `eqv?`, by a comparison the program can't
+    /// rebind
+    Eqv(Box<Expr>, Box<Expr>),
+    /// Call of a thunk within a before and an after thunk, as by
+    /// `dynamic-wind` but without a variable the program can rebind
+    DynamicWind(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// Operation on a parameter object, its first operand
+    Parameter(ParameterOp, Vec<Expr>),
+    /// Object raised again by the handler of a `guard` whose clauses don't
+    /// apply, continuably, to the handlers outside of the `guard`
+    Raise(Box<Expr>),
+}
+
+/// Primitive operation on records, with fields at fixed offsets
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum RecordOp {
+    /// Make a record from the values of all of its fields
+    Make,
+    /// Test whether the operand is a record of the type
+    Is,
+    /// Value of the field at an offset
+    Ref(usize),
+    /// Replace the value of the field at an offset
+    Set(usize),
+}
+
+/// Primitive operation on a parameter object, by which `parameterize`
+/// swaps its value
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum ParameterOp {
+    /// Converter of the values of the parameter, or the identity
+    Converter,
+    /// Replace the value of the parameter, returning the one before
+    Swap,
+}
+
+impl fmt::Display for ParameterOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParameterOp::Converter => f.write_str("parameter-converter"),
+            ParameterOp::Swap => f.write_str("parameter-swap!"),
+        }
+    }
+}
+
+impl fmt::Display for RecordOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordOp::Make => f.write_str("make-record"),
+            RecordOp::Is => f.write_str("record?"),
+            RecordOp::Ref(idx) => write!(f, "record-ref {}", idx),
+            RecordOp::Set(idx) => write!(f, "record-set! {}", idx),
+        }
+    }
 }
 
 /// Indent-level aware pretty printing
 fn pp(e: &Expr, lvl: u32) -> String {
     let mut indent = (0..lvl * 4).map(|_| ' ').collect::<String>();
     let out = match e {
-            Expr::Val(v) => format!("{}", v),
-            Expr::Var(s) => format!("{}", s),
+            Expr::Val(v) => v.write().to_string(),
+            Expr::Var(s) => s.clone(),
             Expr::Let(var, val, body) => format!(
                 "(let (({} {}))\n{})",
                 var,
@@ -42,6 +125,20 @@ fn pp(e: &Expr, lvl: u32) -> String {
             Expr::Lambda(var, Some(rest), body) => {
                 format!("(λ ({} . {})\n{})", var.join(" "), rest, pp(body, lvl+1))
             }
+            Expr::CaseLambda(clauses) => {
+                let pad = (0..(lvl + 1) * 4).map(|_| ' ').collect::<String>();
+                let mut out = "(case-λ".to_string();
+                for (args, rest, body) in clauses {
+                    let formals = match rest {
+                        Some(rest) if args.is_empty() => rest.clone(),
+                        Some(rest) => format!("({} . {})", args.join(" "), rest),
+                        None => format!("({})", args.join(" ")),
+                    };
+                    out.push_str(&format!("\n{}({}\n{})", pad, formals, pp(body, lvl + 2)));
+                }
+                out.push(')');
+                out
+            }
             Expr::App(rator, rand) => format!(
                 "({} {})",
                 rator,
@@ -50,11 +147,51 @@ fn pp(e: &Expr, lvl: u32) -> String {
                     .collect::<Vec<String>>()
                     .join(" ")
             ),
+            Expr::CallWithValues(producer, consumer) => format!(
+                "(call-with-values\n{}\n{})",
+                pp(producer, lvl + 1),
+                pp(consumer, lvl + 1)
+            ),
             Expr::If(test, csq, None) => format!("(if {}\n{})", test, pp(csq, lvl+1)),
             Expr::If(test, csq, Some(alt)) => format!("(if {}\n{}\n{})", test, pp(csq, lvl + 1), pp(alt, lvl + 1)),
             Expr::Set(var, exp) => format!("(set! {}\n{})", var, pp(exp, lvl+1)),
-            Expr::Quote(Value::Nil) => format!("'()"),
-            Expr::Quote(exp) => format!("'{}", exp),
+            Expr::Switch(key, cases, default) => {
+                let pad = (0..(lvl + 1) * 4).map(|_| ' ').collect::<String>();
+                let mut out = format!("(switch {}", key);
+                for (data, body) in cases {
+                    let data = data.iter().map(|d| d.to_string()).collect::<Vec<String>>();
+                    let body = pp(body, lvl + 2);
+                    out.push_str(&format!("\n{}(({})\n{})", pad, data.join(" "), body));
+                }
+                out.push_str(&format!("\n{}(else\n{}))", pad, pp(default, lvl + 2)));
+                out
+            }
+            Expr::Delay(exp) => format!("(delay\n{})", pp(exp, lvl + 1)),
+            Expr::DelayForce(exp) => format!("(delay-force\n{})", pp(exp, lvl + 1)),
+            Expr::Guard(var, body, handler) => format!(
+                "(guard {}\n{}\n{})",
+                var,
+                pp(body, lvl + 1),
+                pp(handler, lvl + 1)
+            ),
+            Expr::RecordType(name, fields) => format!("(record-type {} ({}))", name, fields.join(" ")),
+            Expr::Record(op, args) => format!(
+                "({} {})",
+                op,
+                args.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(" ")
+            ),
+            Expr::Eqv(a, b) => format!("(eqv {} {})", a, b),
+            Expr::Raise(obj) => format!("(raise-continuable {})", obj),
+            Expr::DynamicWind(before, thunk, after) => {
+                format!("(dynamic-wind {} {} {})", before, thunk, after)
+            }
+            Expr::Parameter(op, args) => format!(
+                "({} {})",
+                op,
+                args.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(" ")
+            ),
+            Expr::Quote(Value::Nil) => "'()".to_string(),
+            Expr::Quote(exp) => format!("'{}", exp.write()),
         };
     indent.push_str(&out);
     indent
@@ -1,23 +1,116 @@
 pub mod hir;
 pub mod mir;
 pub mod bytecode;
+pub mod cps;
+use super::Symbol;
+use crate::sexp::{self, Printer};
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Value {
     Str(String),
+    /// Quoted identifier, distinct from the string of its name
+    Symbol(Symbol),
     Bool(bool),
     Int(i64),
     Nil,
+    /// Pair of a quoted list, shared by every evaluation of the quotation
+    Pair(Rc<(Value, Value)>),
 }
 
+impl Value {
+    pub fn cons(car: Value, cdr: Value) -> Value {
+        Value::Pair(Rc::new((car, cdr)))
+    }
+
+    /// Print in `write` form, the external representation of the value
+    pub fn write(&self) -> Printer<'_, Value> {
+        Printer::write(self)
+    }
+
+    /// Print in `display` form, for humans
+    pub fn display(&self) -> Printer<'_, Value> {
+        Printer::display(self)
+    }
+}
+
+impl fmt::Display for Printer<'_, Value> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inner {
+            Value::Str(s) if self.write => sexp::write_string(f, s),
+            Value::Str(s) => f.write_str(s),
+            Value::Symbol(s) => f.write_str(s.name()),
+            Value::Bool(true) => f.write_str("#t"),
+            Value::Bool(false) => f.write_str("#f"),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Nil => f.write_str("()"),
+            Value::Pair(pair) => {
+                write!(f, "({}", self.with(&pair.0))?;
+                let mut tail = &pair.1;
+                while let Value::Pair(pair) = tail {
+                    write!(f, " {}", self.with(&pair.0))?;
+                    tail = &pair.1;
+                }
+                match tail {
+                    Value::Nil => f.write_str(")"),
+                    tail => write!(f, " . {})", self.with(tail)),
+                }
+            }
+        }
+    }
+}
+
+/// Printed in `write` form, so that strings are told apart from symbols
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Value::Str(s) => write!(f, "{}", s),
-            Value::Bool(s) => write!(f, "{}", s),
-            Value::Int(s) => write!(f, "{}", s),
-            Value::Nil => write!(f, "'()"),
+        write!(f, "{}", self.write())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::parse;
+    use crate::{Keyword, Sexp};
+    use proptest::prelude::*;
+
+    fn value() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            any::<String>().prop_map(Value::Str),
+            "[a-z][a-z0-9!?*<>=/+-]*"
+                .prop_filter("keywords are not symbols", |s| Keyword::from_name(s).is_none())
+                .prop_map(|s| Value::Symbol(Symbol::intern(&s))),
+            any::<bool>().prop_map(Value::Bool),
+            any::<i64>().prop_map(Value::Int),
+            Just(Value::Nil),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn write_round_trips(v in value()) {
+            let expected = match v.clone() {
+                Value::Str(s) => Sexp::Literal(s),
+                Value::Symbol(s) => Sexp::Identifier(s.name().to_string()),
+                Value::Bool(b) => Sexp::Boolean(b),
+                Value::Int(i) => Sexp::Integer(i),
+                Value::Nil => Sexp::List(crate::List::Nil),
+                Value::Pair(_) => unreachable!(),
+            };
+            prop_assert_eq!(parse(v.write().to_string()), Ok(vec![expected]));
         }
     }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::Str("abc".into()).to_string(), "\"abc\"");
+        assert_eq!(Value::Symbol(Symbol::intern("abc")).to_string(), "abc");
+        assert_ne!(Value::Str("abc".into()), Value::Symbol(Symbol::intern("abc")));
+        let list = Value::cons(Value::Int(1), Value::cons(Value::Str("a".into()), Value::Nil));
+        assert_eq!(list.to_string(), "(1 \"a\")");
+        assert_eq!(list.display().to_string(), "(1 a)");
+        let dotted = Value::cons(Value::Int(1), Value::cons(Value::Int(2), Value::Int(3)));
+        assert_eq!(dotted.to_string(), "(1 2 . 3)");
+    }
 }
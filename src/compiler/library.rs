@@ -0,0 +1,135 @@
+//! Names of R7RS libraries and the files that define them
+//!
+//! A library named `(foo bar)` is defined by the file `foo/bar.sld` under one
+//! of the directories of a search path, tried in order. Each part of a name
+//! is a file or directory of its own, so names can't lead out of the search
+//! path. Libraries named
+//! `(scheme ...)` are built in: their bindings are the primitives, which are
+//! in scope everywhere already.
+use super::*;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Name of a library, made of identifiers and exact non-negative integers
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LibraryName(Vec<String>);
+
+impl LibraryName {
+    /// Read the name of a library, or `None` if `sexp` is not one
+    pub fn from_sexp(sexp: &Sexp) -> Option<LibraryName> {
+        let list = match sexp {
+            Sexp::List(list) => list,
+            _ => return None,
+        };
+        let parts = list
+            .iter()
+            .map(|part| match part {
+                Sexp::Identifier(id) if is_component(id) => Some(id.clone()),
+                Sexp::Keyword(kw) if *kw != Keyword::Dot => Some(kw.name().to_string()),
+                Sexp::Integer(i) if *i >= 0 => Some(i.to_string()),
+                _ => None,
+            })
+            .collect::<Option<Vec<String>>>()?;
+        match parts.is_empty() {
+            true => None,
+            false => Some(LibraryName(parts)),
+        }
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        self.0[0] == "scheme"
+    }
+
+    /// Path of the file defining the library, relative to a directory of
+    /// the search path
+    fn path(&self) -> PathBuf {
+        let mut path = self.0.iter().collect::<PathBuf>();
+        // Appended to the last part, whatever dots it has
+        path.set_file_name(format!("{}.sld", self.0[self.0.len() - 1]));
+        path
+    }
+}
+
+/// Whether an identifier of a library name names a file or directory within
+/// the one of the parts before it
+fn is_component(part: &str) -> bool {
+    part != ".." && part != "." && !part.contains(std::path::is_separator)
+}
+
+impl fmt::Display for LibraryName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({})", self.0.join(" "))
+    }
+}
+
+/// Directories searched for the files of libraries
+#[derive(Clone, Debug, Default)]
+pub struct SearchPath {
+    dirs: Vec<PathBuf>,
+}
+
+impl SearchPath {
+    /// Search `dir` after the directories added before it
+    pub fn push<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.dirs.push(dir.into());
+    }
+
+    /// The first file defining the library `name`
+    pub fn find(&self, name: &LibraryName) -> Option<PathBuf> {
+        let path = name.path();
+        self.dirs
+            .iter()
+            .map(|dir| dir.join(&path))
+            .find(|file| file.is_file())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::parse;
+
+    fn name(s: &str) -> Option<LibraryName> {
+        LibraryName::from_sexp(&parse(s).unwrap()[0])
+    }
+
+    #[test]
+    fn library_names() {
+        let srfi = name("(srfi 1)").unwrap();
+        assert_eq!(srfi.to_string(), "(srfi 1)");
+        assert_eq!(srfi.path(), PathBuf::from("srfi").join("1.sld"));
+        assert!(!srfi.is_builtin());
+        assert!(name("(scheme base)").unwrap().is_builtin());
+        assert_eq!(name("(foo define)").unwrap().to_string(), "(foo define)");
+        assert_eq!(
+            name("(foo bar.baz)").unwrap().path(),
+            PathBuf::from("foo").join("bar.baz.sld")
+        );
+
+        assert_eq!(name("()"), None);
+        assert_eq!(name("foo"), None);
+        assert_eq!(name("(foo -1)"), None);
+        assert_eq!(name("(foo \"bar\")"), None);
+        assert_eq!(name("(.. .. x)"), None);
+        assert_eq!(name("(foo |.|)"), None);
+        assert_eq!(name("(|/etc| passwd)"), None);
+        assert_eq!(name("(foo |a/b|)"), None);
+    }
+
+    #[test]
+    fn search_path() {
+        let dir = std::env::temp_dir().join(format!("zymogen-search-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("b").join("foo")).unwrap();
+        std::fs::write(dir.join("b").join("foo").join("bar.sld"), "").unwrap();
+        let mut search = SearchPath::default();
+        search.push(dir.join("a"));
+        search.push(dir.join("b"));
+        let foo_bar = name("(foo bar)").unwrap();
+        assert_eq!(
+            search.find(&foo_bar),
+            Some(dir.join("b").join("foo").join("bar.sld"))
+        );
+        assert_eq!(search.find(&name("(foo baz)").unwrap()), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -5,12 +5,17 @@ use ir::Value;
 
 mod analysis;
 mod desugar;
+mod eval;
+mod expand;
+mod library;
 mod normalize;
 mod symbol;
 mod bytecode;
+pub mod cps;
 
 pub use analysis::analyze;
 pub use desugar::desugar;
+pub use expand::Expander;
 pub use ir::hir;
 pub use ir::mir;
 pub use normalize::{normalize_expr};
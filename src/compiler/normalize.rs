@@ -9,12 +9,7 @@ use super::mir::Expr;
 use super::*;
 
 fn is_atomic(expr: &Expr) -> bool {
-    match expr {
-        Expr::Var(_) => true,
-        Expr::Val(_) => true,
-        Expr::Quote(_) => true,
-        _ => false,
-    }
+    matches!(expr, Expr::Var(_) | Expr::Val(_) | Expr::Quote(_))
 }
 
 fn unbind(mut args: Vec<(String, Expr)>, body: Expr, t: &mut SymbolTable) -> Expr {
@@ -30,6 +25,23 @@ fn unbind(mut args: Vec<(String, Expr)>, body: Expr, t: &mut SymbolTable) -> Exp
     }
 }
 
+/// Bind the operands that are not atomic to fresh variables, returning the
+/// atomic operands and the bindings to make around their use
+fn atomize(rands: Vec<Expr>, table: &mut SymbolTable) -> (Vec<Expr>, Vec<(String, Expr)>) {
+    let mut args = Vec::new();
+    let mut stack = Vec::new();
+    for r in rands {
+        if is_atomic(&r) {
+            args.push(r);
+        } else {
+            let g = table.gensym();
+            args.push(Expr::Var(g.clone()));
+            stack.push((g, r));
+        }
+    }
+    (args, stack)
+}
+
 /// Lift a Let expression in a bind position to a top level
 /// e.g.
 /// ```skip
@@ -61,6 +73,12 @@ pub fn normalize_expr(expr: Expr, table: &mut SymbolTable) -> Expr {
         Expr::Lambda(args, rest, body) => {
             Expr::Lambda(args, rest, Box::new(normalize_expr(*body, table)))
         }
+        Expr::CaseLambda(clauses) => Expr::CaseLambda(
+            clauses
+                .into_iter()
+                .map(|(args, rest, body)| (args, rest, normalize_expr(body, table)))
+                .collect(),
+        ),
         Expr::Let(var, val, body) => Expr::Let(
             var,
             Box::new(normalize_expr(*val, table)),
@@ -75,10 +93,10 @@ pub fn normalize_expr(expr: Expr, table: &mut SymbolTable) -> Expr {
                 )
             } else {
                 let g = table.gensym();
-                let n = Expr::Var(table.own(g));
+                let n = Expr::Var(g.clone());
                 println!("normalized if: {}", g);
                 Expr::Let(
-                    table.own(g),
+                    g,
                     Box::new(normalize_expr(*test, table)),
                     Box::new(Expr::If(
                         Box::new(n),
@@ -89,18 +107,67 @@ pub fn normalize_expr(expr: Expr, table: &mut SymbolTable) -> Expr {
             }
         }
         Expr::Set(var, val) => Expr::Set(var, Box::new(normalize_expr(*val, table))),
-        Expr::App(rator, rands) => {
-            let mut args = Vec::new();
-            let mut stack = Vec::new();
-            for r in rands {
-                if is_atomic(&r) {
-                    args.push(r);
-                } else {
-                    let g = table.gensym();
-                    args.push(Expr::Var(table.own(g)));
-                    stack.push((table.own(g), r));
-                }
+        Expr::Delay(exp) => Expr::Delay(Box::new(normalize_expr(*exp, table))),
+        Expr::DelayForce(exp) => Expr::DelayForce(Box::new(normalize_expr(*exp, table))),
+        Expr::Switch(key, cases, default) => {
+            let cases = cases
+                .into_iter()
+                .map(|(data, body)| (data, normalize_expr(body, table)))
+                .collect();
+            let default = Box::new(normalize_expr(*default, table));
+            if is_atomic(&key) {
+                Expr::Switch(key, cases, default)
+            } else {
+                let g = table.gensym();
+                Expr::Let(
+                    g.clone(),
+                    Box::new(normalize_expr(*key, table)),
+                    Box::new(Expr::Switch(
+                        Box::new(Expr::Var(g)),
+                        cases,
+                        default,
+                    )),
+                )
             }
+        }
+        Expr::Guard(var, body, handler) => Expr::Guard(
+            var,
+            Box::new(normalize_expr(*body, table)),
+            Box::new(normalize_expr(*handler, table)),
+        ),
+        Expr::RecordType(..) => expr,
+        Expr::Record(op, rands) => {
+            let (args, stack) = atomize(rands, table);
+            unbind(stack, Expr::Record(op, args), table)
+        }
+        Expr::Raise(obj) => {
+            let (mut args, stack) = atomize(vec![*obj], table);
+            unbind(stack, Expr::Raise(Box::new(args.remove(0))), table)
+        }
+        Expr::Parameter(op, rands) => {
+            let (args, stack) = atomize(rands, table);
+            unbind(stack, Expr::Parameter(op, args), table)
+        }
+        Expr::DynamicWind(before, thunk, after) => {
+            let (args, stack) = atomize(vec![*before, *thunk, *after], table);
+            let mut args = args.into_iter().map(Box::new);
+            let (before, thunk) = (args.next().unwrap(), args.next().unwrap());
+            unbind(stack, Expr::DynamicWind(before, thunk, args.next().unwrap()), table)
+        }
+        Expr::Eqv(a, b) => {
+            let (args, stack) = atomize(vec![*a, *b], table);
+            let mut args = args.into_iter().map(Box::new);
+            let (a, b) = (args.next().unwrap(), args.next().unwrap());
+            unbind(stack, Expr::Eqv(a, b), table)
+        }
+        Expr::CallWithValues(producer, consumer) => {
+            let (args, stack) = atomize(vec![*producer, *consumer], table);
+            let mut args = args.into_iter().map(Box::new);
+            let (producer, consumer) = (args.next().unwrap(), args.next().unwrap());
+            unbind(stack, Expr::CallWithValues(producer, consumer), table)
+        }
+        Expr::App(rator, rands) => {
+            let (args, stack) = atomize(rands, table);
 
             if is_atomic(&rator) {
                 unbind(
@@ -110,9 +177,9 @@ pub fn normalize_expr(expr: Expr, table: &mut SymbolTable) -> Expr {
                 )
             } else {
                 let g = table.gensym();
-                let n = Expr::Var(table.own(g));
+                let n = Expr::Var(g.clone());
                 Expr::Let(
-                    table.own(g),
+                    g,
                     Box::new(normalize_expr(*rator, table)),
                     Box::new(lift_let(unbind(stack, Expr::App(Box::new(n), args), table))),
                 )
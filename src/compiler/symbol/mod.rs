@@ -1,12 +1,53 @@
-use std::collections::HashMap;
+//! Interned symbols
+//!
+//! Every symbol of a program is interned once for the whole process, so that
+//! symbols compare in constant time and print their names without a table.
+//! A [`SymbolTable`] is the set of names of one compilation, from which it
+//! derives fresh names for variables. Those are never interned, so they
+//! don't outlive the table.
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::sync::Mutex;
 
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+/// Interned name
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd)]
 pub struct Symbol(usize);
 
+/// Names of the symbols, by id. Names live as long as the process
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, usize>,
+    names: Vec<&'static str>,
+}
+
+static INTERNER: Mutex<Option<Interner>> = Mutex::new(None);
+
+impl Symbol {
+    /// The symbol named `name`
+    pub fn intern(name: &str) -> Symbol {
+        let mut interner = INTERNER.lock().unwrap_or_else(|e| e.into_inner());
+        let interner = interner.get_or_insert_with(Interner::default);
+        match interner.ids.get(name) {
+            Some(id) => Symbol(*id),
+            None => {
+                let id = interner.names.len();
+                let name: &'static str = Box::leak(name.into());
+                interner.ids.insert(name, id);
+                interner.names.push(name);
+                Symbol(id)
+            }
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        let interner = INTERNER.lock().unwrap_or_else(|e| e.into_inner());
+        interner.as_ref().map_or("", |i| i.names[self.0])
+    }
+}
+
 impl fmt::Display for Symbol {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "$symbol-{}", self.0)
+        f.write_str(self.name())
     }
 }
 
@@ -14,62 +55,71 @@ impl fmt::Display for Symbol {
 /// 
 #[derive(Debug)]
 pub struct SymbolTable {
-    inner: HashMap<String, usize>,
-    v: Vec<String>,
+    symbols: HashSet<Symbol>,
+    names: HashSet<String>,
+    /// Number of the next fresh name
+    next: usize,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        SymbolTable::new()
+    }
 }
 
 impl SymbolTable {
     pub fn new() -> Self {
         SymbolTable {
-            inner: HashMap::new(),
-            v: Vec::new(),
+            symbols: HashSet::new(),
+            names: HashSet::new(),
+            next: 0,
         }
     }
 
     pub fn insert(&mut self, sym: String) -> Symbol {
-        match self.inner.get(&sym) {
-            Some(id) => Symbol(*id),
-            None => {
-                let id = self.v.len();
-                self.inner.insert(sym.clone(), id);
-                self.v.push(sym);
-                Symbol(id)
-            }
-        }
+        self.insert_borrowed(&sym)
     }
 
     pub fn insert_borrowed(&mut self, s: &str) -> Symbol {
-        match self.inner.get(s) {
-            Some(id) => Symbol(*id),
-            None => {
-                let id = self.v.len();
-                self.inner.insert(s.to_string(), id);
-                self.v.push(s.to_string());
-                Symbol(id)
-            }
+        let sym = Symbol::intern(s);
+        if self.symbols.insert(sym) {
+            self.names.insert(s.to_string());
         }
+        sym
     }
 
     pub fn get(&self, sym: Symbol) -> Option<&str> {
-        self.v.get(sym.0).map(|s| s.as_ref())
+        match self.symbols.contains(&sym) {
+            true => Some(sym.name()),
+            false => None,
+        }
     }
 
     pub fn own(&self, sym: Symbol) -> String {
-        self.v[sym.0].clone()
+        sym.name().to_string()
     }
 
-    pub fn gensym(&mut self) -> Symbol {
-        let id = self.v.len();
-        let mut s = format!("$g{}", id);
-
-        let mut i: usize = 0;
-        while self.inner.contains_key(&s) {
-            s = format!("$g{}~{}", id, i);
-            i += 1;
+    /// A new name in the table starting with `prefix`, distinct from every
+    /// name already in it
+    fn unique(&mut self, prefix: &str) -> String {
+        let mut s = format!("{}{}", prefix, self.next);
+        while self.names.contains(&s) {
+            self.next += 1;
+            s = format!("{}{}", prefix, self.next);
         }
-        self.inner.insert(s.clone(), id);
-        self.v.push(s);
-        Symbol(id)
+        self.next += 1;
+        self.names.insert(s.clone());
+        s
+    }
+
+    /// A new name for a variable named after `base`
+    pub fn fresh(&mut self, base: &str) -> String {
+        self.unique(&format!("{}~", base))
+    }
+
+    /// A new name for a variable of a temporary value
+    pub fn gensym(&mut self) -> String {
+        self.unique("$g")
     }
 }
 
@@ -84,7 +134,31 @@ mod test {
         let gs1 = table.gensym();
         let gs2 = table.gensym();
         assert!(gs1 != gs2);
-        assert_eq!(table.get(gs2), Some("$g1"));
+        assert_eq!(gs2, "$g1");
+    }
+
+    #[test]
+    fn test_fresh() {
+        let mut table = SymbolTable::new();
+        table.insert("x~0".to_string());
+
+        let x = table.fresh("x");
+        assert_eq!(x, "x~1");
+        let y = table.fresh("x~1");
+        assert_eq!(y, "x~1~2");
+    }
+
+    #[test]
+    fn interning() {
+        let a = Symbol::intern("interned");
+        assert_eq!(a, Symbol::intern(&String::from("interned")));
+        assert_ne!(a, Symbol::intern("other"));
+        assert_eq!(a.to_string(), "interned");
+        // Symbols are shared between tables
+        let mut table = SymbolTable::new();
+        assert_eq!(table.get(a), None);
+        assert_eq!(table.insert_borrowed("interned"), a);
+        assert_eq!(table.get(a), Some("interned"));
     }
 
 }
@@ -0,0 +1,145 @@
+//! Diagnostics reported by every phase of the compiler
+//!
+//! Lexing, parsing, analysis and bytecode compilation each have their own
+//! error type describing what went wrong, and each converts into a
+//! [`Diagnostic`], which knows where it went wrong and how to present it.
+//! A [`Renderer`] turns diagnostics into annotated source snippets, plain or
+//! colored, or into JSON for tools.
+mod render;
+
+pub use render::{Format, Renderer, Source};
+
+use crate::syntax::{Extent, Span};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        })
+    }
+}
+
+/// A location in the source, annotated with a message
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    /// Position just past the labeled text. When it is not after `span`,
+    /// only the character at `span` is labeled
+    pub end: Span,
+    pub message: String,
+    /// Whether this is where the problem is, rather than related context
+    pub primary: bool,
+}
+
+/// A message about the input, with the source locations it concerns
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable identifier for the kind of problem, such as `E0101`
+    pub code: &'static str,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(severity: Severity, code: &'static str, message: S) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error<S: Into<String>>(code: &'static str, message: S) -> Diagnostic {
+        Diagnostic::new(Severity::Error, code, message)
+    }
+
+    pub fn warning<S: Into<String>>(code: &'static str, message: S) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, code, message)
+    }
+
+    /// Point at where the problem is
+    pub fn with_primary<E: Into<Extent>, S: Into<String>>(
+        mut self,
+        at: E,
+        message: S,
+    ) -> Diagnostic {
+        let at = at.into();
+        self.labels.push(Label {
+            span: at.start,
+            end: at.end,
+            message: message.into(),
+            primary: true,
+        });
+        self
+    }
+
+    /// Point at related context
+    pub fn with_secondary<E: Into<Extent>, S: Into<String>>(
+        mut self,
+        at: E,
+        message: S,
+    ) -> Diagnostic {
+        let at = at.into();
+        self.labels.push(Label {
+            span: at.start,
+            end: at.end,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn with_note<S: Into<String>>(mut self, note: S) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Location of the first primary label
+    pub fn span(&self) -> Option<Span> {
+        self.labels.iter().find(|l| l.primary).map(|l| l.span)
+    }
+}
+
+/// Short form, `error[E0101]: message`, without source context
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.code, self.message)?;
+        if let Some(span) = self.span() {
+            write!(f, " at {}:{}", span.line + 1, span.pos + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let diag = Diagnostic::error("E0001", "unexpected end of input")
+            .with_secondary(Span { line: 0, pos: 0 }, "opened here")
+            .with_primary(Span { line: 2, pos: 4 }, "");
+        assert_eq!(diag.span(), Some(Span { line: 2, pos: 4 }));
+        assert_eq!(
+            diag.to_string(),
+            "error[E0001]: unexpected end of input at 3:5"
+        );
+    }
+}
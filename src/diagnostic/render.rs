@@ -0,0 +1,324 @@
+//! Presentation of diagnostics for humans and for tools
+use super::{Diagnostic, Label, Severity};
+use colored::{ColoredString, Colorize};
+use std::fmt::Write;
+use std::io::IsTerminal;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// Annotated source snippets
+    Human,
+    /// One JSON object per diagnostic, on a single line
+    Json,
+}
+
+/// Named source text that diagnostics point into
+#[derive(Copy, Clone, Debug)]
+pub struct Source<'a> {
+    pub name: &'a str,
+    pub text: &'a str,
+}
+
+impl<'a> Source<'a> {
+    pub fn new(name: &'a str, text: &'a str) -> Source<'a> {
+        Source { name, text }
+    }
+}
+
+/// Turns [`Diagnostic`]s into text. Lines and columns are printed 1-based
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Renderer {
+    pub format: Format,
+    pub color: bool,
+}
+
+impl Renderer {
+    pub fn new(format: Format, color: bool) -> Renderer {
+        Renderer { format, color }
+    }
+
+    /// Human readable output, colored only when standard error is a
+    /// terminal and `NO_COLOR` is not set
+    pub fn from_env() -> Renderer {
+        let no_color = std::env::var_os("NO_COLOR")
+            .map(|v| !v.is_empty())
+            .unwrap_or(false);
+        Renderer::new(Format::Human, !no_color && std::io::stderr().is_terminal())
+    }
+
+    pub fn render(&self, diag: &Diagnostic, source: Option<Source>) -> String {
+        match self.format {
+            Format::Human => self.human(diag, source),
+            Format::Json => json(diag, source),
+        }
+    }
+
+    fn paint(&self, text: &str, f: fn(ColoredString) -> ColoredString) -> String {
+        if self.color {
+            f(text.normal()).to_string()
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn severity_color(severity: Severity) -> fn(ColoredString) -> ColoredString {
+        match severity {
+            Severity::Error => |s| s.red().bold(),
+            Severity::Warning => |s| s.yellow().bold(),
+            Severity::Note => |s| s.cyan().bold(),
+        }
+    }
+
+    fn human(&self, diag: &Diagnostic, source: Option<Source>) -> String {
+        let accent = Renderer::severity_color(diag.severity);
+        let gutter: fn(ColoredString) -> ColoredString = |s| s.blue().bold();
+        let mut out = format!(
+            "{}{}\n",
+            self.paint(&format!("{}[{}]", diag.severity, diag.code), accent),
+            self.paint(&format!(": {}", diag.message), |s| s.bold()),
+        );
+
+        let mut labels = diag.labels.iter().collect::<Vec<&Label>>();
+        labels.sort_by_key(|l| (l.span.line, !l.primary, l.span.pos));
+        let lines = source
+            .map(|s| s.text.lines().collect::<Vec<&str>>())
+            .unwrap_or_default();
+        let width = labels
+            .iter()
+            .map(|l| (l.span.line + 1).to_string().len())
+            .max()
+            .unwrap_or(1);
+        let pad = " ".repeat(width);
+
+        if let Some(span) = diag.span().or_else(|| labels.first().map(|l| l.span)) {
+            let name = source.map(|s| format!("{}:", s.name)).unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "{}{} {}{}:{}",
+                pad,
+                self.paint("-->", gutter),
+                name,
+                span.line + 1,
+                span.pos + 1
+            );
+        }
+
+        let bar = self.paint("|", gutter);
+        let mut shown = None;
+        for label in &labels {
+            let text = match lines.get(label.span.line as usize) {
+                Some(text) => text,
+                None => {
+                    // No source to point into
+                    if !label.message.is_empty() {
+                        let _ =
+                            writeln!(out, "{} {} {}", pad, self.paint("=", gutter), label.message);
+                    }
+                    continue;
+                }
+            };
+            if shown != Some(label.span.line) {
+                if shown.is_none() {
+                    let _ = writeln!(out, "{} {}", pad, bar);
+                }
+                let number = format!("{:>w$}", label.span.line + 1, w = width);
+                let _ = writeln!(out, "{} {} {}", self.paint(&number, gutter), bar, text);
+                shown = Some(label.span.line);
+            }
+            let col = label.span.pos as usize;
+            let (mark, color) = match label.primary {
+                true => ("^", accent),
+                false => ("-", gutter),
+            };
+            let underline = mark.repeat(underline_width(label, text));
+            let mut annotation = format!("{}{}", " ".repeat(col), underline);
+            if !label.message.is_empty() {
+                annotation.push(' ');
+                annotation.push_str(&label.message);
+            }
+            let _ = writeln!(out, "{} {} {}", pad, bar, self.paint(&annotation, color));
+        }
+
+        for note in &diag.notes {
+            let _ = writeln!(
+                out,
+                "{} {} {}: {}",
+                pad,
+                self.paint("=", gutter),
+                self.paint("note", |s| s.bold()),
+                note
+            );
+        }
+        out
+    }
+}
+
+/// Number of characters of `line` to underline for `label`: up to its end
+/// if that is on the same line, otherwise to the end of the line
+fn underline_width(label: &Label, line: &str) -> usize {
+    let col = label.span.pos as usize;
+    let len = if label.end.line > label.span.line {
+        line.chars().count().saturating_sub(col)
+    } else if label.end.line == label.span.line {
+        label.end.pos.saturating_sub(label.span.pos) as usize
+    } else {
+        0
+    };
+    len.max(1)
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", ch as u32);
+            }
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+fn json(diag: &Diagnostic, source: Option<Source>) -> String {
+    let mut out = String::from("{\"severity\":");
+    json_string(&mut out, &diag.severity.to_string());
+    out.push_str(",\"code\":");
+    json_string(&mut out, diag.code);
+    out.push_str(",\"message\":");
+    json_string(&mut out, &diag.message);
+    out.push_str(",\"file\":");
+    match source {
+        Some(source) => json_string(&mut out, source.name),
+        None => out.push_str("null"),
+    }
+    out.push_str(",\"labels\":[");
+    for (idx, label) in diag.labels.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{},\"primary\":{},\"message\":",
+            label.span.line + 1,
+            label.span.pos + 1,
+            label.end.line + 1,
+            label.end.pos + 1,
+            label.primary
+        );
+        json_string(&mut out, &label.message);
+        out.push('}');
+    }
+    out.push_str("],\"notes\":[");
+    for (idx, note) in diag.notes.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+        json_string(&mut out, note);
+    }
+    out.push_str("]}\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::{Extent, Span};
+
+    fn diagnostic() -> Diagnostic {
+        Diagnostic::error("E0101", "malformed `let` expression")
+            .with_primary(
+                Span { line: 1, pos: 7 },
+                "expected an identifier, found `2`",
+            )
+            .with_secondary(Span { line: 0, pos: 0 }, "in this `let` expression")
+            .with_note("`let` has the form `(let ((id expr) ...) body ...+)`")
+    }
+
+    const SOURCE: &str = "(let ((x 1)\n      (2 \"y\"))\n  x)";
+
+    #[test]
+    fn plain() {
+        let out = Renderer::new(Format::Human, false)
+            .render(&diagnostic(), Some(Source::new("a.scm", SOURCE)));
+        let expected = "\
+error[E0101]: malformed `let` expression
+ --> a.scm:2:8
+  |
+1 | (let ((x 1)
+  | - in this `let` expression
+2 |       (2 \"y\"))
+  |        ^ expected an identifier, found `2`
+  = note: `let` has the form `(let ((id expr) ...) body ...+)`
+";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn colored() {
+        let diag = diagnostic();
+        let source = Some(Source::new("a.scm", SOURCE));
+        let plain = Renderer::new(Format::Human, false).render(&diag, source);
+        let colored = Renderer::new(Format::Human, true).render(&diag, source);
+        // Whether escapes are emitted is up to `colored`, which also honors
+        // `NO_COLOR`, but the text must be the same
+        let mut stripped = String::new();
+        let mut escape = false;
+        for ch in colored.chars() {
+            match ch {
+                '\x1b' => escape = true,
+                'm' if escape => escape = false,
+                _ if escape => {}
+                ch => stripped.push(ch),
+            }
+        }
+        assert_eq!(stripped, plain);
+    }
+
+    #[test]
+    fn json_lines() {
+        let out = Renderer::new(Format::Json, false).render(&diagnostic(), None);
+        assert_eq!(
+            out,
+            r#"{"severity":"error","code":"E0101","message":"malformed `let` expression","file":null,"labels":[{"line":2,"column":8,"end_line":2,"end_column":8,"primary":true,"message":"expected an identifier, found `2`"},{"line":1,"column":1,"end_line":1,"end_column":1,"primary":false,"message":"in this `let` expression"}],"notes":["`let` has the form `(let ((id expr) ...) body ...+)`"]}
+"#
+        );
+    }
+
+    #[test]
+    fn underline_extents() {
+        let at = |line, pos| Span { line, pos };
+        let diag = Diagnostic::error("E0101", "malformed `let` expression")
+            .with_primary(
+                Extent {
+                    start: at(1, 9),
+                    end: at(1, 12),
+                },
+                "expected an identifier",
+            )
+            .with_secondary(
+                Extent {
+                    start: at(0, 0),
+                    end: at(2, 4),
+                },
+                "",
+            );
+        let out =
+            Renderer::new(Format::Human, false).render(&diag, Some(Source::new("a.scm", SOURCE)));
+        let expected = "\
+error[E0101]: malformed `let` expression
+ --> a.scm:2:10
+  |
+1 | (let ((x 1)
+  | -----------
+2 |       (2 \"y\"))
+  |          ^^^ expected an identifier
+";
+        assert_eq!(out, expected);
+    }
+}
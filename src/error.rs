@@ -1,4 +1,8 @@
-use super::sexp::Ty;
+use super::diagnostic::Diagnostic;
+use super::sexp::pattern::Mismatch;
+use super::sexp::{Keyword, Ty};
+use super::syntax::SpanTree;
+use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Error {
@@ -6,4 +10,132 @@ pub enum Error {
     Arity,
     EmptyList,
     Message(String),
+    /// Special form that doesn't have the expected shape
+    Syntax(Keyword, Mismatch),
+    /// Error in the sub-expression found by following `path` into the
+    /// analyzed expression
+    Within(Vec<usize>, Box<Error>),
+    /// Error raised by evaluation and not handled, with the names of the
+    /// procedures that were being called, innermost first
+    Backtrace(Vec<String>, Box<Error>),
+    /// Error in a form read from another file by `include` or `load`, with
+    /// the name of the file and the locations of the form in it
+    File(String, SpanTree, Box<Error>),
+}
+
+/// Number of calls of a backtrace that are shown
+const BACKTRACE_LIMIT: usize = 10;
+
+impl Error {
+    /// Locate an error in a sub-expression at `path` of its parent
+    pub fn within(self, mut path: Vec<usize>) -> Error {
+        match self {
+            Error::Within(inner, e) => {
+                path.extend(inner);
+                Error::Within(path, e)
+            }
+            Error::Backtrace(calls, e) => Error::Backtrace(calls, Box::new(e.within(path))),
+            e => Error::Within(path, Box::new(e)),
+        }
+    }
+
+    /// Stable diagnostic code for the kind of error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Message(_) => "E0100",
+            Error::Syntax(_, _) => "E0101",
+            Error::WrongType(_, _) => "E0102",
+            Error::Arity => "E0103",
+            Error::EmptyList => "E0104",
+            Error::Within(_, e) | Error::Backtrace(_, e) | Error::File(_, _, e) => e.code(),
+        }
+    }
+
+    /// Name of the file an error is located in, when that is not the file
+    /// of the form being compiled
+    pub fn file(&self) -> Option<&str> {
+        match self {
+            Error::Within(_, e) | Error::Backtrace(_, e) => e.file(),
+            Error::File(name, _, e) => e.file().or(Some(name)),
+            _ => None,
+        }
+    }
+
+    /// Convert into a [`Diagnostic`], pointing into the source with the
+    /// locations of the analyzed expression, if known
+    pub fn diagnostic(&self, spans: Option<&SpanTree>) -> Diagnostic {
+        if let Error::Backtrace(calls, e) = self {
+            let mut shown = calls
+                .iter()
+                .take(BACKTRACE_LIMIT)
+                .map(|call| format!("`{}`", call))
+                .collect::<Vec<_>>();
+            if calls.len() > BACKTRACE_LIMIT {
+                shown.push(format!("and {} more", calls.len() - BACKTRACE_LIMIT));
+            }
+            let note = format!("backtrace, most recent call first: {}", shown.join(", "));
+            return e.diagnostic(spans).with_note(note);
+        }
+        let (path, error) = match self {
+            Error::Within(path, e) => (path.as_slice(), &**e),
+            e => (&[][..], e),
+        };
+        if let Error::File(name, tree, e) = error {
+            // Located in the other file, and noted where it was included
+            let diag = e.diagnostic(Some(tree));
+            return match spans {
+                Some(spans) => {
+                    let at = spans.locate(path);
+                    let note = format!("in `{}`, included at {}:{}", name, at.line + 1, at.pos + 1);
+                    diag.with_note(note)
+                }
+                None => diag.with_note(format!("in `{}`", name)),
+            };
+        }
+        let spans = match spans {
+            Some(spans) => spans,
+            None => return Diagnostic::error(error.code(), error.to_string()),
+        };
+        match error {
+            Error::Syntax(kw, m) => {
+                let message = format!("malformed `{}` expression", kw.name());
+                let diag = Diagnostic::error(error.code(), message);
+                let form = spans.find(path).extent();
+                let at = spans.find(&[path, &m.path[..]].concat()).extent();
+                let diag = diag.with_primary(at, m.to_string());
+                match at == form {
+                    true => diag,
+                    false => {
+                        diag.with_secondary(form, format!("in this `{}` expression", kw.name()))
+                    }
+                }
+            }
+            _ => Diagnostic::error(error.code(), error.to_string())
+                .with_primary(spans.find(path).extent(), ""),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::WrongType(expected, found) => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            Error::Arity => f.write_str("wrong number of elements"),
+            Error::EmptyList => f.write_str("unexpected empty list"),
+            Error::Message(msg) => f.write_str(msg),
+            Error::Syntax(kw, m) => write!(f, "malformed `{}` expression: {}", kw.name(), m),
+            Error::Within(_, e) | Error::Backtrace(_, e) => e.fmt(f),
+            Error::File(name, _, e) => write!(f, "in `{}`: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for Diagnostic {
+    fn from(e: Error) -> Diagnostic {
+        e.diagnostic(None)
+    }
 }
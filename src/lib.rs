@@ -1,9 +1,11 @@
 pub mod compiler;
+pub mod diagnostic;
 pub mod syntax;
 
 mod error;
 pub mod sexp;
 
+pub use diagnostic::Diagnostic;
 pub use error::Error;
 /// Top level exports
 pub use sexp::{Keyword, List, Sexp};
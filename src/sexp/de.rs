@@ -0,0 +1,579 @@
+//! Deserialize Rust data structures from S-expressions
+//!
+//! Enabled with the `serde` feature. The data model is the one produced by
+//! [`ser`](super::ser):
+//!
+//! - structs and maps are alists of `(key value)` entries; `(key . value)`
+//!   entries are accepted as well
+//! - enum variants are tagged lists `(Variant args...)`, and unit variants
+//!   are bare identifiers
+//! - sequences and tuples are lists, `None` and `()` are the empty list
+//! - `Some(x)` is the one-element list `(x)`, so that `Some(())` and
+//!   `Some(vec![])` are distinct from `None`
+//! - a struct field of `Some(x)` is the entry `(key x)`, and one of `None`
+//!   has no entry. An option within the field is a list as above
+//!
+//! Errors carry the source location of the offending expression when the
+//! input was parsed by [`from_str`].
+use super::{Keyword, List, Sexp};
+use crate::syntax::{Parser, Span, SpanTree};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use std::fmt;
+
+/// Error raised while converting between Rust values and [`Sexp`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    message: String,
+    span: Option<Span>,
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(message: S) -> Error {
+        Error {
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    /// Location of the expression that caused the error, if known
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Attach a location, unless a more precise one is already known
+    fn at(mut self, span: Option<Span>) -> Error {
+        self.span = self.span.or(span);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(
+                f,
+                "{} at line {} char {}",
+                self.message, span.line, span.pos
+            ),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::new(msg.to_string())
+    }
+}
+
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::new(msg.to_string())
+    }
+}
+
+/// Deserialize an instance of `T` from an S-expression
+pub fn from_sexp<'de, T: de::Deserialize<'de>>(sexp: &'de Sexp) -> Result<T, Error> {
+    T::deserialize(Deserializer::new(sexp))
+}
+
+/// Deserialize an instance of `T` from source code containing a single
+/// S-expression. Errors point at the offending expression
+pub fn from_str<T: DeserializeOwned>(s: &str) -> Result<T, Error> {
+    let mut exprs = Parser::new(s).parse_spanned().map_err(|e| Error {
+        message: e.description(),
+        span: Some(Span {
+            line: e.line,
+            pos: e.pos,
+        }),
+    })?;
+    match exprs.len() {
+        0 => Err(Error::new("expected an expression, found end-of-file")),
+        1 => {
+            let (sexp, spans) = exprs.remove(0);
+            T::deserialize(Deserializer::with_spans(&sexp, &spans))
+        }
+        _ => Err(Error::new("trailing expression after value").at(Some(exprs[1].1.span))),
+    }
+}
+
+/// A [`serde::Deserializer`] reading from a borrowed [`Sexp`]
+#[derive(Copy, Clone)]
+pub struct Deserializer<'de> {
+    sexp: &'de Sexp,
+    spans: Option<&'de SpanTree>,
+    /// Whether this is the value of a struct field, where an option is
+    /// present and not wrapped in a list
+    field: bool,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn new(sexp: &'de Sexp) -> Deserializer<'de> {
+        Deserializer {
+            sexp,
+            spans: None,
+            field: false,
+        }
+    }
+
+    /// Construct a deserializer that reports errors at the locations in
+    /// `spans`, as returned by [`Parser::parse_spanned`]
+    pub fn with_spans(sexp: &'de Sexp, spans: &'de SpanTree) -> Deserializer<'de> {
+        Deserializer {
+            sexp,
+            spans: Some(spans),
+            field: false,
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        self.spans.map(|tree| tree.span)
+    }
+
+    fn error<S: Into<String>>(&self, message: S) -> Error {
+        Error::new(message).at(self.span())
+    }
+
+    fn unexpected(&self) -> de::Unexpected<'de> {
+        match self.sexp {
+            Sexp::Boolean(b) => de::Unexpected::Bool(*b),
+            Sexp::Integer(i) => de::Unexpected::Signed(*i),
+            Sexp::Literal(s) => de::Unexpected::Str(s),
+            Sexp::Identifier(_) | Sexp::Keyword(_) => de::Unexpected::Other("identifier"),
+            Sexp::List(List::Nil) => de::Unexpected::Unit,
+            Sexp::List(_) => de::Unexpected::Seq,
+        }
+    }
+
+    fn invalid_type(&self, exp: &dyn de::Expected) -> Error {
+        <Error as de::Error>::invalid_type(self.unexpected(), exp).at(self.span())
+    }
+
+    /// Deserializers for the elements of a list
+    fn elements(&self) -> Option<Vec<Deserializer<'de>>> {
+        match self.sexp {
+            Sexp::List(list) => Some(
+                list.iter()
+                    .enumerate()
+                    .map(|(idx, sexp)| Deserializer {
+                        sexp,
+                        spans: self.spans.and_then(|tree| tree.element(idx)),
+                        field: false,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> Option<&'de str> {
+        match self.sexp {
+            Sexp::Identifier(s) | Sexp::Literal(s) => Some(s),
+            Sexp::Keyword(kw) => Some(kw.name()),
+            _ => None,
+        }
+    }
+
+    fn seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.elements() {
+            Some(elements) => {
+                let mut access = SeqAccess {
+                    elements: elements.into_iter(),
+                };
+                let value = visitor
+                    .visit_seq(&mut access)
+                    .map_err(|e| e.at(self.span()))?;
+                match access.elements.next() {
+                    Some(extra) => Err(extra.error("trailing element in list")),
+                    None => Ok(value),
+                }
+            }
+            None => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    /// Deserialize the entries of an alist, which are struct fields if
+    /// `fields` is set
+    fn alist<V: Visitor<'de>>(self, fields: bool, visitor: V) -> Result<V::Value, Error> {
+        match self.elements() {
+            Some(entries) => visitor
+                .visit_map(MapAccess {
+                    entries: entries.into_iter(),
+                    value: None,
+                    fields,
+                })
+                .map_err(|e| e.at(self.span())),
+            None => Err(self.invalid_type(&visitor)),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let value: Result<V::Value, Error> = match self.sexp {
+            Sexp::Boolean(b) => visitor.visit_bool(*b),
+            Sexp::Integer(i) => visitor.visit_i64(*i),
+            Sexp::Literal(s) | Sexp::Identifier(s) => visitor.visit_borrowed_str(s),
+            Sexp::Keyword(kw) => visitor.visit_borrowed_str(kw.name()),
+            Sexp::List(List::Nil) => visitor.visit_unit(),
+            Sexp::List(_) => return self.seq(visitor),
+        };
+        value.map_err(|e| e.at(self.span()))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.field {
+            let field = false;
+            return visitor.visit_some(Deserializer { field, ..self });
+        }
+        match self.elements() {
+            Some(elements) if elements.is_empty() => visitor.visit_none(),
+            Some(mut elements) if elements.len() == 1 => visitor
+                .visit_some(elements.remove(0))
+                .map_err(|e| e.at(self.span())),
+            _ => Err(self.error(format!(
+                "expected () or a list of one value, found {}",
+                self.sexp.write()
+            ))),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.sexp {
+            Sexp::List(List::Nil) => visitor.visit_unit(),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.seq(visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.alist(false, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.alist(true, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let (tag, args) = match self.elements() {
+            Some(mut elements) if !elements.is_empty() => {
+                let tag = elements.remove(0);
+                (tag, Some(elements))
+            }
+            Some(_) => return Err(self.error("expected a tagged list, found ()")),
+            None => (self, None),
+        };
+        visitor
+            .visit_enum(EnumAccess {
+                list: self,
+                tag,
+                args,
+            })
+            .map_err(|e| e.at(self.span()))
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.name() {
+            Some(name) => visitor
+                .visit_borrowed_str(name)
+                .map_err(|e: Error| e.at(self.span())),
+            None => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf
+    }
+}
+
+struct SeqAccess<'de> {
+    elements: std::vec::IntoIter<Deserializer<'de>>,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.elements.next() {
+            Some(de) => seed.deserialize(de).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.elements.len())
+    }
+}
+
+struct MapAccess<'de> {
+    entries: std::vec::IntoIter<Deserializer<'de>>,
+    value: Option<Deserializer<'de>>,
+    /// Whether the entries are struct fields
+    fields: bool,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let entry = match self.entries.next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let mut elements = entry.elements().unwrap_or_default();
+        if elements.len() == 3 && *elements[1].sexp == Sexp::Keyword(Keyword::Dot) {
+            elements.remove(1);
+        }
+        if elements.len() != 2 {
+            return Err(entry.error(format!(
+                "expected a `(key value)` entry, found `{}`",
+                entry.sexp
+            )));
+        }
+        self.value = elements.pop().map(|value| Deserializer {
+            field: self.fields,
+            ..value
+        });
+        seed.deserialize(elements.remove(0)).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        match self.value.take() {
+            Some(de) => seed.deserialize(de),
+            None => Err(Error::new("value requested before key")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumAccess<'de> {
+    /// The whole tagged list, or the bare tag of a unit variant
+    list: Deserializer<'de>,
+    tag: Deserializer<'de>,
+    args: Option<Vec<Deserializer<'de>>>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let tag = match self.tag.name() {
+            Some(name) => name,
+            None => return Err(self.tag.error("expected an identifier as enum tag")),
+        };
+        let variant = seed
+            .deserialize(tag.into_deserializer())
+            .map_err(|e: Error| e.at(self.tag.span()))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> EnumAccess<'de> {
+    /// Arguments following the tag, which must be a list
+    fn args(self) -> Result<Vec<Deserializer<'de>>, Error> {
+        match self.args {
+            Some(args) => Ok(args),
+            None => Err(self.list.error("expected a tagged list")),
+        }
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for EnumAccess<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match &self.args {
+            Some(args) if !args.is_empty() => {
+                Err(args[0].error("unexpected argument to unit variant"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        let list = self.list;
+        let mut args = self.args()?;
+        match args.len() {
+            1 => seed.deserialize(args.remove(0)),
+            n => Err(list.error(format!("expected 1 argument to variant, found {}", n))),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        let list = self.list;
+        let mut access = SeqAccess {
+            elements: self.args()?.into_iter(),
+        };
+        let value = visitor.visit_seq(&mut access)?;
+        match access.elements.next() {
+            Some(extra) => Err(extra.error("trailing argument to variant")),
+            None => Ok(value),
+        }
+        .map_err(|e| e.at(list.span()))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_map(MapAccess {
+            entries: self.args()?.into_iter(),
+            value: None,
+            fields: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum Shape {
+        Empty,
+        Circle(u32),
+        Rect(u32, u32),
+        Named { name: String },
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        name: String,
+        port: u16,
+        verbose: bool,
+        tags: Vec<String>,
+        limit: Option<i64>,
+        shapes: Vec<Shape>,
+    }
+
+    #[test]
+    fn deserialize_struct() {
+        let input = r#"
+            ((name "server")
+             (port . 8080)
+             (verbose #t)
+             (tags ("a" b))
+             (limit 10)
+             (shapes (Empty (Circle 1) (Rect 2 3) (Named (name "x")))))"#;
+        let config: Config = from_str(input).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                name: "server".into(),
+                port: 8080,
+                verbose: true,
+                tags: vec!["a".into(), "b".into()],
+                limit: Some(10),
+                shapes: vec![
+                    Shape::Empty,
+                    Shape::Circle(1),
+                    Shape::Rect(2, 3),
+                    Shape::Named { name: "x".into() }
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn absent_option_field() {
+        let input = r#"((name "x") (port 1) (verbose #f) (tags ()) (shapes ()))"#;
+        let config: Config = from_str(input).unwrap();
+        assert_eq!(config.limit, None);
+        assert!(from_str::<Config>(r#"((name "x") (limit 1))"#).is_err());
+    }
+
+    #[test]
+    fn deserialize_map() {
+        let map: BTreeMap<String, (i64, bool)> = from_str(r#"(("a" (1 #t)) (b (2 #f)))"#).unwrap();
+        assert_eq!(map["a"], (1, true));
+        assert_eq!(map["b"], (2, false));
+    }
+
+    #[test]
+    fn error_location() {
+        let input = "((name 1)\n (port 99999))";
+        let err = from_str::<BTreeMap<String, u16>>(input).unwrap_err();
+        assert_eq!(err.span(), Some(Span { line: 1, pos: 7 }));
+
+        let err = from_str::<Vec<Shape>>("(Empty\n  (Circle 1 2))").unwrap_err();
+        assert_eq!(err.span(), Some(Span { line: 1, pos: 2 }));
+
+        let err = from_str::<Vec<u8>>("(1 2").unwrap_err();
+        assert!(err.span().is_some());
+
+        let err = from_str::<Vec<Option<u8>>>("(() (1)\n 2)").unwrap_err();
+        assert_eq!(err.span(), Some(Span { line: 1, pos: 1 }));
+    }
+}
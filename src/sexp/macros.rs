@@ -0,0 +1,212 @@
+//! Declarative construction of S-expressions from Rust
+//!
+//! [`sexp!`] reads a single datum and [`list!`] reads the elements of a
+//! list, both written in Scheme-like syntax:
+//!
+//! ```
+//! use zymogen::{sexp, Sexp};
+//!
+//! let body = sexp!((* x x));
+//! let args = vec![Sexp::Integer(1), Sexp::Integer(2)];
+//! let expr = sexp!((define (square x) ,body (list ,@args #t "s")));
+//! assert_eq!(
+//!     expr.write().to_string(),
+//!     r#"(define (square x) (* x x) (list 1 2 #t "s"))"#
+//! );
+//! ```
+//!
+//! - identifiers become [`Sexp::Identifier`], or [`Sexp::Keyword`] when
+//!   they spell a keyword. Rust splits symbols like `set!`, `let*`, `null?`,
+//!   `call-with-values` and `list->vector` into several tokens, which are
+//!   glued back together when they follow an identifier. Write `(f x * y)`
+//!   as `(f x ,(Sexp::symbol("*")) y)` if the gluing gets in the way
+//! - a lone operator such as `+`, `<=` or `.` is an identifier as well
+//! - string, integer and boolean literals, as well as `#t` and `#f`, are
+//!   self-evaluating data. Negative numbers are written `,(-1)`, since a
+//!   leading `-` is read as the subtraction symbol
+//! - `,expr` interpolates a single Rust expression, and `,@expr` splices an
+//!   iterator. Anything implementing `Into<Sexp>` can be interpolated
+//! - Rust can't tokenize a lone `'`, so quotation is written `(quote x)`
+
+/// Build a [`Sexp`](crate::Sexp) from a single datum in Scheme syntax
+///
+/// See the [module documentation](crate::sexp::macros) for the syntax.
+/// Several data are rejected at compile time; use [`list!`] for those:
+///
+/// ```compile_fail
+/// zymogen::sexp!(a b);
+/// ```
+#[macro_export]
+macro_rules! sexp {
+    // Glue the tokens of a symbol that starts with an identifier, as
+    // `list!` does
+    (@symbol [$($name:expr),*] - $i:ident $($rest:tt)*) => {
+        $crate::sexp!(@symbol [$($name,)* "-", stringify!($i)] $($rest)*)
+    };
+    (@symbol [$($name:expr),*] -> $i:ident $($rest:tt)*) => {
+        $crate::sexp!(@symbol [$($name,)* "->", stringify!($i)] $($rest)*)
+    };
+    (@symbol [$($name:expr),*] ! $($rest:tt)*) => {
+        $crate::sexp!(@symbol [$($name,)* "!"] $($rest)*)
+    };
+    (@symbol [$($name:expr),*] ? $($rest:tt)*) => {
+        $crate::sexp!(@symbol [$($name,)* "?"] $($rest)*)
+    };
+    (@symbol [$($name:expr),*] * $($rest:tt)*) => {
+        $crate::sexp!(@symbol [$($name,)* "*"] $($rest)*)
+    };
+    (@symbol [$($name:expr),*]) => {
+        $crate::Sexp::symbol(concat!($($name),*))
+    };
+    (@symbol [$($name:expr),*] $($rest:tt)+) => {
+        compile_error!("sexp! expects a single datum, use list! for several")
+    };
+
+    (($($inner:tt)*)) => {
+        $crate::Sexp::List($crate::list!($($inner)*))
+    };
+    (# t) => {
+        $crate::Sexp::Boolean(true)
+    };
+    (# f) => {
+        $crate::Sexp::Boolean(false)
+    };
+    (, $e:tt) => {
+        $crate::Sexp::from($e)
+    };
+    (-) => {
+        $crate::Sexp::symbol("-")
+    };
+    ($l:literal) => {
+        $crate::Sexp::from($l)
+    };
+    ($i:ident $($rest:tt)*) => {
+        $crate::sexp!(@symbol [stringify!($i)] $($rest)*)
+    };
+    ($op:tt) => {
+        $crate::Sexp::symbol(stringify!($op))
+    };
+    ($($tt:tt)*) => {
+        compile_error!("sexp! expects a single datum, use list! for several")
+    };
+}
+
+/// Build a [`List`](crate::List) from a sequence of data in Scheme syntax
+///
+/// `list!(a b c)` is the same as `sexp!((a b c))`, without the enclosing
+/// [`Sexp::List`](crate::Sexp::List)
+#[macro_export]
+macro_rules! list {
+    (@push $v:ident;) => {};
+    (@push $v:ident; , @ $e:tt $($rest:tt)*) => {
+        $v.extend(::std::iter::IntoIterator::into_iter($e).map(::std::convert::Into::<$crate::Sexp>::into));
+        $crate::list!(@push $v; $($rest)*);
+    };
+    (@push $v:ident; , $e:tt $($rest:tt)*) => {
+        $v.extend(::std::iter::once($crate::Sexp::from($e)));
+        $crate::list!(@push $v; $($rest)*);
+    };
+    (@push $v:ident; ($($inner:tt)*) $($rest:tt)*) => {
+        $v.extend(::std::iter::once($crate::Sexp::List($crate::list!($($inner)*))));
+        $crate::list!(@push $v; $($rest)*);
+    };
+    (@push $v:ident; # t $($rest:tt)*) => {
+        $v.extend(::std::iter::once($crate::Sexp::Boolean(true)));
+        $crate::list!(@push $v; $($rest)*);
+    };
+    (@push $v:ident; # f $($rest:tt)*) => {
+        $v.extend(::std::iter::once($crate::Sexp::Boolean(false)));
+        $crate::list!(@push $v; $($rest)*);
+    };
+    (@push $v:ident; - $($rest:tt)*) => {
+        $v.extend(::std::iter::once($crate::Sexp::symbol("-")));
+        $crate::list!(@push $v; $($rest)*);
+    };
+    (@push $v:ident; $l:literal $($rest:tt)*) => {
+        $v.extend(::std::iter::once($crate::Sexp::from($l)));
+        $crate::list!(@push $v; $($rest)*);
+    };
+    (@push $v:ident; $i:ident $($rest:tt)*) => {
+        $crate::list!(@symbol $v; [stringify!($i)] $($rest)*);
+    };
+    (@push $v:ident; $op:tt $($rest:tt)*) => {
+        $v.extend(::std::iter::once($crate::Sexp::symbol(stringify!($op))));
+        $crate::list!(@push $v; $($rest)*);
+    };
+
+    // Glue the tokens of a symbol that starts with an identifier
+    (@symbol $v:ident; [$($name:expr),*] - $i:ident $($rest:tt)*) => {
+        $crate::list!(@symbol $v; [$($name,)* "-", stringify!($i)] $($rest)*);
+    };
+    (@symbol $v:ident; [$($name:expr),*] -> $i:ident $($rest:tt)*) => {
+        $crate::list!(@symbol $v; [$($name,)* "->", stringify!($i)] $($rest)*);
+    };
+    (@symbol $v:ident; [$($name:expr),*] ! $($rest:tt)*) => {
+        $crate::list!(@symbol $v; [$($name,)* "!"] $($rest)*);
+    };
+    (@symbol $v:ident; [$($name:expr),*] ? $($rest:tt)*) => {
+        $crate::list!(@symbol $v; [$($name,)* "?"] $($rest)*);
+    };
+    (@symbol $v:ident; [$($name:expr),*] * $($rest:tt)*) => {
+        $crate::list!(@symbol $v; [$($name,)* "*"] $($rest)*);
+    };
+    (@symbol $v:ident; [$($name:expr),*] $($rest:tt)*) => {
+        $v.extend(::std::iter::once($crate::Sexp::symbol(concat!($($name),*))));
+        $crate::list!(@push $v; $($rest)*);
+    };
+
+    ($($tt:tt)*) => {{
+        #[allow(unused_mut)]
+        let mut elements = ::std::vec::Vec::<$crate::Sexp>::new();
+        $crate::list!(@push elements; $($tt)*);
+        elements.into_iter().collect::<$crate::List>()
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use crate::syntax::parse;
+    use crate::{Keyword, List, Sexp};
+
+    fn read(s: &str) -> Sexp {
+        parse(s).unwrap().remove(0)
+    }
+
+    #[test]
+    fn atoms() {
+        assert_eq!(sexp!(x), Sexp::Identifier("x".into()));
+        assert_eq!(sexp!(lambda), Sexp::Keyword(Keyword::Lambda));
+        assert_eq!(sexp!(set!), Sexp::Keyword(Keyword::Set));
+        assert_eq!(sexp!(42), Sexp::Integer(42));
+        assert_eq!(sexp!(#t), Sexp::Boolean(true));
+        assert_eq!(sexp!(false), Sexp::Boolean(false));
+        assert_eq!(sexp!("a b"), Sexp::Literal("a b".into()));
+        assert_eq!(sexp!(()), Sexp::List(List::Nil));
+        assert_eq!(sexp!(list->vector), Sexp::Identifier("list->vector".into()));
+        assert_eq!(sexp!(+), Sexp::Identifier("+".into()));
+        assert_eq!(sexp!(-), Sexp::Identifier("-".into()));
+        let n = 2;
+        assert_eq!(sexp!(,n), Sexp::Integer(2));
+        assert_eq!(list!(), List::Nil);
+    }
+
+    #[test]
+    fn glued_symbols() {
+        assert_eq!(
+            sexp!((let* ((x (call-with-values f g))) (null? (list->vector x)) (- x 1))),
+            read("(let* ((x (call-with-values f g))) (null? (list->vector x)) (- x 1))")
+        );
+        assert_eq!(sexp!((<= a b . c)), read("(<= a b . c)"));
+    }
+
+    #[test]
+    fn interpolation() {
+        let expr = sexp!((+ x 1));
+        let args = vec![sexp!(a), sexp!(b)];
+        let n = 3;
+        assert_eq!(
+            sexp!((lambda (x ,@args) ,expr ,(n * 2) ,(-1) ,"s" ,@(list!(#f)))),
+            read(r#"(lambda (x a b) (+ x 1) 6 -1 "s" #f)"#)
+        );
+    }
+}
@@ -3,8 +3,23 @@ use std::fmt;
 use std::iter::FromIterator;
 use std::iter::Iterator;
 
+pub mod macros;
+pub mod pattern;
+mod print;
+pub use print::Printer;
+pub(crate) use print::write_string;
+
+#[cfg(feature = "serde")]
+pub mod de;
+#[cfg(feature = "serde")]
+pub mod ser;
+#[cfg(feature = "serde")]
+pub use de::{from_sexp, from_str, Deserializer, Error as SerdeError};
+#[cfg(feature = "serde")]
+pub use ser::{to_sexp, to_string, Serializer};
+
 /// Primitive S-expression directly parsed
-#[derive(PartialEq, PartialOrd)]
+#[derive(Clone, PartialEq, PartialOrd)]
 pub enum Sexp {
     Boolean(bool),
     Integer(i64),
@@ -25,7 +40,20 @@ pub enum Ty {
     List,
 }
 
-#[derive(PartialEq, PartialOrd)]
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Ty::Boolean => "a boolean",
+            Ty::Integer => "an integer",
+            Ty::Identifier => "an identifier",
+            Ty::Literal => "a string",
+            Ty::Keyword => "a keyword",
+            Ty::List => "a list",
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, PartialOrd)]
 pub enum List {
     Cons(Box<Sexp>, Box<List>),
     Nil,
@@ -42,21 +70,119 @@ pub enum Keyword {
     And,
     Or,
     Case,
+    CaseLambda,
     Let,
     Letstar,
     Letrec,
+    LetValues,
+    LetstarValues,
     Do,
     Delay,
+    DelayForce,
     Quasiquote,
     Else,
     Define,
+    DefineRecordType,
+    DefineValues,
+    When,
+    Unless,
+    Guard,
+    Parameterize,
     Unquote,
     UnquoteAt,
     /// `.` multiple arity
     Dot,
 }
 
+impl Keyword {
+    /// Look up the keyword spelled `name` in source code
+    pub fn from_name(name: &str) -> Option<Keyword> {
+        use Keyword::*;
+        let kw = match name {
+            "quote" => Quote,
+            "lambda" => Lambda,
+            "if" => If,
+            "set!" => Set,
+            "begin" => Begin,
+            "cond" => Cond,
+            "and" => And,
+            "or" => Or,
+            "case" => Case,
+            "case-lambda" => CaseLambda,
+            "let" => Let,
+            "let*" => Letstar,
+            "letrec" => Letrec,
+            "let-values" => LetValues,
+            "let*-values" => LetstarValues,
+            "do" => Do,
+            "delay" => Delay,
+            "delay-force" => DelayForce,
+            "quasiquote" => Quasiquote,
+            "else" => Else,
+            "define" => Define,
+            "define-record-type" => DefineRecordType,
+            "define-values" => DefineValues,
+            "when" => When,
+            "unless" => Unless,
+            "guard" => Guard,
+            "parameterize" => Parameterize,
+            "unquote" => Unquote,
+            "unquote-splicing" | "unquoteat" => UnquoteAt,
+            _ => return None,
+        };
+        Some(kw)
+    }
+
+    /// The source code spelling of the keyword
+    pub fn name(self) -> &'static str {
+        use Keyword::*;
+        match self {
+            Quote => "quote",
+            Lambda => "lambda",
+            If => "if",
+            Set => "set!",
+            Begin => "begin",
+            Cond => "cond",
+            And => "and",
+            Or => "or",
+            Case => "case",
+            CaseLambda => "case-lambda",
+            Let => "let",
+            Letstar => "let*",
+            Letrec => "letrec",
+            LetValues => "let-values",
+            LetstarValues => "let*-values",
+            Do => "do",
+            Delay => "delay",
+            DelayForce => "delay-force",
+            Quasiquote => "quasiquote",
+            Else => "else",
+            Define => "define",
+            DefineRecordType => "define-record-type",
+            DefineValues => "define-values",
+            When => "when",
+            Unless => "unless",
+            Guard => "guard",
+            Parameterize => "parameterize",
+            Unquote => "unquote",
+            UnquoteAt => "unquote-splicing",
+            Dot => ".",
+        }
+    }
+}
+
 impl Sexp {
+    /// Identifier spelled `name`, or the keyword it spells
+    pub fn symbol<S: AsRef<str> + Into<String>>(name: S) -> Sexp {
+        match name.as_ref() {
+            "." => Sexp::Keyword(Keyword::Dot),
+            s => match Keyword::from_name(s) {
+                Some(kw) => Sexp::Keyword(kw),
+                None => Sexp::Identifier(name.into()),
+            },
+        }
+    }
+
     pub fn as_ident(&self) -> Result<&String, Error> {
         match self {
             Sexp::Identifier(s) => Ok(s),
@@ -106,43 +232,61 @@ impl Sexp {
 
 impl fmt::Display for List {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "(")?;
-        let mut ptr = self;
-        while let List::Cons(car, cdr) = ptr {
-            match cdr.as_ref() {
-                List::Nil => write!(f, "{}", car)?,
-                List::Cons(_, _) => write!(f, "{} ", car)?,
-            }
-            ptr = cdr.as_ref();
-        }
-        write!(f, ")")
+        write!(f, "{}", self.display())
     }
 }
 
 impl fmt::Display for Sexp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use Sexp::*;
-        match self {
-            Literal(s) => write!(f, "\"{}\"", s),
-            Integer(i) => write!(f, "{}", i),
-            Identifier(i) => write!(f, "{}", i),
-            List(super::List::Nil) => write!(f, "'()"),
-            List(inner) => write!(f, "{}", inner),
-            Boolean(b) => write!(f, "{}", b),
-            Keyword(k) => write!(f, "{:?}", k),
-        }
+        write!(f, "{}", self.display())
     }
 }
 
 impl fmt::Debug for Sexp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{}", self.write())
     }
 }
 
 impl fmt::Debug for List {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{}", self.write())
+    }
+}
+
+impl From<bool> for Sexp {
+    fn from(b: bool) -> Sexp {
+        Sexp::Boolean(b)
+    }
+}
+
+impl From<i64> for Sexp {
+    fn from(i: i64) -> Sexp {
+        Sexp::Integer(i)
+    }
+}
+
+impl From<&str> for Sexp {
+    fn from(s: &str) -> Sexp {
+        Sexp::Literal(s.to_string())
+    }
+}
+
+impl From<String> for Sexp {
+    fn from(s: String) -> Sexp {
+        Sexp::Literal(s)
+    }
+}
+
+impl From<Keyword> for Sexp {
+    fn from(kw: Keyword) -> Sexp {
+        Sexp::Keyword(kw)
+    }
+}
+
+impl From<List> for Sexp {
+    fn from(list: List) -> Sexp {
+        Sexp::List(list)
     }
 }
 
@@ -163,7 +307,7 @@ impl<'l> IntoIterator for &'l List {
     type Item = &'l Sexp;
     type IntoIter = ListIterator<'l>;
     fn into_iter(self) -> Self::IntoIter {
-        ListIterator { ptr: &self }
+        ListIterator { ptr: self }
     }
 }
 
@@ -182,7 +326,7 @@ impl<'l> Iterator for ListIterator<'l> {
             List::Nil => None,
             List::Cons(car, cdr) => {
                 self.ptr = cdr;
-                Some(&*car)
+                Some(car)
             }
         }
     }
@@ -223,7 +367,7 @@ impl List {
     /// Try to access the head of the list
     pub fn car(&self) -> Result<&Sexp, Error> {
         match self {
-            List::Cons(car, _) => Ok(&*car),
+            List::Cons(car, _) => Ok(car),
             List::Nil => Err(Error::EmptyList),
         }
     }
@@ -233,7 +377,7 @@ impl List {
     /// Should this fail? We could just return List::Nil
     pub fn cdr(&self) -> Result<&List, Error> {
         match self {
-            List::Cons(_, cdr) => Ok(&*cdr),
+            List::Cons(_, cdr) => Ok(cdr),
             List::Nil => Err(Error::EmptyList),
         }
     }
@@ -257,10 +401,7 @@ impl List {
 #[cfg(test)]
 mod test {
     use super::*;
-
-    fn cons(car: Sexp, cdr: List) -> List {
-        List::Cons(Box::new(car), Box::new(cdr))
-    }
+    use crate::list;
 
     fn id(s: &str) -> Sexp {
         Sexp::Identifier(s.to_string())
@@ -270,22 +411,16 @@ mod test {
     fn list_from_iter() {
         let list = vec![
             Sexp::Keyword(Keyword::Lambda),
-            Sexp::List(cons(id("x"), List::Nil)),
+            Sexp::List(vec![id("x")].into_iter().collect()),
             id("y"),
         ];
-        let expected = cons(
-            Sexp::Keyword(Keyword::Lambda),
-            cons(
-                Sexp::List(cons(id("x"), List::Nil)),
-                cons(id("y"), List::Nil),
-            ),
-        );
+        let expected = list!(lambda (x) y);
         assert_eq!(list.into_iter().collect::<List>(), expected);
     }
 
     #[test]
     fn list_into_iter() {
-        let list = cons(id("cons"), cons(id("x"), cons(id("y"), List::Nil)));
+        let list = list!(cons x y);
         let mut iter = list.into_iter();
         assert_eq!(iter.next(), Some(id("cons")));
         assert_eq!(iter.next(), Some(id("x")));
@@ -0,0 +1,483 @@
+//! Structural patterns over S-expressions
+//!
+//! A [`Pattern`] describes the shape of a form in the notation of the R7RS
+//! report, and matching a [`Sexp`] against it either binds the pieces of the
+//! form or pinpoints where it went wrong:
+//!
+//! ```
+//! use zymogen::sexp::pattern::Pattern;
+//! use zymogen::syntax::parse;
+//!
+//! let pat = Pattern::new("(let ((id expr) ...) body ...+)");
+//! let form = parse("(let ((x 1) (y 2)) (+ x y))").unwrap().remove(0);
+//! let m = pat.matches(&form).unwrap();
+//! assert_eq!(m.idents("id"), vec!["x", "y"]);
+//! assert_eq!(m.all("body").len(), 1);
+//!
+//! let bad = parse("(let ((x 1) (2 y)) x)").unwrap().remove(0);
+//! let err = pat.matches(&bad).unwrap_err();
+//! assert_eq!(err.path, vec![1, 1, 0]);
+//! assert_eq!(err.to_string(), "expected an identifier, found `2`");
+//! ```
+//!
+//! Within a pattern:
+//!
+//! - `id`, or an identifier ending in `-id`, matches an identifier
+//! - `_` matches anything, without binding it
+//! - any other identifier matches any datum and binds it
+//! - `'x` matches the identifier `x` itself
+//! - keywords, `.`, numbers, strings and booleans match themselves
+//! - `p ...` matches zero or more consecutive elements matching `p`, and
+//!   `p ...+` one or more. Each list may contain one of them, and the
+//!   variables in `p` are bound to sequences
+use super::{Keyword, List, Sexp};
+use crate::syntax::parse;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Wildcard,
+    /// Pattern variable, and whether it only matches identifiers
+    Var(String, bool),
+    /// Datum that must appear as is
+    Literal(Sexp),
+    List {
+        before: Vec<Node>,
+        /// Repeated element, with the minimum number of repetitions
+        repeat: Option<(Box<Node>, usize)>,
+        after: Vec<Node>,
+        /// Source of the list pattern, for diagnostics
+        shape: String,
+    },
+}
+
+impl Node {
+    fn parse(sexp: &Sexp) -> Result<Node, String> {
+        Ok(match sexp {
+            Sexp::Identifier(s) if s == "_" => Node::Wildcard,
+            Sexp::Identifier(s) if s == "..." || s == "...+" => {
+                return Err(format!("`{}` must follow a pattern in a list", s))
+            }
+            Sexp::Identifier(s) => Node::Var(s.clone(), s == "id" || s.ends_with("-id")),
+            Sexp::List(List::Cons(car, cdr)) if **car == Sexp::Keyword(Keyword::Quote) => {
+                match cdr.car() {
+                    Ok(datum) => Node::Literal(datum.clone()),
+                    Err(_) => return Err("`quote` needs a datum".into()),
+                }
+            }
+            Sexp::List(list) => {
+                let elements = list.iter().collect::<Vec<&Sexp>>();
+                let mut before = Vec::new();
+                let mut repeat = None;
+                let mut after = Vec::new();
+                let mut idx = 0;
+                while idx < elements.len() {
+                    let node = Node::parse(elements[idx])?;
+                    let min = match elements.get(idx + 1) {
+                        Some(Sexp::Identifier(s)) if s == "..." => Some(0),
+                        Some(Sexp::Identifier(s)) if s == "...+" => Some(1),
+                        _ => None,
+                    };
+                    match min {
+                        Some(_) if repeat.is_some() => {
+                            return Err(format!("more than one ellipsis in `{}`", sexp.write()))
+                        }
+                        Some(min) => {
+                            repeat = Some((Box::new(node), min));
+                            idx += 1;
+                        }
+                        None if repeat.is_some() => after.push(node),
+                        None => before.push(node),
+                    }
+                    idx += 1;
+                }
+                Node::List {
+                    before,
+                    repeat,
+                    after,
+                    shape: sexp.write().to_string(),
+                }
+            }
+            datum => Node::Literal(datum.clone()),
+        })
+    }
+
+    /// What a datum matching this node looks like
+    fn expected(&self) -> String {
+        match self {
+            Node::Wildcard | Node::Var(_, false) => "an expression".into(),
+            Node::Var(_, true) => "an identifier".into(),
+            Node::Literal(datum) => format!("`{}`", datum.write()),
+            Node::List { shape, .. } => format!("a list of the form `{}`", shape),
+        }
+    }
+
+    /// Pattern variables bound by this node
+    fn vars<'p>(&'p self, out: &mut Vec<&'p str>) {
+        match self {
+            Node::Var(name, _) => out.push(name),
+            Node::List {
+                before,
+                repeat,
+                after,
+                ..
+            } => {
+                let repeated = repeat.iter().map(|(node, _)| &**node);
+                for node in before.iter().chain(repeated).chain(after) {
+                    node.vars(out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn matches<'a>(
+        &self,
+        sexp: &'a Sexp,
+        path: &mut Vec<usize>,
+        out: &mut HashMap<String, Binding<'a>>,
+    ) -> Result<(), Mismatch> {
+        let mismatch = |path: &Vec<usize>| Mismatch {
+            path: path.clone(),
+            expected: self.expected(),
+            found: Some(sexp.write().to_string()),
+        };
+        match self {
+            Node::Wildcard => Ok(()),
+            Node::Var(name, ident) => {
+                if *ident && !matches!(sexp, Sexp::Identifier(_)) {
+                    return Err(mismatch(path));
+                }
+                out.insert(name.clone(), Binding::One(sexp));
+                Ok(())
+            }
+            Node::Literal(datum) if datum == sexp => Ok(()),
+            Node::Literal(_) => Err(mismatch(path)),
+            Node::List {
+                before,
+                repeat,
+                after,
+                ..
+            } => {
+                let list = match sexp {
+                    Sexp::List(list) => list,
+                    _ => return Err(mismatch(path)),
+                };
+                let elements = list.iter().collect::<Vec<&Sexp>>();
+                // Number of repetitions. When the list is too short, the
+                // repeated element takes its minimum and the elements that
+                // are present are matched from the left
+                let (count, min) = match repeat {
+                    Some((_, min)) => {
+                        let fixed = before.len() + after.len();
+                        (elements.len().saturating_sub(fixed).max(*min), *min)
+                    }
+                    None => (0, 0),
+                };
+                let repeated = before.len()..before.len() + count;
+                let nodes = before
+                    .iter()
+                    .chain(
+                        repeat
+                            .iter()
+                            .flat_map(|(node, _)| std::iter::repeat(&**node).take(count)),
+                    )
+                    .chain(after)
+                    .collect::<Vec<&Node>>();
+
+                let mut vars = Vec::new();
+                if let Some((node, _)) = repeat {
+                    node.vars(&mut vars);
+                }
+                let mut seqs = vars.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+
+                for (idx, node) in nodes.iter().enumerate() {
+                    path.push(idx);
+                    let result = match elements.get(idx) {
+                        None if idx == repeated.start && min > 0 => Err(Mismatch {
+                            path: path.clone(),
+                            expected: format!("{} (one or more)", node.expected()),
+                            found: None,
+                        }),
+                        None => Err(Mismatch {
+                            path: path.clone(),
+                            expected: node.expected(),
+                            found: None,
+                        }),
+                        Some(element) if repeated.contains(&idx) => {
+                            let mut inner = HashMap::new();
+                            let result = node.matches(element, path, &mut inner);
+                            for (var, seq) in vars.iter().zip(seqs.iter_mut()) {
+                                seq.extend(inner.remove(*var));
+                            }
+                            result
+                        }
+                        Some(element) => node.matches(element, path, out),
+                    };
+                    path.pop();
+                    result?;
+                }
+                if let Some(extra) = elements.get(nodes.len()) {
+                    path.push(nodes.len());
+                    let err = Mismatch {
+                        path: path.clone(),
+                        expected: "the end of the list".into(),
+                        found: Some(extra.write().to_string()),
+                    };
+                    path.pop();
+                    return Err(err);
+                }
+                for (var, seq) in vars.iter().zip(seqs) {
+                    out.insert(var.to_string(), Binding::Many(seq));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The shape of a form, with pattern variables naming its parts
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    root: Node,
+    source: String,
+}
+
+impl Pattern {
+    /// Compile a pattern from its source notation
+    ///
+    /// Panics if `source` is not a single valid pattern, since patterns are
+    /// written by the compiler authors rather than read from user input
+    pub fn new(source: &str) -> Pattern {
+        match Pattern::parse(source) {
+            Ok(pattern) => pattern,
+            Err(e) => panic!("invalid pattern `{}`: {}", source, e),
+        }
+    }
+
+    /// Compile a pattern from its source notation
+    pub fn parse(source: &str) -> Result<Pattern, String> {
+        let mut sexps = parse(source).map_err(|e| e.to_string())?;
+        if sexps.len() != 1 {
+            return Err("expected a single pattern".into());
+        }
+        Ok(Pattern {
+            root: Node::parse(&sexps.remove(0))?,
+            source: source.to_string(),
+        })
+    }
+
+    /// Source notation of the pattern
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Match `sexp` against the pattern, returning the bound pieces
+    pub fn matches<'a>(&self, sexp: &'a Sexp) -> Result<Bindings<'a>, Mismatch> {
+        let mut map = HashMap::new();
+        self.root.matches(sexp, &mut Vec::new(), &mut map)?;
+        Ok(Bindings { map })
+    }
+
+    /// Match `sexp` against each pattern in turn, returning the index of the
+    /// first one that matches. When none does, the mismatch that got the
+    /// furthest into `sexp` is reported
+    pub fn match_first<'a>(
+        patterns: &[Pattern],
+        sexp: &'a Sexp,
+    ) -> Result<(usize, Bindings<'a>), Mismatch> {
+        let mut best: Option<Mismatch> = None;
+        for (idx, pattern) in patterns.iter().enumerate() {
+            match pattern.matches(sexp) {
+                Ok(bindings) => return Ok((idx, bindings)),
+                Err(e) => {
+                    if best.as_ref().map(|b| e.path > b.path).unwrap_or(true) {
+                        best = Some(e);
+                    }
+                }
+            }
+        }
+        Err(best.expect("match_first requires at least one pattern"))
+    }
+}
+
+/// Piece of a form bound to a pattern variable
+#[derive(Debug, PartialEq)]
+pub enum Binding<'a> {
+    One(&'a Sexp),
+    /// Variable under an ellipsis, bound once per repetition
+    Many(Vec<Binding<'a>>),
+}
+
+/// Pattern variables bound by a successful match
+///
+/// The accessors panic when asked for a variable that the pattern doesn't
+/// bind at that depth, which is a mistake in the pattern rather than in the
+/// matched form
+#[derive(Debug, PartialEq)]
+pub struct Bindings<'a> {
+    map: HashMap<String, Binding<'a>>,
+}
+
+impl<'a> Bindings<'a> {
+    pub fn binding(&self, var: &str) -> Option<&Binding<'a>> {
+        self.map.get(var)
+    }
+
+    /// Datum bound to `var`
+    pub fn get(&self, var: &str) -> &'a Sexp {
+        match self.map.get(var) {
+            Some(Binding::One(sexp)) => sexp,
+            _ => panic!("pattern variable `{}` is not bound to a datum", var),
+        }
+    }
+
+    /// Datum bound to `var`, if the pattern that matched binds it
+    pub fn opt(&self, var: &str) -> Option<&'a Sexp> {
+        self.map.get(var).map(|_| self.get(var))
+    }
+
+    /// Name of the identifier bound to `var`
+    pub fn ident(&self, var: &str) -> &'a str {
+        match self.get(var) {
+            Sexp::Identifier(s) => s,
+            _ => panic!("pattern variable `{}` is not an identifier", var),
+        }
+    }
+
+    /// Data bound to `var` under one ellipsis
+    pub fn all(&self, var: &str) -> Vec<&'a Sexp> {
+        match self.map.get(var) {
+            Some(Binding::Many(seq)) => seq
+                .iter()
+                .map(|b| match b {
+                    Binding::One(sexp) => *sexp,
+                    Binding::Many(_) => panic!("pattern variable `{}` is nested too deep", var),
+                })
+                .collect(),
+            _ => panic!("pattern variable `{}` is not bound to a sequence", var),
+        }
+    }
+
+    /// Names of the identifiers bound to `var` under one ellipsis
+    pub fn idents(&self, var: &str) -> Vec<&'a str> {
+        self.all(var)
+            .into_iter()
+            .map(|sexp| match sexp {
+                Sexp::Identifier(s) => s.as_str(),
+                _ => panic!("pattern variable `{}` is not an identifier", var),
+            })
+            .collect()
+    }
+}
+
+/// Why a form doesn't match a pattern
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct Mismatch {
+    /// Indices leading from the matched form to the offending element. A
+    /// missing element is reported at the index it should have been at
+    pub path: Vec<usize>,
+    pub expected: String,
+    /// The offending datum in `write` form, or `None` if the list ended
+    /// too early
+    pub found: Option<String>,
+}
+
+impl Mismatch {
+    /// Locate a mismatch in a sub-form at position `idx` of its parent
+    pub fn within(mut self, idx: usize) -> Mismatch {
+        self.path.insert(0, idx);
+        self
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.found {
+            Some(found) => write!(f, "expected {}, found `{}`", self.expected, found),
+            None => write!(f, "expected {}, found the end of the list", self.expected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(s: &str) -> Sexp {
+        parse(s).unwrap().remove(0)
+    }
+
+    #[test]
+    fn binds_sequences() {
+        let pat = Pattern::new("(define (name-id id ... . rest-id) body ...+)");
+        let form = read("(define (f a b . c) (g a) b)");
+        let m = pat.matches(&form).unwrap();
+        assert_eq!(m.ident("name-id"), "f");
+        assert_eq!(m.idents("id"), vec!["a", "b"]);
+        assert_eq!(m.ident("rest-id"), "c");
+        assert_eq!(m.all("body"), vec![&read("(g a)"), &read("b")]);
+
+        let pat = Pattern::new("(cond (test body ...) ... (else last ...+))");
+        let form = read("(cond (a) (b 1 2) (else 3))");
+        let m = pat.matches(&form).unwrap();
+        assert_eq!(m.all("test"), vec![&read("a"), &read("b")]);
+        match m.binding("body") {
+            Some(Binding::Many(seqs)) => assert_eq!(seqs.len(), 2),
+            b => panic!("{:?}", b),
+        }
+    }
+
+    #[test]
+    fn literals() {
+        let pat = Pattern::new("(test '=> receiver)");
+        assert!(pat.matches(&read("(x => f)")).is_ok());
+        let err = pat.matches(&read("(x y f)")).unwrap_err();
+        assert_eq!(err.path, vec![1]);
+        assert_eq!(err.expected, "`=>`");
+    }
+
+    #[test]
+    fn mismatches() {
+        let pat = Pattern::new("(if test csq alt)");
+        assert_eq!(
+            pat.matches(&read("(if a b)")).unwrap_err(),
+            Mismatch {
+                path: vec![3],
+                expected: "an expression".into(),
+                found: None,
+            }
+        );
+        let err = pat.matches(&read("(if a b c d)")).unwrap_err();
+        assert_eq!(err.path, vec![4]);
+        assert_eq!(err.to_string(), "expected the end of the list, found `d`");
+
+        let pat = Pattern::new("(lambda (id ...) body ...+)");
+        let err = pat.matches(&read("(lambda (x))")).unwrap_err();
+        assert_eq!(err.path, vec![2]);
+        assert_eq!(
+            err.to_string(),
+            "expected an expression (one or more), found the end of the list"
+        );
+        let err = pat.matches(&read("(lambda x y)")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected a list of the form `(id ...)`, found `x`"
+        );
+    }
+
+    #[test]
+    fn first_match() {
+        let patterns = [
+            Pattern::new("(let ((id expr) ...) body ...+)"),
+            Pattern::new("(let name-id ((id expr) ...) body ...+)"),
+        ];
+        let form = read("(let loop ((i 0)) (loop i))");
+        assert_eq!(Pattern::match_first(&patterns, &form).unwrap().0, 1);
+
+        let form = read("(let loop ((i)) (loop i))");
+        let err = Pattern::match_first(&patterns, &form).unwrap_err();
+        assert_eq!(err.path, vec![2, 0, 1]);
+    }
+}
@@ -0,0 +1,230 @@
+//! `write` and `display` printing of S-expressions
+//!
+//! The `write` form is an external representation that
+//! [`syntax::parse`](crate::syntax::parse) reads back as the same datum:
+//! keywords are printed with their source spelling, booleans as `#t` and
+//! `#f`, strings are quoted and escaped, and identifiers that would not be
+//! read back as themselves are written between `|` bars.
+//!
+//! The `display` form is meant for humans: strings and identifiers are
+//! printed without any quoting, and quote forms are abbreviated.
+use super::{Keyword, List, Sexp};
+use crate::syntax::is_plain_identifier;
+use std::fmt::{self, Write};
+
+/// Adapter to print a value with `{}` in either `write` or `display` form
+///
+/// Returned by the `write` and `display` methods of [`Sexp`], [`List`] and
+/// compiler values
+pub struct Printer<'a, T: ?Sized> {
+    pub(crate) inner: &'a T,
+    pub(crate) write: bool,
+}
+
+impl<'a, T: ?Sized> Printer<'a, T> {
+    pub(crate) fn write(inner: &'a T) -> Printer<'a, T> {
+        Printer { inner, write: true }
+    }
+
+    pub(crate) fn display(inner: &'a T) -> Printer<'a, T> {
+        Printer {
+            inner,
+            write: false,
+        }
+    }
+
+    /// Print another value in the same form
+    pub(crate) fn with<U: ?Sized>(&self, inner: &'a U) -> Printer<'a, U> {
+        Printer {
+            inner,
+            write: self.write,
+        }
+    }
+}
+
+impl Sexp {
+    /// Print in `write` form, which is read back as the same datum
+    pub fn write(&self) -> Printer<'_, Sexp> {
+        Printer::write(self)
+    }
+
+    /// Print in `display` form, for humans
+    pub fn display(&self) -> Printer<'_, Sexp> {
+        Printer::display(self)
+    }
+}
+
+impl List {
+    /// Print in `write` form, which is read back as the same datum
+    pub fn write(&self) -> Printer<'_, List> {
+        Printer::write(self)
+    }
+
+    /// Print in `display` form, for humans
+    pub fn display(&self) -> Printer<'_, List> {
+        Printer::display(self)
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter, s: &str, delim: char) -> fmt::Result {
+    f.write_char(delim)?;
+    for ch in s.chars() {
+        match ch {
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            '\r' => f.write_str("\\r")?,
+            ch if ch == delim => write!(f, "\\{}", ch)?,
+            ch if ch.is_control() => write!(f, "\\x{:x};", ch as u32)?,
+            ch => f.write_char(ch)?,
+        }
+    }
+    f.write_char(delim)
+}
+
+/// Write `s` as a string literal
+pub(crate) fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write_escaped(f, s, '"')
+}
+
+/// Write `s` as an identifier, between bars if it can't be read back as is
+pub(crate) fn write_identifier(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    if is_plain_identifier(s) {
+        f.write_str(s)
+    } else {
+        write_escaped(f, s, '|')
+    }
+}
+
+/// Abbreviation for `(quote x)` and friends, with the abbreviated datum
+fn abbreviation(list: &List) -> Option<(&'static str, &Sexp)> {
+    let (car, cdr) = match list {
+        List::Cons(car, cdr) => (car, cdr),
+        List::Nil => return None,
+    };
+    let prefix = match **car {
+        Sexp::Keyword(Keyword::Quote) => "'",
+        Sexp::Keyword(Keyword::Quasiquote) => "`",
+        Sexp::Keyword(Keyword::Unquote) => ",",
+        Sexp::Keyword(Keyword::UnquoteAt) => ",@",
+        _ => return None,
+    };
+    match &**cdr {
+        List::Cons(datum, rest) if **rest == List::Nil => Some((prefix, datum)),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Printer<'_, Sexp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inner {
+            Sexp::Boolean(true) => f.write_str("#t"),
+            Sexp::Boolean(false) => f.write_str("#f"),
+            Sexp::Integer(i) => write!(f, "{}", i),
+            Sexp::Identifier(s) if self.write => write_identifier(f, s),
+            Sexp::Literal(s) if self.write => write_string(f, s),
+            Sexp::Identifier(s) | Sexp::Literal(s) => f.write_str(s),
+            Sexp::Keyword(kw) => f.write_str(kw.name()),
+            Sexp::List(list) => self.with(list).fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Printer<'_, List> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.write {
+            if let Some((prefix, datum)) = abbreviation(self.inner) {
+                f.write_str(prefix)?;
+                return self.with(datum).fmt(f);
+            }
+        }
+        f.write_char('(')?;
+        for (idx, sexp) in self.inner.iter().enumerate() {
+            if idx > 0 {
+                f.write_char(' ')?;
+            }
+            self.with(sexp).fmt(f)?;
+        }
+        f.write_char(')')
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::parse;
+    use proptest::prelude::*;
+
+    fn keyword() -> impl Strategy<Value = Keyword> {
+        use Keyword::*;
+        prop::sample::select(vec![
+            Quote, Lambda, If, Set, Begin, Cond, And, Or, Case, CaseLambda, Let, Letstar, Letrec,
+            LetValues, LetstarValues, Do, Delay, DelayForce, Quasiquote, Else, Define,
+            DefineRecordType, DefineValues, When, Unless, Guard, Parameterize, Unquote,
+            UnquoteAt,
+        ])
+    }
+
+    /// Identifiers the parser can produce: anything but a keyword spelling
+    fn identifier() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-z!$%&*/<=>?^_~+-][a-z0-9!$%&*/<=>?^_~+.@-]{0,8}",
+            any::<String>(),
+        ]
+        .prop_filter("keywords are not identifiers", |s| {
+            Keyword::from_name(s).is_none()
+        })
+    }
+
+    fn atom() -> impl Strategy<Value = Sexp> {
+        prop_oneof![
+            any::<bool>().prop_map(Sexp::Boolean),
+            any::<i64>().prop_map(Sexp::Integer),
+            identifier().prop_map(Sexp::Identifier),
+            any::<String>().prop_map(Sexp::Literal),
+            keyword().prop_map(Sexp::Keyword),
+        ]
+    }
+
+    fn sexp() -> impl Strategy<Value = Sexp> {
+        atom().prop_recursive(4, 32, 6, |inner| {
+            prop::collection::vec(inner, 0..6).prop_map(|v| Sexp::List(v.into_iter().collect()))
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn write_round_trips(x in sexp()) {
+            let written = x.write().to_string();
+            prop_assert_eq!(parse(&written), Ok(vec![x]), "{}", written);
+        }
+    }
+
+    #[test]
+    fn write_and_display() {
+        let x = parse(r#"(let* ((s "a \"b\"\n")) '(#t |a b| set!))"#)
+            .unwrap()
+            .remove(0);
+        assert_eq!(
+            x.write().to_string(),
+            r#"(let* ((s "a \"b\"\n")) (quote (#t |a b| set!)))"#
+        );
+        assert_eq!(
+            x.display().to_string(),
+            "(let* ((s a \"b\"\n)) '(#t a b set!))"
+        );
+    }
+
+    #[test]
+    fn write_numbers_and_empty() {
+        let x = Sexp::List(
+            vec![Sexp::Integer(-5), Sexp::List(List::Nil)]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(x.write().to_string(), "(-5 ())");
+        assert_eq!(parse("(-5 ())"), Ok(vec![x]));
+        assert_eq!(Sexp::Identifier("-".into()).write().to_string(), "-");
+        assert_eq!(Sexp::Identifier("12a".into()).write().to_string(), "|12a|");
+    }
+}
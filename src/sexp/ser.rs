@@ -0,0 +1,529 @@
+//! Serialize Rust data structures into S-expressions
+//!
+//! Enabled with the `serde` feature. See [`de`](super::de) for the data
+//! model, which this module produces:
+//!
+//! ```text
+//! struct Point { x: i64, y: i64 }    ((x 1) (y 2))
+//! enum Shape { Circle(u32) }         (Circle 3)
+//! vec![1, 2, 3]                      (1 2 3)
+//! Some(1), None                      (1), ()
+//! Point { x: Some(1), y: None }      ((x 1))
+//! ```
+use super::de::Error;
+use super::{List, Sexp};
+use serde::ser::{self, Serialize};
+
+/// Serialize `value` into an S-expression
+pub fn to_sexp<T: Serialize + ?Sized>(value: &T) -> Result<Sexp, Error> {
+    value.serialize(Serializer)
+}
+
+/// Serialize `value` into source code, in `write` form
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, Error> {
+    Ok(to_sexp(value)?.write().to_string())
+}
+
+fn list(elements: Vec<Sexp>) -> Sexp {
+    Sexp::List(elements.into_iter().collect())
+}
+
+/// A [`serde::Serializer`] producing a [`Sexp`]
+pub struct Serializer;
+
+/// Collects the elements of a list, optionally preceded by a tag
+pub struct SerializeList {
+    elements: Vec<Sexp>,
+    /// Key of the map entry being serialized
+    key: Option<Sexp>,
+}
+
+impl SerializeList {
+    fn new(tag: Option<&str>) -> SerializeList {
+        SerializeList {
+            elements: tag.map(Sexp::symbol).into_iter().collect(),
+            key: None,
+        }
+    }
+
+    fn entry(&mut self, key: Sexp, value: Sexp) {
+        self.elements.push(list(vec![key, value]));
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Sexp;
+    type Error = Error;
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeList;
+    type SerializeStruct = SerializeList;
+    type SerializeStructVariant = SerializeList;
+
+    fn serialize_bool(self, v: bool) -> Result<Sexp, Error> {
+        Ok(Sexp::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Sexp, Error> {
+        Ok(Sexp::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Sexp, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Sexp, Error> {
+        if v > i64::MAX as u64 {
+            return Err(Error::new(format!("integer {} is out of range", v)));
+        }
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Sexp, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Sexp, Error> {
+        Err(Error::new(format!(
+            "floating point number {} can't be represented",
+            v
+        )))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Sexp, Error> {
+        Ok(Sexp::Literal(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Sexp, Error> {
+        Ok(Sexp::Literal(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Sexp, Error> {
+        Ok(list(v.iter().map(|&b| Sexp::Integer(b.into())).collect()))
+    }
+
+    fn serialize_none(self) -> Result<Sexp, Error> {
+        Ok(Sexp::List(List::Nil))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Sexp, Error> {
+        Ok(list(vec![value.serialize(self)?]))
+    }
+
+    fn serialize_unit(self) -> Result<Sexp, Error> {
+        Ok(Sexp::List(List::Nil))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Sexp, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Sexp, Error> {
+        Ok(Sexp::symbol(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Sexp, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Sexp, Error> {
+        Ok(list(vec![Sexp::symbol(variant), value.serialize(self)?]))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(None))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(None))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeList, Error> {
+        Ok(SerializeList::new(Some(variant)))
+    }
+}
+
+/// Serializes the value of a struct field, where `None` leaves the entry out
+/// and `Some(x)` is written as `x`
+struct Field<'a> {
+    absent: &'a mut bool,
+}
+
+/// Serialize the way [`Serializer`] does
+macro_rules! forward {
+    ($($method:ident($($arg:ident: $ty:ty),*) -> $ok:ty;)*) => {
+        $(fn $method(self, $($arg: $ty),*) -> Result<$ok, Error> {
+            Serializer.$method($($arg),*)
+        })*
+    };
+}
+
+impl ser::Serializer for Field<'_> {
+    type Ok = Sexp;
+    type Error = Error;
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeList;
+    type SerializeStruct = SerializeList;
+    type SerializeStructVariant = SerializeList;
+
+    forward! {
+        serialize_bool(v: bool) -> Sexp;
+        serialize_i8(v: i8) -> Sexp;
+        serialize_i16(v: i16) -> Sexp;
+        serialize_i32(v: i32) -> Sexp;
+        serialize_i64(v: i64) -> Sexp;
+        serialize_u8(v: u8) -> Sexp;
+        serialize_u16(v: u16) -> Sexp;
+        serialize_u32(v: u32) -> Sexp;
+        serialize_u64(v: u64) -> Sexp;
+        serialize_f32(v: f32) -> Sexp;
+        serialize_f64(v: f64) -> Sexp;
+        serialize_char(v: char) -> Sexp;
+        serialize_str(v: &str) -> Sexp;
+        serialize_bytes(v: &[u8]) -> Sexp;
+        serialize_unit() -> Sexp;
+        serialize_unit_struct(name: &'static str) -> Sexp;
+        serialize_unit_variant(name: &'static str, index: u32, variant: &'static str) -> Sexp;
+        serialize_seq(len: Option<usize>) -> SerializeList;
+        serialize_tuple(len: usize) -> SerializeList;
+        serialize_tuple_struct(name: &'static str, len: usize) -> SerializeList;
+        serialize_tuple_variant(
+            name: &'static str,
+            index: u32,
+            variant: &'static str,
+            len: usize
+        ) -> SerializeList;
+        serialize_map(len: Option<usize>) -> SerializeList;
+        serialize_struct(name: &'static str, len: usize) -> SerializeList;
+        serialize_struct_variant(
+            name: &'static str,
+            index: u32,
+            variant: &'static str,
+            len: usize
+        ) -> SerializeList;
+    }
+
+    fn serialize_none(self) -> Result<Sexp, Error> {
+        *self.absent = true;
+        Serializer.serialize_none()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Sexp, Error> {
+        to_sexp(value)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Sexp, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Sexp, Error> {
+        Serializer.serialize_newtype_variant(name, index, variant, value)
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.elements.push(to_sexp(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Ok(list(self.elements))
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeMap for SerializeList {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(to_sexp(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error::new("map value serialized before its key"))?;
+        let value = to_sexp(value)?;
+        self.entry(key, value);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Ok(list(self.elements))
+    }
+}
+
+impl ser::SerializeStruct for SerializeList {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let mut absent = false;
+        let value = value.serialize(Field {
+            absent: &mut absent,
+        })?;
+        if !absent {
+            self.entry(Sexp::symbol(key), value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Ok(list(self.elements))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeList {
+    type Ok = Sexp;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<Sexp, Error> {
+        Ok(list(self.elements))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::de::from_str;
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    enum Op {
+        Nop,
+        Push(i64),
+        Jump { target: u32, when: Option<bool> },
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Program {
+        name: String,
+        code: Vec<Op>,
+        labels: BTreeMap<String, u32>,
+        // Spelled like a keyword
+        r#do: (),
+    }
+
+    #[test]
+    fn serialize_layout() {
+        let program = Program {
+            name: "main".into(),
+            code: vec![
+                Op::Nop,
+                Op::Push(-1),
+                Op::Jump {
+                    target: 0,
+                    when: None,
+                },
+            ],
+            labels: vec![("start".to_string(), 0)].into_iter().collect(),
+            r#do: (),
+        };
+        let s = to_string(&program).unwrap();
+        assert_eq!(
+            s,
+            r#"((name "main") (code (Nop (Push -1) (Jump (target 0)))) (labels (("start" 0))) (do ()))"#
+        );
+        assert_eq!(from_str::<Program>(&s), Ok(program));
+    }
+
+    #[test]
+    fn options_round_trip() {
+        let values: Vec<Option<Vec<u8>>> = vec![None, Some(vec![]), Some(vec![1, 2])];
+        let s = to_string(&values).unwrap();
+        assert_eq!(s, "(() (()) ((1 2)))");
+        assert_eq!(from_str::<Vec<Option<Vec<u8>>>>(&s), Ok(values));
+
+        let units = vec![Some(()), None];
+        let s = to_string(&units).unwrap();
+        assert_eq!(s, "((()) ())");
+        assert_eq!(from_str::<Vec<Option<()>>>(&s), Ok(units));
+        let nested = Some(Some(1));
+        assert_eq!(
+            from_str::<Option<Option<u8>>>(&to_string(&nested).unwrap()),
+            Ok(nested)
+        );
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Limits {
+        max: Option<u32>,
+        min: Option<u32>,
+        tags: Option<Vec<u8>>,
+        nested: Option<Option<u8>>,
+    }
+
+    #[test]
+    fn option_fields() {
+        let limits = Limits {
+            max: Some(10),
+            min: None,
+            tags: Some(vec![]),
+            nested: Some(None),
+        };
+        let s = to_string(&limits).unwrap();
+        assert_eq!(s, "((max 10) (tags ()) (nested ()))");
+        assert_eq!(from_str::<Limits>(&s), Ok(limits));
+
+        let nested = Limits {
+            max: None,
+            min: None,
+            tags: None,
+            nested: Some(Some(1)),
+        };
+        let s = to_string(&nested).unwrap();
+        assert_eq!(s, "((nested (1)))");
+        assert_eq!(from_str::<Limits>(&s), Ok(nested));
+
+        // Map values are not fields, so they keep the wrapping
+        let map: BTreeMap<String, Option<u8>> = vec![("a".to_string(), None)].into_iter().collect();
+        let s = to_string(&map).unwrap();
+        assert_eq!(s, r#"(("a" ()))"#);
+        assert_eq!(from_str::<BTreeMap<String, Option<u8>>>(&s), Ok(map));
+    }
+
+    #[test]
+    fn floats_unsupported() {
+        assert!(to_sexp(&1.5f64).is_err());
+    }
+}
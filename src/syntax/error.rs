@@ -1,5 +1,6 @@
-use super::{Token, TokenKind};
-use colored::*;
+use super::{Span, Token, TokenKind};
+use crate::diagnostic::Diagnostic;
+use std::fmt;
 
 #[derive(PartialEq, PartialOrd, Debug, Clone)]
 pub enum ErrorKind {
@@ -7,6 +8,8 @@ pub enum ErrorKind {
     Invalid(char),
     ExpectedToken(TokenKind, TokenKind),
     Unbalanced,
+    /// Integer literal out of range
+    Overflow,
 }
 
 #[derive(PartialEq, PartialOrd, Debug, Clone)]
@@ -17,40 +20,67 @@ pub struct Error {
 }
 
 impl Error {
-    pub fn message<S: AsRef<str>>(&self, source: S) -> String {
-        let msg = match &self.kind {
-            ErrorKind::EOF => String::from("unexpected end-of-file!"),
-            ErrorKind::Invalid(ch) => format!("invalid character in input `{}`!", ch),
+    /// Short description of the error, without source context
+    pub fn description(&self) -> String {
+        match &self.kind {
+            ErrorKind::EOF => String::from("unexpected end of input"),
+            ErrorKind::Invalid(ch) => format!("invalid character `{}` in input", ch),
             ErrorKind::ExpectedToken(exp, got) => {
-                format!("expected TokenKind `{:?}`, found `{:?}!`", exp, got)
+                format!("expected `{:?}`, found `{:?}`", exp, got)
             }
-            ErrorKind::Unbalanced => String::from("unbalanced expression!"),
-        };
+            ErrorKind::Unbalanced => String::from("unbalanced parentheses or delimiters"),
+            ErrorKind::Overflow => String::from("integer literal out of range"),
+        }
+    }
 
-        let mut output = format!("\nError at line {} char {}\n", self.line, self.pos)
-            .yellow()
-            .to_string();
-        for (ln, line) in source
-            .as_ref()
-            .lines()
-            .enumerate()
-            .skip(self.line as usize - std::cmp::min(self.line, 5) as usize)
-            .take(std::cmp::min(self.line, 5) as usize + 1)
-        {
-            output.push_str(&format!("{:>4}|    {}\n", ln, line));
-            if ln == self.line as usize {
-                output.push_str(&(0..9 + self.pos).map(|_| ' ').collect::<String>());
-                output.push_str(&format!("^~~~ {}\n", msg).red().to_string());
-            }
+    /// Stable diagnostic code for the kind of error
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            ErrorKind::EOF => "E0001",
+            ErrorKind::Invalid(_) => "E0002",
+            ErrorKind::ExpectedToken(_, _) => "E0003",
+            ErrorKind::Unbalanced => "E0004",
+            ErrorKind::Overflow => "E0005",
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            pos: self.pos,
         }
-        output
     }
 
     pub fn from_token(token: &Token, kind: ErrorKind) -> Error {
         Error {
             kind,
-            pos: token.pos - token.kind.size().min(token.pos as usize) as u32,
+            pos: token.pos,
             line: token.line,
         }
     }
 }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {} char {}",
+            self.description(),
+            self.line + 1,
+            self.pos + 1
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for Diagnostic {
+    fn from(e: Error) -> Diagnostic {
+        let label = match e.kind {
+            ErrorKind::EOF => "input ends here",
+            ErrorKind::Unbalanced => "no matching delimiter",
+            _ => "",
+        };
+        Diagnostic::error(e.code(), e.description()).with_primary(e.span(), label)
+    }
+}
@@ -0,0 +1,169 @@
+//! A small Wadler-style layout engine
+//!
+//! A [`Doc`] describes a piece of text along with the places where it may be
+//! broken across lines. When rendering, each [`Doc::Group`] is laid out flat
+//! if it fits in the remaining width, and broken at every [`Doc::Line`]
+//! otherwise. This is the "strict" variant of the algorithm described in
+//! Christian Lindig's "Strictly Pretty"
+
+#[derive(Debug, Clone)]
+pub enum Doc {
+    Nil,
+    Text(String),
+    /// A space when the enclosing group is flat, a line break otherwise
+    Line,
+    /// An unconditional line break. A group containing one is never flat
+    HardLine,
+    Concat(Vec<Doc>),
+    /// Indent line breaks inside by an additional number of columns
+    Nest(usize, Box<Doc>),
+    /// Indent line breaks inside to the column the [`Doc`] starts at
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+impl Doc {
+    pub fn text<S: Into<String>>(s: S) -> Doc {
+        Doc::Text(s.into())
+    }
+
+    pub fn nest(self, indent: usize) -> Doc {
+        Doc::Nest(indent, Box::new(self))
+    }
+
+    pub fn align(self) -> Doc {
+        Doc::Align(Box::new(self))
+    }
+
+    pub fn group(self) -> Doc {
+        Doc::Group(Box::new(self))
+    }
+
+    /// Lay out the document, trying to keep lines within `width` columns
+    pub fn render(&self, width: usize) -> String {
+        let mut w = Writer::default();
+        let mut stack = vec![(0, Mode::Break, self)];
+
+        while let Some((indent, mode, doc)) = stack.pop() {
+            match doc {
+                Doc::Nil => {}
+                Doc::Text(s) => w.text(s),
+                Doc::Line if mode == Mode::Flat => w.text(" "),
+                Doc::Line | Doc::HardLine => w.newline(indent),
+                Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|d| (indent, mode, d))),
+                Doc::Nest(n, doc) => stack.push((indent + n, mode, doc)),
+                Doc::Align(doc) => stack.push((w.column(), mode, doc)),
+                Doc::Group(doc) => {
+                    let flat = mode == Mode::Flat
+                        || fits(width as isize - w.column() as isize, doc, &stack);
+                    let mode = if flat { Mode::Flat } else { Mode::Break };
+                    stack.push((indent, mode, doc));
+                }
+            }
+        }
+        w.out
+    }
+}
+
+/// Output buffer that tracks the current column. Indentation is only written
+/// once text follows a line break, so empty lines never carry trailing
+/// whitespace
+#[derive(Default)]
+struct Writer {
+    out: String,
+    col: usize,
+    pending: Option<usize>,
+}
+
+impl Writer {
+    fn column(&self) -> usize {
+        self.pending.unwrap_or(self.col)
+    }
+
+    fn text(&mut self, s: &str) {
+        if let Some(n) = self.pending.take() {
+            self.out.extend((0..n).map(|_| ' '));
+            self.col = n;
+        }
+        self.out.push_str(s);
+        self.col = match s.rfind('\n') {
+            Some(idx) => s[idx + 1..].chars().count(),
+            None => self.col + s.chars().count(),
+        };
+    }
+
+    fn newline(&mut self, indent: usize) {
+        self.out.push('\n');
+        self.col = 0;
+        self.pending = Some(indent);
+    }
+}
+
+/// Determine whether `doc`, laid out flat, and whatever follows it up to the
+/// next line break fit within `width` columns
+fn fits(mut width: isize, doc: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut items = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev().map(|&(_, mode, doc)| (mode, doc));
+
+    while width >= 0 {
+        let (mode, doc) = match items.pop().or_else(|| rest.next()) {
+            Some(item) => item,
+            None => return true,
+        };
+        match doc {
+            Doc::Nil => {}
+            Doc::Text(s) => match s.find('\n') {
+                Some(idx) => return width >= s[..idx].chars().count() as isize,
+                None => width -= s.chars().count() as isize,
+            },
+            Doc::Line if mode == Mode::Flat => width -= 1,
+            Doc::HardLine if mode == Mode::Flat => return false,
+            Doc::Line | Doc::HardLine => return true,
+            Doc::Concat(docs) => items.extend(docs.iter().rev().map(|d| (mode, d))),
+            Doc::Nest(_, doc) | Doc::Align(doc) | Doc::Group(doc) => items.push((mode, doc)),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn words() -> Doc {
+        Doc::Concat(vec![
+            Doc::text("(foo"),
+            Doc::Line,
+            Doc::text("bar"),
+            Doc::Line,
+            Doc::text("baz)"),
+        ])
+        .nest(2)
+        .group()
+    }
+
+    #[test]
+    fn group_flat_or_broken() {
+        assert_eq!(words().render(80), "(foo bar baz)");
+        assert_eq!(words().render(8), "(foo\n  bar\n  baz)");
+    }
+
+    #[test]
+    fn hardline_breaks_group() {
+        let doc = Doc::Concat(vec![
+            Doc::text("a"),
+            Doc::HardLine,
+            Doc::HardLine,
+            Doc::text("b"),
+        ])
+        .nest(4)
+        .group();
+        assert_eq!(doc.render(80), "a\n\n    b");
+    }
+}
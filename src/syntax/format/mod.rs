@@ -0,0 +1,370 @@
+//! Canonical source formatting
+//!
+//! Source is lexed in trivia mode, so that comments and blank lines survive,
+//! into a small concrete syntax tree. The tree is then turned into a
+//! [`Doc`] following a handful of Lisp indentation rules and laid out by the
+//! [`layout`] engine:
+//!
+//! - a list that fits on the remaining line is printed flat
+//! - special forms with a body (`define`, `lambda`, `let`, ...) keep their
+//!   distinguished arguments on the first line and indent the body by 2
+//! - other calls keep the first argument on the first line and align the
+//!   rest with it
+//! - lists that don't start with an identifier align every element
+//!
+//! Formatting is idempotent: the output of [`format`] is a fixed point.
+mod layout;
+
+use super::{Error, ErrorKind, Lexer, Token, TokenKind};
+use crate::{Keyword, Sexp};
+use layout::Doc;
+
+/// Line width the formatter tries to stay within
+pub const WIDTH: usize = 80;
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    /// Identifier, number, boolean, string literal or `.`
+    Atom(String),
+    /// Quote, quasiquote, unquote or unquote-splicing shorthand
+    Prefix(&'static str, Box<Node>),
+    List(Vec<Node>),
+    /// Comment on a line of its own
+    Comment(String),
+    /// Comment following other tokens on the same line
+    Trailing(String),
+    /// One or more empty lines
+    Blank,
+}
+
+impl Node {
+    fn is_comment(&self) -> bool {
+        matches!(self, Node::Comment(_) | Node::Trailing(_))
+    }
+
+    fn is_datum(&self) -> bool {
+        matches!(self, Node::Atom(_) | Node::Prefix(_, _) | Node::List(_))
+    }
+}
+
+/// Build a concrete syntax tree from a trivia-preserving token stream
+struct Builder<'s> {
+    lexer: Lexer<'s>,
+    /// Comments found between a quote prefix and its datum, which are moved
+    /// in front of the prefixed datum
+    hoisted: Vec<Node>,
+}
+
+impl<'s> Builder<'s> {
+    /// Read nodes until the closing paren of the current list, or until the
+    /// end of input at top level
+    fn nodes(&mut self, nested: bool) -> Result<Vec<Node>, Error> {
+        let mut nodes = Vec::new();
+        // A comment is on its own line if a line break precedes it
+        let mut newline = !nested;
+        loop {
+            let token = self.lexer.next_token()?;
+            match token.kind {
+                TokenKind::Newline(n) => {
+                    if n > 1 && nodes.last().map(|n| *n != Node::Blank).unwrap_or(false) {
+                        nodes.push(Node::Blank);
+                    }
+                    newline = true;
+                    continue;
+                }
+                TokenKind::Comment(s) => {
+                    let s = s.trim_end().to_string();
+                    nodes.push(if newline {
+                        Node::Comment(s)
+                    } else {
+                        Node::Trailing(s)
+                    });
+                }
+                TokenKind::RightParen if nested => break,
+                TokenKind::RightParen => {
+                    return Err(Error::from_token(&token, ErrorKind::Unbalanced))
+                }
+                TokenKind::EOF if nested => return Err(Error::from_token(&token, ErrorKind::EOF)),
+                TokenKind::EOF => break,
+                _ => {
+                    let node = self.datum(token)?;
+                    nodes.append(&mut self.hoisted);
+                    nodes.push(node);
+                }
+            }
+            newline = false;
+        }
+        if let Some(Node::Blank) = nodes.last() {
+            nodes.pop();
+        }
+        Ok(nodes)
+    }
+
+    fn datum(&mut self, token: Token) -> Result<Node, Error> {
+        let prefix = match token.kind {
+            TokenKind::LeftParen => return Ok(Node::List(self.nodes(true)?)),
+            TokenKind::Quote => "'",
+            TokenKind::Quasiquote => "`",
+            TokenKind::Unquote => ",",
+            TokenKind::UnquoteAt => ",@",
+            TokenKind::Dot => return Ok(Node::Atom(".".into())),
+            TokenKind::Boolean(true) => return Ok(Node::Atom("#t".into())),
+            TokenKind::Boolean(false) => return Ok(Node::Atom("#f".into())),
+            TokenKind::Integer(i) => return Ok(Node::Atom(i.to_string())),
+            TokenKind::Literal(s) => return Ok(Node::Atom(Sexp::Literal(s).write().to_string())),
+            TokenKind::Identifier(s) => {
+                let sexp = match Keyword::from_name(&s) {
+                    Some(kw) => Sexp::Keyword(kw),
+                    None => Sexp::Identifier(s),
+                };
+                return Ok(Node::Atom(sexp.write().to_string()));
+            }
+            _ => return Err(Error::from_token(&token, ErrorKind::Unbalanced)),
+        };
+        loop {
+            let token = self.lexer.next_token()?;
+            match token.kind {
+                TokenKind::Newline(_) => {}
+                TokenKind::Comment(s) => self.hoisted.push(Node::Comment(s.trim_end().into())),
+                TokenKind::EOF => return Err(Error::from_token(&token, ErrorKind::EOF)),
+                _ => return Ok(Node::Prefix(prefix, Box::new(self.datum(token)?))),
+            }
+        }
+    }
+}
+
+/// Indentation rule selected by the head of a list
+#[derive(Copy, Clone, Debug)]
+enum Style {
+    /// Every form on its own line, at column 0
+    TopLevel,
+    /// Elements aligned with the first one
+    Data,
+    /// Arguments aligned with the first argument, which follows the head
+    Call(usize),
+    /// Special form with a number of distinguished arguments on the first
+    /// line, and a body indented by 2
+    Body(usize, usize),
+}
+
+impl Style {
+    fn of(children: &[Node]) -> Style {
+        let mut datums = children.iter().filter(|n| n.is_datum());
+        let head = match datums.next() {
+            Some(Node::Atom(head)) if is_symbol(head) => head,
+            _ => return Style::Data,
+        };
+        let width = head.chars().count();
+        let specials = match head.as_ref() {
+            "begin" | "delay" | "delay-force" | "case-lambda" => 0,
+            "lambda" | "define" | "define-syntax" | "define-macro" | "define-values"
+            | "define-library" | "define-record-type" | "let*" | "letrec" | "letrec*"
+            | "let-values" | "let*-values" | "let-syntax" | "letrec-syntax" | "syntax-rules"
+            | "when" | "unless" | "case" | "parameterize" | "guard" => 1,
+            "do" => 2,
+            "let" => match datums.next() {
+                Some(Node::Atom(name)) if is_symbol(name) => 2,
+                _ => 1,
+            },
+            _ => return Style::Call(width),
+        };
+        Style::Body(specials, width)
+    }
+
+    /// Indentation, relative to the opening paren, of the `n`th datum when
+    /// it starts a new line
+    fn indent(self, n: usize) -> usize {
+        match self {
+            Style::TopLevel => 0,
+            _ if n == 0 => 1,
+            Style::Data => 1,
+            Style::Call(width) => width + 2,
+            Style::Body(specials, width) if n <= specials => width + 2,
+            Style::Body(_, _) => 2,
+        }
+    }
+
+    /// Whether the `n`th datum always stays on the same line as the head
+    fn hangs(self, n: usize) -> bool {
+        match self {
+            Style::Call(_) => n == 1,
+            Style::Body(specials, _) => n >= 1 && n <= specials,
+            _ => false,
+        }
+    }
+}
+
+fn is_symbol(atom: &str) -> bool {
+    match atom.chars().next() {
+        Some(ch) => !(ch.is_numeric() || ch == '"' || ch == '#' || ch == '.'),
+        None => false,
+    }
+}
+
+fn comment(text: &str) -> Doc {
+    Doc::text(format!(";{}", text))
+}
+
+/// Lay out a sequence of nodes, choosing the separator in front of each one
+fn sequence(children: &[Node], style: Style) -> Vec<Doc> {
+    let mut docs = Vec::new();
+    let mut prev: Option<&Node> = None;
+    let mut blank = false;
+    let mut n = 0;
+
+    for child in children {
+        let indent = style.indent(n);
+        match child {
+            Node::Blank => {
+                blank = true;
+                continue;
+            }
+            Node::Trailing(text) => {
+                docs.push(Doc::text(" "));
+                docs.push(comment(text));
+            }
+            _ => {
+                let sep = match prev {
+                    // An own-line comment directly after an opening paren
+                    None if child.is_comment() && !matches!(style, Style::TopLevel) => {
+                        Doc::HardLine
+                    }
+                    None => Doc::Nil,
+                    Some(prev) if blank || prev.is_comment() || child.is_comment() => Doc::HardLine,
+                    Some(_) if matches!(style, Style::TopLevel) => Doc::HardLine,
+                    Some(_) if style.hangs(n) => Doc::text(" "),
+                    Some(_) => Doc::Line,
+                };
+                if blank {
+                    docs.push(Doc::HardLine);
+                }
+                docs.push(sep.nest(indent));
+                docs.push(match child {
+                    Node::Comment(text) => comment(text),
+                    node => node_doc(node),
+                });
+                if child.is_datum() {
+                    n += 1;
+                }
+            }
+        }
+        prev = Some(child);
+        blank = false;
+    }
+    docs
+}
+
+fn node_doc(node: &Node) -> Doc {
+    match node {
+        Node::Atom(s) => Doc::text(s.as_str()),
+        Node::Prefix(prefix, inner) => Doc::Concat(vec![Doc::text(*prefix), node_doc(inner)]),
+        Node::List(children) => {
+            let mut docs = vec![Doc::text("(")];
+            docs.extend(sequence(children, Style::of(children)));
+            // The closing paren can't follow a comment on the same line
+            if children.last().map(Node::is_comment).unwrap_or(false) {
+                docs.push(Doc::HardLine);
+            }
+            docs.push(Doc::text(")"));
+            Doc::Concat(docs).group().align()
+        }
+        Node::Comment(text) | Node::Trailing(text) => comment(text),
+        Node::Blank => Doc::Nil,
+    }
+}
+
+/// Format source code into canonical layout, within `width` columns where
+/// possible
+pub fn format(input: &str, width: usize) -> Result<String, Error> {
+    let mut builder = Builder {
+        lexer: Lexer::with_trivia(input),
+        hoisted: Vec::new(),
+    };
+    let nodes = builder.nodes(false)?;
+    if nodes.is_empty() {
+        return Ok(String::new());
+    }
+    let mut out = Doc::Concat(sequence(&nodes, Style::TopLevel)).render(width);
+    out.push('\n');
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fmt(s: &str) -> String {
+        let out = format(s, WIDTH).unwrap();
+        assert_eq!(
+            format(&out, WIDTH).unwrap(),
+            out,
+            "formatting is idempotent"
+        );
+        out
+    }
+
+    #[test]
+    fn flat_when_fits() {
+        assert_eq!(
+            fmt("(define   x\n   (cons 1   '( 2 3)))"),
+            "(define x (cons 1 '(2 3)))\n"
+        );
+    }
+
+    #[test]
+    fn body_and_call_indentation() {
+        let input = "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)) (fact (- n 1)) (fact (- n 1)) (fact (- n 1)))))";
+        let expected = "\
+(define (fact n)
+  (if (= n 0)
+      1
+      (* n (fact (- n 1)) (fact (- n 1)) (fact (- n 1)) (fact (- n 1)))))
+";
+        assert_eq!(fmt(input), expected);
+    }
+
+    #[test]
+    fn named_let_and_data() {
+        let input = "(let loop ((i 0) (acc '())) (if (> i 10) acc (loop (+ i 1) (cons i acc))) (display i))";
+        let expected = "\
+(let loop ((i 0) (acc '()))
+  (if (> i 10) acc (loop (+ i 1) (cons i acc)))
+  (display i))
+";
+        assert_eq!(fmt(input), expected);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let input = "\
+;; header
+
+
+(define x 1) ; trailing
+(define (f)
+   ;; inside
+   x   ; more
+
+   (g x ; after x
+      ))
+";
+        let expected = "\
+;; header
+
+(define x 1) ; trailing
+(define (f)
+  ;; inside
+  x ; more
+
+  (g x ; after x
+  ))
+";
+        assert_eq!(fmt(input), expected);
+    }
+
+    #[test]
+    fn unbalanced() {
+        assert!(format("(define x", WIDTH).is_err());
+        assert!(format("x)", WIDTH).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 //! Tokenization of input streams
 #![allow(dead_code)]
 use super::error::{Error, ErrorKind};
+use super::Span;
 use std::iter::Peekable;
 use std::str;
 pub mod token;
@@ -10,6 +11,9 @@ pub struct Lexer<'s> {
     input: Peekable<str::Chars<'s>>,
     pos: u32,
     line: u32,
+    trivia: bool,
+    /// Start of the token being read
+    start: Span,
 }
 
 impl<'s> Lexer<'s> {
@@ -19,21 +23,42 @@ impl<'s> Lexer<'s> {
             input: input.chars().peekable(),
             pos: 0,
             line: 0,
+            trivia: false,
+            start: Span::default(),
         }
     }
 
-    /// Return a [`Token`] containing source position
+    /// Construct a new Lexer that emits comments and line breaks as
+    /// [`TokenKind::Comment`] and [`TokenKind::Newline`] tokens, instead of
+    /// discarding them. Used by the source formatter
+    pub fn with_trivia(input: &'s str) -> Lexer<'s> {
+        Lexer {
+            trivia: true,
+            ..Lexer::new(input)
+        }
+    }
+
+    /// Current position in the source
+    fn here(&self) -> Span {
+        Span {
+            line: self.line,
+            pos: self.pos,
+        }
+    }
+
+    /// Return a [`Token`] spanning from the start of the token being read
+    /// up to the current position
     fn token(&self, kind: TokenKind) -> Result<Token, Error> {
-        let sz = kind.size() as u32;
         Ok(Token {
             kind,
-            pos: self.pos - sz.min(self.pos),
-            line: self.line,
+            pos: self.start.pos,
+            line: self.start.line,
+            end: self.here(),
         })
     }
 
     /// Return an [`Error`] containing source position
-    pub fn error(&self, kind: ErrorKind) -> Result<Token, Error> {
+    pub fn error<T>(&self, kind: ErrorKind) -> Result<T, Error> {
         Err(Error {
             kind,
             pos: self.pos,
@@ -86,43 +111,67 @@ impl<'s> Lexer<'s> {
             return self.error(ErrorKind::EOF);
         }
         match ident.as_ref() {
-            "#t" => self.token(TokenKind::Boolean(true)),
-            "true" => self.token(TokenKind::Boolean(true)),
-            "#f" => self.token(TokenKind::Boolean(false)),
-            "false" => self.token(TokenKind::Boolean(false)),
-            "quote" => self.token(TokenKind::Quote),
-            "quasiquote" => self.token(TokenKind::Quasiquote),
-            "unquote" => self.token(TokenKind::Unquote),
+            "." => self.token(TokenKind::Dot),
+            "#t" | "#true" | "true" => self.token(TokenKind::Boolean(true)),
+            "#f" | "#false" | "false" => self.token(TokenKind::Boolean(false)),
+            _ if is_signed_integer(&ident) => match ident.parse::<i64>() {
+                Ok(i) => self.token(TokenKind::Integer(i)),
+                Err(_) => self.error(ErrorKind::Overflow),
+            },
             _ => self.token(TokenKind::Identifier(ident)),
         }
     }
 
-    fn read_literal(&mut self) -> Result<Token, Error> {
-        if let Some('"') = self.consume() {
-            let start = (self.line, self.pos - 1);
-            let ret = self.consume_while(|ch| ch != '"');
-            if self.consume().is_none() {
-                return Err(Error {
-                    kind: ErrorKind::Unbalanced,
-                    pos: start.1,
-                    line: start.0,
-                });
+    /// Read the contents of a string literal or `|identifier|`, up to the
+    /// closing `delim`, and replace escape sequences
+    fn read_delimited(&mut self, delim: char) -> Result<String, Error> {
+        let start = (self.line, self.pos);
+        self.consume();
+        let mut s = String::new();
+        loop {
+            match self.consume() {
+                Some(ch) if ch == delim => return Ok(s),
+                Some('\\') => match self.consume() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('a') => s.push('\u{7}'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('0') => s.push('\0'),
+                    Some('x') => {
+                        let hex = self.consume_while(|ch| ch != ';');
+                        self.consume();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(std::char::from_u32) {
+                            Some(ch) => s.push(ch),
+                            None => return self.error(ErrorKind::Invalid('x')),
+                        }
+                    }
+                    Some(ch) if ch == '\\' || ch == '"' || ch == '|' => s.push(ch),
+                    Some(ch) => return self.error(ErrorKind::Invalid(ch)),
+                    None => break,
+                },
+                Some(ch) => s.push(ch),
+                None => break,
             }
-            self.token(TokenKind::Literal(ret))
-        } else {
-            self.error(ErrorKind::EOF)
         }
+        Err(Error {
+            kind: ErrorKind::Unbalanced,
+            pos: start.1,
+            line: start.0,
+        })
     }
 
-    fn read_number(&mut self) -> Result<Token, Error> {
-        let s = self.consume_while(char::is_numeric);
-        let i = s.parse::<i64>().expect("Verified numeric chars");
+    fn read_literal(&mut self) -> Result<Token, Error> {
+        let s = self.read_delimited('"')?;
+        self.token(TokenKind::Literal(s))
+    }
 
-        Ok(Token {
-            pos: self.pos - s.len().min(self.pos as usize) as u32,
-            line: self.line,
-            kind: TokenKind::Integer(i),
-        })
+    fn read_number(&mut self) -> Result<Token, Error> {
+        let s = self.consume_while(|ch| ch.is_ascii_digit());
+        match s.parse::<i64>() {
+            Ok(i) => self.token(TokenKind::Integer(i)),
+            Err(_) => self.error(ErrorKind::Overflow),
+        }
     }
 
     fn advance(&mut self, token: TokenKind) -> Result<Token, Error> {
@@ -135,7 +184,14 @@ impl<'s> Lexer<'s> {
     /// Return the next [`Token`] from the input stream
     pub fn next_token(&mut self) -> Result<Token, Error> {
         // Eat whitespace at beginning of current input
-        self.consume_while(char::is_whitespace);
+        let ws = self.consume_while(char::is_whitespace);
+        self.start = self.here();
+        if self.trivia {
+            let lines = ws.matches('\n').count() as u32;
+            if lines > 0 {
+                return self.token(TokenKind::Newline(lines));
+            }
+        }
 
         if let Some(&ch) = self.peek() {
             match ch {
@@ -143,8 +199,12 @@ impl<'s> Lexer<'s> {
                 ')' => self.advance(TokenKind::RightParen),
                 ';' => {
                     // Comment, read til end of line
-                    self.consume_while(|ch| ch != '\n');
-                    self.next_token()
+                    let text = self.consume_while(|ch| ch != '\n');
+                    if self.trivia {
+                        self.token(TokenKind::Comment(text[1..].to_string()))
+                    } else {
+                        self.next_token()
+                    }
                 }
                 '\'' => self.advance(TokenKind::Quote),
                 '`' => self.advance(TokenKind::Quasiquote),
@@ -156,19 +216,20 @@ impl<'s> Lexer<'s> {
                         self.token(TokenKind::Unquote)
                     }
                 }
-                '.' => self.advance(TokenKind::Dot),
+                // `.` on its own, or a peculiar identifier such as `...`
+                '.' => self.read_identifier(),
                 '"' => self.read_literal(),
+                '|' => {
+                    let ident = self.read_delimited('|')?;
+                    self.token(TokenKind::Identifier(ident))
+                }
                 'λ' => self.advance(TokenKind::Identifier("lambda".to_string())),
-                x if x.is_numeric() => self.read_number(),
+                x if x.is_ascii_digit() => self.read_number(),
                 x if is_identifier_char(x) => self.read_identifier(),
                 _ => self.error(ErrorKind::Invalid(ch)),
             }
         } else {
-            Ok(Token {
-                kind: TokenKind::EOF,
-                pos: self.pos,
-                line: self.line,
-            })
+            self.token(TokenKind::EOF)
         }
     }
 
@@ -189,10 +250,33 @@ impl<'s> Lexer<'s> {
 }
 
 fn is_identifier_char(ch: char) -> bool {
-    let valid = "~!@#$%^&*-_+=|?.<>/";
+    let valid = "~!@#$%^&*-_+=?.<>/";
     ch.is_alphanumeric() || valid.contains(ch)
 }
 
+/// `[+-]?[0-9]+`
+fn is_signed_integer(s: &str) -> bool {
+    let digits = s.trim_start_matches(['+', '-']);
+    s.len() - digits.len() <= 1
+        && !digits.is_empty()
+        && digits.chars().all(|ch| ch.is_ascii_digit())
+}
+
+/// Whether `s` is read back by the lexer as a single identifier token
+/// with exactly the same text, i.e. it can be written without `|` bars
+pub fn is_plain_identifier(s: &str) -> bool {
+    match Lexer::new(s).lex() {
+        Ok(tokens) => match tokens.as_slice() {
+            [Token {
+                kind: TokenKind::Identifier(ident),
+                ..
+            }] => ident == s && !s.starts_with('λ'),
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -205,41 +289,49 @@ mod test {
                 line: 0,
                 pos: 0,
                 kind: TokenKind::LeftParen,
+                end: Span { line: 0, pos: 1 },
             },
             Token {
                 line: 0,
                 pos: 1,
                 kind: TokenKind::Identifier("cons".to_string()),
+                end: Span { line: 0, pos: 5 },
             },
             Token {
                 line: 0,
                 pos: 6,
                 kind: TokenKind::LeftParen,
+                end: Span { line: 0, pos: 7 },
             },
             Token {
                 line: 0,
                 pos: 7,
                 kind: TokenKind::Identifier("cons".to_string()),
+                end: Span { line: 0, pos: 11 },
             },
             Token {
                 line: 0,
                 pos: 12,
                 kind: TokenKind::Integer(1),
+                end: Span { line: 0, pos: 13 },
             },
             Token {
                 line: 0,
                 pos: 14,
                 kind: TokenKind::Integer(2),
+                end: Span { line: 0, pos: 15 },
             },
             Token {
                 line: 0,
                 pos: 15,
                 kind: TokenKind::RightParen,
+                end: Span { line: 0, pos: 16 },
             },
             Token {
                 line: 0,
                 pos: 16,
                 kind: TokenKind::RightParen,
+                end: Span { line: 0, pos: 17 },
             },
         ];
 
@@ -259,7 +351,7 @@ mod test {
             .map(|tok| tok.kind)
             .collect::<Vec<TokenKind>>();
         let expected = ["lambda", "define", "let", "if"]
-            .into_iter()
+            .iter()
             .map(|s| TokenKind::Identifier(s.to_string()))
             .collect::<Vec<TokenKind>>();
         assert_eq!(expected, tokens);
@@ -277,4 +369,72 @@ mod test {
         });
         assert_eq!(expected, tokens);
     }
+
+    #[test]
+    fn lex_dots() {
+        let kinds = Lexer::new("(a . b) ... ...+")
+            .lex()
+            .unwrap()
+            .into_iter()
+            .map(|tok| tok.kind)
+            .collect::<Vec<TokenKind>>();
+        let id = |s: &str| TokenKind::Identifier(s.to_string());
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::LeftParen,
+                id("a"),
+                TokenKind::Dot,
+                id("b"),
+                TokenKind::RightParen,
+                id("..."),
+                id("...+"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_trivia() {
+        let input = "(f x) ; trailing\n\n;; own line\ny";
+        let tokens = Lexer::with_trivia(input)
+            .lex()
+            .unwrap()
+            .into_iter()
+            .map(|tok| tok.kind)
+            .collect::<Vec<TokenKind>>();
+        let expected = vec![
+            TokenKind::LeftParen,
+            TokenKind::Identifier("f".to_string()),
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::RightParen,
+            TokenKind::Comment(" trailing".to_string()),
+            TokenKind::Newline(2),
+            TokenKind::Comment("; own line".to_string()),
+            TokenKind::Newline(1),
+            TokenKind::Identifier("y".to_string()),
+        ];
+        assert_eq!(expected, tokens);
+
+        let plain = Lexer::new(input).lex().unwrap();
+        assert_eq!(plain.len(), 5);
+    }
+
+    #[test]
+    fn lex_extents() {
+        let tokens = Lexer::new("\"a\\\"b\" |x y|\n  ,@z").lex().unwrap();
+        let at = |line, pos| Span { line, pos };
+        let extents = tokens
+            .iter()
+            .map(|tok| (tok.span(), tok.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            extents,
+            vec![
+                (at(0, 0), at(0, 6)),
+                (at(0, 7), at(0, 12)),
+                (at(1, 2), at(1, 4)),
+                (at(1, 4), at(1, 5)),
+            ]
+        );
+    }
 }
@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+use super::super::Span;
 
 #[derive(PartialEq, PartialOrd, Debug, Clone)]
 pub enum TokenKind {
@@ -13,6 +14,14 @@ pub enum TokenKind {
     Integer(i64),
     Literal(String),
     Identifier(String),
+    /// Text of a `;` comment, excluding the leading `;`. Only produced
+    /// by a [`Lexer`] constructed with [`Lexer::with_trivia`]
+    ///
+    /// [`Lexer`]: super::Lexer
+    /// [`Lexer::with_trivia`]: super::Lexer::with_trivia
+    Comment(String),
+    /// One or more line breaks. Only produced in trivia mode
+    Newline(u32),
     EOF,
 }
 
@@ -21,17 +30,15 @@ pub struct Token {
     pub kind: TokenKind,
     pub line: u32,
     pub pos: u32,
+    /// Position just past the last character of the token
+    pub end: Span,
 }
 
-impl TokenKind {
-    pub fn size(&self) -> usize {
-        match self {
-            TokenKind::Boolean(true) => 4,
-            TokenKind::Boolean(false) => 5,
-            TokenKind::Integer(i) => i.to_string().len(),
-            TokenKind::Literal(s) => s.len(),
-            TokenKind::Identifier(s) => s.len(),
-            _ => 1,
+impl Token {
+    pub fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            pos: self.pos,
         }
     }
 }
@@ -1,6 +1,8 @@
 mod error;
+mod format;
 mod lexer;
 mod parser;
+mod span;
 
 pub use error::{Error, ErrorKind};
 pub use lexer::{
@@ -8,21 +10,28 @@ pub use lexer::{
     Lexer,
 };
 pub use parser::Parser;
+pub use span::{Extent, Span, SpanTree};
+pub(crate) use lexer::is_plain_identifier;
 
 use super::*;
 
-pub fn lex<S: AsRef<str>>(s: S) -> Result<Vec<Token>, String> {
-    match Lexer::new(s.as_ref()).lex() {
-        Ok(v) => Ok(v),
-        Err(e) => Err(e.message(s)),
-    }
+pub fn lex<S: AsRef<str>>(s: S) -> Result<Vec<Token>, Diagnostic> {
+    Ok(Lexer::new(s.as_ref()).lex()?)
 }
 
-pub fn parse<S: AsRef<str>>(s: S) -> Result<Vec<Sexp>, String> {
-    match Parser::new(s.as_ref()).parse() {
-        Ok(v) => Ok(v),
-        Err(e) => Err(e.message(s)),
-    }
+pub fn parse<S: AsRef<str>>(s: S) -> Result<Vec<Sexp>, Diagnostic> {
+    Ok(Parser::new(s.as_ref()).parse()?)
+}
+
+/// Parse source code, along with the location of every expression
+pub fn parse_spanned<S: AsRef<str>>(s: S) -> Result<Vec<(Sexp, SpanTree)>, Diagnostic> {
+    Ok(Parser::new(s.as_ref()).parse_spanned()?)
+}
+
+/// Rewrite source code into the canonical layout used by `zymogen fmt`,
+/// preserving comments and blank lines
+pub fn format<S: AsRef<str>>(s: S) -> Result<String, Diagnostic> {
+    Ok(format::format(s.as_ref(), format::WIDTH)?)
 }
 
 #[cfg(test)]
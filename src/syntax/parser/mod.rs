@@ -1,5 +1,9 @@
 use super::*;
 
+/// An expression, the locations of its elements if it is a list, and the
+/// position just past its end
+type Parsed = (Sexp, Vec<SpanTree>, Span);
+
 pub struct Parser<'l> {
     lexer: Lexer<'l>,
     peek: Result<Token, Error>,
@@ -33,19 +37,26 @@ impl<'l> Parser<'l> {
         }
     }
 
-    fn parse_list(&mut self) -> Result<Sexp, Error> {
+    /// Parse the rest of a list, returning its elements along with their
+    /// locations, and the end of the closing paren
+    fn parse_list(&mut self) -> Result<Parsed, Error> {
         let mut vec = Vec::new();
-        loop {
+        let mut spans = Vec::new();
+        let end = loop {
             match self.peek() {
                 Ok(token) => {
                     match &token.kind {
                         TokenKind::Dot => {
                             vec.push(Sexp::Keyword(Keyword::Dot));
+                            spans.push(SpanTree {
+                                span: token.span(),
+                                end: token.end,
+                                elements: Vec::new(),
+                            });
                             self.consume()?;
                         }
                         TokenKind::RightParen => {
-                            self.expect(TokenKind::RightParen)?;
-                            break;
+                            break self.expect(TokenKind::RightParen)?.end;
                         }
                         TokenKind::EOF => return Err(Error::from_token(token, ErrorKind::EOF)),
                         _ => {
@@ -54,60 +65,43 @@ impl<'l> Parser<'l> {
                             // parse_expr() only returns None when TokenKind::EOF
                             // is the consumed() token. However we still need to
                             // try! the potential parsing Error
-                            vec.push(self.parse_expr().expect("Unrecoverable error in parser")?);
+                            let (exp, span) = self
+                                .parse_spanned_expr()
+                                .expect("Unrecoverable error in parser")?;
+                            vec.push(exp);
+                            spans.push(span);
                         }
                     }
                 }
                 Err(e) => return Err(e.clone()),
             }
-        }
+        };
 
         let mut list = List::Nil;
         while let Some(exp) = vec.pop() {
             list = List::Cons(Box::new(exp), Box::new(list));
         }
-        Ok(Sexp::List(list))
+        Ok((Sexp::List(list), spans, end))
     }
 
     fn keyword(ident: String) -> Result<Sexp, Error> {
-        use super::Keyword::*;
-        use Sexp::*;
-        let res = match ident.as_ref() {
-            "quote" => Keyword(Quote),
-            "lambda" => Keyword(Lambda),
-            "if" => Keyword(If),
-            "set!" => Keyword(Set),
-            "begin" => Keyword(Begin),
-            "cond" => Keyword(Cond),
-            "and" => Keyword(And),
-            "or" => Keyword(Or),
-            "case" => Keyword(Case),
-            "let" => Keyword(Let),
-            "let*" => Keyword(Letstar),
-            "letrec" => Keyword(Letrec),
-            "do" => Keyword(Do),
-            "delay" => Keyword(Delay),
-            "quasiquote" => Keyword(Quasiquote),
-            "else" => Keyword(Else),
-            "define" => Keyword(Define),
-            "unquote" => Keyword(Unquote),
-            "unqoute-splice" | "unquoteat" => Keyword(UnquoteAt),
-            _ => Identifier(ident),
-        };
-        Ok(res)
+        match super::Keyword::from_name(&ident) {
+            Some(kw) => Ok(Sexp::Keyword(kw)),
+            None => Ok(Sexp::Identifier(ident)),
+        }
     }
 
-    fn parse_quote(&mut self, kw: Keyword) -> Option<Result<Sexp, Error>> {
-        if let Some(exp) = self.parse_expr() {
-            match exp {
-                Ok(exp) => Some(Ok(Sexp::List(List::Cons(
+    fn parse_quote(&mut self, kw: Keyword, quote: SpanTree) -> Option<Result<Parsed, Error>> {
+        match self.parse_spanned_expr()? {
+            Ok((exp, tree)) => {
+                let end = tree.end;
+                let list = Sexp::List(List::Cons(
                     Box::new(Sexp::Keyword(kw)),
                     Box::new(List::Cons(Box::new(exp), Box::new(List::Nil))),
-                )))),
-                Err(e) => Some(Err(e)),
+                ));
+                Some(Ok((list, vec![quote, tree], end)))
             }
-        } else {
-            None
+            Err(e) => Some(Err(e)),
         }
     }
 
@@ -118,27 +112,50 @@ impl<'l> Parser<'l> {
     /// TODO: Look into refactoring the parse module to just call syntax::lex()
     /// and operate on a vec of tokens, instead of lexing on demand
     pub fn parse_expr(&mut self) -> Option<Result<Sexp, Error>> {
+        Some(self.parse_spanned_expr()?.map(|(exp, _)| exp))
+    }
+
+    /// Parse the next expression along with its [`SpanTree`]
+    fn parse_spanned_expr(&mut self) -> Option<Result<(Sexp, SpanTree), Error>> {
         use TokenKind::*;
         let token = match self.consume() {
             Err(e) => return Some(Err(e)),
             Ok(token) => token,
         };
+        let span = token.span();
+        let leaf = SpanTree {
+            span,
+            end: token.end,
+            elements: Vec::new(),
+        };
+        let atom = |exp| Ok((exp, Vec::new(), token.end));
 
         let expr = match token.kind {
             LeftParen => self.parse_list(),
             RightParen => Err(Error::from_token(&token, ErrorKind::Unbalanced)),
-            Quote => self.parse_quote(Keyword::Quote)?,
-            Quasiquote => self.parse_quote(Keyword::Quasiquote)?,
-            Unquote => self.parse_quote(Keyword::Unquote)?,
-            UnquoteAt => self.parse_quote(Keyword::UnquoteAt)?,
+            Quote => self.parse_quote(Keyword::Quote, leaf)?,
+            Quasiquote => self.parse_quote(Keyword::Quasiquote, leaf)?,
+            Unquote => self.parse_quote(Keyword::Unquote, leaf)?,
+            UnquoteAt => self.parse_quote(Keyword::UnquoteAt, leaf)?,
             Dot => Err(Error::from_token(&token, ErrorKind::Unbalanced)),
-            Boolean(b) => Ok(Sexp::Boolean(b)),
-            Integer(i) => Ok(Sexp::Integer(i)),
-            Literal(s) => Ok(Sexp::Literal(s)),
-            Identifier(s) => Parser::keyword(s),
+            Boolean(b) => atom(Sexp::Boolean(b)),
+            Integer(i) => atom(Sexp::Integer(i)),
+            Literal(ref s) => atom(Sexp::Literal(s.clone())),
+            Identifier(ref s) => Parser::keyword(s.clone()).and_then(atom),
+            // Trivia is only produced by a Lexer in trivia mode
+            Comment(_) | Newline(_) => return self.parse_spanned_expr(),
             EOF => return None,
         };
-        Some(expr)
+        Some(expr.map(|(exp, elements, end)| {
+            (
+                exp,
+                SpanTree {
+                    span,
+                    end,
+                    elements,
+                },
+            )
+        }))
     }
 
     /// Consume a [`Parser`], returning a list of [`Expression`]'s, or an
@@ -146,70 +163,50 @@ impl<'l> Parser<'l> {
     pub fn parse(mut self) -> Result<Vec<Sexp>, Error> {
         std::iter::repeat_with(|| self.parse_expr())
             .take_while(Option::is_some)
-            .filter_map(|x| x)
+            .flatten()
             .collect::<Result<Vec<Sexp>, Error>>()
     }
+
+    /// Consume a [`Parser`], returning each expression along with its
+    /// location in the source
+    pub fn parse_spanned(mut self) -> Result<Vec<(Sexp, SpanTree)>, Error> {
+        std::iter::repeat_with(|| self.parse_spanned_expr())
+            .take_while(Option::is_some)
+            .flatten()
+            .collect::<Result<Vec<_>, Error>>()
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Keyword::*;
     use super::*;
 
-    fn cons(car: Sexp, cdr: List) -> List {
-        List::Cons(Box::new(car), Box::new(cdr))
-    }
-
-    fn id(s: &str) -> Sexp {
-        Sexp::Identifier(s.to_string())
-    }
-
     #[test]
     fn parse_keywords() {
         let input = "(let ((x 0) (y 0))
             (lambda () `(cons ,x y)))";
-        use super::Sexp::*;
-        let expected = List(cons(
-            Keyword(Let),
-            cons(
-                List(cons(
-                    List(cons(id("x"), cons(Integer(0), super::List::Nil))),
-                    cons(
-                        List(cons(id("y"), cons(Integer(0), super::List::Nil))),
-                        super::List::Nil,
-                    ),
-                )),
-                cons(
-                    List(cons(
-                        Keyword(Lambda),
-                        cons(
-                            List(super::List::Nil),
-                            cons(
-                                List(cons(
-                                    Keyword(Quasiquote),
-                                    cons(
-                                        List(cons(
-                                            id("cons"),
-                                            cons(
-                                                List(cons(
-                                                    Keyword(Unquote),
-                                                    cons(id("x"), super::List::Nil),
-                                                )),
-                                                cons(id("y"), super::List::Nil),
-                                            ),
-                                        )),
-                                        super::List::Nil,
-                                    ),
-                                )),
-                                super::List::Nil,
-                            ),
-                        ),
-                    )),
-                    super::List::Nil,
-                ),
-            ),
-        ));
-
+        let expected = sexp!((let ((x 0) (y 0)) (lambda () (quasiquote (cons (unquote x) y)))));
         assert_eq!(Parser::new(input).parse(), Ok(vec![expected]));
     }
+
+    #[test]
+    fn parse_spans() {
+        let input = "(f 'x\n   (g 1))";
+        let (_, tree) = Parser::new(input).parse_spanned().unwrap().remove(0);
+        let at = |line, pos| Span { line, pos };
+        assert_eq!(tree.span, at(0, 0));
+        assert_eq!(tree.element(0).unwrap().span, at(0, 1));
+        let quote = tree.element(1).unwrap();
+        assert_eq!(quote.span, at(0, 3));
+        assert_eq!(quote.element(1).unwrap().span, at(0, 4));
+        let inner = tree.element(2).unwrap();
+        assert_eq!(inner.span, at(1, 3));
+        assert_eq!(inner.element(1).unwrap().span, at(1, 6));
+
+        assert_eq!(tree.end, at(1, 9));
+        assert_eq!(quote.end, at(0, 5));
+        assert_eq!(quote.element(0).unwrap().end, at(0, 4));
+        assert_eq!(inner.end, at(1, 8));
+        assert_eq!(inner.element(0).unwrap().end, at(1, 5));
+    }
 }
@@ -0,0 +1,84 @@
+//! Source locations of parsed expressions
+
+/// Line and character position of the start of a token or expression
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub line: u32,
+    pub pos: u32,
+}
+
+/// Source text from `start` up to, but not including, `end`. A [`Span`]
+/// converts into the empty extent at its position, for locations whose end
+/// is not known
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Extent {
+    pub start: Span,
+    pub end: Span,
+}
+
+impl From<Span> for Extent {
+    fn from(span: Span) -> Extent {
+        Extent {
+            start: span,
+            end: span,
+        }
+    }
+}
+
+/// Source location of a parsed [`Sexp`], along with the location of each
+/// element when it is a list.
+///
+/// Returned alongside expressions by [`Parser::parse_spanned`], so that
+/// consumers of a [`Sexp`] can point back into the source.
+///
+/// [`Sexp`]: crate::Sexp
+/// [`Parser::parse_spanned`]: super::Parser::parse_spanned
+#[derive(Clone, Debug, Default, PartialEq, PartialOrd)]
+pub struct SpanTree {
+    pub span: Span,
+    /// Position just past the last character of the expression
+    pub end: Span,
+    pub elements: Vec<SpanTree>,
+}
+
+impl SpanTree {
+    /// Location of an expression whose end is not known
+    pub fn leaf(span: Span) -> SpanTree {
+        SpanTree {
+            span,
+            end: span,
+            elements: Vec::new(),
+        }
+    }
+
+    pub fn extent(&self) -> Extent {
+        Extent {
+            start: self.span,
+            end: self.end,
+        }
+    }
+
+    /// Location of the `idx`th element of a list
+    pub fn element(&self, idx: usize) -> Option<&SpanTree> {
+        self.elements.get(idx)
+    }
+
+    /// Location of the expression found by following `path` into nested
+    /// lists. A path that leads past the end of a list, such as one to a
+    /// missing element, stops at the list itself
+    pub fn locate(&self, path: &[usize]) -> Span {
+        self.find(path).span
+    }
+
+    /// The tree found by following `path`, as in [`SpanTree::locate`]
+    pub fn find(&self, path: &[usize]) -> &SpanTree {
+        let mut tree = self;
+        for &idx in path {
+            match tree.element(idx) {
+                Some(elem) => tree = elem,
+                None => break,
+            }
+        }
+        tree
+    }
+}